@@ -8,13 +8,10 @@ use log::debug;
 use presage::{
     prelude::phonenumber::PhoneNumber,
     prelude::{
-        content::{
-            Content, ContentBody, DataMessage, GroupContext, GroupContextV2, GroupType, SyncMessage,
-        },
-        proto::sync_message::Sent,
-        GroupMasterKey, SignalServers,
+        content::{DataMessage, GroupContext, GroupType},
+        AttachmentSpec, ServiceAddress, ServiceError, SignalServers, Uuid,
     },
-    Manager, SledConfigStore,
+    AccountChanges, Error, Event, Manager, ProvisioningStrategy, SledConfigStore,
 };
 use structopt::StructOpt;
 
@@ -60,6 +57,8 @@ enum Subcommand {
             help = "Name of the device to register in the primary client"
         )]
         device_name: String,
+        #[structopt(long, help = "Print the QR code in the terminal instead of opening it")]
+        print_qr_code: bool,
     },
     #[structopt(about = "verify the code you got from the SMS or voice-call when you registered")]
     Verify {
@@ -71,11 +70,41 @@ enum Subcommand {
     #[structopt(about = "Retrieve the user profile")]
     RetrieveProfile,
     #[structopt(about = "Sets a name, status and avatar")]
-    UpdateProfile,
+    UpdateProfile {
+        #[structopt(long, help = "Given name")]
+        given_name: Option<String>,
+        #[structopt(long, help = "Family name")]
+        family_name: Option<String>,
+        #[structopt(long, help = "About text")]
+        about: Option<String>,
+        #[structopt(long, help = "Emoji shown next to the about text")]
+        about_emoji: Option<String>,
+        #[structopt(long, help = "Path of the avatar image")]
+        avatar: Option<PathBuf>,
+    },
+    #[structopt(about = "Replace the profile key, and share the new one with contacts and groups")]
+    RotateProfileKey,
+    #[structopt(about = "Show the profile of a contact, if they shared their profile key")]
+    GetProfile {
+        #[structopt(long, short = "u", help = "UUID of the contact")]
+        uuid: Uuid,
+        #[structopt(long, help = "Path to save the avatar to")]
+        avatar: Option<PathBuf>,
+    },
     #[structopt(about = "Check if a user is registered on Signal")]
     GetUserStatus,
     #[structopt(about = "Update the account attributes")]
-    UpdateAccount,
+    UpdateAccount {
+        #[structopt(long, help = "New name of this device")]
+        device_name: Option<String>,
+        #[structopt(
+            long,
+            help = "Whether contacts can find this account from its phone number"
+        )]
+        discoverable: Option<bool>,
+        #[structopt(long, help = "Whether contacts can send sealed sender messages")]
+        unidentified_delivery: Option<bool>,
+    },
     #[structopt(about = "Block the provided contacts or groups")]
     Block,
     #[structopt(about = "Unblock the provided contacts or groups")]
@@ -99,15 +128,15 @@ enum Subcommand {
         phone_number: PhoneNumber,
         #[structopt(long, short = "m", help = "Contents of the message to send")]
         message: String,
+        #[structopt(long, short = "a", help = "Path of a file to attach to the message")]
+        attachments: Vec<PathBuf>,
     },
     #[structopt(about = "sends a message to group")]
     SendToGroup {
         #[structopt(
             long = "phone-number",
             short = "n",
-            min_values = 1,
-            required = true,
-            help = "Phone number of the recipient"
+            help = "Phone number of the recipient, for legacy groups"
         )]
         recipients: Vec<PhoneNumber>,
         #[structopt(long, short = "m", help = "Contents of the message to send")]
@@ -117,6 +146,28 @@ enum Subcommand {
         #[structopt(long, short = "k", help = "Master Key of the V2 group (hex string)")]
         master_key: Option<String>,
     },
+    #[structopt(about = "show the safety number of the conversation with a contact")]
+    SafetyNumber {
+        #[structopt(long, short = "u", help = "UUID of the contact")]
+        uuid: Uuid,
+    },
+    #[structopt(
+        about = "mark the identity of a contact as verified, after comparing safety numbers"
+    )]
+    VerifyIdentity {
+        #[structopt(long, short = "u", help = "UUID of the contact")]
+        uuid: Uuid,
+    },
+    #[structopt(about = "trust the changed identity of a contact without verifying it")]
+    TrustIdentity {
+        #[structopt(long, short = "u", help = "UUID of the contact")]
+        uuid: Uuid,
+    },
+    #[structopt(about = "reset the sessions with a contact")]
+    ResetSession {
+        #[structopt(long, short = "u", help = "UUID of the contact")]
+        uuid: Uuid,
+    },
     #[cfg(feature = "quirks")]
     RequestSyncContacts,
     #[cfg(feature = "quirks")]
@@ -141,8 +192,8 @@ async fn main() -> anyhow::Result<()> {
     debug!("opening config database from {}", db_path.display());
     let config_store = SledConfigStore::new(db_path)?;
 
-    let csprng = rand::thread_rng();
-    let mut manager = Manager::new(config_store, csprng)?;
+    let csprng = rand::rngs::OsRng;
+    let mut manager = Manager::new(config_store, csprng).await?;
 
     match args.subcommand {
         Subcommand::Register {
@@ -159,88 +210,138 @@ async fn main() -> anyhow::Result<()> {
         Subcommand::LinkDevice {
             servers,
             device_name,
+            print_qr_code,
         } => {
+            let strategy = if print_qr_code {
+                ProvisioningStrategy::PrintTerminal(Box::new(std::io::stdout()))
+            } else {
+                ProvisioningStrategy::OpenImage
+            };
             manager
-                .link_secondary_device(servers, device_name.clone())
+                .link_secondary_device(servers, device_name.clone(), strategy)
                 .await?;
         }
         Subcommand::Verify { confirmation_code } => {
             manager.confirm_verification_code(confirmation_code).await?;
         }
         Subcommand::Receive => {
-            let messages = manager
-                .clone()
-                .receive_messages()
+            let events = manager
+                .receive_events()
                 .await
                 .context("failed to initialize messages stream")?;
-            pin_mut!(messages);
-            while let Some(Content { metadata, body }) = messages.next().await {
-                match body {
-                    ContentBody::DataMessage(message)
-                    | ContentBody::SynchronizeMessage(SyncMessage {
-                        sent:
-                            Some(Sent {
-                                message: Some(message),
-                                ..
-                            }),
+            pin_mut!(events);
+            while let Some(event) = events.next().await {
+                match event {
+                    Event::Message {
+                        metadata, message, ..
+                    } => {
+                        println!("Message from {:?}: {:?}", metadata, message);
+                    }
+                    Event::Reply {
+                        metadata,
+                        quote,
+                        message,
+                        ..
+                    } => {
+                        println!(
+                            "Quote from {:?}: > {:?} / {}",
+                            metadata.sender,
+                            quote,
+                            message.body(),
+                        );
+                    }
+                    Event::Reaction {
+                        emoji,
+                        target_sent_timestamp,
+                        ..
+                    } => {
+                        println!(
+                            "Reaction to message sent at {:?}: {:?}",
+                            target_sent_timestamp, emoji,
+                        )
+                    }
+                    Event::GroupUpdate {
+                        metadata, context, ..
+                    } => {
+                        println!(
+                            "Group updated by {:?} to revision {}",
+                            metadata.sender,
+                            context.revision()
+                        );
+                    }
+                    Event::SentFromOtherDevice {
+                        destination,
+                        message,
                         ..
-                    }) => {
-                        if let Some(quote) = &message.quote {
-                            println!(
-                                "Quote from {:?}: > {:?} / {}",
-                                metadata.sender,
-                                quote,
-                                message.body().to_string(),
-                            );
-                        } else if let Some(reaction) = message.reaction {
-                            println!(
-                                "Reaction to message sent at {:?}: {:?}",
-                                reaction.target_sent_timestamp, reaction.emoji,
-                            )
-                        } else {
-                            println!("Message from {:?}: {:?}", metadata, message);
-                            // fetch the groups v2 info here, just for testing purposes
-                            if let Some(group_v2) = message.group_v2 {
-                                let _master_key = GroupMasterKey::new(
-                                    group_v2.master_key.unwrap().try_into().unwrap(),
-                                );
-                                // let group = manager.get_group_v2(master_key).await;
-                                // println!("Group v2: {:?}", group);
-                            }
-                        }
+                    } => {
+                        println!("Message sent to {:?}: {:?}", destination, message);
                     }
-                    ContentBody::SynchronizeMessage(m) => {
-                        eprintln!("Unhandled sync message: {:?}", m);
+                    Event::ContactsSynced { .. } => {
+                        println!("Contacts synchronized");
                     }
-                    ContentBody::TypingMessage(_) => {
+                    Event::TypingStarted { metadata, .. } => {
                         println!("{:?} is typing", metadata.sender);
                     }
-                    ContentBody::CallMessage(_) => {
+                    Event::CallOffer { metadata, .. } => {
                         println!("{:?} is calling!", metadata.sender);
                     }
-                    ContentBody::ReceiptMessage(_) => {
+                    Event::ReadReceipt { metadata, .. } => {
                         println!("Got read receipt from: {:?}", metadata.sender);
                     }
+                    Event::IdentityChanged {
+                        address,
+                        trust_level,
+                    } => {
+                        println!(
+                            "Safety number with {:?} changed, identity is now {:?}",
+                            address, trust_level
+                        );
+                    }
+                    event => {
+                        debug!("Unhandled event: {:?}", event);
+                    }
                 }
             }
         }
         Subcommand::Send {
             phone_number,
             message,
+            attachments,
         } => {
             let timestamp = std::time::SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time went backwards")
                 .as_millis() as u64;
 
-            let message = ContentBody::DataMessage(DataMessage {
+            let message = DataMessage {
                 body: Some(message),
                 timestamp: Some(timestamp),
                 ..Default::default()
-            });
+            };
+
+            let attachments = attachments
+                .into_iter()
+                .map(|path| {
+                    let contents = std::fs::read(&path)
+                        .with_context(|| format!("failed to read {}", path.display()))?;
+                    let spec = AttachmentSpec {
+                        content_type: "application/octet-stream".to_string(),
+                        length: contents.len(),
+                        file_name: path.file_name().map(|f| f.to_string_lossy().to_string()),
+                        preview: None,
+                        voice_note: None,
+                        borderless: None,
+                        width: None,
+                        height: None,
+                        caption: None,
+                        blur_hash: None,
+                    };
+                    Ok((spec, contents))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
 
             manager
-                .send_message(phone_number, message, timestamp)
+                .send_message_with_attachments(phone_number, message, attachments, timestamp)
                 .await?;
         }
         Subcommand::SendToGroup {
@@ -255,50 +356,143 @@ async fn main() -> anyhow::Result<()> {
                 _ => (),
             }
 
-            let group_id = group_id.map(hex::decode).transpose()?;
-            let master_key = master_key.map(hex::decode).transpose()?;
-
             let timestamp = std::time::SystemTime::now()
                 .duration_since(UNIX_EPOCH)
                 .expect("Time went backwards")
                 .as_millis() as u64;
 
-            let data_message = DataMessage {
+            let mut data_message = DataMessage {
                 body: Some(message),
                 timestamp: Some(timestamp),
-                group: group_id.map(|id| GroupContext {
-                    id: Some(id),
-                    r#type: Some(GroupType::Deliver.into()),
-                    ..Default::default()
-                }),
-                group_v2: master_key.map(|key| GroupContextV2 {
-                    master_key: Some(key),
-                    revision: Some(0),
-                    ..Default::default()
-                }),
                 ..Default::default()
             };
 
-            manager
-                .send_message_to_group(
-                    recipients.into_iter().map(Into::into),
-                    data_message,
-                    timestamp,
-                )
-                .await?;
+            if let Some(master_key) = master_key {
+                let master_key: [u8; 32] = hex::decode(master_key)?
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("master key should be 32 bytes long"))?;
+                manager
+                    .send_message_to_group(master_key, data_message, timestamp)
+                    .await?;
+            } else if let Some(group_id) = group_id {
+                data_message.group = Some(GroupContext {
+                    id: Some(hex::decode(group_id)?),
+                    r#type: Some(GroupType::Deliver.into()),
+                    ..Default::default()
+                });
+                manager
+                    .send_message_to_recipients(
+                        recipients.into_iter().map(Into::into),
+                        data_message,
+                        timestamp,
+                    )
+                    .await?;
+            }
         }
         Subcommand::Unregister => unimplemented!(),
         Subcommand::RetrieveProfile => {
             let profile = manager.retrieve_profile().await?;
             println!("{:#?}", profile);
         }
-        Subcommand::UpdateProfile => unimplemented!(),
+        Subcommand::UpdateProfile {
+            given_name,
+            family_name,
+            about,
+            about_emoji,
+            avatar,
+        } => {
+            let mut details = match manager.own_profile().await {
+                Ok(details) => details,
+                // no profile was set yet
+                Err(Error::ServiceError(ServiceError::NotFoundError)) => Default::default(),
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(given_name) = given_name {
+                details.given_name = given_name;
+            }
+            if family_name.is_some() {
+                details.family_name = family_name;
+            }
+            if about.is_some() {
+                details.about = about;
+            }
+            if about_emoji.is_some() {
+                details.about_emoji = about_emoji;
+            }
+            if let Some(avatar) = avatar {
+                details.avatar = Some(std::fs::read(avatar)?);
+            }
+            manager.set_profile(&details).await?;
+        }
+        Subcommand::RotateProfileKey => {
+            let timestamp = std::time::SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("Time went backwards")
+                .as_millis() as u64;
+            manager.rotate_profile_key(timestamp).await?;
+        }
+        Subcommand::GetProfile { uuid, avatar } => match manager.profile(uuid).await? {
+            Some(mut details) => {
+                if let (Some(path), Some(picture)) = (avatar, details.avatar.take()) {
+                    std::fs::write(path, picture)?;
+                }
+                println!("{:#?}", details);
+            }
+            None => println!("unknown profile"),
+        },
         Subcommand::GetUserStatus => unimplemented!(),
-        Subcommand::UpdateAccount => unimplemented!(),
+        Subcommand::UpdateAccount {
+            device_name,
+            discoverable,
+            unidentified_delivery,
+        } => {
+            let mut changes = AccountChanges::default();
+            if let Some(device_name) = device_name {
+                changes = changes.device_name(device_name);
+            }
+            if let Some(discoverable) = discoverable {
+                changes = changes.discoverable_by_phone_number(discoverable);
+            }
+            if let Some(unidentified_delivery) = unidentified_delivery {
+                changes = changes.unidentified_delivery(unidentified_delivery);
+            }
+            manager.update_account(changes).await?;
+            println!("{:#?}", manager.account_attributes()?);
+        }
         Subcommand::Block => unimplemented!(),
         Subcommand::Unblock => unimplemented!(),
         Subcommand::UpdateContact => unimplemented!(),
-        Subcommand::ListGroups => unimplemented!(),
+        Subcommand::ListGroups => {
+            for (master_key, group) in manager.groups().await? {
+                println!(
+                    "{}: {} (revision {}, {} members)",
+                    hex::encode(master_key),
+                    group.title,
+                    group.revision,
+                    group.members.len()
+                );
+            }
+        }
+        Subcommand::SafetyNumber { uuid } => {
+            let contact = contact_address(uuid);
+            println!("{}", manager.safety_number(&contact).await?);
+            println!("{:?}", manager.trust_level(&contact).await?);
+        }
+        Subcommand::VerifyIdentity { uuid } => {
+            manager
+                .verify_identity(&contact_address(uuid), timestamp())
+                .await?;
+        }
+        Subcommand::TrustIdentity { uuid } => {
+            manager
+                .trust_identity(&contact_address(uuid), timestamp())
+                .await?;
+        }
+        Subcommand::ResetSession { uuid } => {
+            manager
+                .reset_session(&contact_address(uuid), timestamp())
+                .await?;
+        }
         Subcommand::Whoami => {
             println!("{:?}", &manager.whoami().await?)
         }
@@ -307,7 +501,8 @@ async fn main() -> anyhow::Result<()> {
             ref name,
         } => {
             for contact in manager
-                .get_contacts()?
+                .get_contacts()
+                .await?
                 .filter(|c| {
                     phone_number
                         .as_ref()
@@ -329,3 +524,18 @@ async fn main() -> anyhow::Result<()> {
     };
     Ok(())
 }
+
+fn contact_address(uuid: Uuid) -> ServiceAddress {
+    ServiceAddress {
+        uuid: Some(uuid),
+        phonenumber: None,
+        relay: None,
+    }
+}
+
+fn timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
@@ -1,14 +1,18 @@
-use presage::{prelude::SignalServers, Manager, SledConfigStore};
+use presage::{prelude::SignalServers, Manager, ProvisioningStrategy, SledConfigStore};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let config_store = SledConfigStore::new("/tmp/presage-example")?;
 
-    let csprng = rand::thread_rng();
-    let mut manager = Manager::new(config_store, csprng)?;
+    let csprng = rand::rngs::OsRng;
+    let mut manager = Manager::new(config_store, csprng).await?;
 
     manager
-        .link_secondary_device(SignalServers::Production, "my-linked-client".into())
+        .link_secondary_device(
+            SignalServers::Production,
+            "my-linked-client".into(),
+            ProvisioningStrategy::OpenImage,
+        )
         .await?;
 
     // scan the QR code that's being opened with your main device
@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+use anyhow::bail;
+use env_logger::Env;
+use presage::{SledConfigStore, SqliteConfigStore};
+use structopt::StructOpt;
+
+#[derive(StructOpt)]
+#[structopt(about = "copy a sled store to a new SQLite database")]
+struct Args {
+    #[structopt(long = "sled-path", help = "Path of the existing sled store")]
+    sled_path: PathBuf,
+    #[structopt(long = "sqlite-path", help = "Path of the SQLite database to create")]
+    sqlite_path: PathBuf,
+    #[structopt(long, help = "Passphrase the sled store is encrypted with, if any")]
+    passphrase: Option<String>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    env_logger::from_env(
+        Env::default().default_filter_or(format!("{}=info", env!("CARGO_PKG_NAME"))),
+    )
+    .init();
+
+    let args = Args::from_args();
+    if args.sqlite_path.exists() {
+        bail!("{} already exists", args.sqlite_path.display());
+    }
+
+    let sled = match args.passphrase {
+        Some(passphrase) => SledConfigStore::with_passphrase(args.sled_path, passphrase)?,
+        None => SledConfigStore::new(args.sled_path)?,
+    };
+    let mut sqlite = SqliteConfigStore::new(&args.sqlite_path)?;
+    sqlite.import_sled(&sled).await?;
+
+    println!("migrated store to {}", args.sqlite_path.display());
+    Ok(())
+}
@@ -20,8 +20,8 @@ fn read_line() -> String {
 async fn main() -> anyhow::Result<()> {
     let config_store = SledConfigStore::new("/tmp/presage-example")?;
 
-    let csprng = rand::thread_rng();
-    let mut manager = Manager::new(config_store, csprng)?;
+    let csprng = rand::rngs::OsRng;
+    let mut manager = Manager::new(config_store, csprng).await?;
 
     println!("phone number: ");
     let phone_number: PhoneNumber = io::stdin()
@@ -0,0 +1,161 @@
+use libsignal_service::push_service::DeviceCapabilities;
+use serde::{Deserialize, Serialize};
+
+/// Features of Signal this client supports, which other clients and the server check before
+/// using them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Groups v2
+    pub gv2: bool,
+    /// Storage service, to synchronize contacts and settings with other devices
+    pub storage: bool,
+    /// Migration of groups v1 to groups v2
+    pub gv1_migration: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            gv2: true,
+            storage: false,
+            gv1_migration: true,
+        }
+    }
+}
+
+impl From<Capabilities> for DeviceCapabilities {
+    fn from(capabilities: Capabilities) -> Self {
+        DeviceCapabilities {
+            uuid: true,
+            gv2: capabilities.gv2,
+            storage: capabilities.storage,
+            gv1_migration: capabilities.gv1_migration,
+        }
+    }
+}
+
+/// Attributes of this device sent to Signal servers, see [Manager::update_account](crate::Manager::update_account).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountAttributes {
+    /// Whether messages are fetched through the message pipe, instead of push notifications
+    pub fetches_messages: bool,
+    /// Whether contacts can find the account from its phone number
+    pub discoverable_by_phone_number: bool,
+    /// Whether contacts having our profile key can send us sealed sender messages
+    pub unidentified_delivery: bool,
+    /// Whether anyone can send us sealed sender messages, even without our profile key
+    pub unrestricted_unidentified_access: bool,
+    pub capabilities: Capabilities,
+}
+
+impl Default for AccountAttributes {
+    fn default() -> Self {
+        Self {
+            fetches_messages: true,
+            discoverable_by_phone_number: true,
+            unidentified_delivery: false,
+            unrestricted_unidentified_access: false,
+            capabilities: Default::default(),
+        }
+    }
+}
+
+/// Changes to the attributes of this device, see [Manager::update_account](crate::Manager::update_account).
+///
+/// Attributes which are not changed keep their current value.
+#[derive(Debug, Clone, Default)]
+pub struct AccountChanges {
+    device_name: Option<String>,
+    fetches_messages: Option<bool>,
+    discoverable_by_phone_number: Option<bool>,
+    unidentified_delivery: Option<bool>,
+    unrestricted_unidentified_access: Option<bool>,
+    capabilities: Option<Capabilities>,
+}
+
+impl AccountChanges {
+    /// Renames this device, as shown in the list of linked devices.
+    pub fn device_name(mut self, name: impl Into<String>) -> Self {
+        self.device_name = Some(name.into());
+        self
+    }
+
+    pub fn fetches_messages(mut self, fetches_messages: bool) -> Self {
+        self.fetches_messages = Some(fetches_messages);
+        self
+    }
+
+    pub fn discoverable_by_phone_number(mut self, discoverable: bool) -> Self {
+        self.discoverable_by_phone_number = Some(discoverable);
+        self
+    }
+
+    pub fn unidentified_delivery(mut self, enabled: bool) -> Self {
+        self.unidentified_delivery = Some(enabled);
+        self
+    }
+
+    pub fn unrestricted_unidentified_access(mut self, unrestricted: bool) -> Self {
+        self.unrestricted_unidentified_access = Some(unrestricted);
+        self
+    }
+
+    pub fn capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = Some(capabilities);
+        self
+    }
+
+    /// The new device name, if it changes.
+    pub(crate) fn new_device_name(&self) -> Option<&str> {
+        self.device_name.as_deref()
+    }
+
+    /// Applies the changes to the current attributes.
+    pub(crate) fn apply(&self, attributes: &mut AccountAttributes) {
+        if let Some(fetches_messages) = self.fetches_messages {
+            attributes.fetches_messages = fetches_messages;
+        }
+        if let Some(discoverable) = self.discoverable_by_phone_number {
+            attributes.discoverable_by_phone_number = discoverable;
+        }
+        if let Some(enabled) = self.unidentified_delivery {
+            attributes.unidentified_delivery = enabled;
+        }
+        if let Some(unrestricted) = self.unrestricted_unidentified_access {
+            attributes.unrestricted_unidentified_access = unrestricted;
+        }
+        if let Some(capabilities) = self.capabilities {
+            attributes.capabilities = capabilities;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_apply() {
+        let mut attributes = AccountAttributes::default();
+        AccountChanges::default()
+            .discoverable_by_phone_number(false)
+            .capabilities(Capabilities {
+                storage: true,
+                ..Default::default()
+            })
+            .apply(&mut attributes);
+
+        assert_eq!(
+            attributes,
+            AccountAttributes {
+                discoverable_by_phone_number: false,
+                capabilities: Capabilities {
+                    gv2: true,
+                    storage: true,
+                    gv1_migration: true,
+                },
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -1,15 +1,14 @@
-use std::cell::Cell;
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
+/// A lazily initialized value, which can be shared between threads.
 pub(crate) struct CacheCell<T: Clone> {
-    cell: Cell<Option<T>>,
+    cell: Mutex<Option<T>>,
 }
 
 impl<T: Clone> Clone for CacheCell<T> {
     fn clone(&self) -> Self {
-        let value = self.cell.replace(None);
-        self.cell.set(value.clone());
         Self {
-            cell: Cell::new(value),
+            cell: Mutex::new(self.lock().clone()),
         }
     }
 }
@@ -17,23 +16,31 @@ impl<T: Clone> Clone for CacheCell<T> {
 impl<T: Clone> Default for CacheCell<T> {
     fn default() -> Self {
         Self {
-            cell: Cell::new(None),
+            cell: Mutex::new(None),
         }
     }
 }
 
 impl<T: Clone> CacheCell<T> {
     pub fn get<E>(&self, factory: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
-        let value = match self.cell.replace(None) {
-            Some(value) => value,
-            None => factory()?,
-        };
-        self.cell.set(Some(value.clone()));
-        Ok(value)
+        let mut cell = self.lock();
+        match &*cell {
+            Some(value) => Ok(value.clone()),
+            None => {
+                let value = factory()?;
+                *cell = Some(value.clone());
+                Ok(value)
+            }
+        }
     }
 
     pub fn clear(&self) {
-        self.cell.set(None)
+        *self.lock() = None;
+    }
+
+    // a cached value is either set or not, a panic while holding the lock can't leave it broken
+    fn lock(&self) -> MutexGuard<'_, Option<T>> {
+        self.cell.lock().unwrap_or_else(PoisonError::into_inner)
     }
 }
 
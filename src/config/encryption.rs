@@ -0,0 +1,137 @@
+use std::fmt;
+
+use chacha20poly1305::{
+    aead::{Aead, NewAead, Payload},
+    Key, XChaCha20Poly1305, XNonce,
+};
+use hmac::Hmac;
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+
+use crate::Error;
+
+const NONCE_LEN: usize = 24;
+const PBKDF2_ROUNDS: u32 = 100_000;
+
+pub(crate) const SALT_LEN: usize = 16;
+
+/// Authenticated encryption of store values.
+///
+/// Every value is encrypted with XChaCha20-Poly1305 using a random nonce, which is prepended to the
+/// ciphertext. The key under which the value is stored is used as associated data, so values
+/// cannot be swapped between keys without being noticed.
+pub(crate) struct StoreCipher {
+    key: [u8; 32],
+    cipher: XChaCha20Poly1305,
+}
+
+impl fmt::Debug for StoreCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StoreCipher").finish()
+    }
+}
+
+impl StoreCipher {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        }
+    }
+
+    /// Generates a new random key.
+    pub fn generate_key() -> [u8; 32] {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        key
+    }
+
+    /// Generates a new random salt to derive keys from passphrases.
+    pub fn generate_salt() -> [u8; SALT_LEN] {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    }
+
+    /// Derives a key from a passphrase with PBKDF2-HMAC-SHA256.
+    pub fn derive_key(passphrase: &str, salt: &[u8]) -> [u8; 32] {
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, PBKDF2_ROUNDS, &mut key);
+        key
+    }
+
+    /// The raw key used by this cipher.
+    pub fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    pub fn encrypt(&self, associated_data: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| Error::StoreEncryptionError("failed to encrypt value".into()))?;
+
+        let mut data = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        data.extend_from_slice(&nonce);
+        data.extend_from_slice(&ciphertext);
+        Ok(data)
+    }
+
+    pub fn decrypt(&self, associated_data: &[u8], data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < NONCE_LEN {
+            return Err(Error::StoreEncryptionError(
+                "encrypted value is too short".into(),
+            ));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        self.cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: associated_data,
+                },
+            )
+            .map_err(|_| Error::StoreEncryptionError("failed to decrypt value".into()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::StoreCipher;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let cipher = StoreCipher::new(StoreCipher::generate_key());
+        let data = cipher.encrypt(b"key", b"Hello, World!").unwrap();
+        assert_ne!(&data[data.len() - 13..], b"Hello, World!");
+        assert_eq!(cipher.decrypt(b"key", &data).unwrap(), b"Hello, World!");
+
+        // values are bound to their key, and to the cipher key
+        assert!(cipher.decrypt(b"other-key", &data).is_err());
+        let other_cipher = StoreCipher::new(StoreCipher::generate_key());
+        assert!(other_cipher.decrypt(b"key", &data).is_err());
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let salt = StoreCipher::generate_salt();
+        assert_eq!(
+            StoreCipher::derive_key("passphrase", &salt),
+            StoreCipher::derive_key("passphrase", &salt)
+        );
+        assert_ne!(
+            StoreCipher::derive_key("passphrase", &salt),
+            StoreCipher::derive_key("other passphrase", &salt)
+        );
+    }
+}
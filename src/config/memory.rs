@@ -0,0 +1,799 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryInto,
+    ops::RangeBounds,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+
+use async_trait::async_trait;
+use libsignal_service::{
+    groups_v2::{CredentialsCache, CredentialsCacheError},
+    models::Contact,
+    prelude::{
+        protocol::{
+            Context, Direction, IdentityKey, IdentityKeyPair, IdentityKeyStore, PreKeyRecord,
+            PreKeyStore, ProtocolAddress, SessionRecord, SessionStore, SessionStoreExt,
+            SignalProtocolError, SignedPreKeyRecord, SignedPreKeyStore,
+        },
+        Content, ProtobufMessage, Uuid,
+    },
+    proto::DecryptedGroup,
+    push_service::DEFAULT_DEVICE_ID,
+};
+use log::{trace, warn};
+use serde::{Deserialize, Serialize};
+use zkgroup::auth::AuthCredentialResponse;
+
+use super::{
+    deserialize_content, serialize_content, ConfigStore, ContactsStore, GroupsStore,
+    IdentityChange, MessageStore, ProfilesStore, StoredProfile, Thread, TrustLevel, TrustStore,
+};
+use crate::{manager::State, Error};
+
+/// A store keeping everything in memory, for tests and short-lived clients.
+///
+/// Clones share the same data. The whole store can be saved with [MemoryConfigStore::snapshot]
+/// and loaded back with [MemoryConfigStore::restore].
+#[derive(Debug, Clone, Default)]
+pub struct MemoryConfigStore {
+    data: Arc<RwLock<Data>>,
+}
+
+/// Records are kept serialized like in the other stores, as most types can't be cloned.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Data {
+    /// JSON encoded [State]
+    state: Option<Vec<u8>>,
+    pre_keys_offset_id: u32,
+    next_signed_pre_key_id: u32,
+    contacts: Vec<Contact>,
+    pre_keys: HashMap<u32, Vec<u8>>,
+    signed_pre_keys: HashMap<u32, Vec<u8>>,
+    /// Sessions, by address name and device id
+    sessions: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+    /// Identity keys, by address name and device id
+    identities: HashMap<String, BTreeMap<u32, Vec<u8>>>,
+    trust_levels: HashMap<String, TrustLevel>,
+    identity_changes: BTreeMap<String, TrustLevel>,
+    messages: HashMap<Thread, BTreeMap<u64, Vec<u8>>>,
+    messages_by_sender: HashMap<(Uuid, u64), Thread>,
+    groups: HashMap<[u8; 32], Vec<u8>>,
+    /// bincode encoded groups v2 auth credentials, by day
+    credentials: BTreeMap<i64, Vec<u8>>,
+    profiles: HashMap<Uuid, StoredProfile>,
+}
+
+/// Starts snapshots, followed by their version
+const SNAPSHOT_MAGIC: &[u8] = b"presage-memory";
+
+/// Version of the snapshots written by [MemoryConfigStore::snapshot], bumped whenever [Data]
+/// changes.
+const SNAPSHOT_VERSION: u32 = 1;
+
+impl MemoryConfigStore {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Serializes the whole store.
+    pub fn snapshot(&self) -> Result<Vec<u8>, Error> {
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        snapshot.extend_from_slice(&SNAPSHOT_VERSION.to_be_bytes());
+        bincode::serialize_into(&mut snapshot, &*self.data())?;
+        Ok(snapshot)
+    }
+
+    /// Creates a store from a [MemoryConfigStore::snapshot].
+    ///
+    /// Snapshots written in another version of the format are rejected with
+    /// [Error::UnsupportedSchemaVersion].
+    pub fn restore(snapshot: &[u8]) -> Result<Self, Error> {
+        let versioned = snapshot.strip_prefix(SNAPSHOT_MAGIC).ok_or_else(|| {
+            Error::BincodeError(Box::new(bincode::ErrorKind::Custom(
+                "not a memory store snapshot".into(),
+            )))
+        })?;
+        let version = versioned.get(..4).unwrap_or_default().try_into()?;
+        let data = match u32::from_be_bytes(version) {
+            SNAPSHOT_VERSION => bincode::deserialize(&versioned[4..])?,
+            version => return Err(Error::UnsupportedSchemaVersion(version)),
+        };
+        Ok(Self {
+            data: Arc::new(RwLock::new(data)),
+        })
+    }
+
+    /// Identity key of this device of a contact, or of any other device when this one is new: an
+    /// account has a single identity key, shared by all its devices.
+    fn known_identity(
+        &self,
+        address: &ProtocolAddress,
+    ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        let data = self.data();
+        let identities = match data.identities.get(address.name()) {
+            Some(identities) => identities,
+            None => return Ok(None),
+        };
+        identities
+            .get(&address.device_id())
+            .or_else(|| identities.values().next())
+            .map(|buf| IdentityKey::decode(buf))
+            .transpose()
+    }
+
+    // the data is always left consistent, so a panic while holding the lock can be ignored
+    fn data(&self) -> RwLockReadGuard<'_, Data> {
+        self.data.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn data_mut(&self) -> RwLockWriteGuard<'_, Data> {
+        self.data.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[async_trait]
+impl ConfigStore for MemoryConfigStore {
+    async fn state(&self) -> Result<State, Error> {
+        self.data()
+            .state
+            .as_ref()
+            .map_or(Ok(State::New), |s| Ok(serde_json::from_slice(s)?))
+    }
+
+    async fn save(&self, state: &State) -> Result<(), Error> {
+        self.data_mut().state = Some(serde_json::to_vec(state)?);
+        Ok(())
+    }
+
+    async fn reset(&mut self) -> Result<(), Error> {
+        let mut data = self.data_mut();
+        *data = Data {
+            messages: std::mem::take(&mut data.messages),
+            messages_by_sender: std::mem::take(&mut data.messages_by_sender),
+            groups: std::mem::take(&mut data.groups),
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    async fn pre_keys_offset_id(&self) -> Result<u32, Error> {
+        Ok(self.data().pre_keys_offset_id)
+    }
+
+    async fn set_pre_keys_offset_id(&self, id: u32) -> Result<(), Error> {
+        self.data_mut().pre_keys_offset_id = id;
+        Ok(())
+    }
+
+    async fn next_signed_pre_key_id(&self) -> Result<u32, Error> {
+        Ok(self.data().next_signed_pre_key_id)
+    }
+
+    async fn set_next_signed_pre_key_id(&self, id: u32) -> Result<(), Error> {
+        self.data_mut().next_signed_pre_key_id = id;
+        Ok(())
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: u32) -> Result<(), Error> {
+        self.data_mut().signed_pre_keys.remove(&id);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ContactsStore for MemoryConfigStore {
+    async fn save_contacts(&mut self, contacts: &[Contact]) -> Result<(), Error> {
+        self.data_mut().contacts = contacts.to_vec();
+        Ok(())
+    }
+
+    async fn contacts(&self) -> Result<Vec<Contact>, Error> {
+        Ok(self.data().contacts.clone())
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryConfigStore {
+    type MessagesIter = std::vec::IntoIter<Result<Content, Error>>;
+
+    async fn save_message(&mut self, thread: &Thread, message: Content) -> Result<(), Error> {
+        let timestamp = message.metadata.timestamp;
+        let value = serialize_content(&message)?;
+        let mut data = self.data_mut();
+        let replaced = data
+            .messages
+            .entry(*thread)
+            .or_default()
+            .insert(timestamp, value);
+        if let Some(replaced) = replaced {
+            if let Some(sender) = deserialize_content(&replaced)?.metadata.sender.uuid {
+                if data.messages_by_sender.get(&(sender, timestamp)) == Some(thread) {
+                    data.messages_by_sender.remove(&(sender, timestamp));
+                }
+            }
+        }
+        if let Some(sender) = message.metadata.sender.uuid {
+            data.messages_by_sender.insert((sender, timestamp), *thread);
+        }
+        Ok(())
+    }
+
+    async fn delete_message(&mut self, thread: &Thread, timestamp: u64) -> Result<bool, Error> {
+        let mut data = self.data_mut();
+        let value = match data
+            .messages
+            .get_mut(thread)
+            .and_then(|messages| messages.remove(&timestamp))
+        {
+            Some(value) => value,
+            None => return Ok(false),
+        };
+        if let Some(sender) = deserialize_content(&value)?.metadata.sender.uuid {
+            data.messages_by_sender.remove(&(sender, timestamp));
+        }
+        Ok(true)
+    }
+
+    async fn message(&self, thread: &Thread, timestamp: u64) -> Result<Option<Content>, Error> {
+        self.data()
+            .messages
+            .get(thread)
+            .and_then(|messages| messages.get(&timestamp))
+            .map(|value| deserialize_content(value))
+            .transpose()
+    }
+
+    async fn message_by_sender(
+        &self,
+        sender: &Uuid,
+        timestamp: u64,
+    ) -> Result<Option<(Thread, Content)>, Error> {
+        let thread = match self.data().messages_by_sender.get(&(*sender, timestamp)) {
+            Some(thread) => *thread,
+            None => return Ok(None),
+        };
+        Ok(self
+            .message(&thread, timestamp)
+            .await?
+            .map(|message| (thread, message)))
+    }
+
+    async fn messages(
+        &self,
+        thread: &Thread,
+        range: impl RangeBounds<u64> + Send,
+    ) -> Result<Self::MessagesIter, Error> {
+        let messages: Vec<_> = match self.data().messages.get(thread) {
+            Some(messages) => messages
+                .range(range)
+                .map(|(_, value)| deserialize_content(value))
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok(messages.into_iter())
+    }
+}
+
+#[async_trait]
+impl GroupsStore for MemoryConfigStore {
+    async fn save_group(
+        &mut self,
+        master_key: &[u8; 32],
+        group: &DecryptedGroup,
+    ) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        group
+            .encode(&mut buf)
+            .expect("encoding into a vector never fails");
+        self.data_mut().groups.insert(*master_key, buf);
+        Ok(())
+    }
+
+    async fn delete_group(&mut self, master_key: &[u8; 32]) -> Result<bool, Error> {
+        Ok(self.data_mut().groups.remove(master_key).is_some())
+    }
+
+    async fn group(&self, master_key: &[u8; 32]) -> Result<Option<DecryptedGroup>, Error> {
+        self.data()
+            .groups
+            .get(master_key)
+            .map(|buf| decode_group(buf))
+            .transpose()
+    }
+
+    async fn groups(&self) -> Result<Vec<([u8; 32], DecryptedGroup)>, Error> {
+        self.data()
+            .groups
+            .iter()
+            .map(|(master_key, buf)| Ok((*master_key, decode_group(buf)?)))
+            .collect()
+    }
+}
+
+fn decode_group(buf: &[u8]) -> Result<DecryptedGroup, Error> {
+    DecryptedGroup::decode(buf).map_err(|e| {
+        log::error!("failed to decode stored group: {}", e);
+        Error::MessageDecodeError
+    })
+}
+
+#[async_trait]
+impl TrustStore for MemoryConfigStore {
+    async fn trust_level(&self, name: &str) -> Result<Option<TrustLevel>, Error> {
+        Ok(self.data().trust_levels.get(name).copied())
+    }
+
+    async fn set_trust_level(&mut self, name: &str, level: TrustLevel) -> Result<(), Error> {
+        self.data_mut().trust_levels.insert(name.to_string(), level);
+        Ok(())
+    }
+
+    async fn take_identity_changes(&mut self) -> Result<Vec<IdentityChange>, Error> {
+        Ok(std::mem::take(&mut self.data_mut().identity_changes)
+            .into_iter()
+            .map(|(name, trust_level)| IdentityChange { name, trust_level })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ProfilesStore for MemoryConfigStore {
+    async fn save_profile(&mut self, uuid: &Uuid, profile: &StoredProfile) -> Result<(), Error> {
+        self.data_mut().profiles.insert(*uuid, profile.clone());
+        Ok(())
+    }
+
+    async fn profile(&self, uuid: &Uuid) -> Result<Option<StoredProfile>, Error> {
+        Ok(self.data().profiles.get(uuid).cloned())
+    }
+}
+
+impl CredentialsCache for MemoryConfigStore {
+    fn clear(&mut self) -> Result<(), CredentialsCacheError> {
+        self.data_mut().credentials.clear();
+        Ok(())
+    }
+
+    fn get(&self, key: &i64) -> Result<Option<AuthCredentialResponse>, CredentialsCacheError> {
+        self.data()
+            .credentials
+            .get(key)
+            .map(|buf| bincode::deserialize(buf))
+            .transpose()
+            .map_err(|e| {
+                log::error!("failed to read groups credential: {}", e);
+                CredentialsCacheError::ReadError
+            })
+    }
+
+    fn write(
+        &mut self,
+        map: HashMap<i64, AuthCredentialResponse>,
+    ) -> Result<(), CredentialsCacheError> {
+        let credentials = map
+            .into_iter()
+            .map(|(day, credential)| Ok((day, bincode::serialize(&credential)?)))
+            .collect::<Result<Vec<_>, bincode::Error>>()
+            .map_err(|e| {
+                log::error!("failed to write groups credentials: {}", e);
+                CredentialsCacheError::WriteError
+            })?;
+        let mut data = self.data_mut();
+        // new credentials are fetched from today, the ones of previous days have expired
+        if let Some((oldest, _)) = credentials.iter().min_by_key(|(day, _)| *day) {
+            data.credentials = data.credentials.split_off(oldest);
+        }
+        data.credentials.extend(credentials);
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl PreKeyStore for MemoryConfigStore {
+    async fn get_pre_key(
+        &self,
+        prekey_id: u32,
+        _ctx: Context,
+    ) -> Result<PreKeyRecord, SignalProtocolError> {
+        let data = self.data();
+        let buf = data
+            .pre_keys
+            .get(&prekey_id)
+            .ok_or(SignalProtocolError::InvalidPreKeyId)?;
+        PreKeyRecord::deserialize(buf)
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: u32,
+        record: &PreKeyRecord,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        let record = record.serialize()?;
+        self.data_mut().pre_keys.insert(prekey_id, record);
+        Ok(())
+    }
+
+    async fn remove_pre_key(
+        &mut self,
+        prekey_id: u32,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        self.data_mut().pre_keys.remove(&prekey_id);
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl SignedPreKeyStore for MemoryConfigStore {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: u32,
+        _ctx: Context,
+    ) -> Result<SignedPreKeyRecord, SignalProtocolError> {
+        let data = self.data();
+        let buf = data
+            .signed_pre_keys
+            .get(&signed_prekey_id)
+            .ok_or(SignalProtocolError::InvalidSignedPreKeyId)?;
+        SignedPreKeyRecord::deserialize(buf)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: u32,
+        record: &SignedPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        let record = record.serialize()?;
+        self.data_mut()
+            .signed_pre_keys
+            .insert(signed_prekey_id, record);
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for MemoryConfigStore {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+        trace!("loading session of {}", address);
+        self.data()
+            .sessions
+            .get(address.name())
+            .and_then(|sessions| sessions.get(&address.device_id()))
+            .map(|buf| SessionRecord::deserialize(buf))
+            .transpose()
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        trace!("storing session of {}", address);
+        let record = record.serialize()?;
+        self.data_mut()
+            .sessions
+            .entry(address.name().to_string())
+            .or_default()
+            .insert(address.device_id(), record);
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStoreExt for MemoryConfigStore {
+    async fn get_sub_device_sessions(&self, name: &str) -> Result<Vec<u32>, SignalProtocolError> {
+        Ok(self
+            .data()
+            .sessions
+            .get(name)
+            .map(|sessions| {
+                sessions
+                    .keys()
+                    .copied()
+                    .filter(|device_id| *device_id != DEFAULT_DEVICE_ID)
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn delete_session(&self, address: &ProtocolAddress) -> Result<(), SignalProtocolError> {
+        trace!("deleting session of {}", address);
+        if let Some(sessions) = self.data_mut().sessions.get_mut(address.name()) {
+            sessions.remove(&address.device_id());
+        }
+        Ok(())
+    }
+
+    async fn delete_all_sessions(&self, name: &str) -> Result<usize, SignalProtocolError> {
+        Ok(self
+            .data_mut()
+            .sessions
+            .remove(name)
+            .map_or(0, |sessions| sessions.len()))
+    }
+}
+
+#[async_trait(?Send)]
+impl IdentityKeyStore for MemoryConfigStore {
+    async fn get_identity_key_pair(
+        &self,
+        _ctx: Context,
+    ) -> Result<IdentityKeyPair, SignalProtocolError> {
+        match self.state().await {
+            Ok(State::Registered {
+                private_key,
+                public_key,
+                ..
+            }) => Ok(IdentityKeyPair::new(
+                IdentityKey::new(public_key),
+                private_key,
+            )),
+            Ok(_) => Err(SignalProtocolError::InternalError(
+                "wrong state: no registration data yet",
+            )),
+            Err(e) => {
+                log::error!("identity key store error: {}", e);
+                Err(SignalProtocolError::InternalError("unhandled error"))
+            }
+        }
+    }
+
+    async fn get_local_registration_id(&self, _ctx: Context) -> Result<u32, SignalProtocolError> {
+        match self.state().await {
+            Ok(State::Registered {
+                registration_id, ..
+            }) => Ok(registration_id),
+            Ok(_) => Err(SignalProtocolError::InternalError(
+                "wrong state: no registration data yet",
+            )),
+            Err(e) => {
+                log::error!("identity key store error: {}", e);
+                Err(SignalProtocolError::InternalError("unhandled error"))
+            }
+        }
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity_key: &IdentityKey,
+        _ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        let name = address.name();
+        let previous = self.known_identity(address)?;
+
+        let mut data = self.data_mut();
+        let identities = data.identities.entry(name.to_string()).or_default();
+        let key = identity_key.serialize().into_vec();
+        if identities.get(&address.device_id()) == Some(&key) {
+            return Ok(false);
+        }
+        identities.insert(address.device_id(), key);
+        let replaced = previous.map_or(false, |previous| previous != *identity_key);
+        let current = data.trust_levels.get(name).copied();
+        if let Some(trust_level) = TrustLevel::after_save(replaced, current) {
+            warn!("trusting identity of {}: {:?}", name, trust_level);
+            data.trust_levels.insert(name.to_string(), trust_level);
+            if replaced {
+                data.identity_changes.insert(name.to_string(), trust_level);
+            }
+        }
+        Ok(replaced)
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity_key: &IdentityKey,
+        direction: Direction,
+        _ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        match self.known_identity(address)? {
+            // when we encounter a new identity, we trust it on first use
+            None => Ok(true),
+            Some(stored) => {
+                let trust_level = self
+                    .data()
+                    .trust_levels
+                    .get(address.name())
+                    .copied()
+                    .unwrap_or(TrustLevel::TrustedOnFirstUse);
+                Ok(trust_level.trusts(&stored == identity_key, direction))
+            }
+        }
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        self.data()
+            .identities
+            .get(address.name())
+            .and_then(|identities| identities.get(&address.device_id()))
+            .map(|buf| IdentityKey::decode(buf))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libsignal_service::{
+        content::{ContentBody, DataMessage, Metadata},
+        prelude::protocol::KeyPair,
+        ServiceAddress,
+    };
+
+    use super::*;
+
+    fn content(sender: Uuid, timestamp: u64) -> Content {
+        Content {
+            metadata: Metadata {
+                sender: ServiceAddress {
+                    uuid: Some(sender),
+                    phonenumber: None,
+                    relay: None,
+                },
+                sender_device: 1,
+                timestamp,
+                needs_receipt: false,
+            },
+            body: ContentBody::DataMessage(DataMessage {
+                body: Some(timestamp.to_string()),
+                timestamp: Some(timestamp),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_restore() {
+        let mut store = MemoryConfigStore::new();
+        let address = ProtocolAddress::new("contact".into(), 2);
+        let key_pair = KeyPair::generate(&mut rand::thread_rng());
+        let identity_key = IdentityKey::new(key_pair.public_key);
+        let thread = Thread::Contact(Uuid::nil());
+
+        store
+            .save_pre_key(1, &PreKeyRecord::new(1, &key_pair), None)
+            .await
+            .unwrap();
+        store
+            .store_session(&address, &SessionRecord::new_fresh(), None)
+            .await
+            .unwrap();
+        store
+            .save_identity(&address, &identity_key, None)
+            .await
+            .unwrap();
+        store.set_pre_keys_offset_id(42).await.unwrap();
+        for timestamp in 1..=3 {
+            store
+                .save_message(&thread, content(Uuid::nil(), timestamp))
+                .await
+                .unwrap();
+        }
+
+        let restored = MemoryConfigStore::restore(&store.snapshot().unwrap()).unwrap();
+        assert!(restored.get_pre_key(1, None).await.is_ok());
+        assert!(restored
+            .load_session(&address, None)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            restored.get_sub_device_sessions("contact").await.unwrap(),
+            vec![2]
+        );
+        assert_eq!(
+            restored.get_identity(&address, None).await.unwrap(),
+            Some(identity_key)
+        );
+        assert_eq!(
+            restored.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::TrustedOnFirstUse)
+        );
+        assert_eq!(restored.pre_keys_offset_id().await.unwrap(), 42);
+
+        let timestamps: Vec<_> = restored
+            .messages(&thread, 2..)
+            .await
+            .unwrap()
+            .rev()
+            .map(|message| message.unwrap().metadata.timestamp)
+            .collect();
+        assert_eq!(timestamps, vec![3, 2]);
+        assert!(restored
+            .message_by_sender(&Uuid::nil(), 1)
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_restore_other_snapshots() {
+        let store = MemoryConfigStore::new();
+        store.set_pre_keys_offset_id(42).await.unwrap();
+        let snapshot = store.snapshot().unwrap();
+
+        // without the magic bytes and version
+        let unversioned = &snapshot[SNAPSHOT_MAGIC.len() + 4..];
+        assert!(matches!(
+            MemoryConfigStore::restore(unversioned),
+            Err(Error::BincodeError(_))
+        ));
+
+        let mut newer = snapshot;
+        newer[SNAPSHOT_MAGIC.len() + 3] += 1;
+        assert!(matches!(
+            MemoryConfigStore::restore(&newer),
+            Err(Error::UnsupportedSchemaVersion(v)) if v == SNAPSHOT_VERSION + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_identity_change_on_new_device() {
+        let mut db = MemoryConfigStore::new();
+        let identity_key =
+            || IdentityKey::new(KeyPair::generate(&mut rand::thread_rng()).public_key);
+        let (first, second) = (identity_key(), identity_key());
+        let device = |id| ProtocolAddress::new("contact".into(), id);
+        db.save_identity(&device(1), &first, None).await.unwrap();
+        db.set_trust_level("contact", TrustLevel::Verified)
+            .await
+            .unwrap();
+
+        // another device with the same identity key
+        assert!(db
+            .is_trusted_identity(&device(2), &first, Direction::Sending, None)
+            .await
+            .unwrap());
+        assert!(!db.save_identity(&device(2), &first, None).await.unwrap());
+        assert_eq!(
+            db.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::Verified)
+        );
+
+        // a new device showing another key is a change of identity
+        assert!(!db
+            .is_trusted_identity(&device(3), &second, Direction::Sending, None)
+            .await
+            .unwrap());
+        assert!(db.save_identity(&device(3), &second, None).await.unwrap());
+        assert_eq!(
+            db.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::Untrusted)
+        );
+        assert_eq!(
+            db.take_identity_changes().await.unwrap(),
+            vec![IdentityChange {
+                name: "contact".into(),
+                trust_level: TrustLevel::Untrusted,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clones_share_data() {
+        let mut store = MemoryConfigStore::new();
+        let clone = store.clone();
+        store
+            .store_session(
+                &ProtocolAddress::new("contact".into(), 1),
+                &SessionRecord::new_fresh(),
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(clone.delete_all_sessions("contact").await.unwrap(), 1);
+        assert_eq!(store.delete_all_sessions("contact").await.unwrap(), 0);
+    }
+}
@@ -1,28 +1,361 @@
+use std::{convert::TryInto, ops::RangeBounds};
+
+use async_trait::async_trait;
 use libsignal_service::{
+    content::{ContentBody, Metadata},
+    groups_v2::CredentialsCache,
     models::Contact,
-    prelude::protocol::{IdentityKeyStore, PreKeyStore, SessionStoreExt, SignedPreKeyStore},
+    prelude::{
+        protocol::{Direction, IdentityKeyStore, PreKeyStore, SessionStoreExt, SignedPreKeyStore},
+        Content, ProtobufMessage, Uuid,
+    },
+    proto::{self, sync_message, DecryptedGroup},
+    ServiceAddress,
 };
+use serde::{Deserialize, Serialize};
 
-use crate::{manager::State, Error};
+use crate::{manager::State, Error, ProfileDetails};
 
+#[cfg(feature = "sled-store")]
+mod encryption;
+pub mod memory;
 #[cfg(feature = "sled-store")]
 pub mod sled;
+#[cfg(feature = "sqlite-store")]
+pub mod sqlite;
 
+/// Storage of everything an account needs, see [MemoryConfigStore](memory::MemoryConfigStore)
+/// or the stores behind the `sled-store` and `sqlite-store` features.
+///
+/// Custom implementations report their own errors with [Error::store].
+///
+/// The methods of the traits defined here are async with `Send` futures, so that stores can be
+/// backed by async clients. The futures of the protocol stores of `libsignal-protocol` are not
+/// `Send`, though, so the [Manager](crate::Manager) polls them on a thread of its own, where its
+/// store is sent.
+///
+/// The sled and sqlite stores do their I/O synchronously inside these futures, blocking the thread
+/// polling them while they do.
+#[async_trait]
 pub trait ConfigStore:
-    PreKeyStore + SignedPreKeyStore + SessionStoreExt + IdentityKeyStore + ContactsStore + Clone
+    PreKeyStore
+    + SignedPreKeyStore
+    + SessionStoreExt
+    + IdentityKeyStore
+    + ContactsStore
+    + MessageStore
+    + GroupsStore
+    + TrustStore
+    + ProfilesStore
+    + CredentialsCache
+    + Clone
+    + Send
+    + Sync
 {
-    fn state(&self) -> Result<State, Error>;
+    async fn state(&self) -> Result<State, Error>;
+
+    /// Saves the state, leaving all other data untouched.
+    async fn save(&self, state: &State) -> Result<(), Error>;
 
-    fn save(&self, state: &State) -> Result<(), Error>;
+    /// Deletes the state and the key material of the account (identity, pre-keys, sessions,
+    /// trust in identities, etc.), the contacts and their profiles, before registering or linking
+    /// again.
+    ///
+    /// Messages and groups are kept.
+    async fn reset(&mut self) -> Result<(), Error>;
 
-    fn pre_keys_offset_id(&self) -> Result<u32, Error>;
-    fn set_pre_keys_offset_id(&self, id: u32) -> Result<(), Error>;
+    async fn pre_keys_offset_id(&self) -> Result<u32, Error>;
+    async fn set_pre_keys_offset_id(&self, id: u32) -> Result<(), Error>;
 
-    fn next_signed_pre_key_id(&self) -> Result<u32, Error>;
-    fn set_next_signed_pre_key_id(&self, id: u32) -> Result<(), Error>;
+    async fn next_signed_pre_key_id(&self) -> Result<u32, Error>;
+    async fn set_next_signed_pre_key_id(&self, id: u32) -> Result<(), Error>;
+
+    /// Deletes a signed pre-key which was replaced, see [PreKeysPolicy](crate::PreKeysPolicy).
+    async fn remove_signed_pre_key(&mut self, id: u32) -> Result<(), Error>;
 }
 
+#[async_trait]
 pub trait ContactsStore {
-    fn save_contacts(&mut self, contacts: &[Contact]) -> Result<(), Error>;
-    fn contacts(&self) -> Result<Vec<Contact>, Error>;
+    async fn save_contacts(&mut self, contacts: &[Contact]) -> Result<(), Error>;
+    async fn contacts(&self) -> Result<Vec<Contact>, Error>;
+}
+
+/// A conversation, either with a single contact or within a group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Thread {
+    /// Conversation with a contact, identified by its UUID
+    Contact(Uuid),
+    /// Conversation in a group v2, identified by its master key
+    Group([u8; 32]),
+}
+
+impl Thread {
+    /// Returns the thread a received message belongs to, if it is a message worth keeping.
+    ///
+    /// Only data messages (and their transcripts when sent from another of our devices) are part
+    /// of a conversation, other contents like receipts or typing indicators are not.
+    pub fn from_content(content: &Content) -> Option<Self> {
+        match &content.body {
+            ContentBody::DataMessage(message) => Self::from_group(message)
+                .or_else(|| content.metadata.sender.uuid.map(Thread::Contact)),
+            ContentBody::SynchronizeMessage(proto::SyncMessage {
+                sent:
+                    Some(sync_message::Sent {
+                        message: Some(message),
+                        destination_uuid,
+                        ..
+                    }),
+                ..
+            }) => Self::from_group(message).or_else(|| {
+                destination_uuid
+                    .as_deref()
+                    .and_then(|uuid| Uuid::parse_str(uuid).ok())
+                    .map(Thread::Contact)
+            }),
+            _ => None,
+        }
+    }
+
+    /// Returns the thread of a message sent to a group v2, if any.
+    pub fn from_group(message: &proto::DataMessage) -> Option<Self> {
+        let master_key = message.group_v2.as_ref()?.master_key.as_deref()?;
+        master_key.try_into().ok().map(Thread::Group)
+    }
+}
+
+/// Persists the messages of all conversations.
+///
+/// Messages are indexed by thread and by the timestamp at which they were sent, which is what
+/// Signal uses to reference them (in quotes, reactions, receipts, etc.).
+#[async_trait]
+pub trait MessageStore {
+    /// Iterates over messages in order of timestamp, and can be reversed to get the latest first.
+    type MessagesIter: DoubleEndedIterator<Item = Result<Content, Error>> + Send;
+
+    /// Saves a message, replacing any message of the same thread sent at the same time.
+    async fn save_message(&mut self, thread: &Thread, message: Content) -> Result<(), Error>;
+
+    /// Deletes a message, returning whether it existed.
+    async fn delete_message(&mut self, thread: &Thread, timestamp: u64) -> Result<bool, Error>;
+
+    async fn message(&self, thread: &Thread, timestamp: u64) -> Result<Option<Content>, Error>;
+
+    /// Looks up a message from its author and the timestamp at which it was sent.
+    ///
+    /// If the author sent messages to several threads at this timestamp, the one saved last is
+    /// returned.
+    async fn message_by_sender(
+        &self,
+        sender: &Uuid,
+        timestamp: u64,
+    ) -> Result<Option<(Thread, Content)>, Error>;
+
+    /// Returns the messages of a thread sent within a range of timestamps.
+    ///
+    /// To paginate from the latest message, use `..before` ranges and `rev()` the iterator.
+    async fn messages(
+        &self,
+        thread: &Thread,
+        range: impl RangeBounds<u64> + Send,
+    ) -> Result<Self::MessagesIter, Error>;
+}
+
+/// Persists the decrypted state of the groups v2 we know of, indexed by master key.
+#[async_trait]
+pub trait GroupsStore {
+    /// Saves a group, replacing any previously stored revision.
+    async fn save_group(
+        &mut self,
+        master_key: &[u8; 32],
+        group: &DecryptedGroup,
+    ) -> Result<(), Error>;
+
+    /// Deletes a group, returning whether it existed.
+    async fn delete_group(&mut self, master_key: &[u8; 32]) -> Result<bool, Error>;
+
+    async fn group(&self, master_key: &[u8; 32]) -> Result<Option<DecryptedGroup>, Error>;
+
+    async fn groups(&self) -> Result<Vec<([u8; 32], DecryptedGroup)>, Error>;
+
+    /// Returns the revision of the stored group, if any.
+    async fn group_revision(&self, master_key: &[u8; 32]) -> Result<Option<u32>, Error> {
+        Ok(self.group(master_key).await?.map(|group| group.revision))
+    }
+}
+
+/// How much the identity key of a contact is trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrustLevel {
+    /// The identity key changed after it was verified, sending messages to this contact is refused
+    /// until the new key is trusted or verified
+    Untrusted,
+    /// The identity key was trusted when first seen, or when a change was accepted
+    TrustedOnFirstUse,
+    /// The safety number was verified, on this device or on another of our devices
+    Verified,
+}
+
+impl TrustLevel {
+    /// Whether an identity key can be used, given the trust level of the stored one.
+    ///
+    /// Incoming messages are always accepted, as Signal does, only sending is restricted.
+    pub(crate) fn trusts(self, same_key: bool, direction: Direction) -> bool {
+        match direction {
+            Direction::Receiving => true,
+            Direction::Sending if same_key => self != TrustLevel::Untrusted,
+            Direction::Sending => self == TrustLevel::TrustedOnFirstUse,
+        }
+    }
+
+    /// Trust level of a new identity key replacing one with this level.
+    pub(crate) fn after_change(self) -> Self {
+        match self {
+            TrustLevel::TrustedOnFirstUse => TrustLevel::TrustedOnFirstUse,
+            TrustLevel::Untrusted | TrustLevel::Verified => TrustLevel::Untrusted,
+        }
+    }
+
+    /// Trust level to store after saving a new identity key of a contact, if it must be updated.
+    ///
+    /// When an existing key was replaced, the change must also be recorded with this level.
+    pub(crate) fn after_save(replaced: bool, current: Option<TrustLevel>) -> Option<TrustLevel> {
+        match (replaced, current) {
+            (false, None) => Some(TrustLevel::TrustedOnFirstUse),
+            // another device of the same contact, with the same identity key
+            (false, Some(_)) => None,
+            (true, current) => Some(
+                current
+                    .unwrap_or(TrustLevel::TrustedOnFirstUse)
+                    .after_change(),
+            ),
+        }
+    }
+}
+
+/// The identity key of a contact changed, which happens when they reinstall Signal but could also
+/// mean someone is impersonating them.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdentityChange {
+    /// Address name (UUID or phone number) of the contact
+    pub name: String,
+    /// Trust level of the new identity key
+    pub trust_level: TrustLevel,
+}
+
+/// Persists the trust in the identity keys of contacts, indexed by address name.
+///
+/// The [IdentityKeyStore] implementation is expected to use it: new identities are trusted on
+/// first use, and changes are recorded until taken with [TrustStore::take_identity_changes].
+#[async_trait]
+pub trait TrustStore {
+    async fn trust_level(&self, name: &str) -> Result<Option<TrustLevel>, Error>;
+
+    async fn set_trust_level(&mut self, name: &str, level: TrustLevel) -> Result<(), Error>;
+
+    /// Returns the identity changes detected since the last call, and forgets them.
+    async fn take_identity_changes(&mut self) -> Result<Vec<IdentityChange>, Error>;
+}
+
+/// Profile key of a contact, and its profile as last fetched with it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredProfile {
+    pub profile_key: [u8; 32],
+    /// Decrypted profile, `None` if it was never fetched or could not be decrypted
+    pub details: Option<ProfileDetails>,
+    /// When the profile was last fetched, in milliseconds since the epoch
+    pub fetched_at: Option<u64>,
+}
+
+/// Persists the profile keys of contacts, learnt from their messages or from contact sync, and
+/// caches their decrypted profiles, see [Manager::profile](crate::Manager::profile).
+#[async_trait]
+pub trait ProfilesStore {
+    async fn save_profile(&mut self, uuid: &Uuid, profile: &StoredProfile) -> Result<(), Error>;
+
+    async fn profile(&self, uuid: &Uuid) -> Result<Option<StoredProfile>, Error>;
+
+    /// Saves the profile key of a contact, returning whether it changed.
+    ///
+    /// The cached profile is forgotten when the key changes, as it must be fetched again.
+    async fn save_profile_key(
+        &mut self,
+        uuid: &Uuid,
+        profile_key: [u8; 32],
+    ) -> Result<bool, Error> {
+        if let Some(profile) = self.profile(uuid).await? {
+            if profile.profile_key == profile_key {
+                return Ok(false);
+            }
+        }
+        let profile = StoredProfile {
+            profile_key,
+            details: None,
+            fetched_at: None,
+        };
+        self.save_profile(uuid, &profile).await?;
+        Ok(true)
+    }
+}
+
+/// Stored form of a [Content], as protobuf is the only serialization available for its body.
+#[derive(Serialize, Deserialize)]
+struct StoredContent {
+    sender: ServiceAddress,
+    sender_device: u32,
+    timestamp: u64,
+    needs_receipt: bool,
+    body: Vec<u8>,
+}
+
+pub(crate) fn serialize_content(content: &Content) -> Result<Vec<u8>, Error> {
+    let mut body = proto::Content::default();
+    match content.body.clone() {
+        ContentBody::DataMessage(message) => body.data_message = Some(message),
+        ContentBody::SynchronizeMessage(message) => body.sync_message = Some(message),
+        ContentBody::CallMessage(message) => body.call_message = Some(message),
+        ContentBody::ReceiptMessage(message) => body.receipt_message = Some(message),
+        ContentBody::TypingMessage(message) => body.typing_message = Some(message),
+    }
+    let mut buf = Vec::new();
+    body.encode(&mut buf)
+        .expect("encoding into a vector never fails");
+
+    Ok(serde_json::to_vec(&StoredContent {
+        sender: content.metadata.sender.clone(),
+        sender_device: content.metadata.sender_device,
+        timestamp: content.metadata.timestamp,
+        needs_receipt: content.metadata.needs_receipt,
+        body: buf,
+    })?)
+}
+
+pub(crate) fn deserialize_content(buf: &[u8]) -> Result<Content, Error> {
+    let stored: StoredContent = serde_json::from_slice(buf)?;
+    let body = proto::Content::decode(stored.body.as_slice()).map_err(|e| {
+        log::error!("failed to decode stored message: {}", e);
+        Error::MessageDecodeError
+    })?;
+    let body = if let Some(message) = body.data_message {
+        ContentBody::DataMessage(message)
+    } else if let Some(message) = body.sync_message {
+        ContentBody::SynchronizeMessage(message)
+    } else if let Some(message) = body.call_message {
+        ContentBody::CallMessage(message)
+    } else if let Some(message) = body.receipt_message {
+        ContentBody::ReceiptMessage(message)
+    } else if let Some(message) = body.typing_message {
+        ContentBody::TypingMessage(message)
+    } else {
+        return Err(Error::MessageDecodeError);
+    };
+
+    Ok(Content {
+        metadata: Metadata {
+            sender: stored.sender,
+            sender_device: stored.sender_device,
+            timestamp: stored.timestamp,
+            needs_receipt: stored.needs_receipt,
+        },
+        body,
+    })
 }
@@ -1,46 +1,225 @@
 use std::{
+    collections::HashMap,
+    convert::TryInto,
+    ops::{Bound, RangeBounds},
     path::PathBuf,
-    sync::{Arc, RwLock},
+    sync::Arc,
 };
 
 use async_trait::async_trait;
 use libsignal_service::{
+    groups_v2::{CredentialsCache, CredentialsCacheError},
     models::Contact,
-    prelude::protocol::{
-        Context, Direction, IdentityKey, IdentityKeyPair, IdentityKeyStore, PreKeyRecord,
-        PreKeyStore, ProtocolAddress, SessionRecord, SessionStore, SessionStoreExt,
-        SignalProtocolError, SignedPreKeyRecord, SignedPreKeyStore,
+    prelude::{
+        protocol::{
+            Context, Direction, IdentityKey, IdentityKeyPair, IdentityKeyStore, PreKeyRecord,
+            PreKeyStore, ProtocolAddress, SessionRecord, SessionStore, SessionStoreExt,
+            SignalProtocolError, SignedPreKeyRecord, SignedPreKeyStore,
+        },
+        Content, ProtobufMessage, Uuid,
     },
+    proto::DecryptedGroup,
+    push_service::DEFAULT_DEVICE_ID,
 };
 use log::{trace, warn};
 use sled::IVec;
+use zkgroup::auth::AuthCredentialResponse;
 
-use super::{ConfigStore, ContactsStore};
+use super::{
+    deserialize_content, encryption::StoreCipher, serialize_content, ConfigStore, ContactsStore,
+    GroupsStore, IdentityChange, MessageStore, ProfilesStore, StoredProfile, Thread, TrustLevel,
+    TrustStore,
+};
 use crate::{manager::State, Error};
 
+mod migrations;
+
+use migrations::SLED_KEY_SCHEMA_VERSION;
+pub use migrations::{Migration, MigrationOptions, SCHEMA_VERSION};
+
 const SLED_KEY_STATE: &str = "state";
 const SLED_KEY_CONTACTS: &str = "contacts";
+const SLED_KEY_ENCRYPTION_SALT: &str = "encryption-salt";
+/// Data key, encrypted with the key derived from the passphrase (or the raw key) used to open the
+/// store. Failing to decrypt it means the passphrase is wrong.
+const SLED_KEY_ENCRYPTION_KEY: &str = "encryption-key";
 
 const SLED_TREE_SESSIONS: &str = "sessions";
+const SLED_TREE_MESSAGES: &str = "messages";
+const SLED_TREE_MESSAGES_BY_SENDER: &str = "messages-by-sender";
+const SLED_TREE_GROUPS: &str = "groups";
+/// Groups v2 auth credentials, indexed by the day they're valid for
+const SLED_TREE_GROUPS_CREDENTIALS: &str = "groups-credentials";
+/// Trust levels of the identity keys of contacts, indexed by address name
+const SLED_TREE_TRUST: &str = "trust";
+/// Identity changes not yet taken, indexed by address name
+const SLED_TREE_IDENTITY_CHANGES: &str = "identity-changes";
+/// Profile keys and cached profiles of contacts, indexed by UUID
+const SLED_TREE_PROFILES: &str = "profiles";
 
+/// Clones share the same database, which can be used concurrently without locking.
 #[derive(Debug, Clone)]
 pub struct SledConfigStore {
-    db: Arc<RwLock<sled::Db>>,
+    db: sled::Db,
+    /// Encrypts all values when the store is opened with a passphrase or key
+    cipher: Option<Arc<StoreCipher>>,
 }
 
 impl SledConfigStore {
+    /// Opens a plaintext store, migrating it if it was written by an older version of presage.
+    ///
+    /// Before migrating, the database is copied to `{path}.backup-v{version}`, `version` being
+    /// its schema version. Backups are never removed: once the migrated store works, they can be
+    /// deleted.
     pub fn new(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let db = sled::open(&path)?;
+        if db.contains_key(SLED_KEY_ENCRYPTION_KEY)? {
+            return Err(Error::StoreEncryptionError(
+                "store is encrypted, a passphrase or key is required".into(),
+            ));
+        }
+        SledConfigStore { db, cipher: None }.migrated(&path)
+    }
+
+    /// Opens a store where all values are encrypted with a key derived from `passphrase`.
+    ///
+    /// A new store is initialized if the database is empty, and older stores are migrated like
+    /// with [new](Self::new).
+    pub fn with_passphrase(
+        path: impl Into<PathBuf>,
+        passphrase: impl AsRef<str>,
+    ) -> Result<Self, Error> {
+        let path = path.into();
+        let db = sled::open(&path)?;
+        let salt = match db.get(SLED_KEY_ENCRYPTION_SALT)? {
+            Some(salt) => salt.to_vec(),
+            None => StoreCipher::generate_salt().to_vec(),
+        };
+        let key = StoreCipher::derive_key(passphrase.as_ref(), &salt);
+        let store = Self::open_encrypted(db, key)?;
+        store.db.insert(SLED_KEY_ENCRYPTION_SALT, salt)?;
+        store.migrated(&path)
+    }
+
+    /// Opens a store where all values are encrypted with a raw 256-bit key.
+    ///
+    /// A new store is initialized if the database is empty, and older stores are migrated like
+    /// with [new](Self::new).
+    pub fn with_key(path: impl Into<PathBuf>, key: [u8; 32]) -> Result<Self, Error> {
+        let path = path.into();
+        Self::open_encrypted(sled::open(&path)?, key)?.migrated(&path)
+    }
+
+    fn open_encrypted(db: sled::Db, key: [u8; 32]) -> Result<Self, Error> {
+        let key_cipher = StoreCipher::new(key);
+        let data_key = match db.get(SLED_KEY_ENCRYPTION_KEY)? {
+            Some(encrypted_data_key) => key_cipher
+                .decrypt(SLED_KEY_ENCRYPTION_KEY.as_bytes(), &encrypted_data_key)
+                .map_err(|_| Error::WrongStorePassphraseError)?
+                .as_slice()
+                .try_into()?,
+            None => {
+                if !Self::is_empty(&db)? {
+                    return Err(Error::StoreEncryptionError(
+                        "cannot open an existing plaintext store with a passphrase or key".into(),
+                    ));
+                }
+
+                trace!("initializing encrypted store");
+                let data_key = StoreCipher::generate_key();
+                db.insert(
+                    SLED_KEY_ENCRYPTION_KEY,
+                    key_cipher.encrypt(SLED_KEY_ENCRYPTION_KEY.as_bytes(), &data_key)?,
+                )?;
+                data_key
+            }
+        };
+
         Ok(SledConfigStore {
-            db: Arc::new(RwLock::new(sled::open(path.into())?)),
+            db,
+            cipher: Some(Arc::new(StoreCipher::new(data_key))),
         })
     }
 
+    /// Checks that a database has no data yet, e.g. messages kept after a reset, besides the salt
+    /// and the schema version written when opening it.
+    fn is_empty(db: &sled::Db) -> Result<bool, Error> {
+        for key in db.iter().keys() {
+            let key = key?;
+            if key.as_ref() != SLED_KEY_ENCRYPTION_SALT.as_bytes()
+                && key.as_ref() != SLED_KEY_SCHEMA_VERSION.as_bytes()
+            {
+                return Ok(false);
+            }
+        }
+        for name in db.tree_names() {
+            if name != db.name() && !db.open_tree(&name)?.is_empty() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Changes the passphrase of an encrypted store.
+    ///
+    /// Only the data key is re-encrypted, so this is fast regardless of the size of the store.
+    pub fn change_passphrase(&self, passphrase: impl AsRef<str>) -> Result<(), Error> {
+        let salt = StoreCipher::generate_salt();
+        let key = StoreCipher::derive_key(passphrase.as_ref(), &salt);
+        self.change_encryption_key(key, Some(&salt))
+    }
+
+    /// Changes the raw key of an encrypted store.
+    ///
+    /// This can also be used to switch a store opened with a passphrase to a raw key.
+    pub fn change_key(&self, key: [u8; 32]) -> Result<(), Error> {
+        self.change_encryption_key(key, None)
+    }
+
+    fn change_encryption_key(&self, key: [u8; 32], salt: Option<&[u8]>) -> Result<(), Error> {
+        let cipher = self.cipher.as_ref().ok_or_else(|| {
+            Error::StoreEncryptionError("cannot change the key of a plaintext store".into())
+        })?;
+        let encrypted_data_key =
+            StoreCipher::new(key).encrypt(SLED_KEY_ENCRYPTION_KEY.as_bytes(), cipher.key())?;
+
+        let mut batch = sled::Batch::default();
+        batch.insert(SLED_KEY_ENCRYPTION_KEY, encrypted_data_key);
+        match salt {
+            Some(salt) => batch.insert(SLED_KEY_ENCRYPTION_SALT, salt),
+            None => batch.remove(SLED_KEY_ENCRYPTION_SALT),
+        }
+        self.db.apply_batch(batch)?;
+        Ok(())
+    }
+
     #[cfg(test)]
-    fn temporary() -> Result<Self, Error> {
+    pub(crate) fn temporary() -> Result<Self, Error> {
         let db = sled::Config::new().temporary(true).open()?;
-        Ok(Self {
-            db: Arc::new(RwLock::new(db)),
-        })
+        Ok(Self { db, cipher: None })
+    }
+
+    #[cfg(test)]
+    fn temporary_encrypted() -> Result<Self, Error> {
+        let db = sled::Config::new().temporary(true).open()?;
+        Self::open_encrypted(db, StoreCipher::generate_key())
+    }
+
+    /// Encrypts a value before storing it under `key`, if the store is encrypted.
+    fn seal(&self, key: impl AsRef<[u8]>, value: IVec) -> Result<IVec, Error> {
+        match &self.cipher {
+            Some(cipher) => Ok(cipher.encrypt(key.as_ref(), &value)?.into()),
+            None => Ok(value),
+        }
+    }
+
+    /// Decrypts a value stored under `key`, if the store is encrypted.
+    fn open(&self, key: impl AsRef<[u8]>, value: IVec) -> Result<IVec, Error> {
+        match &self.cipher {
+            Some(cipher) => Ok(cipher.decrypt(key.as_ref(), &value)?.into()),
+            None => Ok(value),
+        }
     }
 
     pub fn get<K>(&self, key: K) -> Result<Option<IVec>, Error>
@@ -48,7 +227,10 @@ impl SledConfigStore {
         K: AsRef<str>,
     {
         trace!("get {}", key.as_ref());
-        Ok(self.db.read().expect("poisoned mutex").get(key.as_ref())?)
+        self.db
+            .get(key.as_ref())?
+            .map(|value| self.open(key.as_ref(), value))
+            .transpose()
     }
 
     fn get_u32<S>(&self, key: S) -> Result<Option<u32>, Error>
@@ -69,11 +251,8 @@ impl SledConfigStore {
         IVec: From<V>,
     {
         trace!("inserting {}", key.as_ref());
-        let _ = self
-            .db
-            .try_write()
-            .expect("poisoned mutex")
-            .insert(key.as_ref(), value)?;
+        let value = self.seal(key.as_ref(), value.into())?;
+        let _ = self.db.insert(key.as_ref(), value)?;
         Ok(())
     }
 
@@ -82,11 +261,7 @@ impl SledConfigStore {
         S: AsRef<str>,
     {
         trace!("inserting u32 {}", key.as_ref());
-        self.db
-            .try_write()
-            .expect("poisoned mutex")
-            .insert(key.as_ref(), &value.to_le_bytes())?;
-        Ok(())
+        self.insert(key, &value.to_le_bytes()[..])
     }
 
     fn remove<S>(&self, key: S) -> Result<(), Error>
@@ -94,10 +269,7 @@ impl SledConfigStore {
         S: AsRef<str>,
     {
         trace!("removing {} from db", key.as_ref());
-        self.db
-            .try_write()
-            .expect("poisoned mutex")
-            .remove(key.as_ref())?;
+        self.db.remove(key.as_ref())?;
         Ok(())
     }
 
@@ -121,8 +293,36 @@ impl SledConfigStore {
         format!("identity-remote-{}", addr)
     }
 
+    fn thread_prefix(&self, thread: &Thread) -> Vec<u8> {
+        match thread {
+            Thread::Contact(uuid) => [&b"contact-"[..], &uuid.as_bytes()[..]].concat(),
+            Thread::Group(master_key) => [&b"group-"[..], &master_key[..]].concat(),
+        }
+    }
+
+    fn message_key(&self, thread: &Thread, timestamp: u64) -> Vec<u8> {
+        [self.thread_prefix(thread), timestamp.to_be_bytes().to_vec()].concat()
+    }
+
+    fn message_by_sender_key(&self, sender: &Uuid, timestamp: u64) -> Vec<u8> {
+        [&sender.as_bytes()[..], &timestamp.to_be_bytes()].concat()
+    }
+
+    fn thread_from_message_key(&self, key: &[u8]) -> Option<Thread> {
+        // strip the timestamp
+        let key = &key[..key.len().checked_sub(8)?];
+        if let Some(uuid) = key.strip_prefix(b"contact-") {
+            Uuid::from_slice(uuid).ok().map(Thread::Contact)
+        } else {
+            key.strip_prefix(b"group-")?
+                .try_into()
+                .ok()
+                .map(Thread::Group)
+        }
+    }
+
     pub fn keys(&self) -> Result<(Vec<String>, Vec<String>), SignalProtocolError> {
-        let db = self.db.read().expect("poisoned mutex");
+        let db = &self.db;
         let global_keys = db
             .iter()
             .filter_map(|r| {
@@ -146,57 +346,553 @@ impl SledConfigStore {
     }
 }
 
+#[async_trait]
 impl ConfigStore for SledConfigStore {
-    fn state(&self) -> Result<State, Error> {
-        let db = self.db.read().expect("poisoned mutex");
-        db.get(SLED_KEY_STATE)?.map_or(Ok(State::New), |s| {
+    async fn state(&self) -> Result<State, Error> {
+        self.get(SLED_KEY_STATE)?.map_or(Ok(State::New), |s| {
             serde_json::from_slice(&s).map_err(Error::from)
         })
     }
 
-    fn save(&self, state: &State) -> Result<(), Error> {
-        let db = self.db.try_write().expect("poisoned mutex");
-        db.clear()?;
-        db.insert(SLED_KEY_STATE, serde_json::to_vec(state)?)?;
+    async fn save(&self, state: &State) -> Result<(), Error> {
+        self.insert(SLED_KEY_STATE, serde_json::to_vec(state)?)
+    }
+
+    async fn reset(&mut self) -> Result<(), Error> {
+        let db = &self.db;
+        // the encryption records must survive, or the store could not be opened anymore, so they
+        // are never removed, even temporarily
+        let kept: &[&[u8]] = &[
+            SLED_KEY_ENCRYPTION_SALT.as_bytes(),
+            SLED_KEY_ENCRYPTION_KEY.as_bytes(),
+            SLED_KEY_SCHEMA_VERSION.as_bytes(),
+        ];
+        let mut batch = sled::Batch::default();
+        for key in db.iter().keys() {
+            let key = key?;
+            if !kept.contains(&key.as_ref()) {
+                batch.remove(key);
+            }
+        }
+        db.apply_batch(batch)?;
+        for tree in &[
+            SLED_TREE_SESSIONS,
+            SLED_TREE_TRUST,
+            SLED_TREE_IDENTITY_CHANGES,
+            SLED_TREE_GROUPS_CREDENTIALS,
+            SLED_TREE_PROFILES,
+        ] {
+            db.open_tree(tree)?.clear()?;
+        }
         Ok(())
     }
 
-    fn pre_keys_offset_id(&self) -> Result<u32, Error> {
+    async fn pre_keys_offset_id(&self) -> Result<u32, Error> {
         Ok(self.get_u32("pre_keys_offset_id")?.unwrap_or(0))
     }
 
-    fn set_pre_keys_offset_id(&self, id: u32) -> Result<(), Error> {
+    async fn set_pre_keys_offset_id(&self, id: u32) -> Result<(), Error> {
         self.insert_u32("pre_keys_offset_id", id)
     }
 
-    fn next_signed_pre_key_id(&self) -> Result<u32, Error> {
+    async fn next_signed_pre_key_id(&self) -> Result<u32, Error> {
         Ok(self.get_u32("next_signed_pre_key_id")?.unwrap_or(0))
     }
 
-    fn set_next_signed_pre_key_id(&self, id: u32) -> Result<(), Error> {
+    async fn set_next_signed_pre_key_id(&self, id: u32) -> Result<(), Error> {
         self.insert_u32("next_signed_pre_key_id", id)
     }
+
+    async fn remove_signed_pre_key(&mut self, id: u32) -> Result<(), Error> {
+        self.remove(self.signed_prekey_key(id))
+    }
 }
 
+#[async_trait]
 impl ContactsStore for SledConfigStore {
-    fn save_contacts(&mut self, contacts: &[Contact]) -> Result<(), Error> {
-        self.db
-            .write()
-            .expect("poisoned mutex")
-            .insert(SLED_KEY_CONTACTS, serde_json::to_vec(contacts)?)?;
+    async fn save_contacts(&mut self, contacts: &[Contact]) -> Result<(), Error> {
+        self.insert(SLED_KEY_CONTACTS, serde_json::to_vec(contacts)?)?;
         trace!("saved contacts");
         Ok(())
     }
 
-    fn contacts(&self) -> Result<Vec<Contact>, Error> {
-        self.db
-            .read()
-            .expect("poisoned mutex")
-            .get(SLED_KEY_CONTACTS)?
+    async fn contacts(&self) -> Result<Vec<Contact>, Error> {
+        self.get(SLED_KEY_CONTACTS)?
             .map_or_else(|| Ok(vec![]), |buf| Ok(serde_json::from_slice(&buf)?))
     }
 }
 
+#[async_trait]
+impl MessageStore for SledConfigStore {
+    type MessagesIter = SledMessagesIter;
+
+    async fn save_message(&mut self, thread: &Thread, message: Content) -> Result<(), Error> {
+        let key = self.message_key(thread, message.metadata.timestamp);
+        trace!("storing message in {:?}", thread);
+        let value = self.seal(&key, serialize_content(&message)?.into())?;
+
+        let replaced = self.message(thread, message.metadata.timestamp).await?;
+        let db = &self.db;
+        let by_sender = db.open_tree(SLED_TREE_MESSAGES_BY_SENDER)?;
+        db.open_tree(SLED_TREE_MESSAGES)?.insert(&key, value)?;
+        // the replaced message may have another sender, whose index entry would be left dangling
+        if let Some(sender) = replaced.and_then(|replaced| replaced.metadata.sender.uuid) {
+            let sender_key = self.message_by_sender_key(&sender, message.metadata.timestamp);
+            if by_sender.get(&sender_key)?.as_deref() == Some(key.as_slice()) {
+                by_sender.remove(sender_key)?;
+            }
+        }
+        if let Some(sender) = &message.metadata.sender.uuid {
+            by_sender.insert(
+                self.message_by_sender_key(sender, message.metadata.timestamp),
+                key,
+            )?;
+        }
+        Ok(())
+    }
+
+    async fn delete_message(&mut self, thread: &Thread, timestamp: u64) -> Result<bool, Error> {
+        let key = self.message_key(thread, timestamp);
+        let message = match self.message(thread, timestamp).await? {
+            Some(message) => message,
+            None => return Ok(false),
+        };
+
+        let db = &self.db;
+        db.open_tree(SLED_TREE_MESSAGES)?.remove(&key)?;
+        if let Some(sender) = &message.metadata.sender.uuid {
+            db.open_tree(SLED_TREE_MESSAGES_BY_SENDER)?
+                .remove(self.message_by_sender_key(sender, timestamp))?;
+        }
+        Ok(true)
+    }
+
+    async fn message(&self, thread: &Thread, timestamp: u64) -> Result<Option<Content>, Error> {
+        let key = self.message_key(thread, timestamp);
+        self.db
+            .open_tree(SLED_TREE_MESSAGES)?
+            .get(&key)?
+            .map(|buf| deserialize_content(&self.open(&key, buf)?))
+            .transpose()
+    }
+
+    async fn message_by_sender(
+        &self,
+        sender: &Uuid,
+        timestamp: u64,
+    ) -> Result<Option<(Thread, Content)>, Error> {
+        let key = self
+            .db
+            .open_tree(SLED_TREE_MESSAGES_BY_SENDER)?
+            .get(self.message_by_sender_key(sender, timestamp))?;
+        let thread = match key.and_then(|key| self.thread_from_message_key(&key)) {
+            Some(thread) => thread,
+            None => return Ok(None),
+        };
+        Ok(self
+            .message(&thread, timestamp)
+            .await?
+            .map(|message| (thread, message)))
+    }
+
+    async fn messages(
+        &self,
+        thread: &Thread,
+        range: impl RangeBounds<u64> + Send,
+    ) -> Result<Self::MessagesIter, Error> {
+        let start = match range.start_bound() {
+            Bound::Included(timestamp) => Bound::Included(self.message_key(thread, *timestamp)),
+            Bound::Excluded(timestamp) => Bound::Excluded(self.message_key(thread, *timestamp)),
+            Bound::Unbounded => Bound::Included(self.message_key(thread, 0)),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(timestamp) => Bound::Included(self.message_key(thread, *timestamp)),
+            Bound::Excluded(timestamp) => Bound::Excluded(self.message_key(thread, *timestamp)),
+            Bound::Unbounded => Bound::Included(self.message_key(thread, u64::MAX)),
+        };
+
+        let iter = self
+            .db
+            .open_tree(SLED_TREE_MESSAGES)?
+            .range::<Vec<u8>, _>((start, end));
+        Ok(SledMessagesIter {
+            store: self.clone(),
+            iter,
+        })
+    }
+}
+
+#[async_trait]
+impl GroupsStore for SledConfigStore {
+    async fn save_group(
+        &mut self,
+        master_key: &[u8; 32],
+        group: &DecryptedGroup,
+    ) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        group
+            .encode(&mut buf)
+            .expect("encoding into a vector never fails");
+        let value = self.seal(master_key, buf.into())?;
+        self.db
+            .open_tree(SLED_TREE_GROUPS)?
+            .insert(master_key, value)?;
+        trace!("stored group at revision {}", group.revision);
+        Ok(())
+    }
+
+    async fn delete_group(&mut self, master_key: &[u8; 32]) -> Result<bool, Error> {
+        Ok(self
+            .db
+            .open_tree(SLED_TREE_GROUPS)?
+            .remove(master_key)?
+            .is_some())
+    }
+
+    async fn group(&self, master_key: &[u8; 32]) -> Result<Option<DecryptedGroup>, Error> {
+        self.db
+            .open_tree(SLED_TREE_GROUPS)?
+            .get(master_key)?
+            .map(|buf| self.decode_group(master_key, buf))
+            .transpose()
+    }
+
+    async fn groups(&self) -> Result<Vec<([u8; 32], DecryptedGroup)>, Error> {
+        self.db
+            .open_tree(SLED_TREE_GROUPS)?
+            .iter()
+            .map(|elem| {
+                let (key, value) = elem?;
+                let master_key: [u8; 32] = key.as_ref().try_into()?;
+                Ok((master_key, self.decode_group(&master_key, value)?))
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl TrustStore for SledConfigStore {
+    async fn trust_level(&self, name: &str) -> Result<Option<TrustLevel>, Error> {
+        self.load_trust_level(name)
+    }
+
+    async fn set_trust_level(&mut self, name: &str, level: TrustLevel) -> Result<(), Error> {
+        self.store_trust_level(name, level)
+    }
+
+    async fn take_identity_changes(&mut self) -> Result<Vec<IdentityChange>, Error> {
+        let tree = self.db.open_tree(SLED_TREE_IDENTITY_CHANGES)?;
+        let mut changes = Vec::new();
+        for elem in tree.iter() {
+            let (key, value) = elem?;
+            tree.remove(&key)?;
+            let name = String::from_utf8_lossy(&key).into_owned();
+            let trust_level = bincode::deserialize(&self.open(&key, value)?)?;
+            changes.push(IdentityChange { name, trust_level });
+        }
+        Ok(changes)
+    }
+}
+
+#[async_trait]
+impl ProfilesStore for SledConfigStore {
+    async fn save_profile(&mut self, uuid: &Uuid, profile: &StoredProfile) -> Result<(), Error> {
+        let value = self.seal(uuid.as_bytes(), bincode::serialize(profile)?.into())?;
+        self.db
+            .open_tree(SLED_TREE_PROFILES)?
+            .insert(uuid.as_bytes(), value)?;
+        Ok(())
+    }
+
+    async fn profile(&self, uuid: &Uuid) -> Result<Option<StoredProfile>, Error> {
+        self.db
+            .open_tree(SLED_TREE_PROFILES)?
+            .get(uuid.as_bytes())?
+            .map(|value| Ok(bincode::deserialize(&self.open(uuid.as_bytes(), value)?)?))
+            .transpose()
+    }
+}
+
+impl CredentialsCache for SledConfigStore {
+    fn clear(&mut self) -> Result<(), CredentialsCacheError> {
+        self.clear_credentials().map_err(|e| {
+            log::error!("failed to clear groups credentials: {}", e);
+            CredentialsCacheError::WriteError
+        })
+    }
+
+    fn get(&self, key: &i64) -> Result<Option<AuthCredentialResponse>, CredentialsCacheError> {
+        self.credential(*key).map_err(|e| {
+            log::error!("failed to read groups credential: {}", e);
+            CredentialsCacheError::ReadError
+        })
+    }
+
+    fn write(
+        &mut self,
+        map: HashMap<i64, AuthCredentialResponse>,
+    ) -> Result<(), CredentialsCacheError> {
+        self.write_credentials(map).map_err(|e| {
+            log::error!("failed to write groups credentials: {}", e);
+            CredentialsCacheError::WriteError
+        })
+    }
+}
+
+impl SledConfigStore {
+    fn load_trust_level(&self, name: &str) -> Result<Option<TrustLevel>, Error> {
+        self.db
+            .open_tree(SLED_TREE_TRUST)?
+            .get(name)?
+            .map(|value| Ok(bincode::deserialize(&self.open(name, value)?)?))
+            .transpose()
+    }
+
+    fn store_trust_level(&self, name: &str, level: TrustLevel) -> Result<(), Error> {
+        let value = self.seal(name, bincode::serialize(&level)?.into())?;
+        self.db.open_tree(SLED_TREE_TRUST)?.insert(name, value)?;
+        Ok(())
+    }
+
+    fn clear_credentials(&self) -> Result<(), Error> {
+        self.db.open_tree(SLED_TREE_GROUPS_CREDENTIALS)?.clear()?;
+        Ok(())
+    }
+
+    fn credential(&self, day: i64) -> Result<Option<AuthCredentialResponse>, Error> {
+        let key = day.to_be_bytes();
+        self.db
+            .open_tree(SLED_TREE_GROUPS_CREDENTIALS)?
+            .get(key)?
+            .map(|value| Ok(bincode::deserialize(&self.open(key, value)?)?))
+            .transpose()
+    }
+
+    fn write_credentials(
+        &self,
+        credentials: HashMap<i64, AuthCredentialResponse>,
+    ) -> Result<(), Error> {
+        let tree = self.db.open_tree(SLED_TREE_GROUPS_CREDENTIALS)?;
+        let mut batch = sled::Batch::default();
+        // new credentials are fetched from today, the ones of previous days have expired
+        if let Some(oldest) = credentials.keys().min() {
+            for stale in tree.range(..oldest.to_be_bytes()) {
+                batch.remove(stale?.0);
+            }
+        }
+        for (day, credential) in credentials {
+            let key = day.to_be_bytes();
+            batch.insert(
+                &key[..],
+                self.seal(key, bincode::serialize(&credential)?.into())?,
+            );
+        }
+        tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// Saves a new identity key of a contact, recording the change if it replaces another one.
+    fn replace_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity_key: &IdentityKey,
+    ) -> Result<bool, Error> {
+        let key = self.identity_key(address);
+        let previous = self.known_identity(address)?;
+        if self.db.contains_key(&key)? && previous.as_ref() == Some(identity_key) {
+            return Ok(false);
+        }
+        self.insert(key, identity_key.serialize())?;
+
+        let name = address.name();
+        let replaced = previous.map_or(false, |previous| previous != *identity_key);
+        if let Some(trust_level) = TrustLevel::after_save(replaced, self.load_trust_level(name)?) {
+            warn!("trusting identity of {}: {:?}", name, trust_level);
+            self.store_trust_level(name, trust_level)?;
+            if replaced {
+                let value = self.seal(name, bincode::serialize(&trust_level)?.into())?;
+                self.db
+                    .open_tree(SLED_TREE_IDENTITY_CHANGES)?
+                    .insert(name, value)?;
+            }
+        }
+        Ok(replaced)
+    }
+
+    /// Identity key stored for this device of a contact or, for a new device, for another one.
+    ///
+    /// All the devices of an account share the same identity key, so a new device showing another
+    /// one is a change of key like any other.
+    fn known_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>, Error> {
+        if let Some(buf) = self.get(self.identity_key(address))? {
+            return Ok(Some(IdentityKey::decode(&buf)?));
+        }
+        let prefix = format!("identity-remote-{}.", address.name());
+        match self.db.scan_prefix(prefix).next() {
+            Some(entry) => {
+                let (key, value) = entry?;
+                Ok(Some(IdentityKey::decode(&self.open(key, value)?)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn decode_group(&self, master_key: &[u8; 32], value: IVec) -> Result<DecryptedGroup, Error> {
+        DecryptedGroup::decode(self.open(master_key, value)?.as_ref()).map_err(|e| {
+            log::error!("failed to decode stored group: {}", e);
+            Error::MessageDecodeError
+        })
+    }
+}
+
+/// Raw records of the store, to copy them into another store.
+#[cfg(feature = "sqlite-store")]
+impl SledConfigStore {
+    /// Decrypted records whose key starts with `prefix`, in the default tree or in `tree`.
+    fn scan(&self, tree: Option<&str>, prefix: &[u8]) -> Result<Vec<(IVec, IVec)>, Error> {
+        let db = &self.db;
+        let tree = match tree {
+            Some(name) => db.open_tree(name)?,
+            None => (**db).clone(),
+        };
+        tree.scan_prefix(prefix)
+            .map(|elem| {
+                let (key, value) = elem?;
+                let value = self.open(&key, value)?;
+                Ok((key, value))
+            })
+            .collect()
+    }
+
+    /// Records of the default tree, indexed by the string following `prefix` in their key.
+    fn scan_suffixes(&self, prefix: &str) -> Result<Vec<(String, IVec)>, Error> {
+        Ok(self
+            .scan(None, prefix.as_bytes())?
+            .into_iter()
+            .map(|(key, value)| {
+                let suffix = String::from_utf8_lossy(&key[prefix.len()..]).into_owned();
+                (suffix, value)
+            })
+            .collect())
+    }
+
+    pub(crate) fn pre_key_records(&self) -> Result<Vec<(u32, IVec)>, Error> {
+        self.scan_suffixes("prekey-")?
+            .into_iter()
+            .map(|(id, value)| Ok((parse_id(&id)?, value)))
+            .collect()
+    }
+
+    pub(crate) fn signed_pre_key_records(&self) -> Result<Vec<(u32, IVec)>, Error> {
+        self.scan_suffixes("signed-prekey-")?
+            .into_iter()
+            .map(|(id, value)| Ok((parse_id(&id)?, value)))
+            .collect()
+    }
+
+    pub(crate) fn session_records(&self) -> Result<Vec<(ProtocolAddress, IVec)>, Error> {
+        self.scan(Some(SLED_TREE_SESSIONS), b"session-")?
+            .into_iter()
+            .map(|(key, value)| {
+                let address = String::from_utf8_lossy(&key[b"session-".len()..]).into_owned();
+                Ok((parse_address(&address)?, value))
+            })
+            .collect()
+    }
+
+    pub(crate) fn identity_records(&self) -> Result<Vec<(ProtocolAddress, IdentityKey)>, Error> {
+        self.scan_suffixes("identity-remote-")?
+            .into_iter()
+            .map(|(address, value)| Ok((parse_address(&address)?, IdentityKey::decode(&value)?)))
+            .collect()
+    }
+
+    pub(crate) fn trust_levels(&self) -> Result<Vec<(String, TrustLevel)>, Error> {
+        self.scan(Some(SLED_TREE_TRUST), b"")?
+            .into_iter()
+            .map(|(name, value)| {
+                let name = String::from_utf8_lossy(&name).into_owned();
+                Ok((name, bincode::deserialize(&value)?))
+            })
+            .collect()
+    }
+
+    pub(crate) fn all_messages(&self) -> Result<Vec<(Thread, Content)>, Error> {
+        self.scan(Some(SLED_TREE_MESSAGES), b"")?
+            .into_iter()
+            .map(|(key, value)| {
+                let thread = self
+                    .thread_from_message_key(&key)
+                    .ok_or(Error::MessageDecodeError)?;
+                Ok((thread, deserialize_content(&value)?))
+            })
+            .collect()
+    }
+
+    pub(crate) fn profiles(&self) -> Result<Vec<(Uuid, StoredProfile)>, Error> {
+        self.scan(Some(SLED_TREE_PROFILES), b"")?
+            .into_iter()
+            .map(|(key, value)| Ok((Uuid::from_slice(&key)?, bincode::deserialize(&value)?)))
+            .collect()
+    }
+
+    pub(crate) fn credentials(&self) -> Result<Vec<(i64, AuthCredentialResponse)>, Error> {
+        self.scan(Some(SLED_TREE_GROUPS_CREDENTIALS), b"")?
+            .into_iter()
+            .map(|(key, value)| {
+                let day = i64::from_be_bytes(key.as_ref().try_into()?);
+                Ok((day, bincode::deserialize(&value)?))
+            })
+            .collect()
+    }
+}
+
+#[cfg(feature = "sqlite-store")]
+fn parse_id(id: &str) -> Result<u32, Error> {
+    id.parse()
+        .map_err(|_| Error::MissingKeyError(format!("invalid record id {}", id).into()))
+}
+
+/// Parses a protocol address formatted as `name.device_id`.
+#[cfg(feature = "sqlite-store")]
+fn parse_address(address: &str) -> Result<ProtocolAddress, Error> {
+    let i = address
+        .rfind('.')
+        .ok_or_else(|| Error::MissingKeyError(format!("invalid address {}", address).into()))?;
+    Ok(ProtocolAddress::new(
+        address[..i].to_string(),
+        parse_id(&address[i + 1..])?,
+    ))
+}
+
+pub struct SledMessagesIter {
+    store: SledConfigStore,
+    iter: sled::Iter,
+}
+
+impl SledMessagesIter {
+    fn decode(&self, elem: sled::Result<(IVec, IVec)>) -> Result<Content, Error> {
+        let (key, value) = elem?;
+        deserialize_content(&self.store.open(&key, value)?)
+    }
+}
+
+impl Iterator for SledMessagesIter {
+    type Item = Result<Content, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let elem = self.iter.next()?;
+        Some(self.decode(elem))
+    }
+}
+
+impl DoubleEndedIterator for SledMessagesIter {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let elem = self.iter.next_back()?;
+        Some(self.decode(elem))
+    }
+}
+
 #[async_trait(?Send)]
 impl PreKeyStore for SledConfigStore {
     async fn get_pre_key(
@@ -221,8 +917,10 @@ impl PreKeyStore for SledConfigStore {
         _ctx: Context,
     ) -> Result<(), SignalProtocolError> {
         self.insert(self.prekey_key(prekey_id), record.serialize()?)
-            .expect("failed to store pre-key");
-        Ok(())
+            .map_err(|e| {
+                log::error!("sled error: {}", e);
+                SignalProtocolError::InternalError("failed to store pre-key")
+            })
     }
 
     async fn remove_pre_key(
@@ -230,9 +928,10 @@ impl PreKeyStore for SledConfigStore {
         prekey_id: u32,
         _ctx: Context,
     ) -> Result<(), SignalProtocolError> {
-        self.remove(self.prekey_key(prekey_id))
-            .expect("failed to remove pre-key");
-        Ok(())
+        self.remove(self.prekey_key(prekey_id)).map_err(|e| {
+            log::error!("sled error: {}", e);
+            SignalProtocolError::InternalError("failed to remove pre-key")
+        })
     }
 }
 
@@ -282,17 +981,21 @@ impl SessionStore for SledConfigStore {
 
         let buf = self
             .db
-            .try_read()
-            .expect("poisoned mutex")
             .open_tree(SLED_TREE_SESSIONS)
             .map_err(|e| {
                 log::error!("failed to open sessions tree: {}", e);
                 SignalProtocolError::InternalError("sled error")
             })?
-            .get(key)
+            .get(&key)
             .map_err(|e| {
                 log::error!("sled error: {}", e);
                 SignalProtocolError::InternalError("sled error")
+            })?
+            .map(|buf| self.open(&key, buf))
+            .transpose()
+            .map_err(|e| {
+                log::error!("failed to decrypt session: {}", e);
+                SignalProtocolError::InternalError("failed to decrypt session")
             })?;
 
         buf.map(|buf| SessionRecord::deserialize(&buf)).transpose()
@@ -306,15 +1009,17 @@ impl SessionStore for SledConfigStore {
     ) -> Result<(), SignalProtocolError> {
         let key = self.session_key(&address);
         trace!("storing session for {:?} at {:?}", address, key);
+        let record = self.seal(&key, record.serialize()?.into()).map_err(|e| {
+            log::error!("failed to encrypt session: {}", e);
+            SignalProtocolError::InternalError("failed to encrypt session")
+        })?;
         self.db
-            .try_write()
-            .expect("poisoned mutex")
             .open_tree(SLED_TREE_SESSIONS)
             .map_err(|e| {
                 log::error!("failed to open sessions tree: {}", e);
                 SignalProtocolError::InternalError("sled error")
             })?
-            .insert(key, record.serialize()?)
+            .insert(key, record)
             .map_err(|e| {
                 log::error!("failed to open sessions tree: {}", e);
                 SignalProtocolError::InternalError("sled error")
@@ -329,8 +1034,6 @@ impl SessionStoreExt for SledConfigStore {
         let session_prefix = self.session_prefix(name);
         let session_ids: Vec<u32> = self
             .db
-            .read()
-            .expect("poisoned mutex")
             .open_tree(SLED_TREE_SESSIONS)
             .map_err(|e| {
                 log::error!("failed to open sessions tree: {}", e);
@@ -343,6 +1046,7 @@ impl SessionStoreExt for SledConfigStore {
                 let device_id = key_str.strip_prefix(&session_prefix)?;
                 device_id.parse().ok()
             })
+            .filter(|device_id| *device_id != DEFAULT_DEVICE_ID)
             .collect();
         Ok(session_ids)
     }
@@ -351,8 +1055,6 @@ impl SessionStoreExt for SledConfigStore {
         let key = self.session_key(&address);
         trace!("deleting session with key: {}", key);
         self.db
-            .try_write()
-            .expect("poisoned mutex")
             .open_tree(SLED_TREE_SESSIONS)
             .map_err(|e| {
                 log::error!("failed to open sessions tree: {}", e);
@@ -363,20 +1065,25 @@ impl SessionStoreExt for SledConfigStore {
         Ok(())
     }
 
-    async fn delete_all_sessions(&self, _name: &str) -> Result<usize, SignalProtocolError> {
-        let tree = self
-            .db
-            .try_write()
-            .expect("poisoned mutex")
-            .open_tree(SLED_TREE_SESSIONS)
-            .map_err(|e| {
-                log::error!("failed to open sessions tree: {}", e);
-                SignalProtocolError::InternalError("sled error")
+    async fn delete_all_sessions(&self, name: &str) -> Result<usize, SignalProtocolError> {
+        let tree = self.db.open_tree(SLED_TREE_SESSIONS).map_err(|e| {
+            log::error!("failed to open sessions tree: {}", e);
+            SignalProtocolError::InternalError("sled error")
+        })?;
+        let mut deleted = 0;
+        for key in tree.scan_prefix(self.session_prefix(name)).keys() {
+            let key = key.map_err(|e| {
+                log::error!("sled error: {}", e);
+                SignalProtocolError::InternalError("failed to delete all sessions")
             })?;
-        let len = tree.len();
-        tree.clear()
-            .map_err(|_e| SignalProtocolError::InternalError("failed to delete all sessions"))?;
-        Ok(len)
+            tree.remove(key).map_err(|e| {
+                log::error!("sled error: {}", e);
+                SignalProtocolError::InternalError("failed to delete all sessions")
+            })?;
+            deleted += 1;
+        }
+        trace!("deleted {} sessions of {}", deleted, name);
+        Ok(deleted)
     }
 }
 
@@ -387,7 +1094,7 @@ impl IdentityKeyStore for SledConfigStore {
         _ctx: Context,
     ) -> Result<IdentityKeyPair, SignalProtocolError> {
         trace!("getting identity_key_pair");
-        match self.state() {
+        match self.state().await {
             Ok(State::Registered {
                 private_key,
                 public_key,
@@ -408,7 +1115,7 @@ impl IdentityKeyStore for SledConfigStore {
 
     async fn get_local_registration_id(&self, _ctx: Context) -> Result<u32, SignalProtocolError> {
         trace!("getting local_registration_id");
-        match self.state() {
+        match self.state().await {
             Ok(State::Registered {
                 registration_id, ..
             }) => Ok(registration_id),
@@ -429,31 +1136,34 @@ impl IdentityKeyStore for SledConfigStore {
         _ctx: Context,
     ) -> Result<bool, SignalProtocolError> {
         trace!("saving identity");
-        self.insert(self.identity_key(&address), identity_key.serialize())
-            .map_err(|e| {
-                log::error!("error saving identity for {:?}: {}", address, e);
-                SignalProtocolError::InternalError("failed to save identity")
-            })?;
-        trace!("saved identity");
-        Ok(false)
+        self.replace_identity(address, identity_key).map_err(|e| {
+            log::error!("error saving identity for {:?}: {}", address, e);
+            SignalProtocolError::InternalError("failed to save identity")
+        })
     }
 
     async fn is_trusted_identity(
         &self,
         address: &ProtocolAddress,
         identity_key: &IdentityKey,
-        _direction: Direction,
+        direction: Direction,
         _ctx: Context,
     ) -> Result<bool, SignalProtocolError> {
-        match self.get(self.identity_key(&address)).map_err(|_| {
+        let internal_error = |e| {
+            log::error!("error checking trust of {:?}: {}", address, e);
             SignalProtocolError::InternalError("failed to check if identity is trusted")
-        })? {
-            None => {
-                // when we encounter a new identity, we trust it by default
-                warn!("trusting new identity {:?}", address);
-                Ok(true)
+        };
+        match self.known_identity(address).map_err(internal_error)? {
+            // when we encounter a new identity, we trust it on first use
+            None => Ok(true),
+            Some(stored) => {
+                let same_key = &stored == identity_key;
+                let trust_level = self
+                    .load_trust_level(address.name())
+                    .map_err(internal_error)?
+                    .unwrap_or(TrustLevel::TrustedOnFirstUse);
+                Ok(trust_level.trusts(same_key, direction))
             }
-            Some(contents) => Ok(&IdentityKey::decode(&contents)? == identity_key),
         }
     }
 
@@ -466,7 +1176,7 @@ impl IdentityKeyStore for SledConfigStore {
             log::error!("error getting identity of {:?}: {}", address, e);
             SignalProtocolError::InternalError("failed to read identity")
         })?;
-        Ok(buf.map(|ref b| IdentityKey::decode(b).unwrap()))
+        buf.map(|b| IdentityKey::decode(&b)).transpose()
     }
 }
 
@@ -476,11 +1186,31 @@ mod tests {
 
     use libsignal_service::prelude::protocol::{
         self, Direction, IdentityKeyStore, PreKeyRecord, PreKeyStore, SessionRecord, SessionStore,
-        SignedPreKeyRecord, SignedPreKeyStore,
+        SessionStoreExt, SignedPreKeyRecord, SignedPreKeyStore,
     };
     use quickcheck::{Arbitrary, Gen};
 
+    use libsignal_service::{
+        content::{ContentBody, DataMessage, Metadata},
+        prelude::{Content, Uuid},
+        proto::DecryptedGroup,
+        ServiceAddress,
+    };
+
+    use std::collections::HashMap;
+
+    use libsignal_service::groups_v2::CredentialsCache;
+    use zkgroup::{auth::AuthCredentialResponse, ServerSecretParams};
+
     use super::SledConfigStore;
+    use crate::{
+        config::{
+            ConfigStore, GroupsStore, IdentityChange, MessageStore, ProfilesStore, StoredProfile,
+            Thread, TrustLevel, TrustStore,
+        },
+        manager::State,
+        Error, ProfileDetails,
+    };
 
     #[derive(Debug, Clone)]
     struct ProtocolAddress(protocol::ProtocolAddress);
@@ -538,6 +1268,85 @@ mod tests {
         session.serialize().unwrap() == loaded_session.serialize().unwrap()
     }
 
+    #[tokio::test]
+    async fn test_save_state_keeps_data() {
+        let mut db = SledConfigStore::temporary_encrypted().unwrap();
+        let addr = protocol::ProtocolAddress::new("contact".into(), 1);
+        let key_pair = protocol::KeyPair::generate(&mut rand::thread_rng());
+        let identity_key = protocol::IdentityKey::new(key_pair.public_key);
+        db.save_pre_key(1, &PreKeyRecord::new(1, &key_pair), None)
+            .await
+            .unwrap();
+        db.save_identity(&addr, &identity_key, None).await.unwrap();
+        db.store_session(&addr, &SessionRecord::new_fresh(), None)
+            .await
+            .unwrap();
+        db.set_pre_keys_offset_id(42).await.unwrap();
+        db.save_message(&Thread::Contact(Uuid::nil()), content(Uuid::nil(), 1, "hi"))
+            .await
+            .unwrap();
+
+        db.save(&State::New).await.unwrap();
+        assert!(matches!(db.state().await.unwrap(), State::New));
+        assert!(db.get_pre_key(1, None).await.is_ok());
+        assert_eq!(
+            db.get_identity(&addr, None).await.unwrap(),
+            Some(identity_key)
+        );
+        assert!(db.load_session(&addr, None).await.unwrap().is_some());
+        assert_eq!(db.pre_keys_offset_id().await.unwrap(), 42);
+
+        db.reset().await.unwrap();
+        assert!(db.get_pre_key(1, None).await.is_err());
+        assert!(db.get_identity(&addr, None).await.unwrap().is_none());
+        assert!(db.load_session(&addr, None).await.unwrap().is_none());
+        assert!(db.trust_level("contact").await.unwrap().is_none());
+        assert_eq!(db.pre_keys_offset_id().await.unwrap(), 0);
+        assert!(db
+            .message(&Thread::Contact(Uuid::nil()), 1)
+            .await
+            .unwrap()
+            .is_some());
+
+        // the store can still be decrypted
+        db.save(&State::New).await.unwrap();
+        assert!(matches!(db.state().await.unwrap(), State::New));
+    }
+
+    #[tokio::test]
+    async fn test_sessions_of_recipient() {
+        let mut db = SledConfigStore::temporary().unwrap();
+        let session = SessionRecord::new_fresh();
+        for (name, device_id) in &[("alice", 1), ("alice", 2), ("alice", 3), ("bob", 1)] {
+            let addr = protocol::ProtocolAddress::new(name.to_string(), *device_id);
+            db.store_session(&addr, &session, None).await.unwrap();
+        }
+
+        assert_eq!(
+            db.get_sub_device_sessions("alice").await.unwrap(),
+            vec![2, 3]
+        );
+        assert!(db.get_sub_device_sessions("bob").await.unwrap().is_empty());
+
+        db.delete_session(&protocol::ProtocolAddress::new("alice".into(), 2))
+            .await
+            .unwrap();
+        assert_eq!(db.get_sub_device_sessions("alice").await.unwrap(), vec![3]);
+
+        assert_eq!(db.delete_all_sessions("alice").await.unwrap(), 2);
+        assert!(db
+            .load_session(&protocol::ProtocolAddress::new("alice".into(), 1), None)
+            .await
+            .unwrap()
+            .is_none());
+        assert!(db
+            .load_session(&protocol::ProtocolAddress::new("bob".into(), 1), None)
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(db.delete_all_sessions("alice").await.unwrap(), 0);
+    }
+
     #[quickcheck_async::tokio]
     async fn test_prekey_store(id: u32, key_pair: KeyPair) -> bool {
         let mut db = SledConfigStore::temporary().unwrap();
@@ -573,4 +1382,347 @@ mod tests {
             .unwrap()
             == signed_pre_key_record.serialize().unwrap()
     }
+
+    #[test]
+    fn test_encrypted_values() {
+        let db = SledConfigStore::temporary_encrypted().unwrap();
+        db.insert("key", "value").unwrap();
+        assert_eq!(db.get("key").unwrap().unwrap(), "value".as_bytes());
+
+        let raw_value = db.db.get("key").unwrap().unwrap();
+        assert_ne!(raw_value, "value".as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_passphrase() {
+        let path = std::env::temp_dir().join(format!("presage-test-{}", rand::random::<u64>()));
+
+        let db = SledConfigStore::with_passphrase(&path, "passphrase").unwrap();
+        db.save(&State::New).await.unwrap();
+        db.set_pre_keys_offset_id(42).await.unwrap();
+        drop(db);
+
+        assert!(matches!(
+            SledConfigStore::new(&path),
+            Err(Error::StoreEncryptionError(_))
+        ));
+        assert!(matches!(
+            SledConfigStore::with_passphrase(&path, "wrong passphrase"),
+            Err(Error::WrongStorePassphraseError)
+        ));
+
+        let db = SledConfigStore::with_passphrase(&path, "passphrase").unwrap();
+        assert_eq!(db.pre_keys_offset_id().await.unwrap(), 42);
+        db.change_passphrase("new passphrase").unwrap();
+        drop(db);
+
+        assert!(matches!(
+            SledConfigStore::with_passphrase(&path, "passphrase"),
+            Err(Error::WrongStorePassphraseError)
+        ));
+        let db = SledConfigStore::with_passphrase(&path, "new passphrase").unwrap();
+        assert_eq!(db.pre_keys_offset_id().await.unwrap(), 42);
+        drop(db);
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_store_cannot_be_opened_encrypted() {
+        let path = std::env::temp_dir().join(format!("presage-test-{}", rand::random::<u64>()));
+
+        let db = SledConfigStore::new(&path).unwrap();
+        db.set_pre_keys_offset_id(42).await.unwrap();
+        drop(db);
+
+        assert!(matches!(
+            SledConfigStore::with_key(&path, [0; 32]),
+            Err(Error::StoreEncryptionError(_))
+        ));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reset_plaintext_store_cannot_be_opened_encrypted() {
+        let path = std::env::temp_dir().join(format!("presage-test-{}", rand::random::<u64>()));
+
+        let mut db = SledConfigStore::new(&path).unwrap();
+        db.save_message(&Thread::Contact(Uuid::nil()), content(Uuid::nil(), 1, "hi"))
+            .await
+            .unwrap();
+        // messages are kept
+        db.reset().await.unwrap();
+        drop(db);
+
+        assert!(matches!(
+            SledConfigStore::with_passphrase(&path, "passphrase"),
+            Err(Error::StoreEncryptionError(_))
+        ));
+
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    fn content(sender: Uuid, timestamp: u64, body: &str) -> Content {
+        Content {
+            metadata: Metadata {
+                sender: ServiceAddress {
+                    uuid: Some(sender),
+                    phonenumber: None,
+                    relay: None,
+                },
+                sender_device: 1,
+                timestamp,
+                needs_receipt: false,
+            },
+            body: ContentBody::DataMessage(DataMessage {
+                body: Some(body.to_string()),
+                timestamp: Some(timestamp),
+                ..Default::default()
+            }),
+        }
+    }
+
+    fn body(content: &Content) -> &str {
+        match &content.body {
+            ContentBody::DataMessage(message) => message.body(),
+            _ => panic!("not a data message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_store() {
+        let mut db = SledConfigStore::temporary_encrypted().unwrap();
+        let alice = Uuid::from_bytes([1; 16]);
+        let bob = Uuid::from_bytes([2; 16]);
+        let thread = Thread::Contact(bob);
+        let group_thread = Thread::Group([3; 32]);
+
+        for timestamp in 1..=10 {
+            let sender = if timestamp % 2 == 0 { alice } else { bob };
+            db.save_message(&thread, content(sender, timestamp, "hello"))
+                .await
+                .unwrap();
+        }
+        db.save_message(&group_thread, content(alice, 42, "hello group"))
+            .await
+            .unwrap();
+
+        assert_eq!(db.messages(&thread, ..).await.unwrap().count(), 10);
+        let latest: Vec<u64> = db
+            .messages(&thread, ..9)
+            .await
+            .unwrap()
+            .rev()
+            .take(3)
+            .map(|m| m.unwrap().metadata.timestamp)
+            .collect();
+        assert_eq!(latest, vec![8, 7, 6]);
+
+        let (found_thread, message) = db.message_by_sender(&alice, 42).await.unwrap().unwrap();
+        assert_eq!(found_thread, group_thread);
+        assert_eq!(body(&message), "hello group");
+        assert!(db.message_by_sender(&bob, 42).await.unwrap().is_none());
+
+        // replacing a message also replaces its sender
+        db.save_message(&thread, content(alice, 3, "replaced"))
+            .await
+            .unwrap();
+        assert!(db.message_by_sender(&bob, 3).await.unwrap().is_none());
+        let (_, message) = db.message_by_sender(&alice, 3).await.unwrap().unwrap();
+        assert_eq!(body(&message), "replaced");
+
+        assert!(db.delete_message(&thread, 4).await.unwrap());
+        assert!(!db.delete_message(&thread, 4).await.unwrap());
+        assert!(db.message(&thread, 4).await.unwrap().is_none());
+        assert!(db.message_by_sender(&alice, 4).await.unwrap().is_none());
+        assert_eq!(db.messages(&thread, 3..=5).await.unwrap().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_groups_store() {
+        let mut db = SledConfigStore::temporary_encrypted().unwrap();
+        let master_key = [1; 32];
+        assert!(db.group(&master_key).await.unwrap().is_none());
+
+        let mut group = DecryptedGroup {
+            title: "Presage".into(),
+            revision: 1,
+            ..Default::default()
+        };
+        db.save_group(&master_key, &group).await.unwrap();
+        group.revision = 2;
+        db.save_group(&master_key, &group).await.unwrap();
+        db.save_group(&[2; 32], &DecryptedGroup::default())
+            .await
+            .unwrap();
+
+        assert_eq!(db.group(&master_key).await.unwrap(), Some(group));
+        assert_eq!(db.group_revision(&master_key).await.unwrap(), Some(2));
+        assert_eq!(db.groups().await.unwrap().len(), 2);
+
+        assert!(db.delete_group(&master_key).await.unwrap());
+        assert!(!db.delete_group(&master_key).await.unwrap());
+        assert_eq!(db.groups().await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_profiles_store() {
+        let mut db = SledConfigStore::temporary_encrypted().unwrap();
+        let uuid = Uuid::new_v4();
+        assert!(db.profile(&uuid).await.unwrap().is_none());
+
+        assert!(db.save_profile_key(&uuid, [1; 32]).await.unwrap());
+        let profile = StoredProfile {
+            profile_key: [1; 32],
+            details: Some(ProfileDetails {
+                given_name: "Alice".into(),
+                ..Default::default()
+            }),
+            fetched_at: Some(42),
+        };
+        db.save_profile(&uuid, &profile).await.unwrap();
+        assert_eq!(db.profile(&uuid).await.unwrap(), Some(profile.clone()));
+
+        // the same key keeps the cached profile, a new one forgets it
+        assert!(!db.save_profile_key(&uuid, [1; 32]).await.unwrap());
+        assert_eq!(db.profile(&uuid).await.unwrap(), Some(profile));
+        assert!(db.save_profile_key(&uuid, [2; 32]).await.unwrap());
+        assert_eq!(
+            db.profile(&uuid).await.unwrap(),
+            Some(StoredProfile {
+                profile_key: [2; 32],
+                details: None,
+                fetched_at: None,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trust_on_first_use() {
+        let mut db = SledConfigStore::temporary().unwrap();
+        let addr = protocol::ProtocolAddress::new("contact".into(), 1);
+        let identity_key = || {
+            protocol::IdentityKey::new(
+                protocol::KeyPair::generate(&mut rand::thread_rng()).public_key,
+            )
+        };
+        let (first, second, third) = (identity_key(), identity_key(), identity_key());
+
+        assert!(!db.save_identity(&addr, &first, None).await.unwrap());
+        assert_eq!(
+            db.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::TrustedOnFirstUse)
+        );
+        assert!(db.take_identity_changes().await.unwrap().is_empty());
+
+        // changes are accepted until the identity is verified
+        assert!(db
+            .is_trusted_identity(&addr, &second, Direction::Sending, None)
+            .await
+            .unwrap());
+        assert!(db.save_identity(&addr, &second, None).await.unwrap());
+        db.set_trust_level("contact", TrustLevel::Verified)
+            .await
+            .unwrap();
+        assert!(!db
+            .is_trusted_identity(&addr, &third, Direction::Sending, None)
+            .await
+            .unwrap());
+        assert!(db.save_identity(&addr, &third, None).await.unwrap());
+        assert!(!db
+            .is_trusted_identity(&addr, &third, Direction::Sending, None)
+            .await
+            .unwrap());
+        assert!(db
+            .is_trusted_identity(&addr, &third, Direction::Receiving, None)
+            .await
+            .unwrap());
+
+        assert_eq!(
+            db.take_identity_changes().await.unwrap(),
+            vec![IdentityChange {
+                name: "contact".into(),
+                trust_level: TrustLevel::Untrusted,
+            }]
+        );
+        assert!(db.take_identity_changes().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_identity_change_on_new_device() {
+        let mut db = SledConfigStore::temporary().unwrap();
+        let identity_key = || {
+            protocol::IdentityKey::new(
+                protocol::KeyPair::generate(&mut rand::thread_rng()).public_key,
+            )
+        };
+        let (first, second) = (identity_key(), identity_key());
+        let device = |id| protocol::ProtocolAddress::new("contact".into(), id);
+        db.save_identity(&device(1), &first, None).await.unwrap();
+        db.set_trust_level("contact", TrustLevel::Verified)
+            .await
+            .unwrap();
+
+        // another device with the same identity key
+        assert!(db
+            .is_trusted_identity(&device(2), &first, Direction::Sending, None)
+            .await
+            .unwrap());
+        assert!(!db.save_identity(&device(2), &first, None).await.unwrap());
+        assert_eq!(
+            db.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::Verified)
+        );
+
+        // a new device showing another key is a change of identity
+        assert!(!db
+            .is_trusted_identity(&device(3), &second, Direction::Sending, None)
+            .await
+            .unwrap());
+        assert!(db.save_identity(&device(3), &second, None).await.unwrap());
+        assert_eq!(
+            db.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::Untrusted)
+        );
+        assert_eq!(
+            db.take_identity_changes().await.unwrap(),
+            vec![IdentityChange {
+                name: "contact".into(),
+                trust_level: TrustLevel::Untrusted,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_credentials_cache() {
+        let mut db = SledConfigStore::temporary_encrypted().unwrap();
+        let server_secret_params = ServerSecretParams::generate([0; 32]);
+        let credentials: HashMap<i64, AuthCredentialResponse> = (100..107)
+            .map(|day| {
+                let credential = server_secret_params.issue_auth_credential([0; 32], [1; 16], day);
+                (day as i64, credential)
+            })
+            .collect();
+
+        db.write(credentials).unwrap();
+        assert!(CredentialsCache::get(&db, &100).unwrap().is_some());
+        assert!(CredentialsCache::get(&db, &106).unwrap().is_some());
+        assert!(CredentialsCache::get(&db, &107).unwrap().is_none());
+
+        // new credentials expire the ones of the previous days
+        let credentials = (103..110)
+            .map(|day| {
+                let credential = server_secret_params.issue_auth_credential([0; 32], [1; 16], day);
+                (day as i64, credential)
+            })
+            .collect();
+        db.write(credentials).unwrap();
+        assert!(CredentialsCache::get(&db, &103).unwrap().is_some());
+        assert!(CredentialsCache::get(&db, &100).unwrap().is_none());
+        assert!(CredentialsCache::get(&db, &102).unwrap().is_none());
+
+        CredentialsCache::clear(&mut db).unwrap();
+        assert!(CredentialsCache::get(&db, &103).unwrap().is_none());
+    }
 }
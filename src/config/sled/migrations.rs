@@ -0,0 +1,278 @@
+use std::{
+    convert::TryInto,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use log::{info, trace};
+
+use super::{SledConfigStore, SLED_KEY_STATE, SLED_TREE_SESSIONS};
+use crate::{config::TrustLevel, Error};
+
+/// Version of the schema written by this version of presage.
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Schema version, stored in plaintext so that pending migrations can be listed without a key
+pub(super) const SLED_KEY_SCHEMA_VERSION: &str = "schema-version";
+
+/// A step upgrading the schema of a store to the next version.
+#[derive(Clone, Copy)]
+pub struct Migration {
+    /// Version of the schema after this migration
+    pub version: u32,
+    pub description: &'static str,
+    /// Must be idempotent: a migration interrupted before the schema version is recorded runs
+    /// again the next time the store is opened
+    run: fn(&mut SledConfigStore) -> Result<(), Error>,
+}
+
+impl fmt::Debug for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Migration")
+            .field("version", &self.version)
+            .field("description", &self.description)
+            .finish()
+    }
+}
+
+const MIGRATIONS: &[Migration] = &[Migration {
+    version: 1,
+    description: "trust the identities known before trust levels were stored on first use",
+    run: trust_known_identities,
+}];
+
+/// How to apply migrations, see [SledConfigStore::migrate].
+#[derive(Debug, Clone, Default)]
+pub struct MigrationOptions {
+    /// Only returns the migrations that would be applied, without modifying the store
+    pub dry_run: bool,
+    /// Copies the whole database there before applying migrations, if any
+    pub backup_path: Option<PathBuf>,
+}
+
+impl SledConfigStore {
+    /// Returns the schema version of the store.
+    ///
+    /// Stores created before schema versioning are at version 0, and new stores are at
+    /// [SCHEMA_VERSION] from the start.
+    pub fn schema_version(&self) -> Result<u32, Error> {
+        schema_version(&self.db)
+    }
+
+    /// Lists the migrations that opening the store at `path` would apply, without modifying it.
+    ///
+    /// The store must exist, as no database is created at `path`, and must not be opened at the
+    /// same time.
+    pub fn pending_migrations(path: impl AsRef<Path>) -> Result<Vec<Migration>, Error> {
+        let path = path.as_ref();
+        // sled would create an empty database, with no pending migrations
+        if !path.exists() {
+            return Err(Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no store at {}", path.display()),
+            )));
+        }
+        let db = sled::open(path)?;
+        Ok(migrations_since(schema_version(&db)?))
+    }
+
+    /// Upgrades the store to [SCHEMA_VERSION], returning the migrations that were applied.
+    ///
+    /// This is done when opening a store, with a backup next to the database, see
+    /// [SledConfigStore::new].
+    pub fn migrate(&mut self, options: &MigrationOptions) -> Result<Vec<Migration>, Error> {
+        let version = self.schema_version()?;
+        if version > SCHEMA_VERSION {
+            return Err(Error::UnsupportedSchemaVersion(version));
+        }
+        let migrations = migrations_since(version);
+        if options.dry_run {
+            return Ok(migrations);
+        }
+        if migrations.is_empty() {
+            // records the version of new stores
+            self.set_schema_version(SCHEMA_VERSION)?;
+            return Ok(migrations);
+        }
+
+        if let Some(backup_path) = &options.backup_path {
+            info!(
+                "backing up store at schema version {} to {}",
+                version,
+                backup_path.display()
+            );
+            self.backup(backup_path)?;
+        }
+        for migration in &migrations {
+            info!(
+                "migrating store to schema version {}: {}",
+                migration.version, migration.description
+            );
+            (migration.run)(self)?;
+            self.set_schema_version(migration.version)?;
+        }
+        Ok(migrations)
+    }
+
+    /// Copies the whole database (encrypted values stay encrypted) to a new database at `path`.
+    pub fn backup(&self, path: impl AsRef<Path>) -> Result<(), Error> {
+        if path.as_ref().exists() {
+            return Err(Error::IoError(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.as_ref().display()),
+            )));
+        }
+        let backup = sled::open(path.as_ref())?;
+        backup.import(self.db.export());
+        backup.flush()?;
+        Ok(())
+    }
+
+    /// Applies migrations when opening the store at `path`, after backing it up to
+    /// `{path}.backup-v{version}`, which is left for the user to remove.
+    ///
+    /// The backup of a previous, interrupted, attempt is kept and reused: it was taken at the
+    /// same schema version, before any migration ran.
+    pub(super) fn migrated(mut self, path: &Path) -> Result<Self, Error> {
+        let version = self.schema_version()?;
+        let backup_path = PathBuf::from(format!("{}.backup-v{}", path.display(), version));
+        let options = MigrationOptions {
+            dry_run: false,
+            backup_path: if backup_path.exists() {
+                info!("reusing existing backup at {}", backup_path.display());
+                None
+            } else {
+                Some(backup_path)
+            },
+        };
+        self.migrate(&options)?;
+        Ok(self)
+    }
+
+    fn set_schema_version(&self, version: u32) -> Result<(), Error> {
+        trace!("setting schema version to {}", version);
+        self.db
+            .insert(SLED_KEY_SCHEMA_VERSION, &version.to_be_bytes())?;
+        Ok(())
+    }
+}
+
+fn schema_version(db: &sled::Db) -> Result<u32, Error> {
+    match db.get(SLED_KEY_SCHEMA_VERSION)? {
+        Some(version) => Ok(u32::from_be_bytes(version.as_ref().try_into()?)),
+        None if db.contains_key(SLED_KEY_STATE)?
+            || !db.open_tree(SLED_TREE_SESSIONS)?.is_empty() =>
+        {
+            Ok(0)
+        }
+        None => Ok(SCHEMA_VERSION),
+    }
+}
+
+fn migrations_since(version: u32) -> Vec<Migration> {
+    MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > version)
+        .copied()
+        .collect()
+}
+
+/// Identities used to be trusted forever once seen.
+fn trust_known_identities(store: &mut SledConfigStore) -> Result<(), Error> {
+    let names: Vec<String> = store
+        .db
+        .scan_prefix("identity-remote-")
+        .keys()
+        .map(|key| {
+            let key = key?;
+            let address = String::from_utf8_lossy(&key["identity-remote-".len()..]).into_owned();
+            // strip the device id
+            Ok(match address.rfind('.') {
+                Some(i) => address[..i].to_string(),
+                None => address,
+            })
+        })
+        .collect::<Result<_, Error>>()?;
+    for name in names {
+        if store.load_trust_level(&name)?.is_none() {
+            store.store_trust_level(&name, TrustLevel::TrustedOnFirstUse)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use libsignal_service::prelude::protocol::{IdentityKey, KeyPair};
+
+    use super::*;
+
+    #[test]
+    fn test_migrate_legacy_store() {
+        let mut db = SledConfigStore::temporary().unwrap();
+        let identity_key = IdentityKey::new(KeyPair::generate(&mut rand::thread_rng()).public_key);
+        db.insert("identity-remote-contact.1", identity_key.serialize())
+            .unwrap();
+        db.insert(SLED_KEY_STATE, &b"{}"[..]).unwrap();
+        assert_eq!(db.schema_version().unwrap(), 0);
+
+        let dry_run = MigrationOptions {
+            dry_run: true,
+            backup_path: None,
+        };
+        assert_eq!(db.migrate(&dry_run).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(db.schema_version().unwrap(), 0);
+        assert!(db.load_trust_level("contact").unwrap().is_none());
+
+        let backup_dir =
+            std::env::temp_dir().join(format!("presage-backup-{}", rand::random::<u64>()));
+        let options = MigrationOptions {
+            dry_run: false,
+            backup_path: Some(backup_dir.clone()),
+        };
+        assert_eq!(db.migrate(&options).unwrap().len(), MIGRATIONS.len());
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        assert_eq!(
+            db.load_trust_level("contact").unwrap(),
+            Some(TrustLevel::TrustedOnFirstUse)
+        );
+        assert!(db.migrate(&options).unwrap().is_empty());
+
+        assert!(SledConfigStore::pending_migrations(&backup_dir)
+            .unwrap()
+            .iter()
+            .any(|migration| migration.version == 1));
+        std::fs::remove_dir_all(backup_dir).unwrap();
+    }
+
+    #[test]
+    fn test_migrate_reuses_existing_backup() {
+        let db = SledConfigStore::temporary().unwrap();
+        db.insert(SLED_KEY_STATE, &b"{}"[..]).unwrap();
+        let path = std::env::temp_dir().join(format!("presage-{}", rand::random::<u64>()));
+        let backup_path = PathBuf::from(format!("{}.backup-v0", path.display()));
+        // left behind by an interrupted migration
+        db.backup(&backup_path).unwrap();
+
+        let db = db.migrated(&path).unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        std::fs::remove_dir_all(backup_path).unwrap();
+    }
+
+    #[test]
+    fn test_pending_migrations_of_missing_store() {
+        let path = std::env::temp_dir().join(format!("presage-{}", rand::random::<u64>()));
+        assert!(matches!(
+            SledConfigStore::pending_migrations(&path),
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::NotFound
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_new_store_is_up_to_date() {
+        let mut db = SledConfigStore::temporary().unwrap();
+        assert_eq!(db.schema_version().unwrap(), SCHEMA_VERSION);
+        assert!(db.migrate(&MigrationOptions::default()).unwrap().is_empty());
+    }
+}
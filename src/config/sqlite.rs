@@ -0,0 +1,1223 @@
+use std::{
+    collections::HashMap,
+    convert::{TryFrom, TryInto},
+    ops::{Bound, RangeBounds},
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use async_trait::async_trait;
+use libsignal_service::{
+    groups_v2::{CredentialsCache, CredentialsCacheError},
+    models::Contact,
+    prelude::{
+        protocol::{
+            Context, Direction, IdentityKey, IdentityKeyPair, IdentityKeyStore, PreKeyRecord,
+            PreKeyStore, ProtocolAddress, SessionRecord, SessionStore, SessionStoreExt,
+            SignalProtocolError, SignedPreKeyRecord, SignedPreKeyStore,
+        },
+        Content, ProtobufMessage, Uuid,
+    },
+    proto::DecryptedGroup,
+    push_service::DEFAULT_DEVICE_ID,
+};
+use log::{trace, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use zkgroup::auth::AuthCredentialResponse;
+
+use super::{
+    deserialize_content, serialize_content, ConfigStore, ContactsStore, GroupsStore,
+    IdentityChange, MessageStore, ProfilesStore, StoredProfile, Thread, TrustLevel, TrustStore,
+};
+use crate::{manager::State, Error};
+
+/// Version of the schema below, stored as the `user_version` of the database
+const SCHEMA_VERSION: u32 = 1;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS state (
+    id INTEGER PRIMARY KEY CHECK (id = 0),
+    state TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS counters (
+    name TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS pre_keys (
+    id INTEGER PRIMARY KEY,
+    record BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS signed_pre_keys (
+    id INTEGER PRIMARY KEY,
+    record BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    name TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    record BLOB NOT NULL,
+    PRIMARY KEY (name, device_id)
+);
+CREATE TABLE IF NOT EXISTS identities (
+    name TEXT NOT NULL,
+    device_id INTEGER NOT NULL,
+    identity_key BLOB NOT NULL,
+    PRIMARY KEY (name, device_id)
+);
+CREATE TABLE IF NOT EXISTS trust_levels (
+    name TEXT PRIMARY KEY,
+    trust_level TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS identity_changes (
+    name TEXT PRIMARY KEY,
+    trust_level TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS contacts (
+    position INTEGER PRIMARY KEY,
+    uuid TEXT,
+    phone_number TEXT,
+    name TEXT NOT NULL,
+    contact TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS messages (
+    thread TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    sender_uuid TEXT,
+    content TEXT NOT NULL,
+    PRIMARY KEY (thread, timestamp)
+);
+CREATE INDEX IF NOT EXISTS messages_by_sender ON messages (sender_uuid, timestamp);
+CREATE TABLE IF NOT EXISTS groups (
+    master_key BLOB PRIMARY KEY,
+    revision INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    group_state BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS groups_credentials (
+    day INTEGER PRIMARY KEY,
+    credential BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS profiles (
+    uuid TEXT PRIMARY KEY,
+    profile_key BLOB NOT NULL,
+    details TEXT,
+    fetched_at INTEGER
+);
+";
+
+/// A store in an SQLite database, with one table per kind of record so that it can be inspected
+/// with standard tools.
+#[derive(Debug, Clone)]
+pub struct SqliteConfigStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteConfigStore {
+    /// Opens the database at `path`, creating the tables if needed.
+    pub fn new(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Self::init(Connection::open(path)?)
+    }
+
+    /// Opens a database that only lives in memory.
+    pub fn in_memory() -> Result<Self, Error> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self, Error> {
+        let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > SCHEMA_VERSION {
+            return Err(Error::UnsupportedSchemaVersion(version));
+        }
+        conn.execute_batch(SCHEMA)?;
+        conn.pragma_update(None, "user_version", &SCHEMA_VERSION)?;
+        Ok(Self {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // every statement leaves the database consistent, so a panic while holding the lock is harmless
+    fn conn(&self) -> MutexGuard<'_, Connection> {
+        self.conn.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn counter(&self, name: &str) -> Result<u32, Error> {
+        Ok(self
+            .conn()
+            .query_row("SELECT value FROM counters WHERE name = ?", [name], |row| {
+                row.get(0)
+            })
+            .optional()?
+            .unwrap_or(0))
+    }
+
+    fn set_counter(&self, name: &str, value: u32) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO counters (name, value) VALUES (?, ?)",
+            params![name, value],
+        )?;
+        Ok(())
+    }
+
+    fn identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>, Error> {
+        let buf: Option<Vec<u8>> = self
+            .conn()
+            .query_row(
+                "SELECT identity_key FROM identities WHERE name = ? AND device_id = ?",
+                params![address.name(), address.device_id()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(buf.map(|buf| IdentityKey::decode(&buf)).transpose()?)
+    }
+
+    /// Identity key of this device of a contact, or else of another of its devices, as they all
+    /// share the identity key of the account.
+    fn known_identity(&self, address: &ProtocolAddress) -> Result<Option<IdentityKey>, Error> {
+        let buf: Option<Vec<u8>> = self
+            .conn()
+            .query_row(
+                "SELECT identity_key FROM identities WHERE name = ?
+                ORDER BY device_id = ? DESC LIMIT 1",
+                params![address.name(), address.device_id()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(buf.map(|buf| IdentityKey::decode(&buf)).transpose()?)
+    }
+
+    async fn replace_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity_key: &IdentityKey,
+    ) -> Result<bool, Error> {
+        let previous = self.known_identity(address)?;
+        if self.identity(address)?.as_ref() == Some(identity_key) {
+            return Ok(false);
+        }
+        self.conn().execute(
+            "INSERT OR REPLACE INTO identities (name, device_id, identity_key) VALUES (?, ?, ?)",
+            params![
+                address.name(),
+                address.device_id(),
+                identity_key.serialize().into_vec()
+            ],
+        )?;
+
+        let name = address.name();
+        let replaced = previous.map_or(false, |previous| previous != *identity_key);
+        if let Some(trust_level) = TrustLevel::after_save(replaced, self.trust_level(name).await?) {
+            warn!("trusting identity of {}: {:?}", name, trust_level);
+            self.set_trust_level(name, trust_level).await?;
+            if replaced {
+                self.conn().execute(
+                    "INSERT OR REPLACE INTO identity_changes (name, trust_level) VALUES (?, ?)",
+                    params![name, serde_json::to_string(&trust_level)?],
+                )?;
+            }
+        }
+        Ok(replaced)
+    }
+
+    fn credential(&self, day: i64) -> Result<Option<AuthCredentialResponse>, Error> {
+        let buf: Option<Vec<u8>> = self
+            .conn()
+            .query_row(
+                "SELECT credential FROM groups_credentials WHERE day = ?",
+                [day],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(buf.map(|buf| bincode::deserialize(&buf)).transpose()?)
+    }
+
+    fn write_credentials(
+        &self,
+        credentials: HashMap<i64, AuthCredentialResponse>,
+    ) -> Result<(), Error> {
+        let mut conn = self.conn();
+        // a savepoint rather than a transaction, as this also runs within `import_sled`
+        let tx = conn.savepoint()?;
+        // new credentials are fetched from today, the ones of previous days have expired
+        if let Some(oldest) = credentials.keys().min() {
+            tx.execute("DELETE FROM groups_credentials WHERE day < ?", [oldest])?;
+        }
+        for (day, credential) in credentials {
+            tx.execute(
+                "INSERT OR REPLACE INTO groups_credentials (day, credential) VALUES (?, ?)",
+                params![day, bincode::serialize(&credential)?],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sled-store")]
+impl SqliteConfigStore {
+    /// Copies all the records of a sled store, so that the account can be used without linking
+    /// again. This is done in a single transaction, the database is left untouched on failure.
+    ///
+    /// The store must not be used by other tasks until the import is done.
+    pub async fn import_sled(&mut self, sled: &super::sled::SledConfigStore) -> Result<(), Error> {
+        self.conn().execute_batch("BEGIN")?;
+        match self.copy_sled(sled).await {
+            Ok(()) => {
+                self.conn().execute_batch("COMMIT")?;
+                Ok(())
+            }
+            Err(e) => {
+                self.conn().execute_batch("ROLLBACK")?;
+                Err(e)
+            }
+        }
+    }
+
+    async fn copy_sled(&mut self, sled: &super::sled::SledConfigStore) -> Result<(), Error> {
+        self.save(&sled.state().await?).await?;
+        self.set_pre_keys_offset_id(sled.pre_keys_offset_id().await?)
+            .await?;
+        self.set_next_signed_pre_key_id(sled.next_signed_pre_key_id().await?)
+            .await?;
+        let contacts = sled.contacts().await?;
+        // already in the transaction of `import_sled`, which can't be nested
+        replace_contacts(&self.conn(), &contacts)?;
+
+        for (id, record) in sled.pre_key_records()? {
+            self.conn().execute(
+                "INSERT OR REPLACE INTO pre_keys (id, record) VALUES (?, ?)",
+                params![id, record.as_ref()],
+            )?;
+        }
+        for (id, record) in sled.signed_pre_key_records()? {
+            self.conn().execute(
+                "INSERT OR REPLACE INTO signed_pre_keys (id, record) VALUES (?, ?)",
+                params![id, record.as_ref()],
+            )?;
+        }
+        for (address, record) in sled.session_records()? {
+            self.conn().execute(
+                "INSERT OR REPLACE INTO sessions (name, device_id, record) VALUES (?, ?, ?)",
+                params![address.name(), address.device_id(), record.as_ref()],
+            )?;
+        }
+        for (address, identity_key) in sled.identity_records()? {
+            self.conn().execute(
+                "INSERT OR REPLACE INTO identities (name, device_id, identity_key)
+                VALUES (?, ?, ?)",
+                params![
+                    address.name(),
+                    address.device_id(),
+                    identity_key.serialize().into_vec()
+                ],
+            )?;
+        }
+        for (name, trust_level) in sled.trust_levels()? {
+            self.set_trust_level(&name, trust_level).await?;
+        }
+        for (uuid, profile) in sled.profiles()? {
+            self.save_profile(&uuid, &profile).await?;
+        }
+        for (thread, message) in sled.all_messages()? {
+            self.save_message(&thread, message).await?;
+        }
+        for (master_key, group) in sled.groups().await? {
+            self.save_group(&master_key, &group).await?;
+        }
+        self.write_credentials(sled.credentials()?.into_iter().collect())?;
+        Ok(())
+    }
+}
+
+/// Converts a timestamp to an SQLite integer, which is signed.
+fn sql_timestamp(timestamp: u64) -> Result<i64, rusqlite::Error> {
+    i64::try_from(timestamp).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+/// Replaces all the contacts, from within a transaction so that they are never partially saved.
+fn replace_contacts(conn: &Connection, contacts: &[Contact]) -> Result<(), Error> {
+    conn.execute("DELETE FROM contacts", [])?;
+    for (position, contact) in contacts.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO contacts (position, uuid, phone_number, name, contact)
+            VALUES (?, ?, ?, ?, ?)",
+            params![
+                position as i64,
+                contact.address.uuid.map(|uuid| uuid.to_string()),
+                contact
+                    .address
+                    .phonenumber
+                    .as_ref()
+                    .map(ToString::to_string),
+                contact.name,
+                serde_json::to_string(contact)?,
+            ],
+        )?;
+    }
+    Ok(())
+}
+
+/// Column value of a thread, readable when inspecting the database.
+fn thread_key(thread: &Thread) -> String {
+    match thread {
+        Thread::Contact(uuid) => format!("contact:{}", uuid),
+        Thread::Group(master_key) => format!("group:{}", hex::encode(master_key)),
+    }
+}
+
+fn thread_from_key(key: &str) -> Option<Thread> {
+    if let Some(uuid) = key.strip_prefix("contact:") {
+        Uuid::parse_str(uuid).ok().map(Thread::Contact)
+    } else {
+        let master_key = hex::decode(key.strip_prefix("group:")?).ok()?;
+        master_key.as_slice().try_into().ok().map(Thread::Group)
+    }
+}
+
+fn decode_group(buf: &[u8]) -> Result<DecryptedGroup, Error> {
+    DecryptedGroup::decode(buf).map_err(|e| {
+        log::error!("failed to decode stored group: {}", e);
+        Error::MessageDecodeError
+    })
+}
+
+/// Maps store errors into the errors of the libsignal-protocol store traits.
+fn protocol_error(e: Error) -> SignalProtocolError {
+    log::error!("sqlite store error: {}", e);
+    SignalProtocolError::InternalError("sqlite store error")
+}
+
+#[async_trait]
+impl ConfigStore for SqliteConfigStore {
+    async fn state(&self) -> Result<State, Error> {
+        let state: Option<String> = self
+            .conn()
+            .query_row("SELECT state FROM state WHERE id = 0", [], |row| row.get(0))
+            .optional()?;
+        state.map_or(Ok(State::New), |state| Ok(serde_json::from_str(&state)?))
+    }
+
+    async fn save(&self, state: &State) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO state (id, state) VALUES (0, ?)",
+            [serde_json::to_string(state)?],
+        )?;
+        Ok(())
+    }
+
+    async fn reset(&mut self) -> Result<(), Error> {
+        self.conn().execute_batch(
+            "BEGIN;
+            DELETE FROM state;
+            DELETE FROM counters;
+            DELETE FROM pre_keys;
+            DELETE FROM signed_pre_keys;
+            DELETE FROM sessions;
+            DELETE FROM identities;
+            DELETE FROM trust_levels;
+            DELETE FROM identity_changes;
+            DELETE FROM contacts;
+            DELETE FROM groups_credentials;
+            DELETE FROM profiles;
+            COMMIT;",
+        )?;
+        Ok(())
+    }
+
+    async fn pre_keys_offset_id(&self) -> Result<u32, Error> {
+        self.counter("pre_keys_offset_id")
+    }
+
+    async fn set_pre_keys_offset_id(&self, id: u32) -> Result<(), Error> {
+        self.set_counter("pre_keys_offset_id", id)
+    }
+
+    async fn next_signed_pre_key_id(&self) -> Result<u32, Error> {
+        self.counter("next_signed_pre_key_id")
+    }
+
+    async fn set_next_signed_pre_key_id(&self, id: u32) -> Result<(), Error> {
+        self.set_counter("next_signed_pre_key_id", id)
+    }
+
+    async fn remove_signed_pre_key(&mut self, id: u32) -> Result<(), Error> {
+        self.conn()
+            .execute("DELETE FROM signed_pre_keys WHERE id = ?", [id])?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ContactsStore for SqliteConfigStore {
+    async fn save_contacts(&mut self, contacts: &[Contact]) -> Result<(), Error> {
+        let mut conn = self.conn();
+        let tx = conn.transaction()?;
+        replace_contacts(&tx, contacts)?;
+        tx.commit()?;
+        trace!("saved contacts");
+        Ok(())
+    }
+
+    async fn contacts(&self) -> Result<Vec<Contact>, Error> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT contact FROM contacts ORDER BY position")?;
+        let contacts = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .map(|contact| Ok(serde_json::from_str(&contact?)?))
+            .collect();
+        contacts
+    }
+}
+
+#[async_trait]
+impl MessageStore for SqliteConfigStore {
+    type MessagesIter = std::vec::IntoIter<Result<Content, Error>>;
+
+    async fn save_message(&mut self, thread: &Thread, message: Content) -> Result<(), Error> {
+        trace!("storing message in {:?}", thread);
+        self.conn().execute(
+            "INSERT OR REPLACE INTO messages (thread, timestamp, sender_uuid, content)
+            VALUES (?, ?, ?, ?)",
+            params![
+                thread_key(thread),
+                sql_timestamp(message.metadata.timestamp)?,
+                message.metadata.sender.uuid.map(|uuid| uuid.to_string()),
+                String::from_utf8(serialize_content(&message)?).expect("stored content is JSON"),
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn delete_message(&mut self, thread: &Thread, timestamp: u64) -> Result<bool, Error> {
+        let timestamp = match i64::try_from(timestamp) {
+            Ok(timestamp) => timestamp,
+            Err(_) => return Ok(false),
+        };
+        let deleted = self.conn().execute(
+            "DELETE FROM messages WHERE thread = ? AND timestamp = ?",
+            params![thread_key(thread), timestamp],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    async fn message(&self, thread: &Thread, timestamp: u64) -> Result<Option<Content>, Error> {
+        let timestamp = match i64::try_from(timestamp) {
+            Ok(timestamp) => timestamp,
+            Err(_) => return Ok(None),
+        };
+        let content: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT content FROM messages WHERE thread = ? AND timestamp = ?",
+                params![thread_key(thread), timestamp],
+                |row| row.get(0),
+            )
+            .optional()?;
+        content
+            .map(|content| deserialize_content(content.as_bytes()))
+            .transpose()
+    }
+
+    async fn message_by_sender(
+        &self,
+        sender: &Uuid,
+        timestamp: u64,
+    ) -> Result<Option<(Thread, Content)>, Error> {
+        let timestamp = match i64::try_from(timestamp) {
+            Ok(timestamp) => timestamp,
+            Err(_) => return Ok(None),
+        };
+        // the sender may have sent messages to several threads at the same timestamp, in which
+        // case the most recently saved one is returned, like the other stores do: rows get a new
+        // rowid, higher than any other, when they are inserted or replaced
+        let row: Option<(String, String)> = self
+            .conn()
+            .query_row(
+                "SELECT thread, content FROM messages
+                WHERE sender_uuid = ? AND timestamp = ?
+                ORDER BY rowid DESC
+                LIMIT 1",
+                params![sender.to_string(), timestamp],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (thread, content) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let thread = thread_from_key(&thread).ok_or(Error::MessageDecodeError)?;
+        Ok(Some((thread, deserialize_content(content.as_bytes())?)))
+    }
+
+    async fn messages(
+        &self,
+        thread: &Thread,
+        range: impl RangeBounds<u64> + Send,
+    ) -> Result<Self::MessagesIter, Error> {
+        // stored timestamps fit in an i64, so a range starting beyond it is empty, and one ending
+        // beyond it ends with it
+        let start = match range.start_bound() {
+            Bound::Included(timestamp) => i64::try_from(*timestamp).ok(),
+            Bound::Excluded(timestamp) => i64::try_from(*timestamp)
+                .ok()
+                .and_then(|timestamp| timestamp.checked_add(1)),
+            Bound::Unbounded => Some(0),
+        };
+        let start = match start {
+            Some(start) => start,
+            None => return Ok(Vec::new().into_iter()),
+        };
+        let end = match range.end_bound() {
+            Bound::Included(timestamp) => i64::try_from(*timestamp).unwrap_or(i64::MAX),
+            Bound::Excluded(timestamp) => {
+                i64::try_from(*timestamp).map_or(i64::MAX, |timestamp| timestamp.saturating_sub(1))
+            }
+            Bound::Unbounded => i64::MAX,
+        };
+
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT content FROM messages
+            WHERE thread = ? AND timestamp BETWEEN ? AND ?
+            ORDER BY timestamp",
+        )?;
+        let messages: Vec<_> = stmt
+            .query_map(params![thread_key(thread), start, end], |row| {
+                row.get::<_, String>(0)
+            })?
+            .map(|content| deserialize_content(content?.as_bytes()))
+            .collect();
+        Ok(messages.into_iter())
+    }
+}
+
+#[async_trait]
+impl GroupsStore for SqliteConfigStore {
+    async fn save_group(
+        &mut self,
+        master_key: &[u8; 32],
+        group: &DecryptedGroup,
+    ) -> Result<(), Error> {
+        let mut buf = Vec::new();
+        group
+            .encode(&mut buf)
+            .expect("encoding into a vector never fails");
+        self.conn().execute(
+            "INSERT OR REPLACE INTO groups (master_key, revision, title, group_state)
+            VALUES (?, ?, ?, ?)",
+            params![&master_key[..], group.revision, group.title, buf],
+        )?;
+        trace!("stored group at revision {}", group.revision);
+        Ok(())
+    }
+
+    async fn delete_group(&mut self, master_key: &[u8; 32]) -> Result<bool, Error> {
+        let deleted = self
+            .conn()
+            .execute("DELETE FROM groups WHERE master_key = ?", [&master_key[..]])?;
+        Ok(deleted > 0)
+    }
+
+    async fn group(&self, master_key: &[u8; 32]) -> Result<Option<DecryptedGroup>, Error> {
+        let buf: Option<Vec<u8>> = self
+            .conn()
+            .query_row(
+                "SELECT group_state FROM groups WHERE master_key = ?",
+                [&master_key[..]],
+                |row| row.get(0),
+            )
+            .optional()?;
+        buf.map(|buf| decode_group(&buf)).transpose()
+    }
+
+    async fn groups(&self) -> Result<Vec<([u8; 32], DecryptedGroup)>, Error> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT master_key, group_state FROM groups")?;
+        let groups = stmt
+            .query_map([], |row| {
+                Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?
+            .map(|row| {
+                let (master_key, buf) = row?;
+                Ok((master_key.as_slice().try_into()?, decode_group(&buf)?))
+            })
+            .collect();
+        groups
+    }
+}
+
+#[async_trait]
+impl TrustStore for SqliteConfigStore {
+    async fn trust_level(&self, name: &str) -> Result<Option<TrustLevel>, Error> {
+        let trust_level: Option<String> = self
+            .conn()
+            .query_row(
+                "SELECT trust_level FROM trust_levels WHERE name = ?",
+                [name],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(trust_level
+            .map(|trust_level| serde_json::from_str(&trust_level))
+            .transpose()?)
+    }
+
+    async fn set_trust_level(&mut self, name: &str, level: TrustLevel) -> Result<(), Error> {
+        self.conn().execute(
+            "INSERT OR REPLACE INTO trust_levels (name, trust_level) VALUES (?, ?)",
+            params![name, serde_json::to_string(&level)?],
+        )?;
+        Ok(())
+    }
+
+    async fn take_identity_changes(&mut self) -> Result<Vec<IdentityChange>, Error> {
+        let mut conn = self.conn();
+        // a savepoint can be nested, should this ever run within another transaction
+        let tx = conn.savepoint()?;
+        let changes = tx
+            .prepare("SELECT name, trust_level FROM identity_changes")?
+            .query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })?
+            .map(|row| {
+                let (name, trust_level) = row?;
+                Ok(IdentityChange {
+                    name,
+                    trust_level: serde_json::from_str(&trust_level)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        tx.execute("DELETE FROM identity_changes", [])?;
+        tx.commit()?;
+        Ok(changes)
+    }
+}
+
+#[async_trait]
+impl ProfilesStore for SqliteConfigStore {
+    async fn save_profile(&mut self, uuid: &Uuid, profile: &StoredProfile) -> Result<(), Error> {
+        let details = profile
+            .details
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        self.conn().execute(
+            "INSERT OR REPLACE INTO profiles (uuid, profile_key, details, fetched_at)
+            VALUES (?, ?, ?, ?)",
+            params![
+                uuid.to_string(),
+                &profile.profile_key[..],
+                details,
+                profile.fetched_at.map(|fetched_at| fetched_at as i64)
+            ],
+        )?;
+        Ok(())
+    }
+
+    async fn profile(&self, uuid: &Uuid) -> Result<Option<StoredProfile>, Error> {
+        let row: Option<(Vec<u8>, Option<String>, Option<i64>)> = self
+            .conn()
+            .query_row(
+                "SELECT profile_key, details, fetched_at FROM profiles WHERE uuid = ?",
+                [uuid.to_string()],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let (profile_key, details, fetched_at) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        Ok(Some(StoredProfile {
+            profile_key: profile_key.as_slice().try_into()?,
+            details: details
+                .map(|details| serde_json::from_str(&details))
+                .transpose()?,
+            fetched_at: fetched_at.map(|fetched_at| fetched_at as u64),
+        }))
+    }
+}
+
+impl CredentialsCache for SqliteConfigStore {
+    fn clear(&mut self) -> Result<(), CredentialsCacheError> {
+        self.conn()
+            .execute("DELETE FROM groups_credentials", [])
+            .map_err(|e| {
+                log::error!("failed to clear groups credentials: {}", e);
+                CredentialsCacheError::WriteError
+            })?;
+        Ok(())
+    }
+
+    fn get(&self, key: &i64) -> Result<Option<AuthCredentialResponse>, CredentialsCacheError> {
+        self.credential(*key).map_err(|e| {
+            log::error!("failed to read groups credential: {}", e);
+            CredentialsCacheError::ReadError
+        })
+    }
+
+    fn write(
+        &mut self,
+        map: HashMap<i64, AuthCredentialResponse>,
+    ) -> Result<(), CredentialsCacheError> {
+        self.write_credentials(map).map_err(|e| {
+            log::error!("failed to write groups credentials: {}", e);
+            CredentialsCacheError::WriteError
+        })
+    }
+}
+
+#[async_trait(?Send)]
+impl PreKeyStore for SqliteConfigStore {
+    async fn get_pre_key(
+        &self,
+        prekey_id: u32,
+        _ctx: Context,
+    ) -> Result<PreKeyRecord, SignalProtocolError> {
+        let buf: Vec<u8> = self
+            .conn()
+            .query_row(
+                "SELECT record FROM pre_keys WHERE id = ?",
+                [prekey_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| protocol_error(e.into()))?
+            .ok_or(SignalProtocolError::InvalidPreKeyId)?;
+        PreKeyRecord::deserialize(&buf)
+    }
+
+    async fn save_pre_key(
+        &mut self,
+        prekey_id: u32,
+        record: &PreKeyRecord,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        self.conn()
+            .execute(
+                "INSERT OR REPLACE INTO pre_keys (id, record) VALUES (?, ?)",
+                params![prekey_id, record.serialize()?],
+            )
+            .map_err(|e| protocol_error(e.into()))?;
+        Ok(())
+    }
+
+    async fn remove_pre_key(
+        &mut self,
+        prekey_id: u32,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        self.conn()
+            .execute("DELETE FROM pre_keys WHERE id = ?", [prekey_id])
+            .map_err(|e| protocol_error(e.into()))?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl SignedPreKeyStore for SqliteConfigStore {
+    async fn get_signed_pre_key(
+        &self,
+        signed_prekey_id: u32,
+        _ctx: Context,
+    ) -> Result<SignedPreKeyRecord, SignalProtocolError> {
+        let buf: Vec<u8> = self
+            .conn()
+            .query_row(
+                "SELECT record FROM signed_pre_keys WHERE id = ?",
+                [signed_prekey_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| protocol_error(e.into()))?
+            .ok_or(SignalProtocolError::InvalidSignedPreKeyId)?;
+        SignedPreKeyRecord::deserialize(&buf)
+    }
+
+    async fn save_signed_pre_key(
+        &mut self,
+        signed_prekey_id: u32,
+        record: &SignedPreKeyRecord,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        self.conn()
+            .execute(
+                "INSERT OR REPLACE INTO signed_pre_keys (id, record) VALUES (?, ?)",
+                params![signed_prekey_id, record.serialize()?],
+            )
+            .map_err(|e| protocol_error(e.into()))?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for SqliteConfigStore {
+    async fn load_session(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<SessionRecord>, SignalProtocolError> {
+        trace!("loading session of {}", address);
+        let buf: Option<Vec<u8>> = self
+            .conn()
+            .query_row(
+                "SELECT record FROM sessions WHERE name = ? AND device_id = ?",
+                params![address.name(), address.device_id()],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| protocol_error(e.into()))?;
+        buf.map(|buf| SessionRecord::deserialize(&buf)).transpose()
+    }
+
+    async fn store_session(
+        &mut self,
+        address: &ProtocolAddress,
+        record: &SessionRecord,
+        _ctx: Context,
+    ) -> Result<(), SignalProtocolError> {
+        trace!("storing session of {}", address);
+        self.conn()
+            .execute(
+                "INSERT OR REPLACE INTO sessions (name, device_id, record) VALUES (?, ?, ?)",
+                params![address.name(), address.device_id(), record.serialize()?],
+            )
+            .map_err(|e| protocol_error(e.into()))?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStoreExt for SqliteConfigStore {
+    async fn get_sub_device_sessions(&self, name: &str) -> Result<Vec<u32>, SignalProtocolError> {
+        let conn = self.conn();
+        let sub_devices = conn
+            .prepare("SELECT device_id FROM sessions WHERE name = ? AND device_id != ?")
+            .and_then(|mut stmt| {
+                let sub_devices = stmt
+                    .query_map(params![name, DEFAULT_DEVICE_ID], |row| row.get(0))?
+                    .collect();
+                sub_devices
+            })
+            .map_err(|e| protocol_error(e.into()))?;
+        Ok(sub_devices)
+    }
+
+    async fn delete_session(&self, address: &ProtocolAddress) -> Result<(), SignalProtocolError> {
+        trace!("deleting session of {}", address);
+        self.conn()
+            .execute(
+                "DELETE FROM sessions WHERE name = ? AND device_id = ?",
+                params![address.name(), address.device_id()],
+            )
+            .map_err(|e| protocol_error(e.into()))?;
+        Ok(())
+    }
+
+    async fn delete_all_sessions(&self, name: &str) -> Result<usize, SignalProtocolError> {
+        self.conn()
+            .execute("DELETE FROM sessions WHERE name = ?", [name])
+            .map_err(|e| protocol_error(e.into()))
+    }
+}
+
+#[async_trait(?Send)]
+impl IdentityKeyStore for SqliteConfigStore {
+    async fn get_identity_key_pair(
+        &self,
+        _ctx: Context,
+    ) -> Result<IdentityKeyPair, SignalProtocolError> {
+        match self.state().await {
+            Ok(State::Registered {
+                private_key,
+                public_key,
+                ..
+            }) => Ok(IdentityKeyPair::new(
+                IdentityKey::new(public_key),
+                private_key,
+            )),
+            Ok(_) => Err(SignalProtocolError::InternalError(
+                "wrong state: no registration data yet",
+            )),
+            Err(e) => Err(protocol_error(e)),
+        }
+    }
+
+    async fn get_local_registration_id(&self, _ctx: Context) -> Result<u32, SignalProtocolError> {
+        match self.state().await {
+            Ok(State::Registered {
+                registration_id, ..
+            }) => Ok(registration_id),
+            Ok(_) => Err(SignalProtocolError::InternalError(
+                "wrong state: no registration data yet",
+            )),
+            Err(e) => Err(protocol_error(e)),
+        }
+    }
+
+    async fn save_identity(
+        &mut self,
+        address: &ProtocolAddress,
+        identity_key: &IdentityKey,
+        _ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        self.replace_identity(address, identity_key)
+            .await
+            .map_err(protocol_error)
+    }
+
+    async fn is_trusted_identity(
+        &self,
+        address: &ProtocolAddress,
+        identity_key: &IdentityKey,
+        direction: Direction,
+        _ctx: Context,
+    ) -> Result<bool, SignalProtocolError> {
+        match self.known_identity(address).map_err(protocol_error)? {
+            // when we encounter a new identity, we trust it on first use
+            None => Ok(true),
+            Some(stored) => {
+                let trust_level = self
+                    .trust_level(address.name())
+                    .await
+                    .map_err(protocol_error)?
+                    .unwrap_or(TrustLevel::TrustedOnFirstUse);
+                Ok(trust_level.trusts(&stored == identity_key, direction))
+            }
+        }
+    }
+
+    async fn get_identity(
+        &self,
+        address: &ProtocolAddress,
+        _ctx: Context,
+    ) -> Result<Option<IdentityKey>, SignalProtocolError> {
+        self.identity(address).map_err(protocol_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libsignal_service::{
+        content::{ContentBody, DataMessage, Metadata},
+        prelude::protocol::KeyPair,
+        ServiceAddress,
+    };
+
+    use super::*;
+
+    fn content(sender: Uuid, timestamp: u64) -> Content {
+        Content {
+            metadata: Metadata {
+                sender: ServiceAddress {
+                    uuid: Some(sender),
+                    phonenumber: None,
+                    relay: None,
+                },
+                sender_device: 1,
+                timestamp,
+                needs_receipt: false,
+            },
+            body: ContentBody::DataMessage(DataMessage {
+                body: Some(timestamp.to_string()),
+                timestamp: Some(timestamp),
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_protocol_stores() {
+        let mut db = SqliteConfigStore::in_memory().unwrap();
+        let key_pair = KeyPair::generate(&mut rand::thread_rng());
+        let address = ProtocolAddress::new("contact".into(), 2);
+
+        db.save_pre_key(1, &PreKeyRecord::new(1, &key_pair), None)
+            .await
+            .unwrap();
+        assert!(db.get_pre_key(1, None).await.is_ok());
+        db.remove_pre_key(1, None).await.unwrap();
+        assert!(db.get_pre_key(1, None).await.is_err());
+
+        db.store_session(&address, &SessionRecord::new_fresh(), None)
+            .await
+            .unwrap();
+        assert!(db.load_session(&address, None).await.unwrap().is_some());
+        assert_eq!(
+            db.get_sub_device_sessions("contact").await.unwrap(),
+            vec![2]
+        );
+        assert_eq!(db.delete_all_sessions("contact").await.unwrap(), 1);
+
+        let identity_key = IdentityKey::new(key_pair.public_key);
+        assert!(!db
+            .save_identity(&address, &identity_key, None)
+            .await
+            .unwrap());
+        assert_eq!(
+            db.get_identity(&address, None).await.unwrap(),
+            Some(identity_key)
+        );
+        assert_eq!(
+            db.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::TrustedOnFirstUse)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_identity_change_on_new_device() {
+        let mut db = SqliteConfigStore::in_memory().unwrap();
+        let identity_key =
+            || IdentityKey::new(KeyPair::generate(&mut rand::thread_rng()).public_key);
+        let (first, second) = (identity_key(), identity_key());
+        let device = |id| ProtocolAddress::new("contact".into(), id);
+        db.save_identity(&device(1), &first, None).await.unwrap();
+        db.set_trust_level("contact", TrustLevel::Verified)
+            .await
+            .unwrap();
+
+        // another device with the same identity key
+        assert!(db
+            .is_trusted_identity(&device(2), &first, Direction::Sending, None)
+            .await
+            .unwrap());
+        assert!(!db.save_identity(&device(2), &first, None).await.unwrap());
+        assert_eq!(
+            db.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::Verified)
+        );
+
+        // a new device showing another key is a change of identity
+        assert!(!db
+            .is_trusted_identity(&device(3), &second, Direction::Sending, None)
+            .await
+            .unwrap());
+        assert!(db.save_identity(&device(3), &second, None).await.unwrap());
+        assert_eq!(
+            db.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::Untrusted)
+        );
+        assert_eq!(
+            db.take_identity_changes().await.unwrap(),
+            vec![IdentityChange {
+                name: "contact".into(),
+                trust_level: TrustLevel::Untrusted,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_message_store() {
+        let mut db = SqliteConfigStore::in_memory().unwrap();
+        let sender = Uuid::new_v4();
+        let thread = Thread::Contact(sender);
+        for timestamp in 1..=4 {
+            db.save_message(&thread, content(sender, timestamp))
+                .await
+                .unwrap();
+        }
+
+        let timestamps = |iter: std::vec::IntoIter<Result<Content, Error>>| -> Vec<u64> {
+            iter.map(|message| message.unwrap().metadata.timestamp)
+                .collect()
+        };
+        assert_eq!(
+            timestamps(db.messages(&thread, ..).await.unwrap()),
+            vec![1, 2, 3, 4]
+        );
+        assert_eq!(
+            timestamps(db.messages(&thread, 2..4).await.unwrap()),
+            vec![2, 3]
+        );
+        assert_eq!(
+            timestamps(
+                db.messages(&thread, ..=2)
+                    .await
+                    .unwrap()
+                    .rev()
+                    .collect::<Vec<_>>()
+                    .into_iter()
+            ),
+            vec![2, 1]
+        );
+
+        // bounds beyond the timestamps SQLite can store
+        assert_eq!(
+            timestamps(db.messages(&thread, 3..=u64::MAX).await.unwrap()),
+            vec![3, 4]
+        );
+        assert_eq!(
+            timestamps(db.messages(&thread, 2..u64::MAX).await.unwrap()),
+            vec![2, 3, 4]
+        );
+        assert!(db
+            .messages(&thread, (i64::MAX as u64 + 1)..)
+            .await
+            .unwrap()
+            .next()
+            .is_none());
+        assert!(db.messages(&thread, ..0).await.unwrap().next().is_none());
+        assert!(db
+            .save_message(&thread, content(sender, u64::MAX))
+            .await
+            .is_err());
+        assert!(db.message(&thread, u64::MAX).await.unwrap().is_none());
+
+        let (found, _) = db.message_by_sender(&sender, 3).await.unwrap().unwrap();
+        assert_eq!(found, thread);
+        assert!(db.delete_message(&thread, 3).await.unwrap());
+        assert!(db.message(&thread, 3).await.unwrap().is_none());
+        assert!(db.message_by_sender(&sender, 3).await.unwrap().is_none());
+
+        // the sender's message saved last wins, like in the other stores
+        let group = Thread::Group([1; 32]);
+        db.save_message(&thread, content(sender, 3)).await.unwrap();
+        db.save_message(&group, content(sender, 3)).await.unwrap();
+        let (found, _) = db.message_by_sender(&sender, 3).await.unwrap().unwrap();
+        assert_eq!(found, group);
+        db.save_message(&thread, content(sender, 3)).await.unwrap();
+        let (found, _) = db.message_by_sender(&sender, 3).await.unwrap().unwrap();
+        assert_eq!(found, thread);
+    }
+
+    #[cfg(feature = "sled-store")]
+    #[tokio::test]
+    async fn test_import_sled() {
+        let mut sled = super::super::sled::SledConfigStore::temporary().unwrap();
+        let key_pair = KeyPair::generate(&mut rand::thread_rng());
+        let address = ProtocolAddress::new("contact".into(), 1);
+        let thread = Thread::Contact(Uuid::nil());
+
+        sled.save_pre_key(7, &PreKeyRecord::new(7, &key_pair), None)
+            .await
+            .unwrap();
+        sled.store_session(&address, &SessionRecord::new_fresh(), None)
+            .await
+            .unwrap();
+        sled.save_identity(&address, &IdentityKey::new(key_pair.public_key), None)
+            .await
+            .unwrap();
+        sled.set_pre_keys_offset_id(8).await.unwrap();
+        sled.save_message(&thread, content(Uuid::nil(), 1))
+            .await
+            .unwrap();
+        let profile = StoredProfile {
+            profile_key: [3; 32],
+            details: Some(Default::default()),
+            fetched_at: Some(1),
+        };
+        sled.save_profile(&Uuid::nil(), &profile).await.unwrap();
+
+        let mut db = SqliteConfigStore::in_memory().unwrap();
+        // contacts (even none) and credentials are written within the import transaction
+        db.import_sled(&sled).await.unwrap();
+        assert!(db.contacts().await.unwrap().is_empty());
+        assert!(db.get_pre_key(7, None).await.is_ok());
+        assert!(db.load_session(&address, None).await.unwrap().is_some());
+        assert!(db.get_identity(&address, None).await.unwrap().is_some());
+        assert_eq!(
+            db.trust_level("contact").await.unwrap(),
+            Some(TrustLevel::TrustedOnFirstUse)
+        );
+        assert_eq!(db.pre_keys_offset_id().await.unwrap(), 8);
+        assert!(db.message(&thread, 1).await.unwrap().is_some());
+        assert_eq!(db.profile(&Uuid::nil()).await.unwrap(), Some(profile));
+    }
+}
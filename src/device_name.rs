@@ -0,0 +1,130 @@
+use aes::Aes256;
+use ctr::{
+    cipher::{generic_array::GenericArray, NewCipher, StreamCipher},
+    Ctr128BE,
+};
+use hmac::{Hmac, Mac, NewMac};
+use libsignal_service::prelude::protocol::{KeyPair, PublicKey};
+use rand::{CryptoRng, Rng};
+use sha2::Sha256;
+
+use crate::Error;
+
+const SYNTHETIC_IV_LEN: usize = 16;
+
+/// Encrypts a device name like official clients do, so that only devices having the identity key
+/// of the account can read it, and returns the base64 encoded `DeviceName` protobuf message.
+///
+/// The name is encrypted with AES-CTR, keyed from an agreement between an ephemeral key and the
+/// identity key, and a synthetic IV which also authenticates it.
+pub(crate) fn encrypt_device_name<R: Rng + CryptoRng>(
+    name: &str,
+    identity_key: &PublicKey,
+    csprng: &mut R,
+) -> Result<String, Error> {
+    let ephemeral = KeyPair::generate(csprng);
+    let master_secret = ephemeral.calculate_agreement(identity_key)?;
+    let synthetic_iv = synthetic_iv(&master_secret, name.as_bytes());
+    let mut ciphertext = name.as_bytes().to_vec();
+    cipher(&master_secret, &synthetic_iv).apply_keystream(&mut ciphertext);
+
+    // the message only has bytes fields, so it is simply encoded by hand
+    let mut message = Vec::new();
+    encode_field(&mut message, 1, &ephemeral.public_key.serialize());
+    encode_field(&mut message, 2, &synthetic_iv);
+    encode_field(&mut message, 3, &ciphertext);
+    Ok(base64::encode(message))
+}
+
+fn synthetic_iv(master_secret: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut iv = hmac_sha256(&hmac_sha256(master_secret, b"auth"), plaintext);
+    iv.truncate(SYNTHETIC_IV_LEN);
+    iv
+}
+
+fn cipher(master_secret: &[u8], synthetic_iv: &[u8]) -> Ctr128BE<Aes256> {
+    let key = hmac_sha256(&hmac_sha256(master_secret, b"cipher"), synthetic_iv);
+    Ctr128BE::new(GenericArray::from_slice(&key), &Default::default())
+}
+
+fn hmac_sha256(key: &[u8], input: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC takes keys of any size");
+    mac.update(input);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Appends a length-delimited field to a protobuf message.
+fn encode_field(message: &mut Vec<u8>, number: u8, bytes: &[u8]) {
+    message.push(number << 3 | 2);
+    let mut len = bytes.len();
+    while len >= 0x80 {
+        message.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    message.push(len as u8);
+    message.extend_from_slice(bytes);
+}
+
+/// Decrypts a name encrypted by [encrypt_device_name], returning `None` if it was not encrypted
+/// for this identity key.
+#[cfg(test)]
+pub(crate) fn decrypt_device_name(
+    encrypted: &str,
+    identity_key: &libsignal_service::prelude::protocol::PrivateKey,
+) -> Option<String> {
+    let message = base64::decode(encrypted).ok()?;
+    let mut fields = std::collections::HashMap::new();
+    let mut rest = &message[..];
+    while let Some((&tag, tail)) = rest.split_first() {
+        // lengths of our fields fit in a single byte
+        let (&len, tail) = tail.split_first()?;
+        if tag & 7 != 2 || len >= 0x80 || tail.len() < len as usize {
+            return None;
+        }
+        let (value, tail) = tail.split_at(len as usize);
+        fields.insert(tag >> 3, value);
+        rest = tail;
+    }
+
+    let ephemeral = PublicKey::deserialize(fields.get(&1)?).ok()?;
+    let master_secret = identity_key.calculate_agreement(&ephemeral).ok()?;
+    let synthetic_iv = fields.get(&2)?;
+    let mut plaintext = fields.get(&3)?.to_vec();
+    cipher(&master_secret, synthetic_iv).apply_keystream(&mut plaintext);
+    if self::synthetic_iv(&master_secret, &plaintext) != *synthetic_iv {
+        return None;
+    }
+    String::from_utf8(plaintext).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_name() {
+        let mut rng = rand::thread_rng();
+        let identity = KeyPair::generate(&mut rng);
+        let encrypted = encrypt_device_name("presage", &identity.public_key, &mut rng).unwrap();
+        assert_eq!(
+            decrypt_device_name(&encrypted, &identity.private_key).as_deref(),
+            Some("presage")
+        );
+
+        // the ephemeral key is random
+        assert_ne!(
+            encrypted,
+            encrypt_device_name("presage", &identity.public_key, &mut rng).unwrap()
+        );
+        let other = KeyPair::generate(&mut rng);
+        assert!(decrypt_device_name(&encrypted, &other.private_key).is_none());
+    }
+
+    #[test]
+    fn test_encode_field() {
+        let mut message = Vec::new();
+        encode_field(&mut message, 3, &[7; 200]);
+        assert_eq!(&message[..3], &[3 << 3 | 2, 200, 1]);
+        assert_eq!(message.len(), 3 + 200);
+    }
+}
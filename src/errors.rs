@@ -2,7 +2,9 @@ use std::borrow::Cow;
 
 use libsignal_service::{models::ParseContactError, prelude::protocol::SignalProtocolError};
 
+/// Variants depend on the enabled store features, so matches must have a wildcard arm.
 #[derive(thiserror::Error, Debug)]
+#[non_exhaustive]
 pub enum Error {
     #[error("captcha from https://signalcaptchas.org required")]
     CaptchaRequired,
@@ -10,8 +12,16 @@ pub enum Error {
     IoError(#[from] std::io::Error),
     #[error("JSON error: {0}")]
     JsonError(#[from] serde_json::Error),
+    #[error("serialization error: {0}")]
+    BincodeError(#[from] bincode::Error),
     #[error("data store error: {0}")]
+    StoreError(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[cfg(feature = "sled-store")]
+    #[error("sled error: {0}")]
     DbError(#[from] sled::Error),
+    #[cfg(feature = "sqlite-store")]
+    #[error("sqlite error: {0}")]
+    SqliteError(#[from] rusqlite::Error),
     #[error("error decoding base64 data: {0}")]
     Base64Error(#[from] base64::DecodeError),
     #[error("wrong slice size: {0}")]
@@ -40,12 +50,39 @@ pub enum Error {
     NoProvisioningMessageReceived,
     #[error("qr code error")]
     QrCodeError,
+    #[error("the receiver of the provisioning URL was dropped")]
+    ProvisioningUrlReceiverDropped,
     #[error("missing key {0} in config DB")]
     MissingKeyError(Cow<'static, str>),
     #[error("receiving pipe was interrupted")]
     MessagePipeInterruptedError,
     #[error("failed to parse contact information: {0}")]
     ParseContactError(#[from] ParseContactError),
+    #[error("store encryption error: {0}")]
+    StoreEncryptionError(Cow<'static, str>),
+    #[error("wrong passphrase or key for the encrypted store")]
+    WrongStorePassphraseError,
+    #[error("store schema version {0} is newer than supported, please upgrade presage")]
+    UnsupportedSchemaVersion(u32),
+    #[error("failed to decode message from the store")]
+    MessageDecodeError,
     #[error("failed to decrypt attachment: {0}")]
     AttachmentCipherError(#[from] libsignal_service::attachment_cipher::AttachmentCipherError),
+    #[error("failed to upload attachment: {0}")]
+    AttachmentUploadError(#[from] libsignal_service::sender::AttachmentUploadError),
+    #[error("no identity key known for {0}")]
+    UnknownIdentityError(String),
+    #[error("unknown phone number of {0}, required for safety numbers")]
+    UnknownPhoneNumberError(String),
+    #[error("groups v2 error: {0}")]
+    GroupsV2Error(Cow<'static, str>),
+    #[error("profile error: {0}")]
+    ProfileError(Cow<'static, str>),
+}
+
+impl Error {
+    /// Wraps an error of a custom [ConfigStore](crate::ConfigStore) implementation.
+    pub fn store(error: impl std::error::Error + Send + Sync + 'static) -> Self {
+        Error::StoreError(Box::new(error))
+    }
 }
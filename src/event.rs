@@ -0,0 +1,592 @@
+use libsignal_service::{
+    content::{ContentBody, Metadata},
+    prelude::{Content, Uuid},
+    proto::{
+        call_message, data_message, receipt_message, sync_message, typing_message, DataMessage,
+        GroupContextV2, SyncMessage, Verified,
+    },
+    ServiceAddress,
+};
+
+use crate::config::{IdentityChange, Thread, TrustLevel};
+
+/// What happened, as seen by an application, when a message was received.
+///
+/// Events are built from the decrypted [Content] of messages, see [Event::from_content].
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// A message with text and/or attachments
+    Message {
+        metadata: Metadata,
+        thread: Thread,
+        message: DataMessage,
+    },
+    /// A message quoting an earlier message
+    Reply {
+        metadata: Metadata,
+        thread: Thread,
+        quote: data_message::Quote,
+        message: DataMessage,
+    },
+    /// An emoji reaction to a message, or the removal of one
+    Reaction {
+        metadata: Metadata,
+        thread: Thread,
+        emoji: String,
+        remove: bool,
+        target_author: Option<Uuid>,
+        target_sent_timestamp: u64,
+    },
+    /// The group was changed (title, members, etc.) and moved to a new revision
+    GroupUpdate {
+        metadata: Metadata,
+        thread: Thread,
+        context: GroupContextV2,
+    },
+    /// The disappearing messages timer of a conversation was changed
+    ExpirationTimerUpdate {
+        metadata: Metadata,
+        thread: Thread,
+        expire_timer: u32,
+    },
+    /// The sender shared a new profile key
+    ProfileKeyUpdate {
+        metadata: Metadata,
+        profile_key: Vec<u8>,
+    },
+    /// The sender reset the session with us
+    EndSession { metadata: Metadata },
+    /// A message was sent by another of our devices
+    SentFromOtherDevice {
+        metadata: Metadata,
+        thread: Thread,
+        destination: Option<ServiceAddress>,
+        message: DataMessage,
+    },
+    /// Messages were read on another of our devices, identified by author and sent timestamp
+    ReadOnOtherDevice {
+        metadata: Metadata,
+        messages: Vec<(Option<Uuid>, u64)>,
+    },
+    /// The contacts sent by the primary device were received.
+    ///
+    /// They are saved in the store while receiving, but failing to do so is only logged.
+    ContactsSynced { metadata: Metadata },
+    TypingStarted {
+        metadata: Metadata,
+        group_id: Option<Vec<u8>>,
+    },
+    TypingStopped {
+        metadata: Metadata,
+        group_id: Option<Vec<u8>>,
+    },
+    /// Messages sent at the given timestamps were delivered to the sender
+    DeliveryReceipt {
+        metadata: Metadata,
+        timestamps: Vec<u64>,
+    },
+    /// Messages sent at the given timestamps were read by the sender
+    ReadReceipt {
+        metadata: Metadata,
+        timestamps: Vec<u64>,
+    },
+    CallOffer {
+        metadata: Metadata,
+        offer: call_message::Offer,
+    },
+    CallAnswer {
+        metadata: Metadata,
+        answer: call_message::Answer,
+    },
+    CallHangup {
+        metadata: Metadata,
+        hangup: call_message::Hangup,
+    },
+    CallBusy {
+        metadata: Metadata,
+        busy: call_message::Busy,
+    },
+    /// The verification state of a contact was changed on another of our devices, and saved
+    VerificationSynced {
+        metadata: Metadata,
+        verified: Verified,
+    },
+    /// The identity key of a contact changed, and its safety number with it
+    IdentityChanged {
+        address: ServiceAddress,
+        trust_level: TrustLevel,
+    },
+    /// Anything else, left for the application to interpret
+    Other(Content),
+}
+
+impl Event {
+    pub fn from_content(content: Content) -> Self {
+        let thread = Thread::from_content(&content);
+        let Content { metadata, body } = content;
+
+        match (body, thread) {
+            (ContentBody::DataMessage(message), Some(thread)) => {
+                Self::from_data_message(metadata, thread, message)
+            }
+            (
+                ContentBody::SynchronizeMessage(SyncMessage {
+                    sent:
+                        Some(sync_message::Sent {
+                            message: Some(message),
+                            destination_e164,
+                            destination_uuid,
+                            ..
+                        }),
+                    ..
+                }),
+                Some(thread),
+            ) => {
+                let uuid = destination_uuid
+                    .as_deref()
+                    .and_then(|uuid| Uuid::parse_str(uuid).ok());
+                let phonenumber = destination_e164.and_then(|e164| e164.parse().ok());
+                let destination = match (uuid, phonenumber) {
+                    (None, None) => None,
+                    (uuid, phonenumber) => Some(ServiceAddress {
+                        uuid,
+                        phonenumber,
+                        relay: None,
+                    }),
+                };
+                Event::SentFromOtherDevice {
+                    metadata,
+                    thread,
+                    destination,
+                    message,
+                }
+            }
+            (
+                ContentBody::SynchronizeMessage(SyncMessage {
+                    contacts: Some(_), ..
+                }),
+                _,
+            ) => Event::ContactsSynced { metadata },
+            (
+                ContentBody::SynchronizeMessage(SyncMessage {
+                    verified: Some(verified),
+                    ..
+                }),
+                _,
+            ) => Event::VerificationSynced { metadata, verified },
+            (ContentBody::SynchronizeMessage(sync_message), _) if !sync_message.read.is_empty() => {
+                Event::ReadOnOtherDevice {
+                    metadata,
+                    messages: sync_message
+                        .read
+                        .iter()
+                        .map(|read| {
+                            let sender = read
+                                .sender_uuid
+                                .as_deref()
+                                .and_then(|uuid| Uuid::parse_str(uuid).ok());
+                            (sender, read.timestamp())
+                        })
+                        .collect(),
+                }
+            }
+            (ContentBody::TypingMessage(typing), _) => match typing.action() {
+                typing_message::Action::Started => Event::TypingStarted {
+                    metadata,
+                    group_id: typing.group_id,
+                },
+                typing_message::Action::Stopped => Event::TypingStopped {
+                    metadata,
+                    group_id: typing.group_id,
+                },
+            },
+            (ContentBody::ReceiptMessage(receipt), _) => match receipt.r#type() {
+                receipt_message::Type::Delivery => Event::DeliveryReceipt {
+                    metadata,
+                    timestamps: receipt.timestamp,
+                },
+                receipt_message::Type::Read => Event::ReadReceipt {
+                    metadata,
+                    timestamps: receipt.timestamp,
+                },
+            },
+            (ContentBody::CallMessage(call), _) => {
+                if let Some(offer) = call.offer {
+                    Event::CallOffer { metadata, offer }
+                } else if let Some(answer) = call.answer {
+                    Event::CallAnswer { metadata, answer }
+                } else if let Some(hangup) = call.hangup.or(call.legacy_hangup) {
+                    Event::CallHangup { metadata, hangup }
+                } else if let Some(busy) = call.busy {
+                    Event::CallBusy { metadata, busy }
+                } else {
+                    Event::Other(Content {
+                        metadata,
+                        body: ContentBody::CallMessage(call),
+                    })
+                }
+            }
+            (body, _) => Event::Other(Content { metadata, body }),
+        }
+    }
+
+    fn from_data_message(metadata: Metadata, thread: Thread, message: DataMessage) -> Self {
+        let flags = message.flags();
+
+        if flags & data_message::Flags::EndSession as u32 != 0 {
+            Event::EndSession { metadata }
+        } else if flags & data_message::Flags::ExpirationTimerUpdate as u32 != 0 {
+            Event::ExpirationTimerUpdate {
+                metadata,
+                thread,
+                expire_timer: message.expire_timer(),
+            }
+        } else if flags & data_message::Flags::ProfileKeyUpdate as u32 != 0 {
+            Event::ProfileKeyUpdate {
+                metadata,
+                profile_key: message.profile_key.unwrap_or_default(),
+            }
+        } else if let Some(reaction) = message.reaction {
+            Event::Reaction {
+                metadata,
+                thread,
+                emoji: reaction.emoji().to_string(),
+                remove: reaction.remove(),
+                target_author: reaction
+                    .target_author_uuid
+                    .as_deref()
+                    .and_then(|uuid| Uuid::parse_str(uuid).ok()),
+                target_sent_timestamp: reaction.target_sent_timestamp(),
+            }
+        } else if let Some(context) = message
+            .group_v2
+            .clone()
+            .filter(|context| context.group_change.is_some())
+        {
+            Event::GroupUpdate {
+                metadata,
+                thread,
+                context,
+            }
+        } else if let Some(quote) = message.quote.clone() {
+            Event::Reply {
+                metadata,
+                thread,
+                quote,
+                message,
+            }
+        } else {
+            Event::Message {
+                metadata,
+                thread,
+                message,
+            }
+        }
+    }
+
+    /// Metadata of the message this event originates from, if any.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        let metadata = match self {
+            Event::Message { metadata, .. }
+            | Event::Reply { metadata, .. }
+            | Event::Reaction { metadata, .. }
+            | Event::GroupUpdate { metadata, .. }
+            | Event::ExpirationTimerUpdate { metadata, .. }
+            | Event::ProfileKeyUpdate { metadata, .. }
+            | Event::EndSession { metadata }
+            | Event::SentFromOtherDevice { metadata, .. }
+            | Event::ReadOnOtherDevice { metadata, .. }
+            | Event::ContactsSynced { metadata }
+            | Event::TypingStarted { metadata, .. }
+            | Event::TypingStopped { metadata, .. }
+            | Event::DeliveryReceipt { metadata, .. }
+            | Event::ReadReceipt { metadata, .. }
+            | Event::CallOffer { metadata, .. }
+            | Event::CallAnswer { metadata, .. }
+            | Event::CallHangup { metadata, .. }
+            | Event::CallBusy { metadata, .. }
+            | Event::VerificationSynced { metadata, .. } => metadata,
+            Event::Other(content) => &content.metadata,
+            Event::IdentityChanged { .. } => return None,
+        };
+        Some(metadata)
+    }
+}
+
+impl From<IdentityChange> for Event {
+    fn from(change: IdentityChange) -> Self {
+        let (uuid, phonenumber) = match Uuid::parse_str(&change.name) {
+            Ok(uuid) => (Some(uuid), None),
+            Err(_) => (None, change.name.parse().ok()),
+        };
+        Event::IdentityChanged {
+            address: ServiceAddress {
+                uuid,
+                phonenumber,
+                relay: None,
+            },
+            trust_level: change.trust_level,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libsignal_service::proto::{
+        call_message, data_message, CallMessage, GroupContextV2, ReceiptMessage, TypingMessage,
+    };
+
+    use super::*;
+
+    const SENDER: Uuid = Uuid::from_bytes([1; 16]);
+
+    fn content(body: ContentBody) -> Content {
+        Content {
+            metadata: Metadata {
+                sender: ServiceAddress {
+                    uuid: Some(SENDER),
+                    phonenumber: None,
+                    relay: None,
+                },
+                sender_device: 1,
+                timestamp: 42,
+                needs_receipt: false,
+            },
+            body,
+        }
+    }
+
+    fn data_message(message: DataMessage) -> ContentBody {
+        ContentBody::DataMessage(DataMessage {
+            body: Some("hello".to_string()),
+            ..message
+        })
+    }
+
+    fn sync_message(message: SyncMessage) -> ContentBody {
+        ContentBody::SynchronizeMessage(message)
+    }
+
+    #[test]
+    fn test_from_content() {
+        let cases: Vec<(ContentBody, fn(&Event) -> bool)> = vec![
+            (data_message(Default::default()), |event| {
+                matches!(
+                    event,
+                    Event::Message {
+                        thread: Thread::Contact(SENDER),
+                        ..
+                    }
+                )
+            }),
+            (
+                data_message(DataMessage {
+                    quote: Some(Default::default()),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::Reply { .. }),
+            ),
+            (
+                data_message(DataMessage {
+                    reaction: Some(data_message::Reaction {
+                        emoji: Some("👍".to_string()),
+                        target_sent_timestamp: Some(7),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::Reaction { emoji, remove: false, target_sent_timestamp: 7, .. } if emoji == "👍"),
+            ),
+            (
+                data_message(DataMessage {
+                    group_v2: Some(GroupContextV2 {
+                        master_key: Some(vec![2; 32]),
+                        group_change: Some(vec![]),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::GroupUpdate { thread: Thread::Group(master_key), .. } if *master_key == [2; 32]),
+            ),
+            (
+                data_message(DataMessage {
+                    group_v2: Some(GroupContextV2 {
+                        master_key: Some(vec![2; 32]),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                |event| {
+                    matches!(
+                        event,
+                        Event::Message {
+                            thread: Thread::Group(_),
+                            ..
+                        }
+                    )
+                },
+            ),
+            (
+                data_message(DataMessage {
+                    flags: Some(data_message::Flags::ExpirationTimerUpdate as u32),
+                    expire_timer: Some(60),
+                    ..Default::default()
+                }),
+                |event| {
+                    matches!(
+                        event,
+                        Event::ExpirationTimerUpdate {
+                            expire_timer: 60,
+                            ..
+                        }
+                    )
+                },
+            ),
+            (
+                data_message(DataMessage {
+                    flags: Some(data_message::Flags::ProfileKeyUpdate as u32),
+                    profile_key: Some(vec![3; 32]),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::ProfileKeyUpdate { profile_key, .. } if profile_key == &[3; 32]),
+            ),
+            (
+                data_message(DataMessage {
+                    flags: Some(data_message::Flags::EndSession as u32),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::EndSession { .. }),
+            ),
+            (
+                sync_message(SyncMessage {
+                    sent: Some(sync_message::Sent {
+                        destination_uuid: Some(Uuid::from_bytes([4; 16]).to_string()),
+                        message: Some(Default::default()),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+                |event| {
+                    matches!(event, Event::SentFromOtherDevice {
+                        thread: Thread::Contact(uuid),
+                        destination: Some(ServiceAddress { uuid: Some(destination), .. }),
+                        ..
+                    } if *uuid == Uuid::from_bytes([4; 16]) && uuid == destination)
+                },
+            ),
+            (
+                sync_message(SyncMessage {
+                    contacts: Some(Default::default()),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::ContactsSynced { .. }),
+            ),
+            (
+                sync_message(SyncMessage {
+                    verified: Some(Default::default()),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::VerificationSynced { .. }),
+            ),
+            (
+                sync_message(SyncMessage {
+                    read: vec![sync_message::Read {
+                        sender_uuid: Some(SENDER.to_string()),
+                        timestamp: Some(5),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::ReadOnOtherDevice { messages, .. } if messages == &[(Some(SENDER), 5)]),
+            ),
+            (
+                ContentBody::TypingMessage(TypingMessage {
+                    action: Some(typing_message::Action::Started as i32),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::TypingStarted { group_id: None, .. }),
+            ),
+            (
+                ContentBody::TypingMessage(TypingMessage {
+                    action: Some(typing_message::Action::Stopped as i32),
+                    group_id: Some(vec![5]),
+                    ..Default::default()
+                }),
+                |event| {
+                    matches!(
+                        event,
+                        Event::TypingStopped {
+                            group_id: Some(_),
+                            ..
+                        }
+                    )
+                },
+            ),
+            (
+                ContentBody::ReceiptMessage(ReceiptMessage {
+                    r#type: Some(receipt_message::Type::Delivery as i32),
+                    timestamp: vec![1, 2],
+                }),
+                |event| matches!(event, Event::DeliveryReceipt { timestamps, .. } if timestamps == &[1, 2]),
+            ),
+            (
+                ContentBody::ReceiptMessage(ReceiptMessage {
+                    r#type: Some(receipt_message::Type::Read as i32),
+                    timestamp: vec![3],
+                }),
+                |event| matches!(event, Event::ReadReceipt { timestamps, .. } if timestamps == &[3]),
+            ),
+            (
+                ContentBody::CallMessage(CallMessage {
+                    offer: Some(Default::default()),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::CallOffer { .. }),
+            ),
+            (
+                ContentBody::CallMessage(CallMessage {
+                    answer: Some(Default::default()),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::CallAnswer { .. }),
+            ),
+            (
+                ContentBody::CallMessage(CallMessage {
+                    legacy_hangup: Some(call_message::Hangup::default()),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::CallHangup { .. }),
+            ),
+            (
+                ContentBody::CallMessage(CallMessage {
+                    busy: Some(Default::default()),
+                    ..Default::default()
+                }),
+                |event| matches!(event, Event::CallBusy { .. }),
+            ),
+            // unknown bodies are left to the application
+            (ContentBody::CallMessage(Default::default()), |event| {
+                matches!(event, Event::Other(_))
+            }),
+            (sync_message(Default::default()), |event| {
+                matches!(event, Event::Other(_))
+            }),
+        ];
+
+        for (body, expected) in cases {
+            let event = Event::from_content(content(body.clone()));
+            assert!(expected(&event), "{:?} gave {:?}", body, event);
+            assert_eq!(
+                event.metadata().map(|metadata| metadata.timestamp),
+                Some(42)
+            );
+        }
+    }
+
+    #[test]
+    fn test_data_message_without_sender() {
+        let mut content = content(data_message(Default::default()));
+        content.metadata.sender.uuid = None;
+        assert!(matches!(Event::from_content(content), Event::Other(_)));
+    }
+}
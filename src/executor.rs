@@ -0,0 +1,177 @@
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    task::{Context, Poll},
+    thread,
+};
+
+use futures::{future, FutureExt, Stream, StreamExt};
+use tokio::{
+    runtime,
+    sync::{mpsc, oneshot},
+    task::{self, LocalSet},
+};
+
+use crate::Error;
+
+type Job = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()>>> + Send>;
+
+/// Runs futures which are not `Send`, like the ones of the protocol stores and of the push service,
+/// on a dedicated thread, so that the futures waiting for them are `Send`.
+///
+/// The thread runs a single threaded runtime, and stops once all clones are dropped and its
+/// futures are done.
+#[derive(Clone)]
+pub(crate) struct LocalExecutor {
+    jobs: mpsc::UnboundedSender<Job>,
+}
+
+impl LocalExecutor {
+    pub(crate) fn new() -> Result<Self, Error> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let (jobs, mut receiver) = mpsc::unbounded_channel::<Job>();
+        thread::Builder::new()
+            .name("presage-local".into())
+            .spawn(move || {
+                let local = LocalSet::new();
+                local.spawn_local(async move {
+                    while let Some(job) = receiver.recv().await {
+                        task::spawn_local(job());
+                    }
+                });
+                runtime.block_on(local);
+            })?;
+        Ok(Self { jobs })
+    }
+
+    /// Runs the future returned by `job` on the executor, and returns its output.
+    ///
+    /// Jobs run concurrently, and may run other jobs themselves. A panic is resumed in the caller.
+    pub(crate) async fn run<F, Fut, T>(&self, job: F) -> T
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = T> + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            Box::pin(async move {
+                let output = AssertUnwindSafe(job()).catch_unwind().await;
+                // the caller may not be waiting anymore
+                let _ = sender.send(output);
+            })
+        });
+        self.jobs
+            .send(job)
+            .unwrap_or_else(|_| panic!("the local executor stopped"));
+        match receiver.await {
+            Ok(Ok(output)) => output,
+            Ok(Err(payload)) => panic::resume_unwind(payload),
+            Err(_) => panic!("the local executor stopped"),
+        }
+    }
+
+    /// Opens a stream on the executor, whose items are forwarded to the returned stream until it
+    /// is dropped.
+    pub(crate) async fn run_stream<F, Fut, S, E>(&self, open: F) -> Result<Forwarded<S::Item>, E>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<S, E>> + 'static,
+        S: Stream + 'static,
+        S::Item: Send + 'static,
+        E: Send + 'static,
+    {
+        // items are only taken from the stream when the previous one was received
+        let (sender, receiver) = mpsc::channel(1);
+        self.run(move || async move {
+            let stream = open().await?;
+            task::spawn_local(async move {
+                futures::pin_mut!(stream);
+                loop {
+                    let closed = sender.closed();
+                    futures::pin_mut!(closed);
+                    match future::select(closed, stream.next()).await {
+                        future::Either::Right((Some(item), _)) => {
+                            if sender.send(item).await.is_err() {
+                                break;
+                            }
+                        }
+                        // the stream ended, or the receiver was dropped
+                        _ => break,
+                    }
+                }
+            });
+            Ok(())
+        })
+        .await?;
+        Ok(Forwarded(receiver))
+    }
+}
+
+/// Items of a stream running on a [LocalExecutor].
+pub(crate) struct Forwarded<T>(mpsc::Receiver<T>);
+
+impl<T> Stream for Forwarded<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_run() {
+        let executor = LocalExecutor::new().unwrap();
+        // Rc is not Send, only its output has to be
+        let output = executor
+            .run(|| async {
+                let value = Rc::new(21);
+                tokio::task::yield_now().await;
+                *value * 2
+            })
+            .await;
+        assert_eq!(output, 42);
+
+        let nested = executor.clone();
+        let output = executor
+            .run(move || async move { nested.run(|| async { 42 }).await })
+            .await;
+        assert_eq!(output, 42);
+    }
+
+    #[tokio::test]
+    async fn test_run_stream() {
+        let executor = LocalExecutor::new().unwrap();
+        let stream = executor
+            .run_stream(|| async {
+                let value = Rc::new(1);
+                Ok::<_, Error>(futures::stream::iter(vec![*value, 2, 3]))
+            })
+            .await
+            .unwrap();
+        assert_eq!(stream.collect::<Vec<_>>().await, vec![1, 2, 3]);
+
+        let result = executor
+            .run_stream(|| async {
+                Err::<futures::stream::Empty<u32>, _>(Error::NotYetRegisteredError)
+            })
+            .await;
+        assert!(matches!(result, Err(Error::NotYetRegisteredError)));
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "boom")]
+    async fn test_run_panics() {
+        let executor = LocalExecutor::new().unwrap();
+        executor.run(|| async { panic!("boom") }).await
+    }
+}
@@ -0,0 +1,2224 @@
+//! An in-process fake Signal server, for end-to-end tests of the [Manager](crate::Manager).
+//!
+//! The server speaks just enough of the Signal REST and websocket protocol to register accounts
+//! (primary devices only), exchange pre-key bundles, deliver envelopes, store attachments and
+//! profiles, issue profile key credentials, and create, serve and change groups v2 (except for
+//! pending members). It implements [PushServiceFactory] so a manager can be pointed at it:
+//!
+//! ```ignore
+//! let server = FakeSignalServer::start();
+//! let manager =
+//!     Manager::with_push_service_factory(store, rand::rngs::OsRng, server.clone()).await?;
+//! ```
+//!
+//! Nothing is persisted and no proof is ever verified: this must never be exposed outside of tests.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use log::{debug, trace, warn};
+use rand::{Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use warp::{
+    http::StatusCode,
+    hyper::Body,
+    multipart::FormData,
+    reply::Response,
+    ws::{Message, WebSocket, Ws},
+    Buf, Filter, Reply,
+};
+use zkgroup::{
+    profiles::{
+        ProfileKeyCommitment, ProfileKeyCredentialPresentation, ProfileKeyCredentialRequest,
+    },
+    ServerPublicParams, ServerSecretParams,
+};
+
+use libsignal_service::{
+    configuration::{ServiceConfiguration, SignalServers},
+    messagepipe::ServiceCredentials,
+    prelude::{phonenumber, phonenumber::PhoneNumber, ProtobufMessage, Uuid},
+    proto::{
+        envelope, group_change, web_socket_message, Envelope, Group, GroupChange, GroupJoinInfo,
+        Member, WebSocketMessage, WebSocketRequestMessage, WebSocketResponseMessage,
+    },
+    push_service::DEFAULT_DEVICE_ID,
+};
+
+use crate::push_service::{HyperPushServiceFactory, PushServiceFactory};
+
+/// Handle to a running fake server.
+///
+/// The server runs on the current tokio runtime until the runtime shuts down.
+#[derive(Clone)]
+pub struct FakeSignalServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+    server_public_params: ServerPublicParams,
+}
+
+struct ServerState {
+    accounts: HashMap<Uuid, Account>,
+    /// Verification codes sent to phone numbers (formatted as E.164)
+    verification_codes: HashMap<String, u32>,
+    attachments: HashMap<u64, Vec<u8>>,
+    /// Encrypted profile avatars, by CDN path
+    avatars: HashMap<String, Vec<u8>>,
+    /// Encrypted groups, indexed by their hex-encoded public params
+    groups: HashMap<String, Vec<u8>>,
+    server_secret_params: ServerSecretParams,
+}
+
+struct Account {
+    phone_number: String,
+    devices: HashMap<u32, Device>,
+    profile: Option<Profile>,
+}
+
+/// Encrypted profile, readable with the profile key it was written for
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    version: String,
+    name: Option<String>,
+    about: Option<String>,
+    about_emoji: Option<String>,
+    /// Whether an avatar is uploaded along with the profile
+    avatar: bool,
+    /// Commitment to the profile key, from which profile key credentials are issued
+    commitment: String,
+    /// CDN path of the avatar
+    #[serde(skip)]
+    avatar_path: Option<String>,
+}
+
+struct Device {
+    password: String,
+    registration_id: u32,
+    name: Option<String>,
+    /// Last account attributes set by the device
+    attributes: Option<serde_json::Value>,
+    identity_key: Option<String>,
+    signed_pre_key: Option<serde_json::Value>,
+    pre_keys: VecDeque<serde_json::Value>,
+    /// Serialized envelopes waiting to be delivered
+    queue: VecDeque<Vec<u8>>,
+    /// Wakes up the message pipe of this device when an envelope is queued
+    notify: Arc<Notify>,
+}
+
+impl Device {
+    fn new(password: String, registration_id: u32) -> Self {
+        Self {
+            password,
+            registration_id,
+            name: None,
+            attributes: None,
+            identity_key: None,
+            signed_pre_key: None,
+            pre_keys: Default::default(),
+            queue: Default::default(),
+            notify: Default::default(),
+        }
+    }
+}
+
+impl ServerState {
+    fn account_uuid(&self, identifier: &str) -> Option<Uuid> {
+        match Uuid::parse_str(identifier) {
+            Ok(uuid) if self.accounts.contains_key(&uuid) => Some(uuid),
+            Ok(_) => None,
+            Err(_) => self
+                .accounts
+                .iter()
+                .find(|(_, account)| account.phone_number == identifier)
+                .map(|(uuid, _)| *uuid),
+        }
+    }
+
+    /// Checks `login:password` credentials (from HTTP basic auth or the websocket query string)
+    fn authenticate(&self, login: &str, password: &str) -> Option<(Uuid, u32)> {
+        let mut parts = login.splitn(2, '.');
+        let identifier = parts.next()?;
+        let device_id = match parts.next() {
+            Some(device_id) => device_id.parse().ok()?,
+            None => DEFAULT_DEVICE_ID,
+        };
+        let uuid = self.account_uuid(identifier)?;
+        let device = self.accounts[&uuid].devices.get(&device_id)?;
+        if device.password == password {
+            Some((uuid, device_id))
+        } else {
+            None
+        }
+    }
+
+    fn authenticate_header(&self, authorization: Option<&str>) -> Option<(Uuid, u32)> {
+        let (login, password) = basic_auth(authorization?)?;
+        self.authenticate(&login, &password)
+    }
+
+    fn device_mut(&mut self, (uuid, device_id): (Uuid, u32)) -> Option<&mut Device> {
+        self.accounts.get_mut(&uuid)?.devices.get_mut(&device_id)
+    }
+
+    fn deliver(&mut self, destination: (Uuid, u32), envelope: Vec<u8>) {
+        if let Some(device) = self.device_mut(destination) {
+            device.queue.push_back(envelope);
+            device.notify.notify_one();
+        }
+    }
+}
+
+impl FakeSignalServer {
+    /// Starts a fake server listening on an ephemeral port of the loopback interface.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn start() -> Self {
+        let mut randomness = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut randomness);
+        let server_secret_params = ServerSecretParams::generate(randomness);
+        let server_public_params = server_secret_params.get_public_params();
+
+        let state = Arc::new(Mutex::new(ServerState {
+            accounts: Default::default(),
+            verification_codes: Default::default(),
+            attachments: Default::default(),
+            avatars: Default::default(),
+            groups: Default::default(),
+            server_secret_params,
+        }));
+
+        let (addr, server) = warp::serve(routes(state.clone()))
+            .try_bind_ephemeral(([127, 0, 0, 1], 0))
+            .expect("failed to bind fake signal server");
+        tokio::spawn(server);
+        debug!("fake signal server listening on {}", addr);
+
+        Self {
+            addr,
+            state,
+            server_public_params,
+        }
+    }
+
+    /// Base URL of the server, used both as service and CDN URL.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Returns the last verification code "sent" to a phone number, if any.
+    pub fn verification_code(&self, phone_number: &PhoneNumber) -> Option<u32> {
+        self.state
+            .lock()
+            .expect("poisoned mutex")
+            .verification_codes
+            .get(&e164(phone_number))
+            .copied()
+    }
+
+    /// Number of envelopes waiting to be delivered to a device of an account.
+    pub fn queued_envelopes(&self, uuid: Uuid, device_id: u32) -> usize {
+        self.state
+            .lock()
+            .expect("poisoned mutex")
+            .device_mut((uuid, device_id))
+            .map_or(0, |device| device.queue.len())
+    }
+
+    /// Number of one-time pre-keys left for a device of an account.
+    pub fn pre_keys(&self, uuid: Uuid, device_id: u32) -> usize {
+        self.state
+            .lock()
+            .expect("poisoned mutex")
+            .device_mut((uuid, device_id))
+            .map_or(0, |device| device.pre_keys.len())
+    }
+
+    /// Last account attributes set by a device of an account, as JSON.
+    pub fn account_attributes(&self, uuid: Uuid, device_id: u32) -> Option<serde_json::Value> {
+        self.state
+            .lock()
+            .expect("poisoned mutex")
+            .device_mut((uuid, device_id))
+            .and_then(|device| device.attributes.clone())
+    }
+
+    /// Name set by a device of an account, if any.
+    pub fn device_name(&self, uuid: Uuid, device_id: u32) -> Option<String> {
+        self.state
+            .lock()
+            .expect("poisoned mutex")
+            .device_mut((uuid, device_id))
+            .and_then(|device| device.name.clone())
+    }
+}
+
+impl PushServiceFactory for FakeSignalServer {
+    type PushService = <HyperPushServiceFactory as PushServiceFactory>::PushService;
+
+    fn service_configuration(&self, _signal_servers: SignalServers) -> ServiceConfiguration {
+        let mut service_configuration: ServiceConfiguration = SignalServers::Staging.into();
+        service_configuration.service_urls = vec![self.url()];
+        service_configuration.cdn_urls =
+            vec![(0, self.url()), (2, self.url())].into_iter().collect();
+        service_configuration.zkgroup_server_public_params = self.server_public_params.clone();
+        service_configuration
+    }
+
+    fn push_service(
+        &self,
+        service_configuration: ServiceConfiguration,
+        credentials: Option<ServiceCredentials>,
+    ) -> Self::PushService {
+        HyperPushServiceFactory.push_service(service_configuration, credentials)
+    }
+}
+
+type SharedState = Arc<Mutex<ServerState>>;
+
+fn routes(
+    state: SharedState,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone + Send + Sync + 'static {
+    let state = warp::any().map(move || state.clone());
+    let authorization = warp::header::optional::<String>("authorization");
+
+    let request_verification_code = warp::get()
+        .and(warp::path!("v1" / "accounts" / String / "code" / String))
+        .and(state.clone())
+        .and_then(request_verification_code);
+    let confirm_verification_code = warp::put()
+        .and(warp::path!("v1" / "accounts" / "code" / u32))
+        .and(authorization.clone())
+        .and(warp::body::json())
+        .and(state.clone())
+        .and_then(confirm_verification_code);
+    let set_account_attributes = warp::put()
+        .and(warp::path!("v1" / "accounts" / "attributes" / ..))
+        .and(authorization.clone())
+        .and(warp::body::json())
+        .and(state.clone())
+        .and_then(set_account_attributes);
+    let set_device_name = warp::put()
+        .and(warp::path!("v1" / "accounts" / "name" / ..))
+        .and(authorization.clone())
+        .and(warp::body::json())
+        .and(state.clone())
+        .and_then(set_device_name);
+    let whoami = warp::get()
+        .and(warp::path!("v1" / "accounts" / "whoami"))
+        .and(authorization.clone())
+        .and(state.clone())
+        .and_then(whoami);
+
+    let get_pre_key_bundles = warp::get()
+        .and(warp::path!("v2" / "keys" / String / String))
+        .and(authorization.clone())
+        .and(state.clone())
+        .and_then(get_pre_key_bundles);
+    let register_pre_keys = warp::put()
+        .and(warp::path!("v2" / "keys" / ..))
+        .and(authorization.clone())
+        .and(warp::body::json())
+        .and(state.clone())
+        .and_then(register_pre_keys);
+    let get_pre_key_status = warp::get()
+        .and(warp::path!("v2" / "keys" / ..))
+        .and(authorization.clone())
+        .and(state.clone())
+        .and_then(get_pre_key_status);
+
+    let send_messages = warp::put()
+        .and(warp::path!("v1" / "messages" / String))
+        .and(authorization.clone())
+        .and(warp::body::json())
+        .and(state.clone())
+        .and_then(send_messages);
+    let message_pipe = warp::path!("v1" / "websocket" / ..)
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::ws())
+        .and(state.clone())
+        .map(message_pipe);
+
+    let attachment_upload_form = warp::get()
+        .and(warp::path!("v2" / "attachments" / "form" / "upload"))
+        .and(authorization.clone())
+        .and(state.clone())
+        .and_then(attachment_upload_form);
+    let upload_attachment = warp::post()
+        .and(warp::path!("attachments" / ..))
+        .and(warp::multipart::form().max_length(100 * 1024 * 1024))
+        .and(state.clone())
+        .and_then(upload_attachment);
+    let get_attachment = warp::get()
+        .and(warp::path!("attachments" / u64))
+        .and(state.clone())
+        .and_then(get_attachment);
+
+    let set_profile = warp::put()
+        .and(warp::path!("v1" / "profile" / ..))
+        .and(warp::path::end())
+        .and(authorization.clone())
+        .and(warp::body::json())
+        .and(state.clone())
+        .and_then(set_profile);
+    let get_profile = warp::get()
+        .and(warp::path!("v1" / "profile" / Uuid / String))
+        .and(state.clone())
+        .and_then(get_profile);
+    let get_profile_key_credential = warp::get()
+        .and(warp::path!("v1" / "profile" / Uuid / String / String))
+        .and(state.clone())
+        .and_then(get_profile_key_credential);
+    let upload_avatar = warp::post()
+        .and(warp::path::end())
+        .and(warp::multipart::form().max_length(10 * 1024 * 1024))
+        .and(state.clone())
+        .and_then(upload_avatar);
+    let get_avatar = warp::get()
+        .and(warp::path!("profiles" / String))
+        .and(state.clone())
+        .and_then(get_avatar);
+
+    let group_credentials = warp::get()
+        .and(warp::path!("v1" / "certificate" / "group" / u32 / u32))
+        .and(authorization.clone())
+        .and(state.clone())
+        .and_then(group_credentials);
+    let put_group = warp::put()
+        .and(warp::path!("v1" / "groups" / ..))
+        .and(authorization.clone())
+        .and(warp::body::bytes())
+        .and(state.clone())
+        .and_then(put_group);
+    let patch_group = warp::patch()
+        .and(warp::path!("v1" / "groups" / ..))
+        .and(authorization.clone())
+        .and(warp::body::bytes())
+        .and(state.clone())
+        .and_then(patch_group);
+    let get_group_join_info = warp::get()
+        .and(warp::path!("v1" / "groups" / "join" / String))
+        .and(authorization.clone())
+        .and(state.clone())
+        .and_then(get_group_join_info);
+    let get_group = warp::get()
+        .and(warp::path!("v1" / "groups" / ..))
+        .and(authorization)
+        .and(state)
+        .and_then(get_group);
+
+    request_verification_code
+        .or(confirm_verification_code)
+        .unify()
+        .or(set_account_attributes)
+        .unify()
+        .or(set_device_name)
+        .unify()
+        .or(whoami)
+        .unify()
+        .or(get_pre_key_bundles)
+        .unify()
+        .or(register_pre_keys)
+        .unify()
+        .or(get_pre_key_status)
+        .unify()
+        .or(send_messages)
+        .unify()
+        .or(message_pipe)
+        .unify()
+        .or(attachment_upload_form)
+        .unify()
+        .or(upload_attachment)
+        .unify()
+        .or(get_attachment)
+        .unify()
+        .or(set_profile)
+        .unify()
+        .or(get_profile)
+        .unify()
+        .or(get_profile_key_credential)
+        .unify()
+        .or(upload_avatar)
+        .unify()
+        .or(get_avatar)
+        .unify()
+        .or(group_credentials)
+        .unify()
+        .or(put_group)
+        .unify()
+        .or(patch_group)
+        .unify()
+        .or(get_group_join_info)
+        .unify()
+        .or(get_group)
+        .unify()
+        .with(warp::log("presage::fake_server"))
+}
+
+async fn request_verification_code(
+    _transport: String,
+    phone_number: String,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let code = rand::thread_rng().gen_range(100_000, 1_000_000);
+    trace!("verification code for {}: {}", phone_number, code);
+    state
+        .lock()
+        .expect("poisoned mutex")
+        .verification_codes
+        .insert(phone_number, code);
+    Ok(empty(StatusCode::OK))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ConfirmCode {
+    registration_id: u32,
+}
+
+async fn confirm_verification_code(
+    code: u32,
+    authorization: Option<String>,
+    confirm_code: ConfirmCode,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let (phone_number, password) = match authorization.as_deref().and_then(basic_auth) {
+        Some(credentials) => credentials,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+
+    let mut state = state.lock().expect("poisoned mutex");
+    if state.verification_codes.get(&phone_number) != Some(&code) {
+        return Ok(empty(StatusCode::FORBIDDEN));
+    }
+    state.verification_codes.remove(&phone_number);
+
+    // registering again replaces all the devices of the account
+    let uuid = state
+        .account_uuid(&phone_number)
+        .unwrap_or_else(|| Uuid::from_bytes(rand::random()));
+    let mut devices = HashMap::new();
+    devices.insert(
+        DEFAULT_DEVICE_ID,
+        Device::new(password, confirm_code.registration_id),
+    );
+    state.accounts.insert(
+        uuid,
+        Account {
+            phone_number,
+            devices,
+            profile: None,
+        },
+    );
+
+    Ok(json(
+        &serde_json::json!({ "uuid": uuid, "storageCapable": false }),
+    ))
+}
+
+async fn set_account_attributes(
+    authorization: Option<String>,
+    attributes: serde_json::Value,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let mut state = state.lock().expect("poisoned mutex");
+    let device = match state.authenticate_header(authorization.as_deref()) {
+        Some(device) => device,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+    if let Some(device) = state.device_mut(device) {
+        device.attributes = Some(attributes);
+    }
+    Ok(empty(StatusCode::NO_CONTENT))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DeviceName {
+    device_name: String,
+}
+
+async fn set_device_name(
+    authorization: Option<String>,
+    body: DeviceName,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let mut state = state.lock().expect("poisoned mutex");
+    let device = match state.authenticate_header(authorization.as_deref()) {
+        Some(device) => device,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+    if let Some(device) = state.device_mut(device) {
+        device.name = Some(body.device_name);
+    }
+    Ok(empty(StatusCode::NO_CONTENT))
+}
+
+async fn whoami(authorization: Option<String>, state: SharedState) -> Result<Response, Infallible> {
+    let state = state.lock().expect("poisoned mutex");
+    Ok(match state.authenticate_header(authorization.as_deref()) {
+        Some((uuid, _)) => json(&serde_json::json!({
+            "uuid": uuid,
+            "number": state.accounts[&uuid].phone_number,
+        })),
+        None => empty(StatusCode::UNAUTHORIZED),
+    })
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PreKeyState {
+    pre_keys: Vec<serde_json::Value>,
+    signed_pre_key: serde_json::Value,
+    identity_key: String,
+}
+
+async fn register_pre_keys(
+    authorization: Option<String>,
+    pre_key_state: PreKeyState,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let mut state = state.lock().expect("poisoned mutex");
+    let device = match state.authenticate_header(authorization.as_deref()) {
+        Some(device) => device,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+
+    let device = state.device_mut(device).expect("authenticated device");
+    device.identity_key = Some(pre_key_state.identity_key);
+    device.signed_pre_key = Some(pre_key_state.signed_pre_key);
+    device.pre_keys.extend(pre_key_state.pre_keys);
+    Ok(empty(StatusCode::NO_CONTENT))
+}
+
+async fn get_pre_key_status(
+    authorization: Option<String>,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let mut state = state.lock().expect("poisoned mutex");
+    let device = match state.authenticate_header(authorization.as_deref()) {
+        Some(device) => device,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+
+    let count = state.device_mut(device).map_or(0, |d| d.pre_keys.len());
+    Ok(json(&serde_json::json!({ "count": count })))
+}
+
+async fn get_pre_key_bundles(
+    identifier: String,
+    device_id: String,
+    authorization: Option<String>,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let mut state = state.lock().expect("poisoned mutex");
+    if state
+        .authenticate_header(authorization.as_deref())
+        .is_none()
+    {
+        return Ok(empty(StatusCode::UNAUTHORIZED));
+    }
+
+    let uuid = match state.account_uuid(&identifier) {
+        Some(uuid) => uuid,
+        None => return Ok(empty(StatusCode::NOT_FOUND)),
+    };
+    let account = state.accounts.get_mut(&uuid).expect("existing account");
+
+    let mut identity_key = None;
+    let mut devices = Vec::new();
+    for (id, device) in account.devices.iter_mut() {
+        if device_id != "*" && device_id != id.to_string() {
+            continue;
+        }
+        identity_key = device.identity_key.clone();
+        devices.push(serde_json::json!({
+            "deviceId": id,
+            "registrationId": device.registration_id,
+            "signedPreKey": device.signed_pre_key,
+            "preKey": device.pre_keys.pop_front(),
+        }));
+    }
+
+    if devices.is_empty() {
+        return Ok(empty(StatusCode::NOT_FOUND));
+    }
+
+    Ok(json(&serde_json::json!({
+        "identityKey": identity_key,
+        "devices": devices,
+    })))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OutgoingPushMessages {
+    timestamp: u64,
+    messages: Vec<OutgoingPushMessage>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct OutgoingPushMessage {
+    #[serde(rename = "type")]
+    r#type: u32,
+    destination_device_id: u32,
+    content: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct MismatchedDevices {
+    missing_devices: Vec<u32>,
+    extra_devices: Vec<u32>,
+}
+
+async fn send_messages(
+    destination: String,
+    authorization: Option<String>,
+    messages: OutgoingPushMessages,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let mut state = state.lock().expect("poisoned mutex");
+    let (source_uuid, source_device) = match state.authenticate_header(authorization.as_deref()) {
+        Some(source) => source,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+    let destination = match state.account_uuid(&destination) {
+        Some(destination) => destination,
+        None => return Ok(empty(StatusCode::NOT_FOUND)),
+    };
+
+    // every device of the recipient has to get a copy, except the sending one
+    let expected_devices: Vec<u32> = state.accounts[&destination]
+        .devices
+        .keys()
+        .copied()
+        .filter(|id| destination != source_uuid || *id != source_device)
+        .collect();
+    let sent_devices: Vec<u32> = messages
+        .messages
+        .iter()
+        .map(|m| m.destination_device_id)
+        .collect();
+    let mismatched_devices = MismatchedDevices {
+        missing_devices: expected_devices
+            .iter()
+            .copied()
+            .filter(|id| !sent_devices.contains(id))
+            .collect(),
+        extra_devices: sent_devices
+            .iter()
+            .copied()
+            .filter(|id| !expected_devices.contains(id))
+            .collect(),
+    };
+    if !mismatched_devices.missing_devices.is_empty()
+        || !mismatched_devices.extra_devices.is_empty()
+    {
+        return Ok(json_with_status(&mismatched_devices, StatusCode::CONFLICT));
+    }
+
+    let source_e164 = state.accounts[&source_uuid].phone_number.clone();
+    for message in messages.messages {
+        let content = match base64::decode(&message.content) {
+            Ok(content) => content,
+            Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+        };
+        let sealed_sender = message.r#type == envelope::Type::UnidentifiedSender as u32;
+        let envelope = Envelope {
+            r#type: Some(message.r#type as i32),
+            source_e164: Some(source_e164.clone()).filter(|_| !sealed_sender),
+            source_uuid: Some(source_uuid.to_string()).filter(|_| !sealed_sender),
+            source_device: Some(source_device).filter(|_| !sealed_sender),
+            timestamp: Some(messages.timestamp),
+            content: Some(content),
+            server_guid: Some(Uuid::from_bytes(rand::random()).to_string()),
+            server_timestamp: Some(now()),
+            ..Default::default()
+        };
+        state.deliver(
+            (destination, message.destination_device_id),
+            encode(&envelope),
+        );
+    }
+
+    Ok(json(&serde_json::json!({ "needsSync": false })))
+}
+
+fn message_pipe(query: HashMap<String, String>, ws: Ws, state: SharedState) -> Response {
+    let device = match (query.get("login"), query.get("password")) {
+        // a `+` in the query string is decoded as a space
+        (Some(login), Some(password)) => state
+            .lock()
+            .expect("poisoned mutex")
+            .authenticate(&login.replace(' ', "+"), password),
+        _ => None,
+    };
+    match device {
+        Some(device) => ws
+            .on_upgrade(move |socket| run_message_pipe(socket, state, device))
+            .into_response(),
+        None => empty(StatusCode::FORBIDDEN),
+    }
+}
+
+async fn run_message_pipe(socket: WebSocket, state: SharedState, device: (Uuid, u32)) {
+    let notify = match state.lock().expect("poisoned mutex").device_mut(device) {
+        Some(device) => device.notify.clone(),
+        None => return,
+    };
+    let (mut sink, mut stream) = socket.split();
+
+    // envelopes sent to the device but not acknowledged yet
+    let mut in_flight: Vec<(u64, Vec<u8>)> = Vec::new();
+    let mut next_request_id = 0;
+    let mut queue_empty_sent = false;
+
+    'pipe: loop {
+        let queued: Vec<Vec<u8>> = match state.lock().expect("poisoned mutex").device_mut(device) {
+            Some(device) => device.queue.drain(..).collect(),
+            None => break,
+        };
+        for envelope in queued {
+            next_request_id += 1;
+            let request = request(
+                next_request_id,
+                "PUT",
+                "/api/v1/message",
+                Some(envelope.clone()),
+            );
+            in_flight.push((next_request_id, envelope));
+            if sink.send(Message::binary(request)).await.is_err() {
+                break 'pipe;
+            }
+        }
+        if !queue_empty_sent {
+            next_request_id += 1;
+            let request = request(next_request_id, "PUT", "/api/v1/queue/empty", None);
+            if sink.send(Message::binary(request)).await.is_err() {
+                break;
+            }
+            queue_empty_sent = true;
+        }
+
+        tokio::select! {
+            _ = notify.notified() => continue,
+            message = stream.next() => match message {
+                Some(Ok(message)) if message.is_binary() => {
+                    let message = match WebSocketMessage::decode(message.as_bytes()) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            warn!("invalid websocket message: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(response) = message.response {
+                        in_flight.retain(|(id, _)| Some(*id) != response.id);
+                    }
+                    if let Some(request) = message.request {
+                        // keep-alive and other requests from the client are always successful
+                        let response = response(request.id.unwrap_or_default(), StatusCode::OK);
+                        if sink.send(Message::binary(response)).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+                Some(Ok(message)) if message.is_close() => break,
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break,
+            }
+        }
+    }
+
+    // put back what was not acknowledged, in the original order
+    if let Some(device) = state.lock().expect("poisoned mutex").device_mut(device) {
+        for (_, envelope) in in_flight.into_iter().rev() {
+            device.queue.push_front(envelope);
+        }
+    }
+}
+
+async fn attachment_upload_form(
+    authorization: Option<String>,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let state = state.lock().expect("poisoned mutex");
+    if state
+        .authenticate_header(authorization.as_deref())
+        .is_none()
+    {
+        return Ok(empty(StatusCode::UNAUTHORIZED));
+    }
+
+    let attachment_id: u64 = rand::thread_rng().gen_range(1, u64::MAX / 2);
+    Ok(json(&serde_json::json!({
+        "key": format!("attachments/{}", attachment_id),
+        "credential": "fake-credential",
+        "acl": "private",
+        "algorithm": "AWS4-HMAC-SHA256",
+        "date": "20210101T000000Z",
+        "policy": "fake-policy",
+        "signature": "fake-signature",
+        "attachmentId": attachment_id,
+        "attachmentIdString": attachment_id.to_string(),
+    })))
+}
+
+/// Reads the key and the file of an upload to the CDN, as posted with the upload form.
+async fn read_upload(form: FormData) -> Option<(String, Vec<u8>)> {
+    let mut key = None;
+    let mut file = None;
+
+    let parts: Vec<_> = form.try_collect().await.ok()?;
+    for part in parts {
+        let name = part.name().to_string();
+        let data = part
+            .stream()
+            .try_fold(Vec::new(), |mut data, buf| async move {
+                data.extend_from_slice(buf.chunk());
+                Ok(data)
+            })
+            .await
+            .ok()?;
+        match name.as_str() {
+            "key" => key = String::from_utf8(data).ok(),
+            "file" => file = Some(data),
+            _ => {}
+        }
+    }
+    Some((key?, file?))
+}
+
+async fn upload_attachment(form: FormData, state: SharedState) -> Result<Response, Infallible> {
+    let (key, file) = match read_upload(form).await {
+        Some(upload) => upload,
+        None => return Ok(empty(StatusCode::BAD_REQUEST)),
+    };
+
+    let attachment_id = key
+        .strip_prefix("attachments/")
+        .and_then(|id| id.parse().ok());
+    match attachment_id {
+        Some(attachment_id) => {
+            state
+                .lock()
+                .expect("poisoned mutex")
+                .attachments
+                .insert(attachment_id, file);
+            Ok(empty(StatusCode::NO_CONTENT))
+        }
+        None => Ok(empty(StatusCode::BAD_REQUEST)),
+    }
+}
+
+async fn get_attachment(attachment_id: u64, state: SharedState) -> Result<Response, Infallible> {
+    let state = state.lock().expect("poisoned mutex");
+    Ok(match state.attachments.get(&attachment_id) {
+        Some(data) => Response::new(Body::from(data.clone())),
+        None => empty(StatusCode::NOT_FOUND),
+    })
+}
+
+async fn set_profile(
+    authorization: Option<String>,
+    mut profile: Profile,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let mut state = state.lock().expect("poisoned mutex");
+    let uuid = match state.authenticate_header(authorization.as_deref()) {
+        Some((uuid, _)) => uuid,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+
+    let form = if profile.avatar {
+        let path = format!(
+            "profiles/{}",
+            hex::encode(rand::thread_rng().gen::<[u8; 16]>())
+        );
+        profile.avatar_path = Some(path.clone());
+        Some(serde_json::json!({
+            "key": path,
+            "credential": "fake-credential",
+            "acl": "private",
+            "algorithm": "AWS4-HMAC-SHA256",
+            "date": "20210101T000000Z",
+            "policy": "fake-policy",
+            "signature": "fake-signature",
+        }))
+    } else {
+        None
+    };
+    if let Some(account) = state.accounts.get_mut(&uuid) {
+        account.profile = Some(profile);
+    }
+    Ok(match form {
+        Some(form) => json(&form),
+        None => empty(StatusCode::OK),
+    })
+}
+
+async fn get_profile(
+    uuid: Uuid,
+    version: String,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let state = state.lock().expect("poisoned mutex");
+    let profile = state
+        .accounts
+        .get(&uuid)
+        .and_then(|account| account.profile.as_ref())
+        .filter(|profile| profile.version == version);
+    Ok(match profile {
+        Some(profile) => json(&profile_json(profile)),
+        None => empty(StatusCode::NOT_FOUND),
+    })
+}
+
+/// Returns a versioned profile along with a profile key credential, which proves that the
+/// requested profile key is the one committed to when the profile was written.
+async fn get_profile_key_credential(
+    uuid: Uuid,
+    version: String,
+    credential_request: String,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let state = state.lock().expect("poisoned mutex");
+    let profile = match state
+        .accounts
+        .get(&uuid)
+        .and_then(|account| account.profile.as_ref())
+        .filter(|profile| profile.version == version)
+    {
+        Some(profile) => profile,
+        None => return Ok(empty(StatusCode::NOT_FOUND)),
+    };
+
+    let commitment: Option<ProfileKeyCommitment> = base64::decode(&profile.commitment)
+        .ok()
+        .and_then(|commitment| bincode::deserialize(&commitment).ok());
+    let request: Option<ProfileKeyCredentialRequest> = hex::decode(&credential_request)
+        .ok()
+        .and_then(|request| bincode::deserialize(&request).ok());
+    let (commitment, request) = match (commitment, request) {
+        (Some(commitment), Some(request)) => (commitment, request),
+        _ => return Ok(empty(StatusCode::BAD_REQUEST)),
+    };
+    let mut randomness = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut randomness);
+    let response = match state.server_secret_params.issue_profile_key_credential(
+        randomness,
+        &request,
+        *uuid.as_bytes(),
+        commitment,
+    ) {
+        Ok(response) => response,
+        Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+    };
+
+    let mut profile = profile_json(profile);
+    profile["credential"] =
+        base64::encode(bincode::serialize(&response).expect("serializable credential response"))
+            .into();
+    Ok(json(&profile))
+}
+
+fn profile_json(profile: &Profile) -> serde_json::Value {
+    serde_json::json!({
+        "name": profile.name,
+        "about": profile.about,
+        "aboutEmoji": profile.about_emoji,
+        "avatar": profile.avatar_path,
+        "unrestrictedUnidentifiedAccess": false,
+        "capabilities": {
+            "uuid": true,
+            "gv2": true,
+            "storage": false,
+            "gv1-migration": true,
+        },
+    })
+}
+
+async fn upload_avatar(form: FormData, state: SharedState) -> Result<Response, Infallible> {
+    match read_upload(form).await {
+        Some((key, file)) if key.starts_with("profiles/") => {
+            state
+                .lock()
+                .expect("poisoned mutex")
+                .avatars
+                .insert(key, file);
+            Ok(empty(StatusCode::NO_CONTENT))
+        }
+        _ => Ok(empty(StatusCode::BAD_REQUEST)),
+    }
+}
+
+async fn get_avatar(id: String, state: SharedState) -> Result<Response, Infallible> {
+    let state = state.lock().expect("poisoned mutex");
+    Ok(match state.avatars.get(&format!("profiles/{}", id)) {
+        Some(data) => Response::new(Body::from(data.clone())),
+        None => empty(StatusCode::NOT_FOUND),
+    })
+}
+
+async fn group_credentials(
+    start_day: u32,
+    end_day: u32,
+    authorization: Option<String>,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let state = state.lock().expect("poisoned mutex");
+    let uuid = match state.authenticate_header(authorization.as_deref()) {
+        Some((uuid, _)) => uuid,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+
+    let credentials: Vec<_> = (start_day..=end_day)
+        .map(|day| {
+            let mut randomness = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut randomness);
+            let credential =
+                state
+                    .server_secret_params
+                    .issue_auth_credential(randomness, *uuid.as_bytes(), day);
+            serde_json::json!({
+                "credential": base64::encode(
+                    bincode::serialize(&credential).expect("serializable credential"),
+                ),
+                "redemptionTime": day,
+            })
+        })
+        .collect();
+
+    Ok(json(&serde_json::json!({ "credentials": credentials })))
+}
+
+async fn put_group(
+    authorization: Option<String>,
+    group: warp::hyper::body::Bytes,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    // groups are authenticated with their public params as login, and the presentation of an
+    // auth credential as password, which we trust blindly
+    let group_public_params = match authorization.as_deref().and_then(basic_auth) {
+        Some((group_public_params, _)) => group_public_params,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+
+    // members are created with their presentation, which the server stores as the encrypted UUID
+    // and profile key it proves
+    let mut group = match Group::decode(group) {
+        Ok(group) => group,
+        Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+    };
+    for member in &mut group.members {
+        if redeem_member(member, group.version).is_none() {
+            return Ok(empty(StatusCode::BAD_REQUEST));
+        }
+    }
+
+    let mut state = state.lock().expect("poisoned mutex");
+    if state.groups.contains_key(&group_public_params) {
+        return Ok(empty(StatusCode::CONFLICT));
+    }
+    state.groups.insert(group_public_params, encode(&group));
+    Ok(empty(StatusCode::OK))
+}
+
+/// Applies a change to a group, and returns it signed by the server, so that members can apply it
+/// to their copy of the group rather than fetching it.
+async fn patch_group(
+    authorization: Option<String>,
+    actions: warp::hyper::body::Bytes,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let group_public_params = match authorization.as_deref().and_then(basic_auth) {
+        Some((group_public_params, _)) => group_public_params,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+    let mut actions = match group_change::Actions::decode(actions) {
+        Ok(actions) => actions,
+        Err(_) => return Ok(empty(StatusCode::BAD_REQUEST)),
+    };
+
+    let mut state = state.lock().expect("poisoned mutex");
+    let mut group = match state.groups.get(&group_public_params) {
+        Some(group) => Group::decode(group.as_slice()).expect("groups are stored encoded"),
+        None => return Ok(empty(StatusCode::NOT_FOUND)),
+    };
+    if actions.version != group.version + 1 {
+        return Ok(empty(StatusCode::CONFLICT));
+    }
+    if apply_actions(&mut group, &mut actions).is_none() {
+        return Ok(empty(StatusCode::BAD_REQUEST));
+    }
+    state.groups.insert(group_public_params, encode(&group));
+
+    let actions = encode(&actions);
+    let mut randomness = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut randomness);
+    let change = GroupChange {
+        server_signature: state
+            .server_secret_params
+            .sign(randomness, &actions)
+            .to_vec(),
+        actions,
+        ..Default::default()
+    };
+    Ok(protobuf(encode(&change)))
+}
+
+/// Applies the actions of a change to an encrypted group like the server does, replacing the
+/// presentations of the actions with the encrypted UUID and profile key they prove.
+///
+/// Returns `None` if an action is invalid, or not supported by the fake server (pending members).
+fn apply_actions(group: &mut Group, actions: &mut group_change::Actions) -> Option<()> {
+    if !actions.add_pending_members.is_empty()
+        || !actions.delete_pending_members.is_empty()
+        || !actions.promote_pending_members.is_empty()
+    {
+        return None;
+    }
+    let version = actions.version;
+    group.version = version;
+
+    if let Some(action) = &actions.modify_title {
+        group.title = action.title.clone();
+    }
+    if let Some(action) = &actions.modify_description {
+        group.description = action.description.clone();
+    }
+    if let Some(action) = &actions.modify_avatar {
+        group.avatar = action.avatar.clone();
+    }
+    if let Some(action) = &actions.modify_disappearing_messages_timer {
+        group.disappearing_messages_timer = action.timer.clone();
+    }
+    let access_control = group.access_control.get_or_insert_with(Default::default);
+    if let Some(action) = &actions.modify_attributes_access {
+        access_control.attributes = action.attributes_access;
+    }
+    if let Some(action) = &actions.modify_member_access {
+        access_control.members = action.members_access;
+    }
+    if let Some(action) = &actions.modify_add_from_invite_link_access {
+        access_control.add_from_invite_link = action.add_from_invite_link_access;
+    }
+    if let Some(action) = &actions.modify_invite_link_password {
+        group.invite_link_password = action.invite_link_password.clone();
+    }
+
+    for action in &mut actions.add_members {
+        let member = action.added.as_mut()?;
+        redeem_member(member, version)?;
+        group
+            .requesting_members
+            .retain(|requesting| requesting.user_id != member.user_id);
+        group.members.push(member.clone());
+    }
+    for action in &actions.delete_members {
+        group
+            .members
+            .retain(|member| member.user_id != action.deleted_user_id);
+    }
+    for action in &actions.modify_member_roles {
+        let member = group
+            .members
+            .iter_mut()
+            .find(|member| member.user_id == action.user_id)?;
+        member.role = action.role;
+    }
+    for action in &actions.modify_member_profile_keys {
+        let (user_id, profile_key) = redeem_presentation(&action.presentation)?;
+        let member = group
+            .members
+            .iter_mut()
+            .find(|member| member.user_id == user_id)?;
+        member.profile_key = profile_key;
+    }
+
+    for action in &mut actions.add_requesting_members {
+        let requesting = action.added.as_mut()?;
+        let (user_id, profile_key) = redeem_presentation(&requesting.presentation)?;
+        requesting.user_id = user_id;
+        requesting.profile_key = profile_key;
+        requesting.presentation.clear();
+        requesting.timestamp = now();
+        group.requesting_members.push(requesting.clone());
+    }
+    for action in &actions.delete_requesting_members {
+        group
+            .requesting_members
+            .retain(|requesting| requesting.user_id != action.deleted_user_id);
+    }
+    for action in &actions.promote_requesting_members {
+        let index = group
+            .requesting_members
+            .iter()
+            .position(|requesting| requesting.user_id == action.user_id)?;
+        let requesting = group.requesting_members.remove(index);
+        group.members.push(Member {
+            user_id: requesting.user_id,
+            role: action.role,
+            profile_key: requesting.profile_key,
+            joined_at_version: version,
+            ..Default::default()
+        });
+    }
+    Some(())
+}
+
+/// Replaces the presentation of a new member with the encrypted UUID and profile key it proves.
+fn redeem_member(member: &mut Member, version: u32) -> Option<()> {
+    let (user_id, profile_key) = redeem_presentation(&member.presentation)?;
+    member.user_id = user_id;
+    member.profile_key = profile_key;
+    member.presentation.clear();
+    member.joined_at_version = version;
+    Some(())
+}
+
+/// Returns the encrypted UUID and profile key of a profile key credential presentation, which is
+/// trusted blindly.
+fn redeem_presentation(presentation: &[u8]) -> Option<(Vec<u8>, Vec<u8>)> {
+    let presentation: ProfileKeyCredentialPresentation = bincode::deserialize(presentation).ok()?;
+    Some((
+        bincode::serialize(&presentation.get_uuid_ciphertext()).ok()?,
+        bincode::serialize(&presentation.get_profile_key_ciphertext()).ok()?,
+    ))
+}
+
+async fn get_group(
+    authorization: Option<String>,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let group_public_params = match authorization.as_deref().and_then(basic_auth) {
+        Some((group_public_params, _)) => group_public_params,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+
+    let state = state.lock().expect("poisoned mutex");
+    Ok(match state.groups.get(&group_public_params) {
+        Some(group) => protobuf(group.clone()),
+        None => empty(StatusCode::NOT_FOUND),
+    })
+}
+
+/// Returns what users with the invite link of a group can see of it before joining.
+async fn get_group_join_info(
+    invite_link_password: String,
+    authorization: Option<String>,
+    state: SharedState,
+) -> Result<Response, Infallible> {
+    let group_public_params = match authorization.as_deref().and_then(basic_auth) {
+        Some((group_public_params, _)) => group_public_params,
+        None => return Ok(empty(StatusCode::UNAUTHORIZED)),
+    };
+
+    let state = state.lock().expect("poisoned mutex");
+    let group = match state.groups.get(&group_public_params) {
+        Some(group) => Group::decode(group.as_slice()).expect("groups are stored encoded"),
+        None => return Ok(empty(StatusCode::NOT_FOUND)),
+    };
+    let invite_link_password =
+        base64::decode_config(&invite_link_password, base64::URL_SAFE_NO_PAD).ok();
+    if group.invite_link_password.is_empty()
+        || invite_link_password.as_ref() != Some(&group.invite_link_password)
+    {
+        return Ok(empty(StatusCode::FORBIDDEN));
+    }
+
+    Ok(protobuf(encode(&GroupJoinInfo {
+        public_key: group.public_key,
+        title: group.title,
+        avatar: group.avatar,
+        member_count: group.members.len() as u32,
+        add_from_invite_link: group
+            .access_control
+            .map_or(0, |access| access.add_from_invite_link),
+        version: group.version,
+        ..Default::default()
+    })))
+}
+
+/// Decodes a `Basic` HTTP authorization header into login and password.
+fn basic_auth(authorization: &str) -> Option<(String, String)> {
+    let encoded = authorization.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64::decode(encoded).ok()?).ok()?;
+    let mut parts = decoded.splitn(2, ':');
+    Some((parts.next()?.to_string(), parts.next()?.to_string()))
+}
+
+fn e164(phone_number: &PhoneNumber) -> String {
+    phone_number
+        .format()
+        .mode(phonenumber::Mode::E164)
+        .to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+fn encode(message: &impl ProtobufMessage) -> Vec<u8> {
+    let mut buf = Vec::new();
+    message
+        .encode(&mut buf)
+        .expect("failed to encode protobuf message");
+    buf
+}
+
+fn request(id: u64, verb: &str, path: &str, body: Option<Vec<u8>>) -> Vec<u8> {
+    encode(&WebSocketMessage {
+        r#type: Some(web_socket_message::Type::Request as i32),
+        request: Some(WebSocketRequestMessage {
+            id: Some(id),
+            verb: Some(verb.to_string()),
+            path: Some(path.to_string()),
+            body,
+            headers: vec![
+                // envelopes are never encrypted with the legacy signaling key
+                "X-Signal-Key: false".to_string(),
+                format!("X-Signal-Timestamp: {}", now()),
+            ],
+        }),
+        response: None,
+    })
+}
+
+fn response(id: u64, status: StatusCode) -> Vec<u8> {
+    encode(&WebSocketMessage {
+        r#type: Some(web_socket_message::Type::Response as i32),
+        request: None,
+        response: Some(WebSocketResponseMessage {
+            id: Some(id),
+            status: Some(status.as_u16().into()),
+            message: status.canonical_reason().map(ToString::to_string),
+            headers: vec![],
+            body: None,
+        }),
+    })
+}
+
+fn empty(status: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply(), status).into_response()
+}
+
+fn json(value: &impl Serialize) -> Response {
+    warp::reply::json(value).into_response()
+}
+
+fn json_with_status(value: &impl Serialize, status: StatusCode) -> Response {
+    warp::reply::with_status(warp::reply::json(value), status).into_response()
+}
+
+fn protobuf(body: Vec<u8>) -> Response {
+    warp::reply::with_header(
+        Response::new(Body::from(body)),
+        "content-type",
+        "application/x-protobuf",
+    )
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use futures::{pin_mut, StreamExt};
+    use libsignal_service::{
+        configuration::SignalServers,
+        content::{Content, ContentBody, DataMessage, GroupContextV2},
+        prelude::{
+            phonenumber::PhoneNumber,
+            protocol::{
+                IdentityKey, IdentityKeyStore, KeyPair, ProtocolAddress, SignedPreKeyStore,
+            },
+            GroupMasterKey, Uuid,
+        },
+        proto::{self, member::Role, sync_message, verified, DecryptedGroup, SyncMessage},
+        sender::AttachmentSpec,
+        ServiceAddress,
+    };
+
+    use super::{now, FakeSignalServer};
+    use crate::{
+        device_name::decrypt_device_name, AccountChanges, Capabilities, ConfigStore, GroupChanges,
+        InviteLinkAccess, Manager, MemoryConfigStore, PreKeysPolicy, ProfileDetails, Thread,
+        TrustLevel, TrustStore,
+    };
+
+    type TestManager = Manager<MemoryConfigStore, rand::rngs::OsRng, FakeSignalServer>;
+
+    async fn register(server: &FakeSignalServer, phone_number: &str) -> TestManager {
+        let phone_number: PhoneNumber = phone_number.parse().unwrap();
+        let mut manager = Manager::with_push_service_factory(
+            MemoryConfigStore::new(),
+            rand::rngs::OsRng,
+            server.clone(),
+        )
+        .await
+        .unwrap();
+        manager
+            .register(
+                SignalServers::Staging,
+                phone_number.clone(),
+                false,
+                None,
+                false,
+            )
+            .await
+            .unwrap();
+        let code = server.verification_code(&phone_number).unwrap();
+        manager.confirm_verification_code(code).await.unwrap();
+        manager
+    }
+
+    /// Registers an account with a profile, as needed to be a member of groups.
+    async fn register_with_profile(
+        server: &FakeSignalServer,
+        phone_number: &str,
+        name: &str,
+    ) -> TestManager {
+        let mut manager = register(server, phone_number).await;
+        let details = ProfileDetails {
+            given_name: name.to_string(),
+            ..Default::default()
+        };
+        manager.set_profile(&details).await.unwrap();
+        manager
+    }
+
+    /// Sends the profile key of an account to another, which needs it to add the account to
+    /// groups.
+    async fn share_profile_key(from: &TestManager, to: &TestManager) {
+        let timestamp = now();
+        let message = DataMessage {
+            profile_key: Some(from.profile_key().unwrap().to_vec()),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        from.send_message(to.phone_number().unwrap().clone(), message, timestamp)
+            .await
+            .unwrap();
+        receive(to).await;
+    }
+
+    async fn receive(manager: &TestManager) -> Content {
+        let messages = manager.receive_messages().await.unwrap();
+        pin_mut!(messages);
+        tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    fn member_role(group: &DecryptedGroup, uuid: Uuid) -> Option<i32> {
+        group
+            .members
+            .iter()
+            .find(|member| member.uuid == uuid.as_bytes())
+            .map(|member| member.role)
+    }
+
+    fn group_context(content: &Content) -> &GroupContextV2 {
+        match &content.body {
+            ContentBody::DataMessage(DataMessage {
+                group_v2: Some(context),
+                ..
+            }) => context,
+            body => panic!("unexpected content: {:?}", body),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register() {
+        let server = FakeSignalServer::start();
+        let alice = register(&server, "+15555550101").await;
+
+        assert!(alice.is_registered());
+        assert_eq!(alice.whoami().await.unwrap().uuid, alice.uuid());
+    }
+
+    #[tokio::test]
+    async fn test_update_account() {
+        let server = FakeSignalServer::start();
+        let mut alice = register(&server, "+15555550101").await;
+        let attributes = server.account_attributes(alice.uuid(), 1).unwrap();
+        assert_eq!(attributes["discoverableByPhoneNumber"], true);
+        assert_eq!(attributes["capabilities"]["storage"], false);
+
+        alice
+            .update_account(
+                AccountChanges::default()
+                    .device_name("presage")
+                    .discoverable_by_phone_number(false)
+                    .capabilities(Capabilities {
+                        storage: true,
+                        ..Default::default()
+                    }),
+            )
+            .await
+            .unwrap();
+
+        let attributes = server.account_attributes(alice.uuid(), 1).unwrap();
+        assert_eq!(attributes["discoverableByPhoneNumber"], false);
+        assert_eq!(attributes["capabilities"]["storage"], true);
+        // encrypted for the identity key, so that only the devices of the account can read it
+        let device_name = server.device_name(alice.uuid(), 1).unwrap();
+        assert_ne!(device_name, "presage");
+        let identity_key = alice
+            .config_store()
+            .get_identity_key_pair(None)
+            .await
+            .unwrap();
+        assert_eq!(
+            decrypt_device_name(&device_name, identity_key.private_key()).as_deref(),
+            Some("presage")
+        );
+
+        // saved in the store
+        let alice: TestManager = Manager::with_push_service_factory(
+            alice.config_store().clone(),
+            rand::rngs::OsRng,
+            server.clone(),
+        )
+        .await
+        .unwrap();
+        assert!(alice.account_attributes().unwrap().capabilities.storage);
+    }
+
+    #[tokio::test]
+    async fn test_profile() {
+        let server = FakeSignalServer::start();
+        let mut alice = register(&server, "+15555550101").await;
+
+        let details = ProfileDetails {
+            given_name: "Alice".to_string(),
+            family_name: Some("Liddell".to_string()),
+            about: Some("down the rabbit hole".to_string()),
+            about_emoji: Some("🐇".to_string()),
+            avatar: Some(b"not really a picture".to_vec()),
+        };
+        assert!(alice.set_profile(&Default::default()).await.is_err());
+        alice.set_profile(&details).await.unwrap();
+        assert_eq!(alice.own_profile().await.unwrap(), details);
+
+        let old_profile_key = alice.profile_key().unwrap();
+        alice.rotate_profile_key(now()).await.unwrap();
+        assert_ne!(alice.profile_key().unwrap(), old_profile_key);
+        assert_eq!(alice.own_profile().await.unwrap(), details);
+        assert!(alice
+            .retrieve_profile_by_uuid(alice.uuid(), old_profile_key)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_contact_profile() {
+        let server = FakeSignalServer::start();
+        let mut alice = register(&server, "+15555550101").await;
+        let mut bob = register(&server, "+15555550102").await;
+
+        let mut details = ProfileDetails {
+            given_name: "Alice".to_string(),
+            avatar: Some(b"not really a picture".to_vec()),
+            ..Default::default()
+        };
+        alice.set_profile(&details).await.unwrap();
+        assert_eq!(bob.profile(alice.uuid()).await.unwrap(), None);
+
+        let timestamp = now();
+        let message = DataMessage {
+            body: Some("Hello, Bob!".to_string()),
+            profile_key: Some(alice.profile_key().unwrap().to_vec()),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        alice
+            .send_message(bob.phone_number().unwrap().clone(), message, timestamp)
+            .await
+            .unwrap();
+        {
+            let messages = bob.receive_messages().await.unwrap();
+            pin_mut!(messages);
+            tokio::time::timeout(Duration::from_secs(5), messages.next())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert_eq!(
+            bob.profile(alice.uuid()).await.unwrap(),
+            Some(details.clone())
+        );
+
+        // the cached profile is used until it expires
+        let cached = details.clone();
+        details.given_name = "Alice L.".to_string();
+        alice.set_profile(&details).await.unwrap();
+        assert_eq!(bob.profile(alice.uuid()).await.unwrap(), Some(cached));
+        bob.set_profile_ttl(Duration::ZERO);
+        assert_eq!(bob.profile(alice.uuid()).await.unwrap(), Some(details));
+    }
+
+    #[tokio::test]
+    async fn test_maintain_pre_keys() {
+        let server = FakeSignalServer::start();
+        let mut alice = register(&server, "+15555550101").await;
+        assert_eq!(server.pre_keys(alice.uuid(), 1), 100);
+        assert_eq!(
+            alice.config_store().next_signed_pre_key_id().await.unwrap(),
+            1
+        );
+
+        // nothing to do yet
+        alice.maintain_pre_keys().await.unwrap();
+        assert_eq!(server.pre_keys(alice.uuid(), 1), 100);
+        assert_eq!(
+            alice.config_store().next_signed_pre_key_id().await.unwrap(),
+            1
+        );
+
+        alice.set_pre_keys_policy(PreKeysPolicy {
+            minimum_pre_keys: 101,
+            ..Default::default()
+        });
+        alice.maintain_pre_keys().await.unwrap();
+        assert_eq!(server.pre_keys(alice.uuid(), 1), 200);
+        assert_eq!(
+            alice.config_store().next_signed_pre_key_id().await.unwrap(),
+            2
+        );
+
+        alice.set_pre_keys_policy(PreKeysPolicy {
+            rotation_interval: Duration::ZERO,
+            grace_period: Duration::ZERO,
+            ..Default::default()
+        });
+        alice.maintain_pre_keys().await.unwrap();
+        assert_eq!(server.pre_keys(alice.uuid(), 1), 200);
+        let store = alice.config_store();
+        assert_eq!(store.next_signed_pre_key_id().await.unwrap(), 3);
+        assert!(store.get_signed_pre_key(2, None).await.is_ok());
+        assert!(store.get_signed_pre_key(1, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_receive_message() {
+        let server = FakeSignalServer::start();
+        let alice = register(&server, "+15555550101").await;
+        let bob = register(&server, "+15555550102").await;
+
+        let timestamp = now();
+        let message = DataMessage {
+            body: Some("Hello, Bob!".to_string()),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        alice
+            .send_message(bob.phone_number().unwrap().clone(), message, timestamp)
+            .await
+            .unwrap();
+        assert_eq!(server.queued_envelopes(bob.uuid(), 1), 1);
+
+        let messages = bob.receive_messages().await.unwrap();
+        pin_mut!(messages);
+        let content = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(content.metadata.sender.uuid, Some(alice.uuid()));
+        match content.body {
+            ContentBody::DataMessage(message) => {
+                assert_eq!(message.body(), "Hello, Bob!");
+                assert_eq!(message.timestamp(), timestamp);
+            }
+            body => panic!("unexpected content: {:?}", body),
+        }
+
+        let saved = bob
+            .message(&Thread::Contact(alice.uuid()), timestamp)
+            .await
+            .unwrap();
+        assert!(saved.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ignore_sync_message_from_contact() {
+        let server = FakeSignalServer::start();
+        let alice = register(&server, "+15555550101").await;
+        let bob = register(&server, "+15555550102").await;
+
+        // pretends that Bob sent a message to Alice from another device
+        let forged = now();
+        let sync_message = SyncMessage {
+            sent: Some(sync_message::Sent {
+                destination_uuid: Some(alice.uuid().to_string()),
+                timestamp: Some(forged),
+                message: Some(DataMessage {
+                    body: Some("I owe Alice $100".to_string()),
+                    timestamp: Some(forged),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        alice
+            .send_message(bob.phone_number().unwrap().clone(), sync_message, forged)
+            .await
+            .unwrap();
+        let timestamp = now() + 1;
+        let message = DataMessage {
+            body: Some("Hello, Bob!".to_string()),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        alice
+            .send_message(bob.phone_number().unwrap().clone(), message, timestamp)
+            .await
+            .unwrap();
+
+        let messages = bob.receive_messages().await.unwrap();
+        pin_mut!(messages);
+        let content = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(content.body, ContentBody::DataMessage(_)));
+        assert!(bob
+            .message(&Thread::Contact(alice.uuid()), forged)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ignore_verification_from_contact() {
+        let server = FakeSignalServer::start();
+        let alice = register(&server, "+15555550101").await;
+        let bob = register(&server, "+15555550102").await;
+        let name = alice.uuid().to_string();
+        let identity_key = *alice
+            .config_store()
+            .get_identity_key_pair(None)
+            .await
+            .unwrap()
+            .identity_key();
+
+        // pretends that Bob verified another identity key of Alice on another device
+        let forged_key = KeyPair::generate(&mut rand::thread_rng()).public_key;
+        let sync_message = SyncMessage {
+            verified: Some(proto::Verified {
+                destination_uuid: Some(name.clone()),
+                identity_key: Some(IdentityKey::new(forged_key).serialize().into_vec()),
+                state: Some(verified::State::Verified as i32),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let timestamp = now();
+        alice
+            .send_message(bob.phone_number().unwrap().clone(), sync_message, timestamp)
+            .await
+            .unwrap();
+        let timestamp = now() + 1;
+        let message = DataMessage {
+            body: Some("Hello, Bob!".to_string()),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        alice
+            .send_message(bob.phone_number().unwrap().clone(), message, timestamp)
+            .await
+            .unwrap();
+
+        let messages = bob.receive_messages().await.unwrap();
+        pin_mut!(messages);
+        let content = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(content.body, ContentBody::DataMessage(_)));
+        assert_eq!(
+            bob.config_store()
+                .get_identity(&ProtocolAddress::new(name.clone(), 1), None)
+                .await
+                .unwrap(),
+            Some(identity_key)
+        );
+        assert_eq!(
+            bob.config_store().trust_level(&name).await.unwrap(),
+            Some(TrustLevel::TrustedOnFirstUse)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_receive_attachment() {
+        let server = FakeSignalServer::start();
+        let alice = register(&server, "+15555550101").await;
+        let bob = register(&server, "+15555550102").await;
+
+        let contents = b"not really a picture".to_vec();
+        let spec = AttachmentSpec {
+            content_type: "image/png".to_string(),
+            length: contents.len(),
+            file_name: Some("picture.png".to_string()),
+            preview: None,
+            voice_note: None,
+            borderless: None,
+            width: None,
+            height: None,
+            caption: None,
+            blur_hash: None,
+        };
+
+        let timestamp = now();
+        let message = DataMessage {
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        alice
+            .send_message_with_attachments(
+                bob.phone_number().unwrap().clone(),
+                message,
+                vec![(spec, contents.clone())],
+                timestamp,
+            )
+            .await
+            .unwrap();
+
+        let messages = bob.receive_messages().await.unwrap();
+        pin_mut!(messages);
+        let content = tokio::time::timeout(Duration::from_secs(5), messages.next())
+            .await
+            .unwrap()
+            .unwrap();
+
+        let attachment_pointer = match content.body {
+            ContentBody::DataMessage(message) => message.attachments[0].clone(),
+            body => panic!("unexpected content: {:?}", body),
+        };
+        assert_eq!(attachment_pointer.content_type(), "image/png");
+        assert_eq!(attachment_pointer.file_name(), "picture.png");
+        assert_eq!(
+            bob.get_attachment(&attachment_pointer).await.unwrap(),
+            contents
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_message_to_recipients() {
+        let server = FakeSignalServer::start();
+        let alice = register(&server, "+15555550101").await;
+        let bob = register(&server, "+15555550102").await;
+        let address = |uuid| ServiceAddress {
+            uuid: Some(uuid),
+            phonenumber: None,
+            relay: None,
+        };
+        let unknown = address(Uuid::new_v4());
+        let master_key = [1; 32];
+        let message = |timestamp| DataMessage {
+            body: Some("Hello, group!".to_string()),
+            group_v2: Some(GroupContextV2 {
+                master_key: Some(master_key.to_vec()),
+                revision: Some(0),
+                group_change: None,
+            }),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+
+        // the message is saved once someone got it, even if others didn't
+        let timestamp = now();
+        alice
+            .send_message_to_recipients(
+                vec![unknown.clone(), address(bob.uuid())],
+                message(timestamp),
+                timestamp,
+            )
+            .await
+            .unwrap();
+        receive(&bob).await;
+        let thread = Thread::Group(master_key);
+        assert!(alice.message(&thread, timestamp).await.unwrap().is_some());
+
+        let timestamp = now() + 1;
+        assert!(alice
+            .send_message_to_recipients(vec![unknown], message(timestamp), timestamp)
+            .await
+            .is_err());
+        assert!(alice.message(&thread, timestamp).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reset_session() {
+        let server = FakeSignalServer::start();
+        let alice = register(&server, "+15555550101").await;
+        let bob = register(&server, "+15555550102").await;
+        let address = |manager: &TestManager| ServiceAddress {
+            uuid: Some(manager.uuid()),
+            phonenumber: None,
+            relay: None,
+        };
+
+        let timestamp = now();
+        let message = DataMessage {
+            body: Some("Hello, Bob!".to_string()),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        alice
+            .send_message(address(&bob), message, timestamp)
+            .await
+            .unwrap();
+        assert_eq!(alice.sessions(&address(&bob)).await.unwrap(), vec![1]);
+
+        alice.reset_session(&address(&bob), now()).await.unwrap();
+        assert!(alice.sessions(&address(&bob)).await.unwrap().is_empty());
+
+        let messages = bob.receive_messages().await.unwrap();
+        pin_mut!(messages);
+        for _ in 0..2 {
+            tokio::time::timeout(Duration::from_secs(5), messages.next())
+                .await
+                .unwrap()
+                .unwrap();
+        }
+        assert!(bob.sessions(&address(&alice)).await.unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_shared_manager() {
+        fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+        let server = FakeSignalServer::start();
+        let alice = Arc::new(register(&server, "+15555550101").await);
+        let bob = Arc::new(register(&server, "+15555550102").await);
+        assert_send_sync(&bob);
+
+        // the futures of the manager are Send, so the tasks sharing it may run on any thread
+        let receiving = tokio::spawn({
+            let bob = bob.clone();
+            async move {
+                let messages = bob.receive_messages().await.unwrap();
+                pin_mut!(messages);
+                tokio::time::timeout(Duration::from_secs(5), messages.next())
+                    .await
+                    .unwrap()
+                    .unwrap()
+            }
+        });
+        let sending = tokio::spawn({
+            let (alice, bob) = (alice.clone(), bob.clone());
+            async move {
+                let message = |body: &str, timestamp| DataMessage {
+                    body: Some(body.to_string()),
+                    timestamp: Some(timestamp),
+                    ..Default::default()
+                };
+                let timestamp = now();
+                bob.send_message(
+                    alice.phone_number().unwrap().clone(),
+                    message("Hello, Alice!", timestamp),
+                    timestamp,
+                )
+                .await
+                .unwrap();
+                let timestamp = now();
+                alice
+                    .send_message(
+                        bob.phone_number().unwrap().clone(),
+                        message("Hello, Bob!", timestamp),
+                        timestamp,
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+        let (content, sent) = futures::join!(receiving, sending);
+        sent.unwrap();
+        let content = content.unwrap();
+
+        assert_eq!(content.metadata.sender.uuid, Some(alice.uuid()));
+        assert_eq!(server.queued_envelopes(alice.uuid(), 1), 1);
+    }
+
+    #[tokio::test]
+    async fn test_group() {
+        let server = FakeSignalServer::start();
+        let mut alice = register_with_profile(&server, "+15555550101", "Alice").await;
+        let bob = register_with_profile(&server, "+15555550102", "Bob").await;
+        share_profile_key(&bob, &alice).await;
+
+        let master_key = alice
+            .create_group("Presage", vec![bob.uuid()], now())
+            .await
+            .unwrap();
+        let group = alice
+            .get_group_v2(GroupMasterKey::new(master_key))
+            .await
+            .unwrap();
+        assert_eq!(group.revision, 0);
+        assert_eq!(group.title, "Presage");
+        assert_eq!(group.members.len(), 2);
+
+        // bob fetches the group he was added to
+        let content = receive(&bob).await;
+        assert_eq!(group_context(&content).revision, Some(0));
+        assert_eq!(bob.groups().await.unwrap(), vec![(master_key, group)]);
+
+        let revision = alice
+            .update_group(
+                master_key,
+                GroupChanges::default()
+                    .title("Presage developers")
+                    .description("Signal, in Rust"),
+                now(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(revision, 1);
+        let group = alice
+            .get_group_v2(GroupMasterKey::new(master_key))
+            .await
+            .unwrap();
+        assert_eq!(group.revision, 1);
+        assert_eq!(group.title, "Presage developers");
+        assert_eq!(group.description, "Signal, in Rust");
+
+        // and applies the change signed by the server
+        let content = receive(&bob).await;
+        let context = group_context(&content);
+        assert_eq!(context.revision, Some(1));
+        assert!(context.group_change.is_some());
+        assert_eq!(bob.groups().await.unwrap(), vec![(master_key, group)]);
+    }
+
+    #[tokio::test]
+    async fn test_group_members() {
+        let server = FakeSignalServer::start();
+        let mut alice = register_with_profile(&server, "+15555550101", "Alice").await;
+        let mut bob = register_with_profile(&server, "+15555550102", "Bob").await;
+        share_profile_key(&bob, &alice).await;
+        let master_key = alice.create_group("Presage", vec![], now()).await.unwrap();
+
+        // new members are notified of the change adding them
+        let changes = GroupChanges::default().add_member(bob.uuid(), Role::Default);
+        assert_eq!(
+            alice
+                .update_group(master_key, changes, now())
+                .await
+                .unwrap(),
+            1
+        );
+        let content = receive(&bob).await;
+        assert_eq!(group_context(&content).revision, Some(1));
+        let group = bob.group(master_key).await.unwrap();
+        assert_eq!(member_role(&group, bob.uuid()), Some(Role::Default as i32));
+
+        let changes = GroupChanges::default()
+            .member_role(bob.uuid(), Role::Administrator)
+            .disappearing_messages_timer(3600);
+        alice
+            .update_group(master_key, changes, now())
+            .await
+            .unwrap();
+        let content = receive(&bob).await;
+        assert_eq!(group_context(&content).revision, Some(2));
+        let group = bob.group(master_key).await.unwrap();
+        assert_eq!(group, alice.group(master_key).await.unwrap());
+        assert_eq!(
+            member_role(&group, bob.uuid()),
+            Some(Role::Administrator as i32)
+        );
+        assert_eq!(group.disappearing_messages_timer.unwrap().duration, 3600);
+
+        // members leaving a group let the others know
+        bob.leave_group(master_key, now()).await.unwrap();
+        let content = receive(&alice).await;
+        assert_eq!(group_context(&content).revision, Some(3));
+        let group = alice.group(master_key).await.unwrap();
+        assert_eq!(group.revision, 3);
+        assert_eq!(member_role(&group, bob.uuid()), None);
+        assert_eq!(group.members.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_join_group_with_invite_link() {
+        let server = FakeSignalServer::start();
+        let mut alice = register_with_profile(&server, "+15555550101", "Alice").await;
+        let mut bob = register_with_profile(&server, "+15555550102", "Bob").await;
+        let mut carol = register_with_profile(&server, "+15555550103", "Carol").await;
+        let master_key = alice.create_group("Presage", vec![], now()).await.unwrap();
+        assert_eq!(alice.group_invite_link(master_key).await.unwrap(), None);
+
+        let changes = GroupChanges::default().invite_link_access(InviteLinkAccess::Any);
+        alice
+            .update_group(master_key, changes, now())
+            .await
+            .unwrap();
+        let invite_link = alice.group_invite_link(master_key).await.unwrap().unwrap();
+
+        // anyone with the link joins the group, and lets its members know
+        assert_eq!(
+            bob.join_group_with_invite_link(&invite_link, now())
+                .await
+                .unwrap(),
+            master_key
+        );
+        let content = receive(&alice).await;
+        assert_eq!(group_context(&content).revision, Some(2));
+        let group = alice.group(master_key).await.unwrap();
+        assert_eq!(member_role(&group, bob.uuid()), Some(Role::Default as i32));
+
+        // or only requests to join it, when administrators approve new members
+        let changes =
+            GroupChanges::default().invite_link_access(InviteLinkAccess::AdministratorApproval);
+        alice
+            .update_group(master_key, changes, now())
+            .await
+            .unwrap();
+        let content = receive(&bob).await;
+        assert_eq!(group_context(&content).revision, Some(3));
+        carol
+            .join_group_with_invite_link(&invite_link, now())
+            .await
+            .unwrap();
+        let group = alice
+            .get_group_v2(GroupMasterKey::new(master_key))
+            .await
+            .unwrap();
+        assert_eq!(group.requesting_members.len(), 1);
+        assert_eq!(group.requesting_members[0].uuid, carol.uuid().as_bytes());
+        assert_eq!(member_role(&group, carol.uuid()), None);
+
+        let changes =
+            GroupChanges::default().approve_requesting_member(carol.uuid(), Role::Default);
+        alice
+            .update_group(master_key, changes, now())
+            .await
+            .unwrap();
+        let content = receive(&carol).await;
+        assert_eq!(group_context(&content).revision, Some(5));
+        let group = carol.group(master_key).await.unwrap();
+        assert!(group.requesting_members.is_empty());
+        assert_eq!(
+            member_role(&group, carol.uuid()),
+            Some(Role::Default as i32)
+        );
+    }
+}
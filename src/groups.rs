@@ -0,0 +1,901 @@
+use std::{collections::HashMap, convert::TryInto};
+
+use libsignal_service::{
+    configuration::Endpoint,
+    content::GroupContextV2,
+    groups_v2::{CredentialsCache, GroupsManager},
+    prelude::{GroupMasterKey, GroupSecretParams, ProtobufMessage, PushService, Uuid},
+    proto::{
+        access_control::AccessRequired,
+        group_attribute_blob,
+        group_change::{actions, Actions},
+        group_invite_link,
+        member::Role,
+        DecryptedGroup, DecryptedMember, DecryptedPendingMember, DecryptedRequestingMember,
+        DecryptedTimer, GroupAttributeBlob, GroupChange, GroupInviteLink, Member,
+    },
+    push_service::{HttpAuth, HttpAuthOverride, ServiceError},
+};
+use log::{trace, warn};
+use rand::{CryptoRng, Rng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use zkgroup::{
+    profiles::{
+        ProfileKey, ProfileKeyCredential, ProfileKeyCredentialPresentation,
+        ProfileKeyCredentialResponse,
+    },
+    NotarySignatureBytes, ServerPublicParams,
+};
+
+use crate::{config::GroupsStore, Error};
+
+const INVITE_LINK_PREFIX: &str = "https://signal.group/#";
+const INVITE_LINK_PASSWORD_LEN: usize = 16;
+
+/// How members can join a group with its invite link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InviteLinkAccess {
+    /// The invite link doesn't work
+    Disabled,
+    /// Anyone with the invite link joins the group
+    Any,
+    /// Anyone with the invite link can ask to join the group, an administrator has to approve
+    AdministratorApproval,
+}
+
+impl From<InviteLinkAccess> for AccessRequired {
+    fn from(access: InviteLinkAccess) -> Self {
+        match access {
+            InviteLinkAccess::Disabled => AccessRequired::Unsatisfiable,
+            InviteLinkAccess::Any => AccessRequired::Any,
+            InviteLinkAccess::AdministratorApproval => AccessRequired::Administrator,
+        }
+    }
+}
+
+/// Changes to apply to a group, see [Manager::update_group](crate::Manager::update_group).
+///
+/// Members are identified by their UUID. The profile keys of the members to add have to be known,
+/// from their messages or from the synchronized contacts.
+#[derive(Debug, Clone, Default)]
+pub struct GroupChanges {
+    title: Option<String>,
+    description: Option<String>,
+    avatar: Option<String>,
+    disappearing_messages_timer: Option<u32>,
+    attributes_access: Option<AccessRequired>,
+    members_access: Option<AccessRequired>,
+    invite_link_access: Option<InviteLinkAccess>,
+    reset_invite_link: bool,
+    add_members: Vec<(Uuid, Role)>,
+    remove_members: Vec<Uuid>,
+    member_roles: Vec<(Uuid, Role)>,
+    approve_requesting_members: Vec<(Uuid, Role)>,
+    deny_requesting_members: Vec<Uuid>,
+}
+
+impl GroupChanges {
+    pub fn title(mut self, title: impl Into<String>) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn description(mut self, description: impl Into<String>) -> Self {
+        self.description = Some(description.into());
+        self
+    }
+
+    /// Sets the avatar, given the key returned by
+    /// [Manager::upload_group_avatar](crate::Manager::upload_group_avatar), or removes it with an
+    /// empty key.
+    pub fn avatar(mut self, key: impl Into<String>) -> Self {
+        self.avatar = Some(key.into());
+        self
+    }
+
+    /// Sets the disappearing messages timer in seconds, 0 disables it.
+    pub fn disappearing_messages_timer(mut self, seconds: u32) -> Self {
+        self.disappearing_messages_timer = Some(seconds);
+        self
+    }
+
+    /// Who can change the title, description, avatar and timer of the group.
+    pub fn attributes_access(mut self, access: AccessRequired) -> Self {
+        self.attributes_access = Some(access);
+        self
+    }
+
+    /// Who can add members to the group.
+    pub fn members_access(mut self, access: AccessRequired) -> Self {
+        self.members_access = Some(access);
+        self
+    }
+
+    pub fn invite_link_access(mut self, access: InviteLinkAccess) -> Self {
+        self.invite_link_access = Some(access);
+        self
+    }
+
+    /// Generates a new invite link, the previous one won't work anymore.
+    pub fn reset_invite_link(mut self) -> Self {
+        self.reset_invite_link = true;
+        self
+    }
+
+    pub fn add_member(mut self, uuid: Uuid, role: Role) -> Self {
+        self.add_members.push((uuid, role));
+        self
+    }
+
+    pub fn remove_member(mut self, uuid: Uuid) -> Self {
+        self.remove_members.push(uuid);
+        self
+    }
+
+    /// Changes the role of a member, e.g. to promote them to administrator.
+    pub fn member_role(mut self, uuid: Uuid, role: Role) -> Self {
+        self.member_roles.push((uuid, role));
+        self
+    }
+
+    /// Approves a request to join the group made with the invite link.
+    pub fn approve_requesting_member(mut self, uuid: Uuid, role: Role) -> Self {
+        self.approve_requesting_members.push((uuid, role));
+        self
+    }
+
+    /// Denies a request to join the group made with the invite link.
+    pub fn deny_requesting_member(mut self, uuid: Uuid) -> Self {
+        self.deny_requesting_members.push(uuid);
+        self
+    }
+
+    pub(crate) fn members_to_add(&self) -> impl Iterator<Item = &Uuid> {
+        self.add_members.iter().map(|(uuid, _)| uuid)
+    }
+
+    /// Users to notify of the changes: the current members, including the ones being removed, the
+    /// new members and the users whose request to join is approved or denied.
+    pub(crate) fn recipients(&self, group: &DecryptedGroup) -> Vec<Uuid> {
+        let mut recipients: Vec<Uuid> = group
+            .members
+            .iter()
+            .filter_map(|member| Uuid::from_slice(&member.uuid).ok())
+            .collect();
+        let others = self
+            .add_members
+            .iter()
+            .chain(&self.approve_requesting_members)
+            .map(|(uuid, _)| uuid)
+            .chain(&self.remove_members)
+            .chain(&self.deny_requesting_members);
+        for uuid in others {
+            if !recipients.contains(uuid) {
+                recipients.push(*uuid);
+            }
+        }
+        recipients
+    }
+
+    /// Whether the changes need a new invite link password, given the current one.
+    pub(crate) fn needs_invite_link_password(&self, current_password: &[u8]) -> bool {
+        let enabled = matches!(
+            self.invite_link_access,
+            Some(InviteLinkAccess::Any) | Some(InviteLinkAccess::AdministratorApproval)
+        );
+        self.reset_invite_link || (enabled && current_password.is_empty())
+    }
+
+    /// Builds the encrypted actions moving the group to the given revision.
+    ///
+    /// `presentations` contains the profile key credential presentations of the members to add.
+    pub(crate) fn into_actions<R: Rng + CryptoRng>(
+        self,
+        secret_params: &GroupSecretParams,
+        csprng: &mut R,
+        presentations: &HashMap<Uuid, Vec<u8>>,
+        invite_link_password: Option<Vec<u8>>,
+        revision: u32,
+    ) -> Result<Actions, Error> {
+        let mut actions = Actions {
+            version: revision,
+            ..Default::default()
+        };
+
+        if let Some(title) = self.title {
+            actions.modify_title = Some(actions::ModifyTitleAction {
+                title: encrypt_blob(
+                    secret_params,
+                    csprng,
+                    group_attribute_blob::Content::Title(title),
+                )?,
+            });
+        }
+        if let Some(description) = self.description {
+            actions.modify_description = Some(actions::ModifyDescriptionAction {
+                description: encrypt_blob(
+                    secret_params,
+                    csprng,
+                    group_attribute_blob::Content::Description(description),
+                )?,
+            });
+        }
+        if let Some(avatar) = self.avatar {
+            actions.modify_avatar = Some(actions::ModifyAvatarAction { avatar });
+        }
+        if let Some(timer) = self.disappearing_messages_timer {
+            actions.modify_disappearing_messages_timer =
+                Some(actions::ModifyDisappearingMessagesTimerAction {
+                    timer: encrypt_blob(
+                        secret_params,
+                        csprng,
+                        group_attribute_blob::Content::DisappearingMessagesDuration(timer),
+                    )?,
+                });
+        }
+        if let Some(access) = self.attributes_access {
+            actions.modify_attributes_access = Some(actions::ModifyAttributesAccessControlAction {
+                attributes_access: access as i32,
+            });
+        }
+        if let Some(access) = self.members_access {
+            actions.modify_member_access = Some(actions::ModifyMembersAccessControlAction {
+                members_access: access as i32,
+            });
+        }
+        if let Some(access) = self.invite_link_access {
+            actions.modify_add_from_invite_link_access =
+                Some(actions::ModifyAddFromInviteLinkAccessControlAction {
+                    add_from_invite_link_access: AccessRequired::from(access) as i32,
+                });
+        }
+        if let Some(invite_link_password) = invite_link_password {
+            actions.modify_invite_link_password = Some(actions::ModifyInviteLinkPasswordAction {
+                invite_link_password,
+            });
+        }
+
+        for (uuid, role) in self.add_members {
+            let presentation = presentations.get(&uuid).cloned().ok_or_else(|| {
+                Error::GroupsV2Error(format!("no profile key credential for {}", uuid).into())
+            })?;
+            actions.add_members.push(actions::AddMemberAction {
+                added: Some(Member {
+                    role: role as i32,
+                    presentation,
+                    ..Default::default()
+                }),
+                join_from_invite_link: false,
+            });
+        }
+        for uuid in self.remove_members {
+            actions.delete_members.push(actions::DeleteMemberAction {
+                deleted_user_id: encrypt_uuid(secret_params, uuid)?,
+            });
+        }
+        for (uuid, role) in self.member_roles {
+            actions
+                .modify_member_roles
+                .push(actions::ModifyMemberRoleAction {
+                    user_id: encrypt_uuid(secret_params, uuid)?,
+                    role: role as i32,
+                });
+        }
+        for (uuid, role) in self.approve_requesting_members {
+            actions
+                .promote_requesting_members
+                .push(actions::PromoteRequestingMemberAction {
+                    user_id: encrypt_uuid(secret_params, uuid)?,
+                    role: role as i32,
+                });
+        }
+        for uuid in self.deny_requesting_members {
+            actions
+                .delete_requesting_members
+                .push(actions::DeleteRequestingMemberAction {
+                    deleted_user_id: encrypt_uuid(secret_params, uuid)?,
+                });
+        }
+
+        Ok(actions)
+    }
+}
+
+/// Builds the `https://signal.group/#...` link to join a group.
+pub(crate) fn invite_link(master_key: [u8; 32], invite_link_password: &[u8]) -> String {
+    let link = GroupInviteLink {
+        contents: Some(group_invite_link::Contents::V1Contents(
+            group_invite_link::GroupInviteLinkContentsV1 {
+                group_master_key: master_key.to_vec(),
+                invite_link_password: invite_link_password.to_vec(),
+            },
+        )),
+    };
+    format!(
+        "{}{}",
+        INVITE_LINK_PREFIX,
+        base64::encode_config(encode(&link), base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Parses a group invite link into the group master key and the invite link password.
+pub(crate) fn parse_invite_link(url: &str) -> Result<([u8; 32], Vec<u8>), Error> {
+    let invalid = || Error::GroupsV2Error("invalid group invite link".into());
+
+    let encoded = url.strip_prefix(INVITE_LINK_PREFIX).ok_or_else(invalid)?;
+    let link = GroupInviteLink::decode(
+        base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)?.as_slice(),
+    )
+    .map_err(|_| invalid())?;
+    match link.contents {
+        Some(group_invite_link::Contents::V1Contents(contents)) => Ok((
+            contents.group_master_key.as_slice().try_into()?,
+            contents.invite_link_password,
+        )),
+        None => Err(invalid()),
+    }
+}
+
+pub(crate) fn generate_invite_link_password<R: Rng + CryptoRng>(csprng: &mut R) -> Vec<u8> {
+    let mut password = vec![0u8; INVITE_LINK_PASSWORD_LEN];
+    csprng.fill_bytes(&mut password);
+    password
+}
+
+pub(crate) fn secret_params(master_key: [u8; 32]) -> GroupSecretParams {
+    GroupSecretParams::derive_from_master_key(GroupMasterKey::new(master_key))
+}
+
+pub(crate) fn master_key_bytes(master_key: &GroupMasterKey) -> Result<[u8; 32], Error> {
+    Ok(serialize(master_key)?.as_slice().try_into()?)
+}
+
+pub(crate) fn encrypt_blob<R: Rng + CryptoRng>(
+    secret_params: &GroupSecretParams,
+    csprng: &mut R,
+    content: group_attribute_blob::Content,
+) -> Result<Vec<u8>, Error> {
+    let blob = GroupAttributeBlob {
+        content: Some(content),
+    };
+    secret_params
+        .encrypt_blob(csprng.gen(), &encode(&blob))
+        .map_err(|_| Error::GroupsV2Error("failed to encrypt group attribute".into()))
+}
+
+pub(crate) fn decrypt_blob(
+    secret_params: &GroupSecretParams,
+    ciphertext: &[u8],
+) -> Result<Option<group_attribute_blob::Content>, Error> {
+    if ciphertext.is_empty() {
+        return Ok(None);
+    }
+    let plaintext = secret_params
+        .decrypt_blob(ciphertext)
+        .map_err(|_| Error::GroupsV2Error("failed to decrypt group attribute".into()))?;
+    let blob = GroupAttributeBlob::decode(plaintext.as_slice())
+        .map_err(|_| Error::GroupsV2Error("invalid group attribute".into()))?;
+    Ok(blob.content)
+}
+
+pub(crate) fn encrypt_uuid(
+    secret_params: &GroupSecretParams,
+    uuid: Uuid,
+) -> Result<Vec<u8>, Error> {
+    serialize(&secret_params.encrypt_uuid(*uuid.as_bytes()))
+}
+
+pub(crate) fn serialize(value: &impl Serialize) -> Result<Vec<u8>, Error> {
+    bincode::serialize(value).map_err(|e| Error::GroupsV2Error(e.to_string().into()))
+}
+
+fn deserialize<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, Error> {
+    bincode::deserialize(bytes).map_err(|e| Error::GroupsV2Error(e.to_string().into()))
+}
+
+fn encode(message: &impl ProtobufMessage) -> Vec<u8> {
+    let mut buf = Vec::new();
+    message
+        .encode(&mut buf)
+        .expect("encoding into a vector never fails");
+    buf
+}
+
+/// Fetches the profile key credential of a user, needed to add them to a group.
+pub(crate) async fn profile_key_credential<S: PushService, R: Rng + CryptoRng>(
+    push_service: &mut S,
+    server_public_params: &ServerPublicParams,
+    csprng: &mut R,
+    uuid: Uuid,
+    profile_key: [u8; 32],
+) -> Result<ProfileKeyCredential, Error> {
+    #[derive(Deserialize)]
+    struct VersionedProfile {
+        credential: Option<String>,
+    }
+
+    let profile_key = ProfileKey::create(profile_key);
+    let version = serialize(&profile_key.get_profile_key_version(*uuid.as_bytes()))?;
+    let request_context = server_public_params.create_profile_key_credential_request_context(
+        csprng.gen(),
+        *uuid.as_bytes(),
+        profile_key,
+    );
+    let request = serialize(&request_context.get_request())?;
+
+    let path = format!(
+        "/v1/profile/{}/{}/{}",
+        uuid,
+        String::from_utf8_lossy(&version),
+        hex::encode(request)
+    );
+    let profile: VersionedProfile = push_service
+        .get_json(Endpoint::Service, &path, HttpAuthOverride::NoOverride)
+        .await?;
+
+    let response: ProfileKeyCredentialResponse = deserialize(&base64::decode(
+        profile.credential.ok_or(ServiceError::NotFoundError)?,
+    )?)?;
+    server_public_params
+        .receive_profile_key_credential(&request_context, &response)
+        .map_err(|_| Error::GroupsV2Error("invalid profile key credential".into()))
+}
+
+/// Keeps the groups of the store up to date with the group contexts of received messages.
+pub(crate) struct GroupsUpdater<S, C> {
+    pub push_service: S,
+    pub server_public_params: ServerPublicParams,
+    pub uuid: Uuid,
+    /// Auth credentials, persisted so they are only requested once a week
+    pub credentials_cache: C,
+}
+
+impl<S, C> GroupsUpdater<S, C>
+where
+    S: PushService + Clone,
+    C: CredentialsCache,
+{
+    /// Brings the stored group to the revision of the context, applying the change it carries
+    /// when it directly follows the stored revision, or fetching the whole group otherwise.
+    ///
+    /// Changes come from other members, so they are only applied when signed by the server, or
+    /// anyone in the group could forge them.
+    pub async fn update<G: GroupsStore>(
+        &mut self,
+        store: &mut G,
+        context: &GroupContextV2,
+    ) -> Result<(), Error> {
+        let master_key: [u8; 32] = context.master_key().try_into()?;
+        let secret_params = secret_params(master_key);
+        let revision = context.revision();
+
+        match store.group(&master_key).await? {
+            Some(group) if group.revision >= revision => return Ok(()),
+            Some(mut group) if group.revision + 1 == revision && context.group_change.is_some() => {
+                match apply_group_change(
+                    &mut group,
+                    &secret_params,
+                    &self.server_public_params,
+                    context.group_change(),
+                ) {
+                    Ok(()) => return store.save_group(&master_key, &group).await,
+                    Err(e) => warn!("failed to apply group change, fetching group: {}", e),
+                }
+            }
+            _ => trace!("fetching group at revision {}", revision),
+        }
+
+        let group = self.fetch(secret_params).await?;
+        store.save_group(&master_key, &group).await
+    }
+
+    pub async fn fetch(
+        &mut self,
+        secret_params: GroupSecretParams,
+    ) -> Result<DecryptedGroup, Error> {
+        let mut groups_manager = GroupsManager::new(
+            self.push_service.clone(),
+            &mut self.credentials_cache,
+            self.server_public_params.clone(),
+        );
+        let authorization = groups_manager
+            .get_authorization_for_today(self.uuid, secret_params)
+            .await?;
+        Ok(groups_manager
+            .get_group(secret_params, authorization)
+            .await?)
+    }
+
+    /// Authorization to access a group, valid for today.
+    pub async fn authorization(
+        &mut self,
+        secret_params: GroupSecretParams,
+    ) -> Result<HttpAuth, Error> {
+        let mut groups_manager = GroupsManager::new(
+            self.push_service.clone(),
+            &mut self.credentials_cache,
+            self.server_public_params.clone(),
+        );
+        Ok(groups_manager
+            .get_authorization_for_today(self.uuid, secret_params)
+            .await?)
+    }
+}
+
+/// Applies a signed group change, as sent by the server, to the group at the previous revision.
+///
+/// The change is rejected unless its actions are signed by the server, and if some of them can't
+/// be applied, in which case the group has to be fetched instead.
+pub(crate) fn apply_group_change(
+    group: &mut DecryptedGroup,
+    secret_params: &GroupSecretParams,
+    server_public_params: &ServerPublicParams,
+    change: &[u8],
+) -> Result<(), Error> {
+    let invalid = || Error::GroupsV2Error("invalid group change".into());
+    let change = GroupChange::decode(change).map_err(|_| invalid())?;
+    let signature: NotarySignatureBytes = change
+        .server_signature
+        .as_slice()
+        .try_into()
+        .map_err(|_| Error::GroupsV2Error("group change not signed by the server".into()))?;
+    server_public_params
+        .verify_signature(&change.actions, signature)
+        .map_err(|_| Error::GroupsV2Error("invalid server signature of group change".into()))?;
+    let actions = Actions::decode(change.actions.as_slice()).map_err(|_| invalid())?;
+    if actions.version != group.revision + 1 {
+        return Err(Error::GroupsV2Error(
+            format!(
+                "group change to revision {} doesn't apply to revision {}",
+                actions.version, group.revision
+            )
+            .into(),
+        ));
+    }
+    if has_unhandled_actions(&actions, change.actions.len()) {
+        return Err(Error::GroupsV2Error(
+            "group change has actions which can't be applied".into(),
+        ));
+    }
+
+    if let Some(action) = actions.modify_title {
+        group.title = match decrypt_blob(secret_params, &action.title)? {
+            Some(group_attribute_blob::Content::Title(title)) => title,
+            _ => String::new(),
+        };
+    }
+    if let Some(action) = actions.modify_description {
+        group.description = match decrypt_blob(secret_params, &action.description)? {
+            Some(group_attribute_blob::Content::Description(description)) => description,
+            _ => String::new(),
+        };
+    }
+    if let Some(action) = actions.modify_avatar {
+        group.avatar = action.avatar;
+    }
+    if let Some(action) = actions.modify_disappearing_messages_timer {
+        let duration = match decrypt_blob(secret_params, &action.timer)? {
+            Some(group_attribute_blob::Content::DisappearingMessagesDuration(duration)) => duration,
+            _ => 0,
+        };
+        group.disappearing_messages_timer = Some(DecryptedTimer { duration });
+    }
+
+    let access_control = group.access_control.get_or_insert_with(Default::default);
+    if let Some(action) = actions.modify_attributes_access {
+        access_control.attributes = action.attributes_access;
+    }
+    if let Some(action) = actions.modify_member_access {
+        access_control.members = action.members_access;
+    }
+    if let Some(action) = actions.modify_add_from_invite_link_access {
+        access_control.add_from_invite_link = action.add_from_invite_link_access;
+    }
+    if let Some(action) = actions.modify_invite_link_password {
+        group.invite_link_password = action.invite_link_password;
+    }
+
+    for action in actions.add_members {
+        let member = action.added.ok_or_else(invalid)?;
+        let uuid = decrypt_uuid(secret_params, &member.user_id)?;
+        let profile_key = decrypt_profile_key(secret_params, &member.profile_key, &uuid)?;
+        group.requesting_members.retain(|m| m.uuid != uuid);
+        group.pending_members.retain(|m| m.uuid != uuid);
+        group.members.push(DecryptedMember {
+            uuid,
+            role: member.role,
+            profile_key,
+            joined_at_revision: actions.version,
+        });
+    }
+    for action in actions.delete_members {
+        let uuid = decrypt_uuid(secret_params, &action.deleted_user_id)?;
+        group.members.retain(|m| m.uuid != uuid);
+    }
+    for action in actions.modify_member_roles {
+        let uuid = decrypt_uuid(secret_params, &action.user_id)?;
+        if let Some(member) = group.members.iter_mut().find(|m| m.uuid == uuid) {
+            member.role = action.role;
+        }
+    }
+    for action in actions.modify_member_profile_keys {
+        let (uuid, profile_key) = decrypt_presentation(secret_params, &action.presentation)?;
+        if let Some(member) = group.members.iter_mut().find(|m| m.uuid == uuid) {
+            member.profile_key = profile_key;
+        }
+    }
+
+    for action in actions.add_pending_members {
+        let pending = action.added.ok_or_else(invalid)?;
+        let member = pending.member.ok_or_else(invalid)?;
+        group.pending_members.push(DecryptedPendingMember {
+            uuid: decrypt_uuid(secret_params, &member.user_id)?,
+            role: member.role,
+            added_by_uuid: decrypt_uuid(secret_params, &pending.added_by_user_id)?,
+            timestamp: pending.timestamp,
+            uuid_cipher_text: member.user_id,
+        });
+    }
+    for action in actions.delete_pending_members {
+        let uuid = decrypt_uuid(secret_params, &action.deleted_user_id)?;
+        group.pending_members.retain(|m| m.uuid != uuid);
+    }
+    for action in actions.promote_pending_members {
+        let (uuid, profile_key) = decrypt_presentation(secret_params, &action.presentation)?;
+        let role = group
+            .pending_members
+            .iter()
+            .find(|m| m.uuid == uuid)
+            .map_or(Role::Default as i32, |m| m.role);
+        group.pending_members.retain(|m| m.uuid != uuid);
+        group.members.push(DecryptedMember {
+            uuid,
+            role,
+            profile_key,
+            joined_at_revision: actions.version,
+        });
+    }
+
+    for action in actions.add_requesting_members {
+        let requesting = action.added.ok_or_else(invalid)?;
+        let uuid = decrypt_uuid(secret_params, &requesting.user_id)?;
+        let profile_key = decrypt_profile_key(secret_params, &requesting.profile_key, &uuid)?;
+        group.requesting_members.push(DecryptedRequestingMember {
+            uuid,
+            profile_key,
+            timestamp: requesting.timestamp,
+        });
+    }
+    for action in actions.delete_requesting_members {
+        let uuid = decrypt_uuid(secret_params, &action.deleted_user_id)?;
+        group.requesting_members.retain(|m| m.uuid != uuid);
+    }
+    for action in actions.promote_requesting_members {
+        let uuid = decrypt_uuid(secret_params, &action.user_id)?;
+        if let Some(index) = group.requesting_members.iter().position(|m| m.uuid == uuid) {
+            let requesting = group.requesting_members.remove(index);
+            group.members.push(DecryptedMember {
+                uuid,
+                role: action.role,
+                profile_key: requesting.profile_key,
+                joined_at_revision: actions.version,
+            });
+        }
+    }
+
+    group.revision = actions.version;
+    Ok(())
+}
+
+/// Whether a group change has actions [apply_group_change] doesn't know how to apply, including
+/// fields unknown to our protobuf definitions, which are dropped when decoding the actions.
+fn has_unhandled_actions(actions: &Actions, encoded_len: usize) -> bool {
+    let mut unhandled = actions.clone();
+    unhandled.source_uuid.clear();
+    unhandled.version = 0;
+    unhandled.modify_title = None;
+    unhandled.modify_description = None;
+    unhandled.modify_avatar = None;
+    unhandled.modify_disappearing_messages_timer = None;
+    unhandled.modify_attributes_access = None;
+    unhandled.modify_member_access = None;
+    unhandled.modify_add_from_invite_link_access = None;
+    unhandled.modify_invite_link_password = None;
+    unhandled.add_members.clear();
+    unhandled.delete_members.clear();
+    unhandled.modify_member_roles.clear();
+    unhandled.modify_member_profile_keys.clear();
+    unhandled.add_pending_members.clear();
+    unhandled.delete_pending_members.clear();
+    unhandled.promote_pending_members.clear();
+    unhandled.add_requesting_members.clear();
+    unhandled.delete_requesting_members.clear();
+    unhandled.promote_requesting_members.clear();
+    unhandled != Actions::default() || actions.encoded_len() != encoded_len
+}
+
+fn decrypt_uuid(secret_params: &GroupSecretParams, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+    let uuid = secret_params
+        .decrypt_uuid(deserialize(ciphertext)?)
+        .map_err(|_| Error::GroupsV2Error("failed to decrypt member uuid".into()))?;
+    Ok(uuid.to_vec())
+}
+
+fn decrypt_profile_key(
+    secret_params: &GroupSecretParams,
+    ciphertext: &[u8],
+    uuid: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let profile_key = secret_params
+        .decrypt_profile_key(deserialize(ciphertext)?, uuid.try_into()?)
+        .map_err(|_| Error::GroupsV2Error("failed to decrypt member profile key".into()))?;
+    Ok(profile_key.get_bytes().to_vec())
+}
+
+/// Decrypts the UUID and profile key of a member from their profile key credential presentation.
+fn decrypt_presentation(
+    secret_params: &GroupSecretParams,
+    presentation: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let presentation: ProfileKeyCredentialPresentation = deserialize(presentation)?;
+    let uuid = decrypt_uuid(
+        secret_params,
+        &serialize(&presentation.get_uuid_ciphertext())?,
+    )?;
+    let profile_key = decrypt_profile_key(
+        secret_params,
+        &serialize(&presentation.get_profile_key_ciphertext())?,
+        &uuid,
+    )?;
+    Ok((uuid, profile_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use zkgroup::ServerSecretParams;
+
+    use super::*;
+
+    #[test]
+    fn test_recipients() {
+        let uuid = |n| Uuid::from_bytes([n; 16]);
+        let group = DecryptedGroup {
+            members: [1, 2]
+                .iter()
+                .map(|n| DecryptedMember {
+                    uuid: uuid(*n).as_bytes().to_vec(),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        };
+        let changes = GroupChanges::default()
+            .remove_member(uuid(2))
+            .add_member(uuid(3), Role::Default)
+            .approve_requesting_member(uuid(4), Role::Default)
+            .deny_requesting_member(uuid(5));
+        assert_eq!(
+            changes.recipients(&group),
+            (1..=5).map(uuid).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_invite_link() {
+        let master_key = [42u8; 32];
+        let password = generate_invite_link_password(&mut rand::thread_rng());
+        let link = invite_link(master_key, &password);
+        assert!(link.starts_with("https://signal.group/#"));
+        assert_eq!(parse_invite_link(&link).unwrap(), (master_key, password));
+
+        assert!(parse_invite_link("https://signal.group/#AAAA").is_err());
+        assert!(parse_invite_link("https://example.com/").is_err());
+    }
+
+    #[test]
+    fn test_actions() {
+        let mut csprng = rand::thread_rng();
+        let secret_params = secret_params(csprng.gen());
+        let member = Uuid::from_bytes(csprng.gen());
+
+        let actions = GroupChanges::default()
+            .title("Presage")
+            .remove_member(member)
+            .into_actions(&secret_params, &mut csprng, &HashMap::new(), None, 3)
+            .unwrap();
+        assert_eq!(actions.version, 3);
+
+        let title = actions.modify_title.unwrap().title;
+        assert_eq!(
+            decrypt_blob(&secret_params, &title).unwrap(),
+            Some(group_attribute_blob::Content::Title("Presage".into()))
+        );
+        let deleted = deserialize(&actions.delete_members[0].deleted_user_id).unwrap();
+        assert_eq!(
+            secret_params.decrypt_uuid(deleted).unwrap(),
+            *member.as_bytes()
+        );
+
+        // members can't be added without their profile key credential
+        assert!(GroupChanges::default()
+            .add_member(member, Role::Default)
+            .into_actions(&secret_params, &mut csprng, &HashMap::new(), None, 4)
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply_group_change() {
+        let mut csprng = rand::thread_rng();
+        let secret_params = secret_params(csprng.gen());
+        let server_secret_params = ServerSecretParams::generate(csprng.gen());
+        let server_public_params = server_secret_params.get_public_params();
+        let member = Uuid::from_bytes(csprng.gen());
+
+        let mut group = DecryptedGroup {
+            revision: 2,
+            members: vec![DecryptedMember {
+                uuid: member.as_bytes().to_vec(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let change = |revision| {
+            let actions = GroupChanges::default()
+                .title("Presage")
+                .disappearing_messages_timer(3600)
+                .remove_member(member)
+                .into_actions(
+                    &secret_params,
+                    &mut rand::thread_rng(),
+                    &HashMap::new(),
+                    None,
+                    revision,
+                )
+                .unwrap();
+            let actions = encode(&actions);
+            GroupChange {
+                server_signature: server_secret_params
+                    .sign(rand::thread_rng().gen(), &actions)
+                    .to_vec(),
+                actions,
+                ..Default::default()
+            }
+        };
+        let apply = |group: &mut DecryptedGroup, change: &GroupChange| {
+            apply_group_change(
+                group,
+                &secret_params,
+                &server_public_params,
+                &encode(change),
+            )
+        };
+
+        // changes only apply to the previous revision
+        assert!(apply(&mut group, &change(4)).is_err());
+
+        // and must be signed by the server, not by some member of the group
+        let mut forged = change(3);
+        forged.server_signature = ServerSecretParams::generate(csprng.gen())
+            .sign(csprng.gen(), &forged.actions)
+            .to_vec();
+        assert!(apply(&mut group, &forged).is_err());
+        forged.server_signature.clear();
+        assert!(apply(&mut group, &forged).is_err());
+        assert_eq!(group.revision, 2);
+
+        // actions unknown to us, here field 100, are only applied by fetching the group
+        let mut unknown = change(3);
+        unknown.actions.extend_from_slice(&[0xa0, 0x06, 1]);
+        unknown.server_signature = server_secret_params
+            .sign(csprng.gen(), &unknown.actions)
+            .to_vec();
+        assert!(apply(&mut group, &unknown).is_err());
+        assert_eq!(group.revision, 2);
+
+        apply(&mut group, &change(3)).unwrap();
+        assert_eq!(group.revision, 3);
+        assert_eq!(group.title, "Presage");
+        assert_eq!(group.disappearing_messages_timer.unwrap().duration, 3600);
+        assert!(group.members.is_empty());
+    }
+}
@@ -1,30 +1,62 @@
+mod account;
 mod cache;
 mod config;
+mod device_name;
 mod errors;
+mod event;
+mod executor;
+#[cfg(feature = "fake-server")]
+pub mod fake_server;
+mod groups;
 mod manager;
+mod pre_keys;
+mod profile;
+mod provisioning;
+mod push_service;
+mod reconnect;
+mod safety_number;
 
 #[cfg(feature = "sled-store")]
-pub use config::sled::SledConfigStore;
+pub use config::sled::{Migration, MigrationOptions, SledConfigStore, SCHEMA_VERSION};
+#[cfg(feature = "sqlite-store")]
+pub use config::sqlite::SqliteConfigStore;
 
-pub use config::ConfigStore;
+pub use account::{AccountAttributes, AccountChanges, Capabilities};
+pub use config::memory::MemoryConfigStore;
+pub use config::{
+    ConfigStore, ContactsStore, GroupsStore, IdentityChange, MessageStore, ProfilesStore,
+    StoredProfile, Thread, TrustLevel, TrustStore,
+};
 pub use errors::Error;
+pub use event::Event;
+pub use groups::{GroupChanges, InviteLinkAccess};
 pub use manager::{Manager, State};
+pub use pre_keys::PreKeysPolicy;
+pub use profile::ProfileDetails;
+pub use provisioning::{ProvisioningStrategy, ProvisioningUrl};
+pub use push_service::{HyperPushServiceFactory, PushServiceFactory};
+pub use reconnect::{ConnectionState, Received, ReconnectPolicy};
+pub use safety_number::SafetyNumber;
 
 #[deprecated(note = "Please help use improve the prelude module instead")]
 pub use libsignal_service;
 
 pub mod prelude {
     pub use libsignal_service::{
-        configuration::SignalServers,
+        configuration::{ServiceConfiguration, SignalServers},
         content::{
             self, Content, ContentBody, DataMessage, GroupContext, GroupContextV2, GroupType,
             Metadata, SyncMessage,
         },
+        messagepipe::ServiceCredentials,
         prelude::{
             phonenumber::{self, PhoneNumber},
             GroupMasterKey, GroupSecretParams, Uuid,
         },
-        proto, ServiceAddress,
+        proto,
+        push_service::{PushService, ServiceError},
+        sender::AttachmentSpec,
+        ServiceAddress,
     };
 }
 
@@ -1,67 +1,123 @@
-use std::{convert::TryInto, time::UNIX_EPOCH};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    ops::RangeBounds,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, UNIX_EPOCH},
+};
 
-use futures::{channel::mpsc, future, AsyncReadExt, Stream, StreamExt};
-use image::Luma;
-use log::{error, trace, warn};
-use qrcode::QrCode;
-use rand::{distributions::Alphanumeric, CryptoRng, Rng, RngCore};
+use futures::{
+    channel::mpsc,
+    future::{self, LocalBoxFuture},
+    stream::LocalBoxStream,
+    AsyncRead, AsyncReadExt, Stream, StreamExt,
+};
+use log::{error, info, trace, warn};
+use rand::{distributions::Alphanumeric, rngs::StdRng, CryptoRng, Rng, RngCore, SeedableRng};
 use serde::{Deserialize, Serialize};
 
 use libsignal_service::{
     attachment_cipher::decrypt_in_place,
     cipher,
-    configuration::{ServiceConfiguration, SignalServers, SignalingKey},
-    content::{ContentBody, DataMessage},
-    groups_v2::{GroupsManager, InMemoryCredentialsCache},
+    configuration::{Endpoint, SignalServers, SignalingKey},
+    content::{ContentBody, DataMessage, GroupContextV2, Metadata},
     messagepipe::ServiceCredentials,
     models::Contact,
     prelude::{
         phonenumber::PhoneNumber,
-        protocol::{KeyPair, PrivateKey, PublicKey},
-        Content, Envelope, GroupMasterKey, GroupSecretParams, PushService, Uuid,
+        protocol::{IdentityKey, KeyPair, PrivateKey, ProtocolAddress, PublicKey},
+        Content, Envelope, GroupMasterKey, GroupSecretParams, ProtobufMessage, PushService, Uuid,
+    },
+    proto::{
+        self, access_control::AccessRequired, group_attribute_blob, group_change, member::Role,
+        sync_message, verified, AttachmentPointer, DecryptedGroup, SyncMessage,
     },
-    proto::{sync_message, AttachmentPointer, SyncMessage},
     provisioning::{
         generate_registration_id, ConfirmCodeMessage, LinkingManager, ProvisioningManager,
         SecondaryDeviceProvisioning, VerificationCodeResponse,
     },
     push_service::{
-        DeviceCapabilities, ProfileKey, ServiceError, WhoAmIResponse, DEFAULT_DEVICE_ID,
+        HttpAuth, HttpAuthOverride, ProfileKey, ServiceError, WhoAmIResponse, DEFAULT_DEVICE_ID,
     },
     receiver::MessageReceiver,
+    sender::AttachmentSpec,
     utils::{serde_private_key, serde_public_key, serde_signaling_key},
     AccountManager, Profile, ServiceAddress,
 };
 
-use libsignal_service_hyper::push_service::HyperPushService;
+use zkgroup::ServerPublicParams;
 
 use crate::cache::CacheCell;
-use crate::{config::ConfigStore, Error};
+use crate::push_service::{HyperPushServiceFactory, PushServiceFactory};
+use crate::{
+    account::{AccountAttributes, AccountChanges},
+    config::{
+        ConfigStore, ContactsStore, GroupsStore, MessageStore, ProfilesStore, StoredProfile,
+        Thread, TrustLevel, TrustStore,
+    },
+    device_name::encrypt_device_name,
+    executor::{Forwarded, LocalExecutor},
+    groups::{self, GroupChanges, GroupsUpdater},
+    pre_keys::{self, PreKeysMaintenance, PreKeysPolicy},
+    profile::{self, ProfileDetails},
+    provisioning::{ProvisioningStrategy, ProvisioningUrl},
+    reconnect::{ConnectionState, Received, ReconnectPolicy},
+    Error, Event, SafetyNumber,
+};
 
 type ServiceCipher<C, R> = cipher::ServiceCipher<C, C, C, C, R>;
-type MessageSender<C, R> =
-    libsignal_service::prelude::MessageSender<HyperPushService, C, C, C, C, R>;
-
+type MessageSender<S, C, R> = libsignal_service::prelude::MessageSender<S, C, C, C, C, R>;
+
+/// Contacts rarely change their profile, see [Manager::set_profile_ttl]
+const DEFAULT_PROFILE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Client of a Signal account.
+///
+/// The manager is `Send + Sync`, and so are the futures of its methods: it can be shared, e.g. in
+/// an `Arc`, to receive messages in a task of a multi-threaded runtime while sending from others.
+///
+/// The futures of the protocol stores and of the push service are not `Send` though, so the methods
+/// using them run on a clone of the manager, on a thread the manager and its clones share. Clones
+/// share the store and the random generator, but not the cache.
 #[derive(Clone)]
-pub struct Manager<C, R = rand::rngs::ThreadRng> {
+pub struct Manager<C, R = rand::rngs::OsRng, P = HyperPushServiceFactory>
+where
+    P: PushServiceFactory,
+{
     /// Persistent store
     config_store: C,
-    /// Random generator
-    csprng: R,
+    /// Random generator, shared with the clones so that they never draw the same randomness
+    csprng: Arc<Mutex<R>>,
+    /// Creates the push services used to talk to Signal servers
+    push_service_factory: P,
+    /// When to upload new pre-keys and rotate the signed pre-key
+    pre_keys_policy: PreKeysPolicy,
+    /// How long cached profiles of contacts are used before being fetched again
+    profile_ttl: Duration,
     /// Part of the manager which is persisted in the store.
     state: State,
     /// Part of the manager which is cached.
     ///
     /// The cache should be cleared when state changes.
-    cache: Cache,
+    cache: Cache<P::PushService>,
+    /// Runs the futures which are not `Send`
+    executor: LocalExecutor,
 }
 
-#[derive(Clone, Default)]
-struct Cache {
-    push_service: CacheCell<HyperPushService>,
+#[derive(Clone)]
+struct Cache<S: Clone> {
+    push_service: CacheCell<S>,
+}
+
+impl<S: Clone> Default for Cache<S> {
+    fn default() -> Self {
+        Self {
+            push_service: Default::default(),
+        }
+    }
 }
 
-impl Cache {
+impl<S: Clone> Cache<S> {
     fn clear(&self) {
         self.push_service.clear();
     }
@@ -101,41 +157,142 @@ pub enum State {
         #[serde(with = "serde_public_key")]
         public_key: PublicKey,
         profile_key: ProfileKey,
+        #[serde(default)]
+        account_attributes: AccountAttributes,
     },
 }
 
 impl<C> Manager<C>
 where
-    C: ConfigStore,
+    C: ConfigStore + 'static,
 {
     /// Creates a new manager from a store with a default random generator.
-    pub fn with_store(store: C) -> Result<Self, Error> {
-        Self::new(store, rand::thread_rng())
+    pub async fn with_store(store: C) -> Result<Self, Error> {
+        Self::new(store, rand::rngs::OsRng).await
     }
 }
 
 impl<C, R> Manager<C, R>
 where
-    C: ConfigStore,
-    R: Rng + CryptoRng + Clone,
+    C: ConfigStore + 'static,
+    R: Rng + CryptoRng + Send + 'static,
 {
-    pub fn new(config_store: C, csprng: R) -> Result<Self, Error> {
-        let state = config_store.state()?;
+    /// Creates a new manager talking to Signal servers over hyper.
+    pub async fn new(config_store: C, csprng: R) -> Result<Self, Error> {
+        Self::with_push_service_factory(config_store, csprng, HyperPushServiceFactory).await
+    }
+}
+
+impl<C, R, P> Manager<C, R, P>
+where
+    C: ConfigStore + 'static,
+    R: Rng + CryptoRng + Send + 'static,
+    P: PushServiceFactory,
+{
+    /// Creates a new manager using push services created by `push_service_factory`.
+    pub async fn with_push_service_factory(
+        config_store: C,
+        csprng: R,
+        push_service_factory: P,
+    ) -> Result<Self, Error> {
+        let state = config_store.state().await?;
         Ok(Manager {
             config_store,
-            csprng,
+            csprng: Arc::new(Mutex::new(csprng)),
+            push_service_factory,
+            pre_keys_policy: Default::default(),
+            profile_ttl: DEFAULT_PROFILE_TTL,
             state,
             cache: Default::default(),
+            executor: LocalExecutor::new()?,
         })
     }
 
+    /// Changes when pre-keys are uploaded and the signed pre-key rotated, see [Manager::maintain_pre_keys].
+    pub fn set_pre_keys_policy(&mut self, policy: PreKeysPolicy) {
+        self.pre_keys_policy = policy;
+    }
+
+    /// Changes how long cached profiles are used before being fetched again, see [Manager::profile].
+    pub fn set_profile_ttl(&mut self, ttl: Duration) {
+        self.profile_ttl = ttl;
+    }
+
+    /// Returns a random generator seeded from ours, for operations and long-lived helpers which
+    /// need one of their own.
+    ///
+    /// Ours moves on, so that even a deterministic generator never gives the same randomness to two
+    /// operations.
+    fn seeded_rng(&self) -> StdRng {
+        // drawing a seed can't leave the generator broken
+        let mut csprng = self.csprng.lock().unwrap_or_else(PoisonError::into_inner);
+        StdRng::from_seed(csprng.gen())
+    }
+
     /// Sets the state and saves it into the store.
     ///
     /// The cache is also cleared.
-    fn set_state(&mut self, state: State) -> Result<(), Error> {
+    async fn set_state(&mut self, state: State) -> Result<(), Error> {
         self.state = state;
         self.cache.clear();
-        self.config_store.save(&self.state)
+        self.config_store.save(&self.state).await
+    }
+
+    /// Runs a job which isn't `Send` on a clone of the manager, on the thread of the executor.
+    async fn run<F, T>(&self, job: F) -> Result<T, Error>
+    where
+        F: for<'a> FnOnce(&'a Self) -> LocalBoxFuture<'a, Result<T, Error>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let manager = self.clone();
+        self.executor
+            .run(move || async move { job(&manager).await })
+            .await
+    }
+
+    /// Same as [Manager::run], keeping the changes the job makes to the manager, e.g. to its state.
+    async fn run_mut<F, T>(&mut self, job: F) -> Result<T, Error>
+    where
+        F: for<'a> FnOnce(&'a mut Self) -> LocalBoxFuture<'a, Result<T, Error>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let mut manager = self.clone();
+        let (manager, output) = self
+            .executor
+            .run(move || async move {
+                let output = job(&mut manager).await;
+                (manager, output)
+            })
+            .await;
+        *self = manager;
+        output
+    }
+
+    /// Opens a stream which isn't `Send` on the thread of the executor, see [Manager::run].
+    async fn run_stream<F, T>(&self, open: F) -> Result<Forwarded<T>, Error>
+    where
+        F: for<'a> FnOnce(
+                &'a Self,
+            )
+                -> LocalBoxFuture<'a, Result<LocalBoxStream<'static, T>, Error>>
+            + Send
+            + 'static,
+        T: Send + 'static,
+    {
+        let manager = self.clone();
+        self.executor
+            .run_stream(move || async move { open(&manager).await })
+            .await
+    }
+
+    /// Deletes the account and its key material from the store, to register or link again.
+    ///
+    /// Messages and groups are kept, see [ConfigStore::reset].
+    pub async fn reset_account(&mut self) -> Result<(), Error> {
+        self.config_store.reset().await?;
+        self.state = State::New;
+        self.cache.clear();
+        Ok(())
     }
 
     fn credentials(&self) -> Result<Option<ServiceCredentials>, Error> {
@@ -194,6 +351,14 @@ where
         }
     }
 
+    /// Returns our profile key, which contacts need to see our profile.
+    pub fn profile_key(&self) -> Option<[u8; 32]> {
+        match &self.state {
+            State::Registered { profile_key, .. } => Some(**profile_key),
+            _ => None,
+        }
+    }
+
     #[cfg(feature = "quirks")]
     pub fn dump_config(&mut self) -> Result<(), Error> {
         serde_json::to_writer_pretty(std::io::stderr(), &self.state)?;
@@ -208,321 +373,629 @@ where
         captcha: Option<String>,
         force: bool,
     ) -> Result<(), Error> {
-        // generate a random 24 bytes password
-        let rng = rand::rngs::OsRng::default();
-        let password: String = rng.sample_iter(&Alphanumeric).take(24).collect();
-
-        if !force
-            && matches!(
-                self.state,
-                State::Registration { .. } | State::Registered { .. }
-            )
-        {
-            return Err(Error::AlreadyRegisteredError);
-        }
-
-        // re-initialize the state to new with specified servers & phone number
-        self.set_state(State::Registration {
-            signal_servers,
-            phone_number: phone_number.clone(),
-            use_voice_call,
-            captcha: captcha.clone(),
-        })?;
-
-        let mut push_service = self.push_service()?;
-        let mut provisioning_manager: ProvisioningManager<HyperPushService> =
-            ProvisioningManager::new(&mut push_service, phone_number.clone(), password.clone());
-
-        let verification_code_response = if use_voice_call {
-            provisioning_manager
-                .request_voice_verification_code(captcha.as_deref(), None)
-                .await?
-        } else {
-            provisioning_manager
-                .request_sms_verification_code(captcha.as_deref(), None)
-                .await?
-        };
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                // generate a random 24 bytes password
+                let rng = rand::rngs::OsRng::default();
+                let password: String = rng.sample_iter(&Alphanumeric).take(24).collect();
+
+                if !force
+                    && matches!(
+                        manager.state,
+                        State::Registration { .. } | State::Registered { .. }
+                    )
+                {
+                    return Err(Error::AlreadyRegisteredError);
+                }
 
-        if let VerificationCodeResponse::CaptchaRequired = verification_code_response {
-            return Err(Error::CaptchaRequired);
-        }
+                // a new identity is generated, previous key material becomes useless
+                manager.reset_account().await?;
+                manager
+                    .set_state(State::Registration {
+                        signal_servers,
+                        phone_number: phone_number.clone(),
+                        use_voice_call,
+                        captcha: captcha.clone(),
+                    })
+                    .await?;
+
+                let mut push_service = manager.push_service()?;
+                let mut provisioning_manager: ProvisioningManager<P::PushService> =
+                    ProvisioningManager::new(
+                        &mut push_service,
+                        phone_number.clone(),
+                        password.clone(),
+                    );
+
+                let verification_code_response = if use_voice_call {
+                    provisioning_manager
+                        .request_voice_verification_code(captcha.as_deref(), None)
+                        .await?
+                } else {
+                    provisioning_manager
+                        .request_sms_verification_code(captcha.as_deref(), None)
+                        .await?
+                };
+
+                if let VerificationCodeResponse::CaptchaRequired = verification_code_response {
+                    return Err(Error::CaptchaRequired);
+                }
 
-        self.set_state(State::Confirmation {
-            signal_servers,
-            phone_number,
-            password,
+                manager
+                    .set_state(State::Confirmation {
+                        signal_servers,
+                        phone_number,
+                        password,
+                    })
+                    .await
+            })
         })
+        .await
     }
 
     pub async fn confirm_verification_code(&mut self, confirm_code: u32) -> Result<(), Error> {
-        trace!("confirming verification code");
-        let (signal_servers, phone_number, password) = match &self.state {
-            State::Confirmation {
-                signal_servers,
-                phone_number,
-                password,
-            } => (*signal_servers, phone_number, password),
-            State::Registered { .. } => return Err(Error::AlreadyRegisteredError),
-            _ => return Err(Error::NotYetRegisteredError),
-        };
-
-        // see libsignal-protocol-c / signal_protocol_key_helper_generate_registration_id
-        let registration_id = generate_registration_id(&mut self.csprng);
-        trace!("registration_id: {}", registration_id);
-
-        // let mut push_service = HyperPushService::new(
-        //     (*signal_servers).into(),
-        //     Some(ServiceCredentials {
-        //         phonenumber: phone_number.clone(),
-        //         password: Some(password.clone()),
-        //         uuid: None,
-        //         signaling_key: None,
-        //         device_id: None,
-        //     }),
-        //     USER_AGENT,
-        // );
-        let mut push_service = self.push_service()?;
-        let mut provisioning_manager: ProvisioningManager<HyperPushService> =
-            ProvisioningManager::new(
-                &mut push_service,
-                phone_number.clone(),
-                password.to_string(),
-            );
-
-        let mut rng = rand::rngs::OsRng::default();
-        // generate a 52 bytes signaling key
-        let mut signaling_key = [0u8; 52];
-        rng.fill_bytes(&mut signaling_key);
-
-        let mut profile_key = [0u8; 32];
-        rng.fill_bytes(&mut profile_key);
-        let profile_key = ProfileKey(profile_key);
-
-        let registered = provisioning_manager
-            .confirm_verification_code(
-                confirm_code,
-                ConfirmCodeMessage::new(
-                    signaling_key.to_vec(),
-                    registration_id,
-                    profile_key.derive_access_key(),
-                ),
-            )
-            .await?;
-
-        let identity_key_pair = KeyPair::generate(&mut self.csprng);
-
-        let phone_number = phone_number.clone();
-        let password = password.clone();
-        self.set_state(State::Registered {
-            signal_servers,
-            phone_number,
-            uuid: registered.uuid,
-            password,
-            signaling_key,
-            device_id: None,
-            registration_id,
-            private_key: identity_key_pair.private_key,
-            public_key: identity_key_pair.public_key,
-            profile_key,
-        })?;
-
-        trace!("confirmed! (and registered)");
-
-        self.register_pre_keys().await?;
-        self.set_account_attributes().await?;
-
-        Ok(())
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                trace!("confirming verification code");
+                let (signal_servers, phone_number, password) = match &manager.state {
+                    State::Confirmation {
+                        signal_servers,
+                        phone_number,
+                        password,
+                    } => (*signal_servers, phone_number, password),
+                    State::Registered { .. } => return Err(Error::AlreadyRegisteredError),
+                    _ => return Err(Error::NotYetRegisteredError),
+                };
+
+                // see libsignal-protocol-c / signal_protocol_key_helper_generate_registration_id
+                let mut csprng = manager.seeded_rng();
+                let registration_id = generate_registration_id(&mut csprng);
+                trace!("registration_id: {}", registration_id);
+
+                let mut push_service = manager.push_service()?;
+                let mut provisioning_manager: ProvisioningManager<P::PushService> =
+                    ProvisioningManager::new(
+                        &mut push_service,
+                        phone_number.clone(),
+                        password.to_string(),
+                    );
+
+                let mut rng = rand::rngs::OsRng::default();
+                // generate a 52 bytes signaling key
+                let mut signaling_key = [0u8; 52];
+                rng.fill_bytes(&mut signaling_key);
+
+                let mut profile_key = [0u8; 32];
+                rng.fill_bytes(&mut profile_key);
+                let profile_key = ProfileKey(profile_key);
+
+                let registered = provisioning_manager
+                    .confirm_verification_code(
+                        confirm_code,
+                        ConfirmCodeMessage::new(
+                            signaling_key.to_vec(),
+                            registration_id,
+                            profile_key.derive_access_key(),
+                        ),
+                    )
+                    .await?;
+
+                let identity_key_pair = KeyPair::generate(&mut csprng);
+
+                let phone_number = phone_number.clone();
+                let password = password.clone();
+                manager
+                    .set_state(State::Registered {
+                        signal_servers,
+                        phone_number,
+                        uuid: registered.uuid,
+                        password,
+                        signaling_key,
+                        device_id: None,
+                        registration_id,
+                        private_key: identity_key_pair.private_key,
+                        public_key: identity_key_pair.public_key,
+                        profile_key,
+                        account_attributes: Default::default(),
+                    })
+                    .await?;
+
+                trace!("confirmed! (and registered)");
+
+                manager.maintain_pre_keys().await?;
+                manager.update_account(Default::default()).await?;
+
+                Ok(())
+            })
+        })
+        .await
     }
 
+    /// Links this client as a secondary device of an existing account.
+    ///
+    /// The primary device has to scan the provisioning URL as a QR code, which is handed to the
+    /// given [ProvisioningStrategy].
     pub async fn link_secondary_device(
         &mut self,
         signal_servers: SignalServers,
         device_name: String,
+        mut strategy: ProvisioningStrategy,
     ) -> Result<(), Error> {
-        // generate a random 24 bytes password
-        let mut rng = rand::rngs::OsRng::default();
-        let password: String = rng.sample_iter(&Alphanumeric).take(24).collect();
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                // generate a random 24 bytes password
+                let mut rng = rand::rngs::OsRng::default();
+                let password: String = rng.sample_iter(&Alphanumeric).take(24).collect();
+
+                // generate a 52 bytes signaling key
+                let mut signaling_key = [0u8; 52];
+                rng.fill_bytes(&mut signaling_key);
+
+                manager.reset_account().await?;
+                manager
+                    .set_state(State::Linking {
+                        signal_servers,
+                        password: password.clone(),
+                        signaling_key,
+                    })
+                    .await?;
+
+                let push_service = manager.push_service()?;
+                let mut linking_manager: LinkingManager<P::PushService> =
+                    LinkingManager::new(push_service, password.clone());
+
+                let (tx, mut rx) = mpsc::channel(1);
+
+                let (fut1, fut2) = future::join(
+                    linking_manager.provision_secondary_device(
+                        &mut manager.seeded_rng(),
+                        signaling_key,
+                        &device_name,
+                        tx,
+                    ),
+                    async move {
+                        while let Some(provisioning_step) = rx.next().await {
+                            match provisioning_step {
+                                SecondaryDeviceProvisioning::Url(url) => {
+                                    strategy.provisioning_url(ProvisioningUrl::new(url))?;
+                                }
+                                SecondaryDeviceProvisioning::NewDeviceRegistration {
+                                    phone_number,
+                                    device_id,
+                                    registration_id,
+                                    uuid,
+                                    private_key,
+                                    public_key,
+                                    profile_key,
+                                } => {
+                                    log::info!("successfully registered device {}", &uuid);
+                                    return Ok((
+                                        phone_number,
+                                        device_id.device_id,
+                                        registration_id,
+                                        uuid,
+                                        private_key,
+                                        public_key,
+                                        profile_key,
+                                    ));
+                                }
+                            }
+                        }
+                        Err(Error::NoProvisioningMessageReceived)
+                    },
+                )
+                .await;
+
+                let _ = fut1?;
+                let (
+                    phone_number,
+                    device_id,
+                    registration_id,
+                    uuid,
+                    private_key,
+                    public_key,
+                    profile_key,
+                ) = fut2?;
+
+                manager
+                    .set_state(State::Registered {
+                        signal_servers,
+                        phone_number,
+                        uuid,
+                        signaling_key,
+                        password,
+                        device_id: Some(device_id),
+                        registration_id,
+                        public_key,
+                        private_key,
+                        profile_key: ProfileKey(
+                            profile_key.try_into().expect("32 bytes for profile key"),
+                        ),
+                        account_attributes: Default::default(),
+                    })
+                    .await?;
+
+                manager.maintain_pre_keys().await?;
+                manager.update_account(Default::default()).await?;
+                manager.request_contacts_sync().await?;
+
+                Ok(())
+            })
+        })
+        .await
+    }
 
-        // generate a 52 bytes signaling key
-        let mut signaling_key = [0u8; 52];
-        rng.fill_bytes(&mut signaling_key);
+    pub async fn whoami(&self) -> Result<WhoAmIResponse, Error> {
+        self.run(move |manager| {
+            Box::pin(async move { Ok(manager.push_service()?.whoami().await?) })
+        })
+        .await
+    }
 
-        self.set_state(State::Linking {
-            signal_servers,
-            password: password.clone(),
-            signaling_key,
-        })?;
+    pub async fn retrieve_profile(&self) -> Result<Profile, Error> {
+        self.run(move |manager| {
+            Box::pin(async move {
+                match &manager.state {
+                    State::Registered {
+                        uuid, profile_key, ..
+                    } => manager.retrieve_profile_by_uuid(*uuid, **profile_key).await,
+                    _ => return Err(Error::NotYetRegisteredError),
+                }
+            })
+        })
+        .await
+    }
 
-        let push_service = self.push_service()?;
-        let mut linking_manager: LinkingManager<HyperPushService> =
-            LinkingManager::new(push_service, password.clone());
+    pub async fn retrieve_profile_by_uuid(
+        &self,
+        uuid: Uuid,
+        profile_key: [u8; 32],
+    ) -> Result<Profile, Error> {
+        self.run(move |manager| {
+            Box::pin(async move {
+                let mut account_manager =
+                    AccountManager::new(manager.push_service()?, Some(profile_key));
+                Ok(account_manager.retrieve_profile(uuid).await?)
+            })
+        })
+        .await
+    }
 
-        let (tx, mut rx) = mpsc::channel(1);
+    /// Fetches and decrypts our own profile, avatar included.
+    pub async fn own_profile(&self) -> Result<ProfileDetails, Error> {
+        self.run(move |manager| {
+            Box::pin(async move {
+                let (uuid, profile_key) = manager.own_profile_key()?;
+                profile::fetch_profile(&mut manager.push_service()?, uuid, profile_key, true).await
+            })
+        })
+        .await
+    }
 
-        let (fut1, fut2) = future::join(
-            linking_manager.provision_secondary_device(
-                &mut self.csprng,
-                signaling_key,
-                &device_name,
-                tx,
-            ),
-            async move {
-                while let Some(provisioning_step) = rx.next().await {
-                    match provisioning_step {
-                        SecondaryDeviceProvisioning::Url(url) => {
-                            log::info!("generating qrcode from provisioning link: {}", &url);
-                            let code =
-                                QrCode::new(url.as_str()).expect("failed to generate qrcode");
-                            let image = code.render::<Luma<u8>>().build();
-                            let path = std::env::temp_dir().join("device-link.png");
-                            image.save(&path).map_err(|e| {
-                                log::error!("failed to generate qr code: {}", e);
-                                Error::QrCodeError
-                            })?;
-                            opener::open(path).map_err(|e| {
-                                log::error!("failed to open qr code: {}", e);
-                                Error::QrCodeError
-                            })?;
+    /// Returns the profile of a contact, or our own, decrypted with the profile key they shared with
+    /// us, avatar included.
+    ///
+    /// Profile keys are learnt from the messages of contacts and from contacts sync, so `None` is
+    /// returned for contacts whose key we don't know yet, or who have no profile. Profiles are
+    /// cached in the store and fetched again once older than the TTL set with
+    /// [Manager::set_profile_ttl], the cached profile being returned if that fails.
+    pub async fn profile(&self, uuid: Uuid) -> Result<Option<ProfileDetails>, Error> {
+        self.run(move |manager| {
+            Box::pin(async move {
+                let (own_uuid, own_profile_key) = manager.own_profile_key()?;
+                let mut config_store = manager.config_store.clone();
+                let stored = config_store.profile(&uuid).await?;
+                let profile_key = match &stored {
+                    _ if uuid == own_uuid => own_profile_key,
+                    Some(stored) => stored.profile_key,
+                    None => return Ok(None),
+                };
+                // our profile key may have been rotated on another device
+                let stored = stored.filter(|stored| stored.profile_key == profile_key);
+
+                let now = std::time::SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .expect("Time went backwards")
+                    .as_millis() as u64;
+                if let Some(StoredProfile {
+                    details,
+                    fetched_at: Some(fetched_at),
+                    ..
+                }) = &stored
+                {
+                    if now.saturating_sub(*fetched_at) < manager.profile_ttl.as_millis() as u64 {
+                        return Ok(details.clone());
+                    }
+                }
+
+                let details = match profile::fetch_profile(
+                    &mut manager.push_service()?,
+                    uuid,
+                    profile_key,
+                    true,
+                )
+                .await
+                {
+                    Ok(details) => Some(details),
+                    // no profile, or not encrypted with the key we know
+                    Err(Error::ServiceError(ServiceError::NotFoundError)) => None,
+                    Err(e) => match stored.and_then(|stored| stored.details) {
+                        Some(details) => {
+                            warn!("failed to fetch the profile of {}: {}", uuid, e);
+                            return Ok(Some(details));
                         }
-                        SecondaryDeviceProvisioning::NewDeviceRegistration {
-                            phone_number,
-                            device_id,
-                            registration_id,
-                            uuid,
-                            private_key,
-                            public_key,
+                        None => return Err(e),
+                    },
+                };
+                trace!("fetched the profile of {}", uuid);
+                config_store
+                    .save_profile(
+                        &uuid,
+                        &StoredProfile {
                             profile_key,
-                        } => {
-                            log::info!("successfully registered device {}", &uuid);
-                            return Ok((
-                                phone_number,
-                                device_id.device_id,
-                                registration_id,
-                                uuid,
-                                private_key,
-                                public_key,
-                                profile_key,
-                            ));
-                        }
-                    }
+                            details: details.clone(),
+                            fetched_at: Some(now),
+                        },
+                    )
+                    .await?;
+                Ok(details)
+            })
+        })
+        .await
+    }
+
+    /// Encrypts our profile with our profile key and uploads it, replacing the current one.
+    ///
+    /// Official clients require a given name, so it can't be empty.
+    pub async fn set_profile(&mut self, details: &ProfileDetails) -> Result<(), Error> {
+        let details = details.clone();
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                let details = &details;
+                if details.given_name.is_empty() {
+                    return Err(Error::ProfileError("the given name can't be empty".into()));
                 }
-                Err(Error::NoProvisioningMessageReceived)
-            },
-        )
-        .await;
+                let (uuid, profile_key) = manager.own_profile_key()?;
+                profile::write_profile(
+                    &mut manager.push_service()?,
+                    &mut manager.seeded_rng(),
+                    uuid,
+                    profile_key,
+                    details,
+                )
+                .await?;
+                // fetched again by the next call to `profile`
+                let stored = StoredProfile {
+                    profile_key,
+                    details: None,
+                    fetched_at: None,
+                };
+                manager
+                    .config_store
+                    .clone()
+                    .save_profile(&uuid, &stored)
+                    .await
+            })
+        })
+        .await
+    }
 
-        let _ = fut1?;
-        let (phone_number, device_id, registration_id, uuid, private_key, public_key, profile_key) =
-            fut2?;
+    /// Replaces our profile key, e.g. after blocking a contact, so that only the contacts and
+    /// groups we share the new key with can see our profile.
+    ///
+    /// The profile is encrypted again with the new key, then the key is sent to our contacts and
+    /// updated in the groups we are a member of. Our other devices get it from the transcripts of
+    /// these messages.
+    pub async fn rotate_profile_key(&mut self, timestamp: u64) -> Result<(), Error> {
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                let uuid = manager.registered_uuid()?;
+                let details = match manager.own_profile().await {
+                    Ok(details) => Some(details),
+                    Err(Error::ServiceError(ServiceError::NotFoundError)) => None,
+                    Err(e) => return Err(e),
+                };
+
+                let mut profile_key = [0u8; 32];
+                manager.seeded_rng().fill_bytes(&mut profile_key);
+                if let Some(details) = &details {
+                    profile::write_profile(
+                        &mut manager.push_service()?,
+                        &mut manager.seeded_rng(),
+                        uuid,
+                        profile_key,
+                        details,
+                    )
+                    .await?;
+                }
 
-        self.set_state(State::Registered {
-            signal_servers,
-            phone_number,
-            uuid,
-            signaling_key,
-            password,
-            device_id: Some(device_id),
-            registration_id,
-            public_key,
-            private_key,
-            profile_key: ProfileKey(profile_key.try_into().expect("32 bytes for profile key")),
-        })?;
-
-        self.register_pre_keys().await?;
-        self.set_account_attributes().await?;
-        self.request_contacts_sync().await?;
+                let mut state = manager.state.clone();
+                if let State::Registered {
+                    profile_key: key, ..
+                } = &mut state
+                {
+                    *key = ProfileKey(profile_key);
+                }
+                manager.set_state(state).await?;
+                // the unidentified access key is derived from the profile key
+                manager.update_account(Default::default()).await?;
 
-        Ok(())
+                manager.share_profile_key(timestamp).await
+            })
+        })
+        .await
     }
 
-    pub async fn whoami(&self) -> Result<WhoAmIResponse, Error> {
-        Ok(self.push_service()?.whoami().await?)
+    /// Sends our profile key to our contacts, and updates it in the groups we are a member of.
+    ///
+    /// Failures are only logged, so that one contact or group doesn't prevent the others from
+    /// getting the key.
+    async fn share_profile_key(&mut self, timestamp: u64) -> Result<(), Error> {
+        let (uuid, profile_key) = self.own_profile_key()?;
+
+        for (master_key, group) in self.groups().await? {
+            let is_member = group
+                .members
+                .iter()
+                .any(|member| Uuid::from_slice(&member.uuid).ok() == Some(uuid));
+            if !is_member {
+                continue;
+            }
+            if let Err(e) = self.update_group_profile_key(master_key, timestamp).await {
+                warn!(
+                    "failed to update our profile key in group {}: {}",
+                    hex::encode(master_key),
+                    e
+                );
+            }
+        }
+
+        let message = DataMessage {
+            profile_key: Some(profile_key.to_vec()),
+            flags: Some(proto::data_message::Flags::ProfileKeyUpdate as u32),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        let mut sender = self.new_message_sender()?;
+        for contact in self.config_store.contacts().await? {
+            let contact_uuid = match contact.address.uuid {
+                Some(contact_uuid) if contact_uuid != uuid => contact_uuid,
+                _ => continue,
+            };
+            let recipient = ServiceAddress {
+                uuid: Some(contact_uuid),
+                phonenumber: None,
+                relay: None,
+            };
+            if let Err(e) = sender
+                .send_message(
+                    &recipient,
+                    None,
+                    ContentBody::DataMessage(message.clone()),
+                    timestamp,
+                    false,
+                )
+                .await
+            {
+                warn!("failed to send our profile key to {}: {}", contact_uuid, e);
+            }
+        }
+        Ok(())
     }
 
-    pub async fn retrieve_profile(&self) -> Result<Profile, Error> {
+    fn own_profile_key(&self) -> Result<(Uuid, [u8; 32]), Error> {
         match &self.state {
             State::Registered {
                 uuid, profile_key, ..
-            } => self.retrieve_profile_by_uuid(*uuid, **profile_key).await,
-            _ => return Err(Error::NotYetRegisteredError),
+            } => Ok((*uuid, **profile_key)),
+            _ => Err(Error::NotYetRegisteredError),
         }
     }
 
-    pub async fn retrieve_profile_by_uuid(
-        &self,
-        uuid: Uuid,
-        profile_key: [u8; 32],
-    ) -> Result<Profile, Error> {
-        let mut account_manager = AccountManager::new(self.push_service()?, Some(profile_key));
-        Ok(account_manager.retrieve_profile(uuid).await?)
+    /// Uploads new pre-keys when the server is running out of them, rotates the signed pre-key when
+    /// it gets too old and deletes the replaced ones after a grace period, as configured by the
+    /// [PreKeysPolicy].
+    ///
+    /// The receive loops already do this periodically, but clients only sending messages should
+    /// call it from time to time.
+    pub async fn maintain_pre_keys(&mut self) -> Result<(), Error> {
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                if !manager.is_registered() {
+                    return Err(Error::NotYetRegisteredError);
+                }
+                pre_keys::maintain_pre_keys(
+                    &mut manager.push_service()?,
+                    &mut manager.config_store.clone(),
+                    &mut manager.seeded_rng(),
+                    &manager.pre_keys_policy,
+                )
+                .await
+            })
+        })
+        .await
     }
 
-    async fn register_pre_keys(&mut self) -> Result<(), Error> {
-        let profile_key = match &self.state {
-            State::Registered { profile_key, .. } => profile_key,
-            _ => return Err(Error::NotYetRegisteredError),
-        };
-
-        let mut account_manager = AccountManager::new(self.push_service()?, Some(**profile_key));
-
-        let (pre_keys_offset_id, next_signed_pre_key_id) = account_manager
-            .update_pre_key_bundle(
-                &self.config_store.clone(),
-                &mut self.config_store.clone(),
-                &mut self.config_store.clone(),
-                &mut self.csprng,
-                self.config_store.pre_keys_offset_id()?,
-                self.config_store.next_signed_pre_key_id()?,
-                true,
-            )
-            .await?;
-
-        self.config_store
-            .set_pre_keys_offset_id(pre_keys_offset_id)?;
-        self.config_store
-            .set_next_signed_pre_key_id(next_signed_pre_key_id)?;
-
-        Ok(())
+    /// Returns the attributes of this device, as last sent to Signal servers.
+    pub fn account_attributes(&self) -> Result<&AccountAttributes, Error> {
+        match &self.state {
+            State::Registered {
+                account_attributes, ..
+            } => Ok(account_attributes),
+            _ => Err(Error::NotYetRegisteredError),
+        }
     }
 
-    async fn set_account_attributes(&mut self) -> Result<(), Error> {
-        let (profile_key, registration_id) = match &self.state {
-            State::Registered {
-                profile_key,
-                registration_id,
-                ..
-            } => (profile_key, registration_id),
-            _ => return Err(Error::NotYetRegisteredError),
-        };
-        let mut account_manager = AccountManager::new(self.push_service()?, Some(**profile_key));
-        account_manager
-            .set_account_attributes(
-                None,
-                *registration_id,
-                false,
-                false,
-                true,
-                None,
-                None,
-                None,
-                false,
-                true,
-                DeviceCapabilities {
-                    uuid: true,
-                    gv2: true,
-                    storage: false,
-                    gv1_migration: true,
-                },
-            )
-            .await?;
-        Ok(())
+    /// Updates the attributes of this device on Signal servers, and saves them.
+    ///
+    /// Without any change, the current attributes are sent again, e.g. to advertise new
+    /// capabilities after an upgrade.
+    pub async fn update_account(&mut self, changes: AccountChanges) -> Result<(), Error> {
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                let mut state = manager.state.clone();
+                let (registration_id, public_key, profile_key, attributes) = match &mut state {
+                    State::Registered {
+                        registration_id,
+                        public_key,
+                        profile_key,
+                        account_attributes,
+                        ..
+                    } => (
+                        *registration_id,
+                        *public_key,
+                        ProfileKey(**profile_key),
+                        account_attributes,
+                    ),
+                    _ => return Err(Error::NotYetRegisteredError),
+                };
+                changes.apply(attributes);
+                let attributes = *attributes;
+
+                let mut push_service = manager.push_service()?;
+                if let Some(device_name) = changes.new_device_name() {
+                    let device_name =
+                        encrypt_device_name(device_name, &public_key, &mut manager.seeded_rng())?;
+                    push_service
+                        .put_json::<(), _>(
+                            Endpoint::Service,
+                            "/v1/accounts/name/",
+                            HttpAuthOverride::NoOverride,
+                            serde_json::json!({ "deviceName": device_name }),
+                        )
+                        .await?;
+                }
+
+                let mut account_manager = AccountManager::new(push_service, Some(*profile_key));
+                account_manager
+                    .set_account_attributes(
+                        None,
+                        registration_id,
+                        false,
+                        false,
+                        attributes.fetches_messages,
+                        None,
+                        None,
+                        attributes
+                            .unidentified_delivery
+                            .then(|| profile_key.derive_access_key()),
+                        attributes.unrestricted_unidentified_access,
+                        attributes.discoverable_by_phone_number,
+                        attributes.capabilities.into(),
+                    )
+                    .await?;
+
+                manager.set_state(state).await
+            })
+        })
+        .await
     }
 
     /// Request that the primary device to encrypt & send all of its contacts as a message to ourselves
     /// which can be then received, decrypted and stored in the message receiving loop.
     ///
     /// Note: if this is successful, the contacts are not yet received & stored, and will only be
-    /// processed when they're received using the `MessageReceiver`.
+    /// processed when they're received by [Manager::receive_messages].
     pub async fn request_contacts_sync(&self) -> Result<(), Error> {
         let phone_number = match &self.state {
             State::Registered { phone_number, .. } => phone_number,
@@ -547,50 +1020,266 @@ where
         Ok(())
     }
 
-    pub fn get_contacts(&self) -> Result<impl Iterator<Item = Contact>, Error> {
-        Ok(self.config_store.contacts()?.into_iter())
+    pub async fn get_contacts(&self) -> Result<impl Iterator<Item = Contact>, Error> {
+        Ok(self.config_store.contacts().await?.into_iter())
     }
 
     async fn receive_messages_encrypted(
         &self,
-    ) -> Result<impl Stream<Item = Result<Envelope, ServiceError>>, Error> {
+    ) -> Result<LocalBoxStream<'static, Result<Envelope, ServiceError>>, Error> {
         // TODO: error if we're primary registered device, as this is only for secondary devices
 
         let credentials = self.credentials()?.ok_or(Error::NotYetRegisteredError)?;
-        let pipe = MessageReceiver::new(self.push_service()?)
-            .create_message_pipe(credentials)
-            .await?;
-        Ok(pipe.stream())
+        Ok(open_message_pipe(self.push_service()?, credentials)
+            .await?
+            .boxed_local())
     }
 
     pub async fn receive_messages(&self) -> Result<impl Stream<Item = Content>, Error> {
-        struct StreamState<S, C, R> {
+        self.run_stream(|manager| Box::pin(manager.message_stream()))
+            .await
+    }
+
+    /// Stream of [Manager::receive_messages], running on the thread of the executor.
+    async fn message_stream(&self) -> Result<LocalBoxStream<'static, Content>, Error> {
+        struct StreamState<S, C, R, Svc> {
             encrypted_messages: S,
             service_cipher: ServiceCipher<C, R>,
+            message_receiver: MessageReceiver<Svc>,
+            groups_updater: GroupsUpdater<Svc, C>,
+            pre_keys: PreKeysMaintenance<Svc, R>,
+            config_store: C,
+            uuid: Uuid,
         }
 
         let init = StreamState {
-            encrypted_messages: Box::pin(self.receive_messages_encrypted().await?),
+            encrypted_messages: self.receive_messages_encrypted().await?,
             service_cipher: self.new_service_cipher()?,
+            message_receiver: MessageReceiver::new(self.push_service()?),
+            groups_updater: self.groups_updater()?,
+            pre_keys: self.pre_keys_maintenance()?,
+            config_store: self.config_store.clone(),
+            uuid: self.registered_uuid()?,
         };
 
         Ok(futures::stream::unfold(init, |mut state| async move {
             loop {
-                match state.encrypted_messages.next().await {
-                    Some(Ok(envelope)) => {
-                        match state.service_cipher.open_envelope(envelope).await {
-                            Ok(Some(content)) => return Some((content, state)),
-                            Ok(None) => warn!("Empty envelope..., message will be skipped!"),
-                            Err(e) => {
-                                error!("Error opening envelope: {:?}, message will be skipped!", e);
-                            }
+                state.pre_keys.tick(&mut state.config_store).await;
+                let next = tokio::time::timeout(
+                    state.pre_keys.next_check(),
+                    state.encrypted_messages.next(),
+                );
+                match next.await {
+                    // time to check the pre-keys again
+                    Err(_) => (),
+                    Ok(Some(Ok(envelope))) => {
+                        if let Some(content) = process_envelope(
+                            &mut state.service_cipher,
+                            &mut state.message_receiver,
+                            &mut state.groups_updater,
+                            &mut state.config_store,
+                            state.uuid,
+                            envelope,
+                        )
+                        .await
+                        {
+                            return Some((content, state));
                         }
                     }
-                    Some(Err(e)) => error!("Error: {}", e),
-                    None => return None,
+                    Ok(Some(Err(e))) => error!("Error: {}", e),
+                    Ok(None) => return None,
                 }
             }
-        }))
+        })
+        .boxed_local())
+    }
+
+    /// Receives messages like [Manager::receive_messages], but reopens the message pipe whenever it
+    /// gets closed or fails, waiting between attempts as configured by the [ReconnectPolicy].
+    ///
+    /// Changes of the connection state are interleaved with the received messages. The stream only
+    /// ends when the policy gives up reconnecting.
+    ///
+    /// Envelopes are kept queued by the server until acknowledged through the pipe, so the ones
+    /// that were not received before a disconnection are delivered again once reconnected.
+    pub async fn receive_messages_reconnecting(
+        &self,
+        policy: ReconnectPolicy,
+    ) -> Result<impl Stream<Item = Received>, Error> {
+        self.run_stream(move |manager| Box::pin(manager.reconnecting_message_stream(policy)))
+            .await
+    }
+
+    /// Stream of [Manager::receive_messages_reconnecting], running on the thread of the executor.
+    async fn reconnecting_message_stream(
+        &self,
+        policy: ReconnectPolicy,
+    ) -> Result<LocalBoxStream<'static, Received>, Error> {
+        enum Connection<S> {
+            Connected(S),
+            Disconnected { attempt: u32 },
+            Reconnecting { attempt: u32, delay: Duration },
+        }
+
+        struct StreamState<S, C, R, Svc> {
+            connection: Connection<S>,
+            policy: ReconnectPolicy,
+            csprng: R,
+            push_service: Svc,
+            credentials: ServiceCredentials,
+            service_cipher: ServiceCipher<C, R>,
+            message_receiver: MessageReceiver<Svc>,
+            groups_updater: GroupsUpdater<Svc, C>,
+            pre_keys: PreKeysMaintenance<Svc, R>,
+            config_store: C,
+            uuid: Uuid,
+        }
+
+        let credentials = self.credentials()?.ok_or(Error::NotYetRegisteredError)?;
+        let push_service = self.push_service()?;
+        let pipe = open_message_pipe(push_service.clone(), credentials.clone()).await?;
+
+        let init = StreamState {
+            connection: Connection::Connected(Box::pin(pipe)),
+            policy,
+            csprng: self.seeded_rng(),
+            push_service: push_service.clone(),
+            credentials,
+            service_cipher: self.new_service_cipher()?,
+            message_receiver: MessageReceiver::new(push_service),
+            groups_updater: self.groups_updater()?,
+            pre_keys: self.pre_keys_maintenance()?,
+            config_store: self.config_store.clone(),
+            uuid: self.registered_uuid()?,
+        };
+
+        let connected = futures::stream::once(future::ready(Received::ConnectionState(
+            ConnectionState::Connected,
+        )));
+
+        Ok(connected
+            .chain(futures::stream::unfold(init, |mut state| async move {
+                loop {
+                    match &mut state.connection {
+                        Connection::Connected(pipe) => {
+                            state.pre_keys.tick(&mut state.config_store).await;
+                            let next =
+                                tokio::time::timeout(state.pre_keys.next_check(), pipe.next());
+                            match next.await {
+                                // time to check the pre-keys again
+                                Err(_) => (),
+                                Ok(Some(Ok(envelope))) => {
+                                    if let Some(content) = process_envelope(
+                                        &mut state.service_cipher,
+                                        &mut state.message_receiver,
+                                        &mut state.groups_updater,
+                                        &mut state.config_store,
+                                        state.uuid,
+                                        envelope,
+                                    )
+                                    .await
+                                    {
+                                        return Some((Received::Content(content), state));
+                                    }
+                                }
+                                Ok(Some(Err(e))) => {
+                                    warn!("message pipe failed: {}", e);
+                                    state.connection = Connection::Disconnected { attempt: 0 };
+                                    return Some((
+                                        Received::ConnectionState(ConnectionState::Offline),
+                                        state,
+                                    ));
+                                }
+                                Ok(None) => {
+                                    info!("message pipe closed");
+                                    state.connection = Connection::Disconnected { attempt: 0 };
+                                    return Some((
+                                        Received::ConnectionState(ConnectionState::Offline),
+                                        state,
+                                    ));
+                                }
+                            }
+                        }
+                        Connection::Disconnected { attempt } => {
+                            let attempt = *attempt + 1;
+                            if state.policy.gave_up(attempt) {
+                                error!("giving up reconnecting after {} attempts", attempt - 1);
+                                return None;
+                            }
+                            let delay = state.policy.delay(attempt, &mut state.csprng);
+                            state.connection = Connection::Reconnecting { attempt, delay };
+                            return Some((
+                                Received::ConnectionState(ConnectionState::Reconnecting {
+                                    attempt,
+                                    delay,
+                                }),
+                                state,
+                            ));
+                        }
+                        Connection::Reconnecting { attempt, delay } => {
+                            let attempt = *attempt;
+                            tokio::time::sleep(*delay).await;
+                            match open_message_pipe(
+                                state.push_service.clone(),
+                                state.credentials.clone(),
+                            )
+                            .await
+                            {
+                                Ok(pipe) => {
+                                    info!("message pipe reconnected");
+                                    state.connection = Connection::Connected(Box::pin(pipe));
+                                    return Some((
+                                        Received::ConnectionState(ConnectionState::Connected),
+                                        state,
+                                    ));
+                                }
+                                Err(e) => {
+                                    warn!("failed to reconnect message pipe: {}", e);
+                                    state.connection = Connection::Disconnected { attempt };
+                                }
+                            }
+                        }
+                    }
+                }
+            }))
+            .boxed_local())
+    }
+
+    /// Receives messages like [Manager::receive_messages], turned into typed [Event]s.
+    ///
+    /// Changes of identity keys detected while decrypting a message are reported before the event
+    /// of the message itself.
+    pub async fn receive_events(&self) -> Result<impl Stream<Item = Event>, Error> {
+        // changes are taken on the thread of the executor, so that they come with their message
+        self.run_stream(|manager| {
+            Box::pin(async move {
+                let config_store = manager.config_store.clone();
+                Ok(manager
+                    .message_stream()
+                    .await?
+                    .then(move |content| {
+                        let mut config_store = config_store.clone();
+                        async move {
+                            let changes = config_store
+                                .take_identity_changes()
+                                .await
+                                .unwrap_or_else(|e| {
+                                    error!("Error reading identity changes: {}", e);
+                                    Vec::new()
+                                });
+                            futures::stream::iter(
+                                changes
+                                    .into_iter()
+                                    .map(Event::from)
+                                    .chain(std::iter::once(Event::from_content(content))),
+                            )
+                        }
+                    })
+                    .flatten()
+                    .boxed_local())
+            })
+        })
+        .await
     }
 
     pub async fn send_message(
@@ -599,108 +1288,1046 @@ where
         message: impl Into<ContentBody>,
         timestamp: u64,
     ) -> Result<(), Error> {
-        let mut sender = self.new_message_sender()?;
-
-        let online_only = false;
-        sender
-            .send_message(
-                &recipient_addr.into(),
-                None,
-                message,
-                timestamp,
-                online_only,
-            )
-            .await?;
+        let recipient_addr = recipient_addr.into();
+        let body = message.into();
+        self.run(move |manager| {
+            Box::pin(async move {
+                let mut sender = manager.new_message_sender()?;
+
+                let online_only = false;
+                sender
+                    .send_message(&recipient_addr, None, body.clone(), timestamp, online_only)
+                    .await?;
+
+                if let ContentBody::DataMessage(message) = &body {
+                    let thread = match Thread::from_group(message) {
+                        Some(thread) => Some(thread),
+                        None => manager.contact_thread(&recipient_addr).await,
+                    };
+                    manager.save_sent_message(thread, body, timestamp).await?;
+                }
 
-        Ok(())
+                Ok(())
+            })
+        })
+        .await
     }
 
+    /// Sends a message to all the members of a group v2, as known from the store.
+    ///
+    /// The group context of the message is set to the stored revision of the group.
     pub async fn send_message_to_group(
+        &self,
+        master_key: [u8; 32],
+        mut message: DataMessage,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let uuid = self.registered_uuid()?;
+        let group = self.group(master_key).await?;
+
+        message.group_v2 = Some(GroupContextV2 {
+            master_key: Some(master_key.to_vec()),
+            revision: Some(group.revision),
+            ..message.group_v2.unwrap_or_default()
+        });
+
+        let recipients = group
+            .members
+            .iter()
+            .filter_map(|member| Uuid::from_slice(&member.uuid).ok())
+            .filter(|member| *member != uuid)
+            .map(|member| ServiceAddress {
+                uuid: Some(member),
+                phonenumber: None,
+                relay: None,
+            });
+        self.send_message_to_recipients(recipients, message, timestamp)
+            .await
+    }
+
+    /// Sends a message to each of the given recipients, e.g. the members of a legacy group.
+    ///
+    /// The message is saved in the conversation history as soon as one of the recipients got it.
+    /// Failing to send it to the others is then only logged, and an error is returned if none of
+    /// them got it.
+    pub async fn send_message_to_recipients(
         &self,
         recipients: impl IntoIterator<Item = ServiceAddress>,
         message: DataMessage,
         timestamp: u64,
     ) -> Result<(), Error> {
-        let mut sender = self.new_message_sender()?;
-
         let recipients: Vec<_> = recipients.into_iter().collect();
+        self.run(move |manager| {
+            Box::pin(async move {
+                let mut sender = manager.new_message_sender()?;
+
+                let thread = Thread::from_group(&message);
+
+                let online_only = false;
+                let results = sender
+                    .send_message_to_group(
+                        recipients.clone(),
+                        None,
+                        message.clone(),
+                        timestamp,
+                        online_only,
+                    )
+                    .await;
+
+                let mut delivered = recipients.is_empty();
+                let mut first_error = None;
+                for (recipient, result) in recipients.iter().zip(results) {
+                    match result {
+                        Ok(_) => delivered = true,
+                        Err(e) => {
+                            error!(
+                                "failed to send message to {}: {}",
+                                recipient.identifier(),
+                                e
+                            );
+                            first_error.get_or_insert(e);
+                        }
+                    }
+                }
+                if let (false, Some(e)) = (delivered, first_error) {
+                    return Err(e.into());
+                }
 
-        let online_only = false;
-        let results = sender
-            .send_message_to_group(recipients, None, message, timestamp, online_only)
-            .await;
+                manager
+                    .save_sent_message(thread, ContentBody::DataMessage(message), timestamp)
+                    .await?;
+
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    /// Returns a message of the conversation history.
+    pub async fn message(&self, thread: &Thread, timestamp: u64) -> Result<Option<Content>, Error> {
+        self.config_store.message(thread, timestamp).await
+    }
 
-        // return first error if any
-        results.into_iter().find(|res| res.is_err()).transpose()?;
+    /// Returns the messages of a thread sent within a range of timestamps, oldest first.
+    pub async fn messages(
+        &self,
+        thread: &Thread,
+        range: impl RangeBounds<u64> + Send,
+    ) -> Result<C::MessagesIter, Error> {
+        self.config_store.messages(thread, range).await
+    }
 
+    /// Saves a message we sent in the conversation history.
+    async fn save_sent_message(
+        &self,
+        thread: Option<Thread>,
+        body: ContentBody,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let thread = match thread {
+            Some(thread) => thread,
+            None => {
+                warn!(
+                    "unknown conversation for message sent at {}, it won't be saved",
+                    timestamp
+                );
+                return Ok(());
+            }
+        };
+
+        let (sender, sender_device) = self.local_address()?;
+        let content = Content {
+            metadata: Metadata {
+                sender,
+                sender_device,
+                timestamp,
+                needs_receipt: false,
+            },
+            body,
+        };
+        if let Err(e) = self
+            .config_store
+            .clone()
+            .save_message(&thread, content)
+            .await
+        {
+            error!("failed to save sent message: {}", e);
+        }
         Ok(())
     }
 
+    /// Finds the conversation with a contact, which might only be known by phone number.
+    async fn contact_thread(&self, address: &ServiceAddress) -> Option<Thread> {
+        if let Some(uuid) = address.uuid {
+            return Some(Thread::Contact(uuid));
+        }
+        let phone_number = address.phonenumber.as_ref()?;
+        self.config_store
+            .contacts()
+            .await
+            .ok()?
+            .into_iter()
+            .find(|contact| contact.address.phonenumber.as_ref() == Some(phone_number))?
+            .address
+            .uuid
+            .map(Thread::Contact)
+    }
+
+    /// Deletes the sessions with all the devices of a contact, a new one will be established when
+    /// sending the next message.
     pub async fn clear_sessions(&self, recipient: &ServiceAddress) -> Result<(), Error> {
+        let recipient = recipient.clone();
+        self.run(move |manager| {
+            Box::pin(async move {
+                let recipient = &recipient;
+                manager
+                    .config_store
+                    .delete_all_sessions(&recipient.identifier())
+                    .await?;
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    /// Returns the devices of a contact we have an active session with.
+    pub async fn sessions(&self, contact: &ServiceAddress) -> Result<Vec<u32>, Error> {
+        let contact = contact.clone();
+        self.run(move |manager| {
+            Box::pin(async move {
+                let contact = &contact;
+                let name = contact.identifier();
+                let sub_devices = manager.config_store.get_sub_device_sessions(&name).await?;
+                let mut devices = Vec::new();
+                for device_id in std::iter::once(DEFAULT_DEVICE_ID).chain(sub_devices) {
+                    let address = ProtocolAddress::new(name.clone(), device_id);
+                    if let Some(session) = manager.config_store.load_session(&address, None).await?
+                    {
+                        if session.has_current_session_state() {
+                            devices.push(device_id);
+                        }
+                    }
+                }
+                Ok(devices)
+            })
+        })
+        .await
+    }
+
+    /// Resets the sessions with a contact: they are told to do the same with an end session
+    /// message, and ours are archived so that new ones are established for the next messages.
+    pub async fn reset_session(
+        &self,
+        contact: &ServiceAddress,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let contact = contact.clone();
+        self.run(move |manager| {
+            Box::pin(async move {
+                let contact = &contact;
+                let message = DataMessage {
+                    flags: Some(proto::data_message::Flags::EndSession as u32),
+                    timestamp: Some(timestamp),
+                    ..Default::default()
+                };
+                manager
+                    .new_message_sender()?
+                    .send_message(contact, None, message, timestamp, false)
+                    .await?;
+                archive_sessions(&mut manager.config_store.clone(), &contact.identifier()).await
+            })
+        })
+        .await
+    }
+
+    /// Returns how much the identity key of a contact is trusted, if it is known.
+    pub async fn trust_level(&self, contact: &ServiceAddress) -> Result<Option<TrustLevel>, Error> {
+        self.config_store.trust_level(&contact.identifier()).await
+    }
+
+    /// Computes the safety number of the conversation with a contact, to verify their identity.
+    pub async fn safety_number(&self, contact: &ServiceAddress) -> Result<SafetyNumber, Error> {
+        let contact = contact.clone();
+        self.run(move |manager| {
+            Box::pin(async move {
+                let contact = &contact;
+                let (local_address, _) = manager.local_address()?;
+                let local_phone_number = local_address
+                    .phonenumber
+                    .ok_or_else(|| Error::UnknownPhoneNumberError(local_address.identifier()))?;
+                let local_identity = manager.config_store.get_identity_key_pair(None).await?;
+
+                let phone_number = manager
+                    .contact_phone_number(contact)
+                    .await
+                    .ok_or_else(|| Error::UnknownPhoneNumberError(contact.identifier()))?;
+                let identity_key = manager
+                    .contact_identity(contact)
+                    .await?
+                    .ok_or_else(|| Error::UnknownIdentityError(contact.identifier()))?;
+
+                SafetyNumber::new(
+                    (&local_phone_number, local_identity.identity_key()),
+                    (&phone_number, &identity_key),
+                )
+            })
+        })
+        .await
+    }
+
+    /// Marks the identity key of a contact as verified, after comparing safety numbers, and lets
+    /// our other devices know.
+    pub async fn verify_identity(
+        &self,
+        contact: &ServiceAddress,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        self.set_trust_level(contact, TrustLevel::Verified, timestamp)
+            .await
+    }
+
+    /// Trusts the identity key of a contact without verifying it, e.g. to accept a change and be
+    /// able to send messages to them again, and lets our other devices know.
+    pub async fn trust_identity(
+        &self,
+        contact: &ServiceAddress,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        self.set_trust_level(contact, TrustLevel::TrustedOnFirstUse, timestamp)
+            .await
+    }
+
+    async fn set_trust_level(
+        &self,
+        contact: &ServiceAddress,
+        trust_level: TrustLevel,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let contact = contact.clone();
+        self.run(move |manager| {
+            Box::pin(async move {
+                let contact = &contact;
+                let identity_key = manager
+                    .contact_identity(contact)
+                    .await?
+                    .ok_or_else(|| Error::UnknownIdentityError(contact.identifier()))?;
+                manager
+                    .config_store
+                    .clone()
+                    .set_trust_level(&contact.identifier(), trust_level)
+                    .await?;
+
+                let state = match trust_level {
+                    TrustLevel::Verified => verified::State::Verified,
+                    TrustLevel::TrustedOnFirstUse | TrustLevel::Untrusted => {
+                        verified::State::Default
+                    }
+                };
+                let sync_message = SyncMessage {
+                    verified: Some(proto::Verified {
+                        destination_uuid: contact.uuid.map(|uuid| uuid.to_string()),
+                        destination_e164: contact.phonenumber.as_ref().map(ToString::to_string),
+                        identity_key: Some(identity_key.serialize().into_vec()),
+                        state: Some(state as i32),
+                        null_message: None,
+                    }),
+                    ..Default::default()
+                };
+                let (local_address, _) = manager.local_address()?;
+                manager
+                    .send_message(local_address, sync_message, timestamp)
+                    .await
+            })
+        })
+        .await
+    }
+
+    /// Returns the identity key of a contact, as saved when establishing a session with them.
+    async fn contact_identity(
+        &self,
+        contact: &ServiceAddress,
+    ) -> Result<Option<IdentityKey>, Error> {
+        let name = contact.identifier();
+        let sub_devices = self.config_store.get_sub_device_sessions(&name).await?;
+        for device_id in std::iter::once(DEFAULT_DEVICE_ID).chain(sub_devices) {
+            let address = ProtocolAddress::new(name.clone(), device_id);
+            if let Some(identity_key) = self.config_store.get_identity(&address, None).await? {
+                return Ok(Some(identity_key));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Finds the phone number of a contact, which might only be known by UUID.
+    async fn contact_phone_number(&self, address: &ServiceAddress) -> Option<PhoneNumber> {
+        if let Some(phone_number) = &address.phonenumber {
+            return Some(phone_number.clone());
+        }
+        let uuid = address.uuid?;
         self.config_store
-            .delete_all_sessions(&recipient.identifier())
+            .contacts()
+            .await
+            .ok()?
+            .into_iter()
+            .find(|contact| contact.address.uuid == Some(uuid))?
+            .address
+            .phonenumber
+    }
+
+    /// Fetches the current state of a group from the server, and saves it in the store.
+    pub async fn get_group_v2(
+        &self,
+        group_master_key: GroupMasterKey,
+    ) -> Result<DecryptedGroup, Error> {
+        self.run(move |manager| {
+            Box::pin(async move {
+                let group_secret_params =
+                    GroupSecretParams::derive_from_master_key(group_master_key);
+                let group = manager.groups_updater()?.fetch(group_secret_params).await?;
+
+                let master_key = group_secret_params.get_master_key();
+                manager
+                    .config_store
+                    .clone()
+                    .save_group(&groups::master_key_bytes(&master_key)?, &group)
+                    .await?;
+                Ok(group)
+            })
+        })
+        .await
+    }
+
+    /// Returns the stored state of a group, fetching it if it isn't known yet.
+    ///
+    /// Stored groups are kept up to date while receiving messages.
+    pub async fn group(&self, master_key: [u8; 32]) -> Result<DecryptedGroup, Error> {
+        match self.config_store.group(&master_key).await? {
+            Some(group) => Ok(group),
+            None => self.get_group_v2(GroupMasterKey::new(master_key)).await,
+        }
+    }
+
+    /// Returns the stored groups, with their master key.
+    pub async fn groups(&self) -> Result<Vec<([u8; 32], DecryptedGroup)>, Error> {
+        self.config_store.groups().await
+    }
+
+    /// Creates a new group, with ourselves as administrator and the given members.
+    ///
+    /// The profile keys of the members have to be known, see [GroupChanges]. Returns the master
+    /// key of the group.
+    pub async fn create_group(
+        &mut self,
+        title: &str,
+        members: impl IntoIterator<Item = Uuid>,
+        timestamp: u64,
+    ) -> Result<[u8; 32], Error> {
+        let title = title.to_string();
+        let members: Vec<Uuid> = members.into_iter().collect();
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                let uuid = manager.registered_uuid()?;
+                let mut csprng = manager.seeded_rng();
+
+                let master_key: [u8; 32] = csprng.gen();
+                let secret_params = groups::secret_params(master_key);
+
+                let members: Vec<Uuid> = members.into_iter().filter(|m| *m != uuid).collect();
+                let mut group = proto::Group {
+                    public_key: groups::serialize(&secret_params.get_public_params())?,
+                    title: groups::encrypt_blob(
+                        &secret_params,
+                        &mut csprng,
+                        group_attribute_blob::Content::Title(title.to_string()),
+                    )?,
+                    access_control: Some(proto::AccessControl {
+                        attributes: AccessRequired::Member as i32,
+                        members: AccessRequired::Member as i32,
+                        add_from_invite_link: AccessRequired::Unsatisfiable as i32,
+                    }),
+                    version: 0,
+                    ..Default::default()
+                };
+                let roles = std::iter::once((uuid, Role::Administrator))
+                    .chain(members.iter().map(|member| (*member, Role::Default)));
+                for (member, role) in roles {
+                    group.members.push(proto::Member {
+                        role: role as i32,
+                        presentation: manager
+                            .profile_key_credential_presentation(
+                                &secret_params,
+                                &mut csprng,
+                                member,
+                            )
+                            .await?,
+                        ..Default::default()
+                    });
+                }
+
+                let authorization = manager.group_authorization(secret_params).await?;
+                manager
+                    .push_service()?
+                    .put_protobuf::<proto::Group, _>(
+                        Endpoint::Storage,
+                        "/v1/groups/",
+                        HttpAuthOverride::Identified(authorization),
+                        group,
+                    )
+                    .await?;
+
+                manager
+                    .send_group_update(master_key, 0, None, members, timestamp)
+                    .await?;
+                manager
+                    .get_group_v2(GroupMasterKey::new(master_key))
+                    .await?;
+                Ok(master_key)
+            })
+        })
+        .await
+    }
+
+    /// Applies changes to a group, and lets its members know about it.
+    ///
+    /// Returns the new revision of the group.
+    pub async fn update_group(
+        &mut self,
+        master_key: [u8; 32],
+        changes: GroupChanges,
+        timestamp: u64,
+    ) -> Result<u32, Error> {
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                let mut csprng = manager.seeded_rng();
+                let secret_params = groups::secret_params(master_key);
+                let group = manager
+                    .get_group_v2(GroupMasterKey::new(master_key))
+                    .await?;
+
+                let mut presentations = HashMap::new();
+                for member in changes.members_to_add() {
+                    let presentation = manager
+                        .profile_key_credential_presentation(&secret_params, &mut csprng, *member)
+                        .await?;
+                    presentations.insert(*member, presentation);
+                }
+                let invite_link_password =
+                    if changes.needs_invite_link_password(&group.invite_link_password) {
+                        Some(groups::generate_invite_link_password(&mut csprng))
+                    } else {
+                        None
+                    };
+
+                let recipients = changes.recipients(&group);
+                let revision = group.revision + 1;
+                let actions = changes.into_actions(
+                    &secret_params,
+                    &mut csprng,
+                    &presentations,
+                    invite_link_password,
+                    revision,
+                )?;
+                let change = manager
+                    .modify_group(master_key, actions, None, recipients, timestamp)
+                    .await?;
+                manager
+                    .save_own_group_change(master_key, group, &change)
+                    .await?;
+                Ok(revision)
+            })
+        })
+        .await
+    }
+
+    /// Updates our profile key in a group, after it was rotated.
+    async fn update_group_profile_key(
+        &mut self,
+        master_key: [u8; 32],
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let mut csprng = self.seeded_rng();
+        let secret_params = groups::secret_params(master_key);
+        let group = self.get_group_v2(GroupMasterKey::new(master_key)).await?;
+
+        let presentation = self
+            .profile_key_credential_presentation(
+                &secret_params,
+                &mut csprng,
+                self.registered_uuid()?,
+            )
+            .await?;
+        let actions = group_change::Actions {
+            version: group.revision + 1,
+            modify_member_profile_keys: vec![group_change::actions::ModifyMemberProfileKeyAction {
+                presentation,
+            }],
+            ..Default::default()
+        };
+        let recipients = group
+            .members
+            .iter()
+            .filter_map(|member| Uuid::from_slice(&member.uuid).ok())
+            .collect();
+        let change = self
+            .modify_group(master_key, actions, None, recipients, timestamp)
             .await?;
+        self.save_own_group_change(master_key, group, &change).await
+    }
+
+    /// Applies a change we made to a group to the stored group, so that it doesn't need to be
+    /// fetched again.
+    async fn save_own_group_change(
+        &self,
+        master_key: [u8; 32],
+        mut group: DecryptedGroup,
+        change: &[u8],
+    ) -> Result<(), Error> {
+        let secret_params = groups::secret_params(master_key);
+        let server_public_params = self.server_public_params()?;
+        match groups::apply_group_change(&mut group, &secret_params, &server_public_params, change)
+        {
+            Ok(()) => {
+                self.config_store
+                    .clone()
+                    .save_group(&master_key, &group)
+                    .await?
+            }
+            Err(e) => warn!("failed to apply our own group change: {}", e),
+        }
         Ok(())
     }
 
-    pub async fn get_group_v2(
+    /// Leaves a group we're a member of.
+    pub async fn leave_group(&mut self, master_key: [u8; 32], timestamp: u64) -> Result<(), Error> {
+        let uuid = self.registered_uuid()?;
+        self.update_group(
+            master_key,
+            GroupChanges::default().remove_member(uuid),
+            timestamp,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Returns the link to join the group, if enabled (see [GroupChanges::invite_link_access]).
+    pub async fn group_invite_link(&self, master_key: [u8; 32]) -> Result<Option<String>, Error> {
+        let group = self.get_group_v2(GroupMasterKey::new(master_key)).await?;
+        let enabled = group.access_control.map_or(false, |access| {
+            access.add_from_invite_link != AccessRequired::Unsatisfiable as i32
+        });
+        if !enabled || group.invite_link_password.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(groups::invite_link(
+            master_key,
+            &group.invite_link_password,
+        )))
+    }
+
+    /// Joins a group with an invite link, or requests to join it if an administrator has to
+    /// approve new members.
+    ///
+    /// Returns the master key of the group.
+    pub async fn join_group_with_invite_link(
         &mut self,
-        group_master_key: GroupMasterKey,
-    ) -> Result<libsignal_service::proto::DecryptedGroup, Error> {
-        let (signal_servers, _phone_number, uuid, _device_id) = match &self.state {
+        invite_link: &str,
+        timestamp: u64,
+    ) -> Result<[u8; 32], Error> {
+        let invite_link = invite_link.to_string();
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                let uuid = manager.registered_uuid()?;
+                let mut csprng = manager.seeded_rng();
+
+                let (master_key, password) = groups::parse_invite_link(&invite_link)?;
+                let secret_params = groups::secret_params(master_key);
+                let password = base64::encode_config(&password, base64::URL_SAFE_NO_PAD);
+
+                let authorization = manager.group_authorization(secret_params).await?;
+                let join_info: proto::GroupJoinInfo = manager
+                    .push_service()?
+                    .get_protobuf(
+                        Endpoint::Storage,
+                        &format!("/v1/groups/join/{}", password),
+                        HttpAuthOverride::Identified(authorization),
+                    )
+                    .await?;
+
+                let presentation = manager
+                    .profile_key_credential_presentation(&secret_params, &mut csprng, uuid)
+                    .await?;
+                let mut actions = group_change::Actions {
+                    version: join_info.version + 1,
+                    ..Default::default()
+                };
+                let joined = match AccessRequired::from_i32(join_info.add_from_invite_link) {
+                    Some(AccessRequired::Any) => {
+                        actions
+                            .add_members
+                            .push(group_change::actions::AddMemberAction {
+                                added: Some(proto::Member {
+                                    role: Role::Default as i32,
+                                    presentation,
+                                    ..Default::default()
+                                }),
+                                join_from_invite_link: true,
+                            });
+                        true
+                    }
+                    Some(AccessRequired::Administrator) => {
+                        actions.add_requesting_members.push(
+                            group_change::actions::AddRequestingMemberAction {
+                                added: Some(proto::RequestingMember {
+                                    presentation,
+                                    ..Default::default()
+                                }),
+                            },
+                        );
+                        false
+                    }
+                    _ => {
+                        return Err(Error::GroupsV2Error(
+                            "joining this group with its invite link is disabled".into(),
+                        ))
+                    }
+                };
+
+                let change = manager
+                    .modify_group(master_key, actions, Some(&password), vec![], timestamp)
+                    .await?;
+                if joined {
+                    let group = manager
+                        .get_group_v2(GroupMasterKey::new(master_key))
+                        .await?;
+                    let members = group
+                        .members
+                        .iter()
+                        .filter_map(|member| Uuid::from_slice(&member.uuid).ok())
+                        .collect();
+                    manager
+                        .send_group_update(
+                            master_key,
+                            join_info.version + 1,
+                            Some(change),
+                            members,
+                            timestamp,
+                        )
+                        .await?;
+                }
+                Ok(master_key)
+            })
+        })
+        .await
+    }
+
+    /// Encrypts and uploads the avatar of a group, returning the key to use in
+    /// [GroupChanges::avatar].
+    pub async fn upload_group_avatar(
+        &mut self,
+        master_key: [u8; 32],
+        avatar: Vec<u8>,
+    ) -> Result<String, Error> {
+        self.run_mut(move |manager| {
+            Box::pin(async move {
+                let mut csprng = manager.seeded_rng();
+                let secret_params = groups::secret_params(master_key);
+                let mut push_service = manager.push_service()?;
+
+                let authorization = manager.group_authorization(secret_params).await?;
+                let form: proto::AvatarUploadAttributes = push_service
+                    .get_protobuf(
+                        Endpoint::Storage,
+                        "/v1/groups/avatar/form",
+                        HttpAuthOverride::Identified(authorization),
+                    )
+                    .await?;
+
+                let avatar = groups::encrypt_blob(
+                    &secret_params,
+                    &mut csprng,
+                    group_attribute_blob::Content::Avatar(avatar),
+                )?;
+                let mut avatar = std::io::Cursor::new(avatar);
+                push_service
+                    .post_to_cdn0(
+                        "",
+                        &[
+                            ("key", &form.key),
+                            ("x-amz-credential", &form.credential),
+                            ("acl", &form.acl),
+                            ("x-amz-algorithm", &form.algorithm),
+                            ("x-amz-date", &form.date),
+                            ("policy", &form.policy),
+                            ("x-amz-signature", &form.signature),
+                            ("Content-Type", "application/octet-stream"),
+                        ],
+                        Some(("file", &mut avatar)),
+                    )
+                    .await?;
+                Ok(form.key)
+            })
+        })
+        .await
+    }
+
+    /// Submits a change to a group, and sends the signed change to the given members.
+    ///
+    /// Returns the signed change, as serialized by the server.
+    async fn modify_group(
+        &self,
+        master_key: [u8; 32],
+        actions: group_change::Actions,
+        invite_link_password: Option<&str>,
+        recipients: Vec<Uuid>,
+        timestamp: u64,
+    ) -> Result<Vec<u8>, Error> {
+        let revision = actions.version;
+        let authorization = self
+            .group_authorization(groups::secret_params(master_key))
+            .await?;
+        let path = match invite_link_password {
+            Some(password) => format!("/v1/groups/?inviteLinkPassword={}", password),
+            None => "/v1/groups/".to_string(),
+        };
+        let change: proto::GroupChange = self
+            .push_service()?
+            .patch_protobuf(
+                Endpoint::Storage,
+                &path,
+                HttpAuthOverride::Identified(authorization),
+                actions,
+            )
+            .await?;
+
+        let mut change_bytes = Vec::new();
+        change
+            .encode(&mut change_bytes)
+            .expect("encoding into a vector never fails");
+        if !recipients.is_empty() {
+            self.send_group_update(
+                master_key,
+                revision,
+                Some(change_bytes.clone()),
+                recipients,
+                timestamp,
+            )
+            .await?;
+        }
+        Ok(change_bytes)
+    }
+
+    /// Sends the new state of a group to its members, who will fetch the group or apply the change.
+    async fn send_group_update(
+        &self,
+        master_key: [u8; 32],
+        revision: u32,
+        group_change: Option<Vec<u8>>,
+        members: Vec<Uuid>,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let uuid = self.registered_uuid()?;
+        let message = DataMessage {
+            group_v2: Some(GroupContextV2 {
+                master_key: Some(master_key.to_vec()),
+                revision: Some(revision),
+                group_change,
+            }),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        };
+        let recipients = members
+            .into_iter()
+            .filter(|member| *member != uuid)
+            .map(|member| ServiceAddress {
+                uuid: Some(member),
+                phonenumber: None,
+                relay: None,
+            });
+        self.send_message_to_recipients(recipients, message, timestamp)
+            .await
+    }
+
+    /// Creates the presentation of the profile key credential of a user, proving to the server
+    /// that the encrypted UUID and profile key of a new member match.
+    async fn profile_key_credential_presentation(
+        &self,
+        secret_params: &GroupSecretParams,
+        csprng: &mut StdRng,
+        uuid: Uuid,
+    ) -> Result<Vec<u8>, Error> {
+        let profile_key: [u8; 32] = match &self.state {
             State::Registered {
-                signal_servers,
-                phone_number,
-                uuid,
-                device_id,
+                uuid: own_uuid,
+                profile_key,
                 ..
-            } => (signal_servers, phone_number, uuid, device_id),
-            _ => return Err(Error::NotYetRegisteredError),
+            } if *own_uuid == uuid => **profile_key,
+            _ => match self.config_store.profile(&uuid).await? {
+                Some(profile) => profile.profile_key,
+                None => self
+                    .config_store
+                    .contacts()
+                    .await?
+                    .into_iter()
+                    .find(|contact| contact.address.uuid == Some(uuid))
+                    .and_then(|contact| contact.profile_key.as_slice().try_into().ok())
+                    .ok_or_else(|| {
+                        Error::GroupsV2Error(format!("unknown profile key for {}", uuid).into())
+                    })?,
+            },
         };
 
-        let service_configuration: ServiceConfiguration = (*signal_servers).into();
-        let server_public_params = service_configuration.zkgroup_server_public_params;
+        let server_public_params = self.server_public_params()?;
+        let credential = groups::profile_key_credential(
+            &mut self.push_service()?,
+            &server_public_params,
+            csprng,
+            uuid,
+            profile_key,
+        )
+        .await?;
+        let presentation = server_public_params.create_profile_key_credential_presentation(
+            csprng.gen(),
+            *secret_params,
+            credential,
+        );
+        groups::serialize(&presentation)
+    }
+
+    /// Authorization to access a group, valid for today.
+    async fn group_authorization(
+        &self,
+        secret_params: GroupSecretParams,
+    ) -> Result<HttpAuth, Error> {
+        self.groups_updater()?.authorization(secret_params).await
+    }
 
-        let mut groups_v2_credentials_cache = InMemoryCredentialsCache::default();
-        let mut groups_v2_api = GroupsManager::new(
+    fn groups_updater(&self) -> Result<GroupsUpdater<P::PushService, C>, Error> {
+        Ok(GroupsUpdater {
+            push_service: self.push_service()?,
+            server_public_params: self.server_public_params()?,
+            uuid: self.registered_uuid()?,
+            credentials_cache: self.config_store.clone(),
+        })
+    }
+
+    fn pre_keys_maintenance(&self) -> Result<PreKeysMaintenance<P::PushService, StdRng>, Error> {
+        Ok(PreKeysMaintenance::new(
             self.push_service()?,
-            &mut groups_v2_credentials_cache,
-            server_public_params,
-        );
+            self.seeded_rng(),
+            self.pre_keys_policy,
+        ))
+    }
 
-        let group_secret_params = GroupSecretParams::derive_from_master_key(group_master_key);
-        let authorization = groups_v2_api
-            .get_authorization_for_today(*uuid, group_secret_params)
-            .await?;
+    fn server_public_params(&self) -> Result<ServerPublicParams, Error> {
+        match &self.state {
+            State::Registered { signal_servers, .. } => Ok(self
+                .push_service_factory
+                .service_configuration(*signal_servers)
+                .zkgroup_server_public_params),
+            _ => Err(Error::NotYetRegisteredError),
+        }
+    }
 
-        Ok(groups_v2_api
-            .get_group(group_secret_params, authorization)
-            .await?)
+    fn registered_uuid(&self) -> Result<Uuid, Error> {
+        match &self.state {
+            State::Registered { uuid, .. } => Ok(*uuid),
+            _ => Err(Error::NotYetRegisteredError),
+        }
     }
 
     pub async fn get_attachment(
         &self,
         attachment_pointer: &AttachmentPointer,
     ) -> Result<Vec<u8>, Error> {
-        let mut service = self.push_service()?;
-        let mut attachment_stream = service.get_attachment(&attachment_pointer).await?;
+        let attachment_pointer = attachment_pointer.clone();
+        self.run(move |manager| {
+            Box::pin(async move {
+                let mut service = manager.push_service()?;
+                let mut attachment_stream = service.get_attachment(&attachment_pointer).await?;
+
+                // We need the whole file for the crypto to check out
+                let mut ciphertext = Vec::new();
+                let len = attachment_stream.read_to_end(&mut ciphertext).await?;
+
+                trace!("downloaded encrypted attachment of {} bytes", len);
+
+                let key: [u8; 64] = attachment_pointer.key().try_into()?;
+                decrypt_in_place(key, &mut ciphertext)?;
 
-        // We need the whole file for the crypto to check out
-        let mut ciphertext = Vec::new();
-        let len = attachment_stream.read_to_end(&mut ciphertext).await?;
+                Ok(ciphertext)
+            })
+        })
+        .await
+    }
 
-        trace!("downloaded encrypted attachment of {} bytes", len);
+    /// Encrypts and uploads an attachment to the CDN.
+    ///
+    /// The returned pointer can be added to the `attachments` of a [DataMessage].
+    pub async fn upload_attachment(
+        &self,
+        spec: AttachmentSpec,
+        contents: Vec<u8>,
+    ) -> Result<AttachmentPointer, Error> {
+        self.run(move |manager| {
+            Box::pin(async move {
+                let mut sender = manager.new_message_sender()?;
+                let attachment_pointer = sender.upload_attachment(spec, contents).await?;
+                trace!("uploaded attachment {:?}", attachment_pointer.cdn_id);
+                Ok(attachment_pointer)
+            })
+        })
+        .await
+    }
 
-        let key: [u8; 64] = attachment_pointer.key().try_into()?;
-        decrypt_in_place(key, &mut ciphertext)?;
+    /// Same as [Manager::upload_attachment], reading the contents of the attachment from `reader`.
+    ///
+    /// The whole attachment is read in memory, and the length in `spec` is set accordingly.
+    pub async fn upload_attachment_from_reader(
+        &self,
+        mut spec: AttachmentSpec,
+        mut reader: impl AsyncRead + Unpin,
+    ) -> Result<AttachmentPointer, Error> {
+        let mut contents = Vec::new();
+        spec.length = reader.read_to_end(&mut contents).await?;
+        self.upload_attachment(spec, contents).await
+    }
 
-        Ok(ciphertext)
+    /// Encrypts and uploads several attachments, returning their pointers in the same order.
+    pub async fn upload_attachments(
+        &self,
+        attachments: impl IntoIterator<Item = (AttachmentSpec, Vec<u8>)>,
+    ) -> Result<Vec<AttachmentPointer>, Error> {
+        let attachments: Vec<_> = attachments.into_iter().collect();
+        self.run(move |manager| {
+            Box::pin(async move {
+                let mut sender = manager.new_message_sender()?;
+                let mut attachment_pointers = Vec::new();
+                for (spec, contents) in attachments {
+                    attachment_pointers.push(sender.upload_attachment(spec, contents).await?);
+                }
+                Ok(attachment_pointers)
+            })
+        })
+        .await
+    }
+
+    /// Uploads attachments, and sends a message with them to a contact.
+    pub async fn send_message_with_attachments(
+        &self,
+        recipient_addr: impl Into<ServiceAddress>,
+        mut message: DataMessage,
+        attachments: impl IntoIterator<Item = (AttachmentSpec, Vec<u8>)>,
+        timestamp: u64,
+    ) -> Result<(), Error> {
+        let attachment_pointers = self.upload_attachments(attachments).await?;
+        message.attachments.extend(attachment_pointers);
+        self.send_message(recipient_addr, message, timestamp).await
     }
 
     /// Returns a clone of a cached push service.
     ///
     /// If no service is yet cached, it will create and cache one.
-    fn push_service(&self) -> Result<HyperPushService, Error> {
+    fn push_service(&self) -> Result<P::PushService, Error> {
         self.cache.push_service.get(|| {
             let signal_servers = match &self.state {
                 State::Registration { signal_servers, .. }
@@ -711,63 +2338,275 @@ where
             };
 
             let credentials = self.credentials()?;
-            let service_configuration: ServiceConfiguration = (*signal_servers).into();
+            let service_configuration = self
+                .push_service_factory
+                .service_configuration(*signal_servers);
 
-            Ok(HyperPushService::new(
-                service_configuration,
-                credentials,
-                crate::USER_AGENT.to_string(),
-            ))
+            Ok(self
+                .push_service_factory
+                .push_service(service_configuration, credentials))
         })
     }
 
-    /// Creates a new message sender.
-    fn new_message_sender(&self) -> Result<MessageSender<C, R>, Error> {
-        let (phone_number, uuid, device_id) = match &self.state {
+    /// Returns the address and device id of this client.
+    fn local_address(&self) -> Result<(ServiceAddress, u32), Error> {
+        match &self.state {
             State::Registered {
                 phone_number,
                 uuid,
                 device_id,
                 ..
-            } => (phone_number, uuid, device_id),
-            _ => return Err(Error::NotYetRegisteredError),
-        };
+            } => Ok((
+                ServiceAddress {
+                    uuid: Some(*uuid),
+                    phonenumber: Some(phone_number.clone()),
+                    relay: None,
+                },
+                device_id.unwrap_or(DEFAULT_DEVICE_ID),
+            )),
+            _ => Err(Error::NotYetRegisteredError),
+        }
+    }
 
-        let local_addr = ServiceAddress {
-            uuid: Some(*uuid),
-            phonenumber: Some(phone_number.clone()),
-            relay: None,
-        };
+    /// Creates a new message sender.
+    fn new_message_sender(&self) -> Result<MessageSender<P::PushService, C, StdRng>, Error> {
+        let (local_addr, device_id) = self.local_address()?;
 
         Ok(MessageSender::new(
             self.push_service()?,
             self.new_service_cipher()?,
-            self.csprng.clone(),
+            self.seeded_rng(),
             self.config_store.clone(),
             self.config_store.clone(),
             local_addr,
-            device_id.unwrap_or(DEFAULT_DEVICE_ID),
+            device_id,
         ))
     }
 
     /// Creates a new service cipher.
-    fn new_service_cipher(&self) -> Result<ServiceCipher<C, R>, Error> {
+    fn new_service_cipher(&self) -> Result<ServiceCipher<C, StdRng>, Error> {
         let signal_servers = match &self.state {
             State::Registered { signal_servers, .. } => signal_servers,
             _ => return Err(Error::NotYetRegisteredError),
         };
 
-        let service_configuration: ServiceConfiguration = (*signal_servers).into();
+        let service_configuration = self
+            .push_service_factory
+            .service_configuration(*signal_servers);
         let certificate_validator = service_configuration.credentials_validator()?;
         let service_cipher = ServiceCipher::new(
             self.config_store.clone(),
             self.config_store.clone(),
             self.config_store.clone(),
             self.config_store.clone(),
-            self.csprng.clone(),
+            self.seeded_rng(),
             certificate_validator,
         );
 
         Ok(service_cipher)
     }
 }
+
+/// Opens a new message pipe, whose stream of envelopes ends when the connection is lost.
+async fn open_message_pipe<S: PushService>(
+    push_service: S,
+    credentials: ServiceCredentials,
+) -> Result<impl Stream<Item = Result<Envelope, ServiceError>>, Error> {
+    let pipe = MessageReceiver::new(push_service)
+        .create_message_pipe(credentials)
+        .await?;
+    Ok(pipe.stream())
+}
+
+/// Decrypts an envelope, and saves the resulting message and synchronized contacts in the store.
+async fn process_envelope<C, R, S>(
+    service_cipher: &mut ServiceCipher<C, R>,
+    message_receiver: &mut MessageReceiver<S>,
+    groups_updater: &mut GroupsUpdater<S, C>,
+    config_store: &mut C,
+    uuid: Uuid,
+    envelope: Envelope,
+) -> Option<Content>
+where
+    C: ConfigStore,
+    R: Rng + CryptoRng,
+    S: PushService + Clone,
+{
+    match service_cipher.open_envelope(envelope).await {
+        Ok(Some(content)) => {
+            // only our other devices may sync anything, e.g. our contacts or sent messages
+            if let ContentBody::SynchronizeMessage(_) = &content.body {
+                if content.metadata.sender.uuid != Some(uuid) {
+                    warn!(
+                        "ignoring sync message from {}, not our account",
+                        content.metadata.sender.identifier()
+                    );
+                    return None;
+                }
+            }
+            if let ContentBody::SynchronizeMessage(SyncMessage {
+                contacts: Some(contacts),
+                ..
+            }) = &content.body
+            {
+                match save_synced_contacts(message_receiver, config_store, contacts).await {
+                    Ok(n) => info!("saved {} synchronized contacts", n),
+                    Err(e) => error!("Error saving contacts: {}", e),
+                }
+            }
+            if let Err(e) = save_profile_key(config_store, &content).await {
+                error!("Error saving profile key: {}", e);
+            }
+            if let ContentBody::DataMessage(message) = &content.body {
+                if message.flags() & proto::data_message::Flags::EndSession as u32 != 0 {
+                    let sender = content.metadata.sender.identifier();
+                    if let Err(e) = archive_sessions(config_store, &sender).await {
+                        error!("Error archiving sessions: {}", e);
+                    }
+                }
+            }
+            if let ContentBody::SynchronizeMessage(SyncMessage {
+                verified: Some(verified),
+                ..
+            }) = &content.body
+            {
+                if let Err(e) = save_synced_verification(config_store, verified).await {
+                    error!("Error saving verification state: {}", e);
+                }
+            }
+            if let Some(context) = group_context(&content) {
+                if let Err(e) = groups_updater.update(config_store, context).await {
+                    error!("Error updating group: {}", e);
+                }
+            }
+            if let Some(thread) = Thread::from_content(&content) {
+                if let Err(e) = config_store.save_message(&thread, content.clone()).await {
+                    error!("Error saving message: {}", e);
+                }
+            }
+            Some(content)
+        }
+        Ok(None) => {
+            warn!("Empty envelope..., message will be skipped!");
+            None
+        }
+        Err(e) => {
+            error!("Error opening envelope: {:?}, message will be skipped!", e);
+            None
+        }
+    }
+}
+
+/// The group v2 context of a message sent to a group, by someone else or by another of our devices.
+fn group_context(content: &Content) -> Option<&GroupContextV2> {
+    match &content.body {
+        ContentBody::DataMessage(message)
+        | ContentBody::SynchronizeMessage(SyncMessage {
+            sent:
+                Some(sync_message::Sent {
+                    message: Some(message),
+                    ..
+                }),
+            ..
+        }) => message.group_v2.as_ref(),
+        _ => None,
+    }
+}
+
+/// Saves the profile key a contact sent along with a message, so that we can fetch their profile.
+async fn save_profile_key<C: ProfilesStore>(
+    config_store: &mut C,
+    content: &Content,
+) -> Result<(), Error> {
+    let (sender, profile_key) = match (&content.metadata.sender.uuid, &content.body) {
+        (
+            Some(sender),
+            ContentBody::DataMessage(DataMessage {
+                profile_key: Some(profile_key),
+                ..
+            }),
+        ) => (sender, profile_key),
+        _ => return Ok(()),
+    };
+    if config_store
+        .save_profile_key(sender, profile_key.as_slice().try_into()?)
+        .await?
+    {
+        info!("new profile key of {}", sender);
+    }
+    Ok(())
+}
+
+/// Archives the current sessions with all the devices of a contact, keeping them to decrypt
+/// messages still in flight.
+async fn archive_sessions<C: ConfigStore>(config_store: &mut C, name: &str) -> Result<(), Error> {
+    let sub_devices = config_store.get_sub_device_sessions(name).await?;
+    for device_id in std::iter::once(DEFAULT_DEVICE_ID).chain(sub_devices) {
+        let address = ProtocolAddress::new(name.to_string(), device_id);
+        if let Some(mut session) = config_store.load_session(&address, None).await? {
+            session.archive_current_state()?;
+            config_store.store_session(&address, &session, None).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Saves the verification state of a contact, as changed on another of our devices.
+///
+/// This must only be called for sync messages sent by our own account.
+async fn save_synced_verification<C: ConfigStore>(
+    config_store: &mut C,
+    verified: &proto::Verified,
+) -> Result<(), Error> {
+    let name = match verified
+        .destination_uuid
+        .as_ref()
+        .or_else(|| verified.destination_e164.as_ref())
+    {
+        Some(name) => name,
+        None => {
+            warn!("verification state synced without destination, ignoring");
+            return Ok(());
+        }
+    };
+
+    // our other device compared safety numbers for this key, so it replaces whatever we have
+    let identity_key = IdentityKey::decode(verified.identity_key())?;
+    config_store
+        .save_identity(
+            &ProtocolAddress::new(name.clone(), DEFAULT_DEVICE_ID),
+            &identity_key,
+            None,
+        )
+        .await?;
+
+    let trust_level = match verified.state() {
+        verified::State::Verified => TrustLevel::Verified,
+        verified::State::Default | verified::State::Unverified => TrustLevel::TrustedOnFirstUse,
+    };
+    config_store.set_trust_level(name, trust_level).await
+}
+
+/// Downloads, decrypts and parses the contacts sent by the primary device, and saves them along
+/// with their profile keys.
+async fn save_synced_contacts<S: PushService, C: ContactsStore + ProfilesStore>(
+    message_receiver: &mut MessageReceiver<S>,
+    config_store: &mut C,
+    contacts: &sync_message::Contacts,
+) -> Result<usize, Error> {
+    let contacts = message_receiver
+        .retrieve_contacts(contacts)
+        .await?
+        .collect::<Result<Vec<Contact>, _>>()?;
+    config_store.save_contacts(&contacts).await?;
+    for contact in &contacts {
+        let uuid = match &contact.address.uuid {
+            Some(uuid) => uuid,
+            None => continue,
+        };
+        if let Ok(profile_key) = contact.profile_key.as_slice().try_into() {
+            config_store.save_profile_key(uuid, profile_key).await?;
+        }
+    }
+    Ok(contacts.len())
+}
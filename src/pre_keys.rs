@@ -0,0 +1,297 @@
+use std::{
+    convert::TryInto,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use libsignal_service::{
+    pre_keys::PreKeyState,
+    prelude::protocol::{
+        IdentityKeyStore, KeyPair, PreKeyRecord, PreKeyStore, SignedPreKeyRecord, SignedPreKeyStore,
+    },
+    push_service::PushService,
+};
+use log::{error, info, trace};
+use rand::{CryptoRng, Rng};
+
+use crate::{config::ConfigStore, Error};
+
+/// Number of one-time pre-keys uploaded at once
+const PRE_KEY_BATCH_SIZE: u32 = 100;
+/// Pre-key ids are 24-bit integers
+const PRE_KEY_MEDIUM_MAX_VALUE: u32 = 0xFFFFFF;
+
+/// When to upload new pre-keys and rotate the signed pre-key.
+///
+/// Contacts starting a session with us consume one of our one-time pre-keys, along with the signed
+/// pre-key, so the server must never run out of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PreKeysPolicy {
+    /// Upload a new batch of one-time pre-keys when fewer remain on the server
+    pub minimum_pre_keys: u32,
+    /// Age after which the signed pre-key is replaced
+    pub rotation_interval: Duration,
+    /// How long a replaced signed pre-key is kept, for messages sent before it was replaced
+    pub grace_period: Duration,
+    /// How often the receive loops check the pre-keys
+    pub check_interval: Duration,
+}
+
+impl Default for PreKeysPolicy {
+    fn default() -> Self {
+        Self {
+            minimum_pre_keys: 10,
+            rotation_interval: Duration::from_secs(2 * 24 * 60 * 60),
+            grace_period: Duration::from_secs(30 * 24 * 60 * 60),
+            check_interval: Duration::from_secs(12 * 60 * 60),
+        }
+    }
+}
+
+impl PreKeysPolicy {
+    /// Whether a signed pre-key generated at `timestamp` must be replaced, in milliseconds.
+    pub(crate) fn needs_rotation(&self, timestamp: u64, now: u64) -> bool {
+        now.saturating_sub(timestamp) >= self.rotation_interval.as_millis() as u64
+    }
+
+    /// Whether a signed pre-key replaced at `replaced_at` can be deleted, in milliseconds.
+    pub(crate) fn expired(&self, replaced_at: u64, now: u64) -> bool {
+        now.saturating_sub(replaced_at) >= self.grace_period.as_millis() as u64
+    }
+}
+
+/// Uploads new pre-keys if the server is running out of them or if the signed pre-key is due for
+/// rotation, then deletes the signed pre-keys replaced for longer than the grace period.
+pub(crate) async fn maintain_pre_keys<S, C, R>(
+    push_service: &mut S,
+    config_store: &mut C,
+    csprng: &mut R,
+    policy: &PreKeysPolicy,
+) -> Result<(), Error>
+where
+    S: PushService,
+    C: ConfigStore,
+    R: Rng + CryptoRng,
+{
+    let now = now_millis();
+    let count = push_service.get_pre_key_status().await?.count;
+    let refill = count < policy.minimum_pre_keys;
+    let rotate = match current_signed_pre_key(config_store).await? {
+        Some((_, record)) => policy.needs_rotation(record.timestamp()?, now),
+        None => true,
+    };
+    trace!("{} pre-keys left on the server", count);
+
+    if refill || rotate {
+        upload_pre_keys(push_service, config_store, csprng, refill, now).await?;
+    }
+
+    purge_signed_pre_keys(config_store, policy, now).await
+}
+
+/// Generates a new signed pre-key, and a batch of one-time pre-keys if `refill`, and uploads them.
+async fn upload_pre_keys<S, C, R>(
+    push_service: &mut S,
+    config_store: &mut C,
+    csprng: &mut R,
+    refill: bool,
+    now: u64,
+) -> Result<(), Error>
+where
+    S: PushService,
+    C: ConfigStore,
+    R: Rng + CryptoRng,
+{
+    let identity_key_pair = config_store.get_identity_key_pair(None).await?;
+
+    let pre_keys_offset_id = config_store.pre_keys_offset_id().await?;
+    let mut pre_keys = Vec::new();
+    if refill {
+        for i in 0..PRE_KEY_BATCH_SIZE {
+            let id = ((pre_keys_offset_id + i) % (PRE_KEY_MEDIUM_MAX_VALUE - 1)) + 1;
+            let record = PreKeyRecord::new(id, &KeyPair::generate(csprng));
+            config_store.save_pre_key(id, &record, None).await?;
+            pre_keys.push(record.try_into()?);
+        }
+    }
+
+    let signed_pre_key_id = config_store.next_signed_pre_key_id().await?;
+    let key_pair = KeyPair::generate(csprng);
+    let signature = identity_key_pair
+        .private_key()
+        .calculate_signature(&key_pair.public_key.serialize(), csprng)?;
+    let record = SignedPreKeyRecord::new(signed_pre_key_id, now, &key_pair, &signature);
+    config_store
+        .save_signed_pre_key(signed_pre_key_id, &record, None)
+        .await?;
+
+    push_service
+        .register_pre_keys(PreKeyState {
+            pre_keys,
+            signed_pre_key: record.try_into()?,
+            identity_key: *identity_key_pair.public_key(),
+        })
+        .await?;
+    info!(
+        "uploaded signed pre-key {}{}",
+        signed_pre_key_id,
+        if refill { " and new pre-keys" } else { "" }
+    );
+
+    if refill {
+        config_store
+            .set_pre_keys_offset_id(
+                (pre_keys_offset_id + PRE_KEY_BATCH_SIZE) % (PRE_KEY_MEDIUM_MAX_VALUE - 1),
+            )
+            .await?;
+    }
+    config_store
+        .set_next_signed_pre_key_id(signed_pre_key_id + 1)
+        .await
+}
+
+/// The signed pre-key currently on the server, which is the last one generated.
+async fn current_signed_pre_key<C: ConfigStore>(
+    config_store: &C,
+) -> Result<Option<(u32, SignedPreKeyRecord)>, Error> {
+    let id = match config_store.next_signed_pre_key_id().await?.checked_sub(1) {
+        Some(id) => id,
+        None => return Ok(None),
+    };
+    Ok(config_store
+        .get_signed_pre_key(id, None)
+        .await
+        .ok()
+        .map(|record| (id, record)))
+}
+
+/// Deletes the signed pre-keys replaced for longer than the grace period.
+///
+/// Each signed pre-key is replaced when the next one is generated, so they are walked down from
+/// the current one until there are no more.
+async fn purge_signed_pre_keys<C: ConfigStore>(
+    config_store: &mut C,
+    policy: &PreKeysPolicy,
+    now: u64,
+) -> Result<(), Error> {
+    let (current_id, current) = match current_signed_pre_key(config_store).await? {
+        Some(current) => current,
+        None => return Ok(()),
+    };
+    let mut replaced_at = current.timestamp()?;
+    for id in (0..current_id).rev() {
+        let record = match config_store.get_signed_pre_key(id, None).await {
+            Ok(record) => record,
+            Err(_) => break,
+        };
+        if policy.expired(replaced_at, now) {
+            trace!("removing signed pre-key {}", id);
+            config_store.remove_signed_pre_key(id).await?;
+        }
+        replaced_at = record.timestamp()?;
+    }
+    Ok(())
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time went backwards")
+        .as_millis() as u64
+}
+
+/// Runs [maintain_pre_keys] from the receive loops, every [PreKeysPolicy::check_interval].
+pub(crate) struct PreKeysMaintenance<S, R> {
+    push_service: S,
+    csprng: R,
+    policy: PreKeysPolicy,
+    last_check: Option<Instant>,
+}
+
+impl<S, R> PreKeysMaintenance<S, R>
+where
+    S: PushService,
+    R: Rng + CryptoRng,
+{
+    pub(crate) fn new(push_service: S, csprng: R, policy: PreKeysPolicy) -> Self {
+        Self {
+            push_service,
+            csprng,
+            policy,
+            last_check: None,
+        }
+    }
+
+    /// Time left before the next check is due.
+    pub(crate) fn next_check(&self) -> Duration {
+        self.last_check.map_or(Duration::ZERO, |last_check| {
+            self.policy
+                .check_interval
+                .saturating_sub(last_check.elapsed())
+        })
+    }
+
+    /// Checks the pre-keys if due, only logging failures so that receiving goes on.
+    pub(crate) async fn tick<C: ConfigStore>(&mut self, config_store: &mut C) {
+        if self.next_check() > Duration::ZERO {
+            return;
+        }
+        if let Err(e) = maintain_pre_keys(
+            &mut self.push_service,
+            config_store,
+            &mut self.csprng,
+            &self.policy,
+        )
+        .await
+        {
+            error!("failed to maintain pre-keys: {}", e);
+        }
+        self.last_check = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::MemoryConfigStore;
+
+    use super::*;
+
+    const DAY: u64 = 24 * 60 * 60 * 1000;
+
+    #[test]
+    fn test_needs_rotation() {
+        let policy = PreKeysPolicy::default();
+        assert!(!policy.needs_rotation(10 * DAY, 10 * DAY));
+        assert!(!policy.needs_rotation(10 * DAY, 12 * DAY - 1));
+        assert!(policy.needs_rotation(10 * DAY, 12 * DAY));
+        // clock going backwards
+        assert!(!policy.needs_rotation(10 * DAY, 9 * DAY));
+    }
+
+    async fn save_signed_pre_key(store: &mut MemoryConfigStore, id: u32, timestamp: u64) {
+        let key_pair = KeyPair::generate(&mut rand::thread_rng());
+        let record = SignedPreKeyRecord::new(id, timestamp, &key_pair, &[0; 64]);
+        store.save_signed_pre_key(id, &record, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_purge_signed_pre_keys() {
+        let mut store = MemoryConfigStore::new();
+        let policy = PreKeysPolicy::default();
+        // replaced on days 10, 20, 50 and 60, and the current one
+        for (id, day) in [0, 10, 20, 50, 60].iter().enumerate() {
+            save_signed_pre_key(&mut store, id as u32, day * DAY).await;
+        }
+        store.set_next_signed_pre_key_id(5).await.unwrap();
+
+        purge_signed_pre_keys(&mut store, &policy, 70 * DAY)
+            .await
+            .unwrap();
+
+        for id in 0..2 {
+            assert!(store.get_signed_pre_key(id, None).await.is_err());
+        }
+        for id in 2..5 {
+            assert!(store.get_signed_pre_key(id, None).await.is_ok());
+        }
+    }
+}
@@ -0,0 +1,359 @@
+use aes_gcm::{
+    aead::{Aead, NewAead},
+    Aes256Gcm, Key, Nonce,
+};
+use futures::AsyncReadExt;
+use libsignal_service::{
+    configuration::Endpoint,
+    prelude::{PushService, Uuid},
+    push_service::HttpAuthOverride,
+};
+use log::trace;
+use rand::{CryptoRng, Rng};
+use serde::{Deserialize, Serialize};
+use zkgroup::profiles::ProfileKey;
+
+use crate::{groups, Error};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Encrypted fields are padded to the first of these lengths they fit in, so that their size
+/// doesn't leak.
+const NAME_PADDED_LENGTHS: &[usize] = &[53, 257];
+const ABOUT_PADDED_LENGTHS: &[usize] = &[128, 254, 512];
+const EMOJI_PADDED_LENGTHS: &[usize] = &[32];
+
+/// Decrypted profile of an account, as shown to the contacts knowing its profile key.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProfileDetails {
+    pub given_name: String,
+    pub family_name: Option<String>,
+    pub about: Option<String>,
+    pub about_emoji: Option<String>,
+    /// Avatar image, usually a JPEG
+    pub avatar: Option<Vec<u8>>,
+}
+
+/// Encrypts the fields of a profile with AES-GCM, keyed by the profile key.
+pub(crate) struct ProfileCipher {
+    key: [u8; 32],
+}
+
+impl ProfileCipher {
+    pub(crate) fn new(profile_key: [u8; 32]) -> Self {
+        Self { key: profile_key }
+    }
+
+    pub(crate) fn encrypt_name<R: Rng + CryptoRng>(
+        &self,
+        given_name: &str,
+        family_name: Option<&str>,
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        let mut name = given_name.as_bytes().to_vec();
+        if let Some(family_name) = family_name {
+            name.push(0);
+            name.extend_from_slice(family_name.as_bytes());
+        }
+        self.encrypt(&pad(name, NAME_PADDED_LENGTHS, "name")?, csprng)
+    }
+
+    pub(crate) fn decrypt_name(
+        &self,
+        ciphertext: &[u8],
+    ) -> Result<(String, Option<String>), Error> {
+        let name = self.decrypt_string(ciphertext)?;
+        let mut parts = name.splitn(2, '\0');
+        let given_name = parts.next().unwrap_or_default().to_string();
+        let family_name = parts.next().filter(|name| !name.is_empty()).map(Into::into);
+        Ok((given_name, family_name))
+    }
+
+    pub(crate) fn encrypt_about<R: Rng + CryptoRng>(
+        &self,
+        about: &str,
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        let about = pad(about.as_bytes().to_vec(), ABOUT_PADDED_LENGTHS, "about")?;
+        self.encrypt(&about, csprng)
+    }
+
+    pub(crate) fn encrypt_emoji<R: Rng + CryptoRng>(
+        &self,
+        emoji: &str,
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        let emoji = pad(
+            emoji.as_bytes().to_vec(),
+            EMOJI_PADDED_LENGTHS,
+            "about emoji",
+        )?;
+        self.encrypt(&emoji, csprng)
+    }
+
+    /// Decrypts the about text or emoji.
+    pub(crate) fn decrypt_string(&self, ciphertext: &[u8]) -> Result<String, Error> {
+        let mut plaintext = self.decrypt(ciphertext)?;
+        let len = plaintext.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        plaintext.truncate(len);
+        String::from_utf8(plaintext).map_err(|_| Error::ProfileError("invalid UTF-8".into()))
+    }
+
+    pub(crate) fn encrypt_avatar<R: Rng + CryptoRng>(
+        &self,
+        avatar: &[u8],
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        self.encrypt(avatar, csprng)
+    }
+
+    pub(crate) fn decrypt_avatar(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        self.decrypt(ciphertext)
+    }
+
+    /// Returns the random nonce followed by the ciphertext and its tag.
+    fn encrypt<R: Rng + CryptoRng>(
+        &self,
+        plaintext: &[u8],
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        let nonce: [u8; NONCE_LEN] = csprng.gen();
+        let ciphertext = self
+            .cipher()
+            .encrypt(Nonce::from_slice(&nonce), plaintext)
+            .map_err(|_| Error::ProfileError("failed to encrypt profile".into()))?;
+        Ok([&nonce[..], &ciphertext].concat())
+    }
+
+    fn decrypt(&self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        if ciphertext.len() < NONCE_LEN + TAG_LEN {
+            return Err(Error::ProfileError("profile ciphertext too short".into()));
+        }
+        let (nonce, ciphertext) = ciphertext.split_at(NONCE_LEN);
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| Error::ProfileError("failed to decrypt profile".into()))
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::from_slice(&self.key))
+    }
+}
+
+fn pad(mut bytes: Vec<u8>, lengths: &[usize], field: &str) -> Result<Vec<u8>, Error> {
+    let len = lengths
+        .iter()
+        .find(|len| **len >= bytes.len())
+        .ok_or_else(|| Error::ProfileError(format!("{} is too long", field).into()))?;
+    bytes.resize(*len, 0);
+    Ok(bytes)
+}
+
+/// Form to upload an encrypted avatar to the CDN, returned when setting a profile with an avatar.
+#[derive(Deserialize)]
+struct AvatarUploadAttributes {
+    key: String,
+    credential: String,
+    acl: String,
+    algorithm: String,
+    date: String,
+    policy: String,
+    signature: String,
+}
+
+/// Encrypts a profile and uploads it, replacing the current one.
+///
+/// The profile is stored by the server under a version derived from the profile key, so only
+/// contacts knowing this profile key can fetch it.
+pub(crate) async fn write_profile<S, R>(
+    push_service: &mut S,
+    csprng: &mut R,
+    uuid: Uuid,
+    profile_key: [u8; 32],
+    details: &ProfileDetails,
+) -> Result<(), Error>
+where
+    S: PushService,
+    R: Rng + CryptoRng,
+{
+    let cipher = ProfileCipher::new(profile_key);
+    let key = ProfileKey::create(profile_key);
+    let version = groups::serialize(&key.get_profile_key_version(*uuid.as_bytes()))?;
+    let commitment = groups::serialize(&key.get_commitment(*uuid.as_bytes()))?;
+
+    let name = cipher.encrypt_name(&details.given_name, details.family_name.as_deref(), csprng)?;
+    let about = match &details.about {
+        Some(about) => Some(base64::encode(cipher.encrypt_about(about, csprng)?)),
+        None => None,
+    };
+    let about_emoji = match &details.about_emoji {
+        Some(emoji) => Some(base64::encode(cipher.encrypt_emoji(emoji, csprng)?)),
+        None => None,
+    };
+    let request = serde_json::json!({
+        "version": String::from_utf8_lossy(&version),
+        "name": base64::encode(name),
+        "about": about,
+        "aboutEmoji": about_emoji,
+        "avatar": details.avatar.is_some(),
+        "commitment": base64::encode(commitment),
+    });
+
+    let avatar = match &details.avatar {
+        Some(avatar) => cipher.encrypt_avatar(avatar, csprng)?,
+        None => {
+            push_service
+                .put_json::<(), _>(
+                    Endpoint::Service,
+                    "/v1/profile",
+                    HttpAuthOverride::NoOverride,
+                    request,
+                )
+                .await?;
+            return Ok(());
+        }
+    };
+    let form: AvatarUploadAttributes = push_service
+        .put_json(
+            Endpoint::Service,
+            "/v1/profile",
+            HttpAuthOverride::NoOverride,
+            request,
+        )
+        .await?;
+    let mut avatar = std::io::Cursor::new(avatar);
+    push_service
+        .post_to_cdn0(
+            "",
+            &[
+                ("key", &form.key),
+                ("x-amz-credential", &form.credential),
+                ("acl", &form.acl),
+                ("x-amz-algorithm", &form.algorithm),
+                ("x-amz-date", &form.date),
+                ("policy", &form.policy),
+                ("x-amz-signature", &form.signature),
+                ("Content-Type", "application/octet-stream"),
+            ],
+            Some(("file", &mut avatar)),
+        )
+        .await?;
+    trace!("uploaded profile avatar {}", form.key);
+    Ok(())
+}
+
+/// Fetches the profile of an account and decrypts it with its profile key.
+///
+/// The avatar is downloaded too, if `with_avatar`.
+pub(crate) async fn fetch_profile<S: PushService>(
+    push_service: &mut S,
+    uuid: Uuid,
+    profile_key: [u8; 32],
+    with_avatar: bool,
+) -> Result<ProfileDetails, Error> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct EncryptedProfile {
+        name: Option<String>,
+        about: Option<String>,
+        about_emoji: Option<String>,
+        avatar: Option<String>,
+    }
+
+    let cipher = ProfileCipher::new(profile_key);
+    let key = ProfileKey::create(profile_key);
+    let version = groups::serialize(&key.get_profile_key_version(*uuid.as_bytes()))?;
+    let path = format!("/v1/profile/{}/{}", uuid, String::from_utf8_lossy(&version));
+    let profile: EncryptedProfile = push_service
+        .get_json(Endpoint::Service, &path, HttpAuthOverride::NoOverride)
+        .await?;
+
+    let decrypt_string = |field: Option<String>| -> Result<Option<String>, Error> {
+        let field = match field {
+            Some(field) => cipher.decrypt_string(&base64::decode(field)?)?,
+            None => return Ok(None),
+        };
+        Ok(Some(field).filter(|field| !field.is_empty()))
+    };
+    let (given_name, family_name) = match profile.name {
+        Some(name) => cipher.decrypt_name(&base64::decode(name)?)?,
+        None => Default::default(),
+    };
+    let avatar = match profile.avatar.filter(|path| !path.is_empty()) {
+        Some(path) if with_avatar => {
+            let mut ciphertext = Vec::new();
+            push_service
+                .get_from_cdn(0, &path)
+                .await?
+                .read_to_end(&mut ciphertext)
+                .await?;
+            Some(cipher.decrypt_avatar(&ciphertext)?)
+        }
+        _ => None,
+    };
+
+    Ok(ProfileDetails {
+        given_name,
+        family_name,
+        about: decrypt_string(profile.about)?,
+        about_emoji: decrypt_string(profile.about_emoji)?,
+        avatar,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_name() {
+        let mut rng = rand::thread_rng();
+        let cipher = ProfileCipher::new(rng.gen());
+
+        let ciphertext = cipher
+            .encrypt_name("Alice", Some("Liddell"), &mut rng)
+            .unwrap();
+        assert_eq!(ciphertext.len(), NONCE_LEN + 53 + TAG_LEN);
+        assert_eq!(
+            cipher.decrypt_name(&ciphertext).unwrap(),
+            ("Alice".to_string(), Some("Liddell".to_string()))
+        );
+
+        let ciphertext = cipher
+            .encrypt_name(&"a".repeat(60), None, &mut rng)
+            .unwrap();
+        assert_eq!(ciphertext.len(), NONCE_LEN + 257 + TAG_LEN);
+        assert_eq!(
+            cipher.decrypt_name(&ciphertext).unwrap(),
+            ("a".repeat(60), None)
+        );
+
+        assert!(cipher
+            .encrypt_name(&"a".repeat(300), None, &mut rng)
+            .is_err());
+    }
+
+    #[test]
+    fn test_about() {
+        let mut rng = rand::thread_rng();
+        let cipher = ProfileCipher::new(rng.gen());
+
+        let ciphertext = cipher
+            .encrypt_about("down the rabbit hole", &mut rng)
+            .unwrap();
+        assert_eq!(ciphertext.len(), NONCE_LEN + 128 + TAG_LEN);
+        assert_eq!(
+            cipher.decrypt_string(&ciphertext).unwrap(),
+            "down the rabbit hole"
+        );
+
+        let ciphertext = cipher.encrypt_emoji("🐇", &mut rng).unwrap();
+        assert_eq!(cipher.decrypt_string(&ciphertext).unwrap(), "🐇");
+
+        // wrong key
+        assert!(ProfileCipher::new(rng.gen())
+            .decrypt_string(&ciphertext)
+            .is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::{fmt, io::Write};
+
+use futures::channel::mpsc;
+use image::{png::PngEncoder, ColorType, Luma};
+use qrcode::{
+    render::{svg, unicode::Dense1x2},
+    QrCode,
+};
+
+use crate::Error;
+
+/// URL to scan with the primary device (as a QR code) to link a secondary device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvisioningUrl(String);
+
+impl ProvisioningUrl {
+    pub(crate) fn new(url: impl ToString) -> Self {
+        Self(url.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn qr_code(&self) -> Result<QrCode, Error> {
+        QrCode::new(self.0.as_bytes()).map_err(|e| {
+            log::error!("failed to generate qr code: {}", e);
+            Error::QrCodeError
+        })
+    }
+
+    /// Renders the QR code with unicode half blocks, to print it in a terminal.
+    pub fn to_terminal(&self) -> Result<String, Error> {
+        Ok(self
+            .qr_code()?
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build())
+    }
+
+    /// Renders the QR code with plain ASCII characters.
+    pub fn to_ascii(&self) -> Result<String, Error> {
+        Ok(self
+            .qr_code()?
+            .render::<char>()
+            .dark_color('#')
+            .light_color(' ')
+            .module_dimensions(2, 1)
+            .build())
+    }
+
+    pub fn to_svg(&self) -> Result<String, Error> {
+        Ok(self.qr_code()?.render::<svg::Color>().build())
+    }
+
+    pub fn to_png(&self) -> Result<Vec<u8>, Error> {
+        let image = self.qr_code()?.render::<Luma<u8>>().build();
+        let mut png = Vec::new();
+        PngEncoder::new(&mut png)
+            .encode(&image, image.width(), image.height(), ColorType::L8)
+            .map_err(|e| {
+                log::error!("failed to encode qr code: {}", e);
+                Error::QrCodeError
+            })?;
+        Ok(png)
+    }
+}
+
+impl fmt::Display for ProvisioningUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// What to do with the [ProvisioningUrl] when linking a secondary device.
+pub enum ProvisioningStrategy {
+    /// Saves the QR code as a PNG image in the temporary directory, and opens it with the default
+    /// image viewer
+    OpenImage,
+    /// Writes the QR code, rendered for a terminal, into the given writer (e.g. the standard
+    /// output)
+    PrintTerminal(Box<dyn Write + Send>),
+    /// Sends the URL into a channel
+    Channel(mpsc::UnboundedSender<ProvisioningUrl>),
+    /// Calls the given function with the URL
+    Callback(Box<dyn FnMut(ProvisioningUrl) + Send>),
+}
+
+impl fmt::Debug for ProvisioningStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::OpenImage => f.write_str("OpenImage"),
+            Self::PrintTerminal(_) => f.write_str("PrintTerminal"),
+            Self::Channel(_) => f.write_str("Channel"),
+            Self::Callback(_) => f.write_str("Callback"),
+        }
+    }
+}
+
+impl ProvisioningStrategy {
+    pub(crate) fn provisioning_url(&mut self, url: ProvisioningUrl) -> Result<(), Error> {
+        log::info!("generating qrcode from provisioning link: {}", &url);
+        match self {
+            Self::OpenImage => {
+                let path = std::env::temp_dir().join("device-link.png");
+                std::fs::write(&path, url.to_png()?).map_err(|e| {
+                    log::error!("failed to save qr code: {}", e);
+                    Error::QrCodeError
+                })?;
+                opener::open(path).map_err(|e| {
+                    log::error!("failed to open qr code: {}", e);
+                    Error::QrCodeError
+                })
+            }
+            Self::PrintTerminal(writer) => {
+                writeln!(writer, "{}", url.to_terminal()?)?;
+                Ok(writer.flush()?)
+            }
+            Self::Channel(tx) => tx
+                .unbounded_send(url)
+                .map_err(|_| Error::ProvisioningUrlReceiverDropped),
+            Self::Callback(callback) => {
+                callback(url);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Default for ProvisioningStrategy {
+    fn default() -> Self {
+        Self::OpenImage
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renderings() {
+        let url = ProvisioningUrl::new("tsdevice:/?uuid=abcdef&pub_key=AAAA");
+        assert!(url.to_svg().unwrap().starts_with("<?xml"));
+        assert!(url.to_png().unwrap().starts_with(b"\x89PNG"));
+        assert!(url.to_ascii().unwrap().contains('#'));
+        assert!(!url.to_terminal().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_channel() {
+        let (tx, mut rx) = mpsc::unbounded();
+        let url = ProvisioningUrl::new("tsdevice:/?uuid=abcdef&pub_key=AAAA");
+        ProvisioningStrategy::Channel(tx)
+            .provisioning_url(url.clone())
+            .unwrap();
+        assert_eq!(rx.try_next().unwrap(), Some(url));
+    }
+
+    #[test]
+    fn test_channel_closed() {
+        let (tx, rx) = mpsc::unbounded();
+        drop(rx);
+        let url = ProvisioningUrl::new("tsdevice:/?uuid=abcdef&pub_key=AAAA");
+        assert!(matches!(
+            ProvisioningStrategy::Channel(tx).provisioning_url(url),
+            Err(Error::ProvisioningUrlReceiverDropped)
+        ));
+    }
+}
@@ -0,0 +1,47 @@
+use libsignal_service::{
+    configuration::{ServiceConfiguration, SignalServers},
+    messagepipe::ServiceCredentials,
+    prelude::PushService,
+};
+use libsignal_service_hyper::push_service::HyperPushService;
+
+/// Builds the [PushService] a [Manager](crate::Manager) uses to talk to Signal servers.
+///
+/// Implement this to swap the HTTP stack, e.g. for a recording transport or a local mock server.
+///
+/// The factory and its push services are sent to the thread running the futures which are not
+/// `Send`, see [Manager](crate::Manager).
+pub trait PushServiceFactory: Clone + Send + Sync + 'static {
+    type PushService: PushService + Clone + Send;
+
+    /// Returns the configuration (URLs, trust roots, zkgroup parameters) for the given servers.
+    fn service_configuration(&self, signal_servers: SignalServers) -> ServiceConfiguration {
+        signal_servers.into()
+    }
+
+    fn push_service(
+        &self,
+        service_configuration: ServiceConfiguration,
+        credentials: Option<ServiceCredentials>,
+    ) -> Self::PushService;
+}
+
+/// Default factory, creating push services backed by hyper.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HyperPushServiceFactory;
+
+impl PushServiceFactory for HyperPushServiceFactory {
+    type PushService = HyperPushService;
+
+    fn push_service(
+        &self,
+        service_configuration: ServiceConfiguration,
+        credentials: Option<ServiceCredentials>,
+    ) -> Self::PushService {
+        HyperPushService::new(
+            service_configuration,
+            credentials,
+            crate::USER_AGENT.to_string(),
+        )
+    }
+}
@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use libsignal_service::prelude::Content;
+use rand::Rng;
+
+/// State of the connection to Signal servers while receiving messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The message pipe is open: queued and new messages are being received.
+    Connected,
+    /// The message pipe was closed or failed.
+    Offline,
+    /// A new connection will be attempted after the given delay.
+    Reconnecting { attempt: u32, delay: Duration },
+}
+
+/// Item of the stream returned by [Manager::receive_messages_reconnecting](crate::Manager::receive_messages_reconnecting).
+#[derive(Debug, Clone)]
+pub enum Received {
+    Content(Content),
+    ConnectionState(ConnectionState),
+}
+
+/// How to reconnect the message pipe when it gets closed.
+///
+/// The delay before the `n`-th attempt is `initial_delay * multiplier^(n - 1)`, capped to
+/// `max_delay`. A random fraction (up to `jitter`) of this delay is removed, so that many clients
+/// disconnected at the same time don't all come back at the same time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f64,
+    /// Between 0 (no jitter) and 1 (full jitter)
+    pub jitter: f64,
+    /// Give up after this many failed attempts in a row, never if `None`
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5 * 60),
+            multiplier: 2.0,
+            jitter: 0.5,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay to wait before the given attempt, starting at 1.
+    pub fn delay(&self, attempt: u32, rng: &mut impl Rng) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        // clamped, as `from_secs_f64` panics on negative or NaN values from a bad multiplier
+        let delay = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .max(0.0)
+            .min(self.max_delay.as_secs_f64());
+        let jitter = self.jitter.max(0.0).min(1.0) * rng.gen::<f64>();
+        Duration::from_secs_f64(delay * (1.0 - jitter))
+    }
+
+    pub(crate) fn gave_up(&self, attempt: u32) -> bool {
+        self.max_attempts.map_or(false, |max| attempt > max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay() {
+        let mut rng = rand::thread_rng();
+        let policy = ReconnectPolicy {
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1, &mut rng), Duration::from_secs(1));
+        assert_eq!(policy.delay(2, &mut rng), Duration::from_secs(2));
+        assert_eq!(policy.delay(4, &mut rng), Duration::from_secs(8));
+        assert_eq!(policy.delay(100, &mut rng), policy.max_delay);
+        assert_eq!(policy.delay(u32::MAX, &mut rng), policy.max_delay);
+
+        for multiplier in &[-2.0, f64::NAN, f64::INFINITY] {
+            let policy = ReconnectPolicy {
+                multiplier: *multiplier,
+                jitter: f64::NAN,
+                ..Default::default()
+            };
+            for attempt in 1..4 {
+                assert!(policy.delay(attempt, &mut rng) <= policy.max_delay);
+            }
+        }
+
+        let policy = ReconnectPolicy::default();
+        for attempt in 1..20 {
+            let delay = policy.delay(attempt, &mut rng);
+            let max = ReconnectPolicy {
+                jitter: 0.0,
+                ..policy
+            }
+            .delay(attempt, &mut rng);
+            assert!(delay <= max && delay >= max / 2);
+        }
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        let policy = ReconnectPolicy {
+            max_attempts: Some(3),
+            ..Default::default()
+        };
+        assert!(!policy.gave_up(3));
+        assert!(policy.gave_up(4));
+        assert!(!ReconnectPolicy::default().gave_up(u32::MAX));
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use libsignal_service::prelude::{
+    phonenumber::{self, PhoneNumber},
+    protocol::{Fingerprint, IdentityKey},
+};
+
+use crate::Error;
+
+/// Version of the fingerprints computed from phone numbers, as displayed by the official clients
+const FINGERPRINT_VERSION: u32 = 1;
+const FINGERPRINT_ITERATIONS: u32 = 5200;
+
+/// Safety number of a conversation, computed from our identity key and the one of the contact.
+///
+/// Both sides get the same number, which can be compared by reading it aloud, or by scanning the
+/// QR code of the other side (see [SafetyNumber::scannable]).
+pub struct SafetyNumber(Fingerprint);
+
+impl SafetyNumber {
+    pub(crate) fn new(
+        local: (&PhoneNumber, &IdentityKey),
+        remote: (&PhoneNumber, &IdentityKey),
+    ) -> Result<Self, Error> {
+        let e164 = |phone_number: &PhoneNumber| {
+            phone_number
+                .format()
+                .mode(phonenumber::Mode::E164)
+                .to_string()
+        };
+        Ok(Self(Fingerprint::new(
+            FINGERPRINT_VERSION,
+            FINGERPRINT_ITERATIONS,
+            e164(local.0).as_bytes(),
+            local.1,
+            e164(remote.0).as_bytes(),
+            remote.1,
+        )?))
+    }
+
+    /// The 60 digits of the safety number.
+    pub fn digits(&self) -> Result<String, Error> {
+        Ok(self.0.display_string()?)
+    }
+
+    /// Content of the QR code to be scanned by the contact.
+    pub fn scannable(&self) -> Result<Vec<u8>, Error> {
+        Ok(self.0.scannable.serialize()?)
+    }
+
+    /// Whether the QR code scanned from the device of the contact matches this safety number.
+    pub fn matches_scanned(&self, scanned: &[u8]) -> Result<bool, Error> {
+        Ok(self.0.scannable.compare(scanned)?)
+    }
+}
+
+impl fmt::Debug for SafetyNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SafetyNumber")
+            .field(&self.digits().map_err(|_| fmt::Error)?)
+            .finish()
+    }
+}
+
+impl fmt::Display for SafetyNumber {
+    /// Formats the digits in 12 groups of 5, like the official clients.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = self.digits().map_err(|_| fmt::Error)?;
+        for (i, chunk) in digits.as_bytes().chunks(5).enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            f.write_str(std::str::from_utf8(chunk).map_err(|_| fmt::Error)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libsignal_service::prelude::protocol::KeyPair;
+
+    use super::*;
+
+    #[test]
+    fn test_both_sides_match() {
+        let mut rng = rand::thread_rng();
+        let alice = (
+            "+33612345678".parse::<PhoneNumber>().unwrap(),
+            IdentityKey::new(KeyPair::generate(&mut rng).public_key),
+        );
+        let bob = (
+            "+14155550100".parse::<PhoneNumber>().unwrap(),
+            IdentityKey::new(KeyPair::generate(&mut rng).public_key),
+        );
+
+        let from_alice = SafetyNumber::new((&alice.0, &alice.1), (&bob.0, &bob.1)).unwrap();
+        let from_bob = SafetyNumber::new((&bob.0, &bob.1), (&alice.0, &alice.1)).unwrap();
+        assert_eq!(from_alice.digits().unwrap(), from_bob.digits().unwrap());
+        assert_eq!(from_alice.digits().unwrap().len(), 60);
+        assert_eq!(from_alice.to_string().split(' ').count(), 12);
+        assert!(from_alice
+            .matches_scanned(&from_bob.scannable().unwrap())
+            .unwrap());
+    }
+}